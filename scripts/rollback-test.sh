@@ -0,0 +1,41 @@
+#!/bin/sh
+# Checks that an update failing its health check is rolled back. Runs against a device flashed with
+# `cargo run --release` and reachable at the given address: installs a build that never reaches the
+# Online milestone through /ota, then waits for the device to come back on its previous image with a
+# failed verdict in the journal.
+#
+# Needs curl, jq and espflash.
+set -eu
+
+host=${1:?usage: scripts/rollback-test.sh <device address>}
+image=target/rollback-test.bin
+# Verification gives up after 180 s, leave time for the two reboots
+deadline=$(($(date +%s) + 300))
+
+partition=$(curl -fsS "http://$host/version" | jq -r .partition)
+# Only verdicts after the last one so far count, `since` left out when there is none
+since=$(curl -fsS "http://$host/events?type=ota_verdict" | jq -r 'map("&since=\(.seq)") | last // ""')
+echo "Running from $partition"
+
+cargo build --release --features fail-health
+espflash save-image --chip esp32c3 target/riscv32imc-esp-espidf/release/cobitis-esp32c3 "$image"
+curl -fsS --data-binary "@$image" "http://$host/ota"
+echo
+
+while [ "$(date +%s)" -lt "$deadline" ]; do
+    sleep 10
+    current=$(curl -fsS --max-time 5 "http://$host/version" | jq -r .partition) || continue
+    verdict=$(curl -fsS --max-time 5 "http://$host/events?type=ota_verdict$since" | jq -c 'last') \
+        || continue
+    if [ "$current" = "$partition" ] && [ "$verdict" != null ]; then
+        echo "Back on $current, verdict $verdict"
+        echo "$verdict" | jq -e '.valid == false and any(.milestones[]; .milestone == "online" and .uptime_ms == null)' \
+            >/dev/null || { echo "FAIL: unexpected verdict" >&2; exit 1; }
+        echo PASS
+        exit 0
+    fi
+    echo "Waiting, running from ${current:-?}"
+done
+
+echo "FAIL: no rollback to $partition within the deadline" >&2
+exit 1
@@ -0,0 +1,659 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_svc::hal::{
+    delay::FreeRtos,
+    gpio::{self, AnyInputPin, InterruptType, PinDriver, Pull},
+    i2c::I2cError,
+};
+use serde::Serialize;
+
+use crate::nvs;
+
+/// I2C address of the ADS1115 with ADDR tied to GND.
+pub(crate) const ADDRESS: u8 = 0x48;
+
+const MAX_VOLTAGE: f32 = 4.096;
+const MAX_RAW_VALUE: f32 = 32767.0;
+
+const DEFAULT_SPS: u32 = 128;
+const BURST_SPS: u32 = 860;
+const MAX_READY_POLLS: u32 = 20;
+const DEFAULT_BURST_SAMPLES: u32 = 16;
+// A burst this long already holds the bus for about a third of a second
+const MAX_BURST_SAMPLES: u32 = 256;
+
+// Registers, see the ADS1115 datasheet section 8.6
+const CONVERSION: u8 = 0x00;
+const CONFIG: u8 = 0x01;
+const LO_THRESH: u8 = 0x02;
+const HI_THRESH: u8 = 0x03;
+
+// Fields of the config register. OS starts a one-shot conversion when written and reads as set once it is done
+const OS: u16 = 0x8000;
+const MUX: u16 = 0x7000;
+const MUX_A0: u16 = 0x4000;
+const MUX_A3: u16 = 0x7000;
+const PGA_4_096V: u16 = 0x0200;
+const MODE_SINGLE_SHOT: u16 = 0x0100;
+const DATA_RATE: u16 = 0x00e0;
+const COMPARATOR_DISABLED: u16 = 0x0003;
+// Thresholds turning ALERT/RDY into a conversion-ready signal, pulsed low after each conversion
+const READY_LO_THRESH: u16 = 0x0000;
+const READY_HI_THRESH: u16 = 0x8000;
+
+/// GPIO wired to ALERT/RDY, as set by `board.pin.adc_ready`.
+pub(crate) type ReadyPin = PinDriver<'static, AnyInputPin, gpio::Input>;
+
+/// Acquisition strategy, as set by `sensor.adc.mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    OneShot,
+    Continuous,
+    Burst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    Tds,
+    Supply,
+}
+
+impl Input {
+    fn mux(self) -> u16 {
+        match self {
+            Self::Tds => MUX_A0,
+            Self::Supply => MUX_A3,
+        }
+    }
+}
+
+/// Per-strategy timing, so the latency cost of each acquisition strategy can be compared.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct Timing {
+    pub strategy: &'static str,
+    pub reads: u32,
+    pub last_us: u64,
+    pub max_us: u64,
+    pub total_us: u64,
+    /// Conversions that were not ready after the calibrated wait and had to be polled for
+    pub ready_fallbacks: u32,
+}
+
+static TIMING: Mutex<Option<Timing>> = Mutex::new(None);
+static READY_FALLBACKS: AtomicU32 = AtomicU32::new(0);
+// Falling edges on ALERT/RDY, counted from the GPIO interrupt
+static READY_PULSES: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn timing() -> Option<Timing> {
+    let mut timing = (*TIMING.lock().unwrap())?;
+    timing.ready_fallbacks = READY_FALLBACKS.load(Ordering::Relaxed);
+    Some(timing)
+}
+
+/// Owns the ADS1115 and keeps a copy of its config register, so changing a field or starting a conversion
+/// is a single write instead of a read, modify and write on the bus.
+struct Device<I2C> {
+    i2c: I2C,
+    /// As last written, without OS
+    config: u16,
+    /// Armed for one pulse at a time, continuous conversions have no other ready flag since OS reads as
+    /// busy throughout.
+    ready: Option<ReadyPin>,
+}
+
+impl<I2C> Device<I2C>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    fn new(i2c: I2C, ready: Option<ReadyPin>) -> Self {
+        Self {
+            i2c,
+            // The power-on value, with PGA at 2.048 V and the multiplexer on A0 against A1
+            config: 0x0583,
+            ready,
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u16) -> anyhow::Result<()> {
+        let [msb, lsb] = value.to_be_bytes();
+        self.i2c
+            .write(ADDRESS, &[register, msb, lsb])
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    fn read_register(&mut self, register: u8) -> anyhow::Result<u16> {
+        let mut value = [0; 2];
+        self.i2c
+            .write_read(ADDRESS, &[register], &mut value)
+            .map_err(|e| anyhow!("{e:?}"))?;
+
+        Ok(u16::from_be_bytes(value))
+    }
+
+    /// Replaces the `mask` bits of the config register with `bits`, starting a one-shot conversion if `start`.
+    fn set(&mut self, mask: u16, bits: u16, start: bool) -> anyhow::Result<()> {
+        let config = (self.config & !mask) | (bits & mask);
+        self.write_register(CONFIG, if start { config | OS } else { config })?;
+        self.config = config;
+
+        Ok(())
+    }
+
+    /// Writes the whole config register, powered down on the TDS input, and turns ALERT/RDY into a ready
+    /// signal if it is wired.
+    fn configure(&mut self, sps: u32) -> anyhow::Result<()> {
+        let config = Input::Tds.mux() | PGA_4_096V | MODE_SINGLE_SHOT | data_rate(sps)?;
+        if self.ready.is_none() {
+            return self.set(u16::MAX, config | COMPARATOR_DISABLED, false);
+        }
+
+        self.write_register(LO_THRESH, READY_LO_THRESH)?;
+        self.write_register(HI_THRESH, READY_HI_THRESH)?;
+        // With the queue bits clear the comparator asserts after every conversion
+        self.set(u16::MAX, config, false)
+    }
+
+    fn set_data_rate(&mut self, sps: u32) -> anyhow::Result<()> {
+        self.set(DATA_RATE, data_rate(sps)?, false)
+    }
+
+    /// Stops converting after the conversion in flight, if any.
+    fn power_down(&mut self) -> anyhow::Result<()> {
+        self.set(MODE_SINGLE_SHOT, MODE_SINGLE_SHOT, false)
+    }
+
+    /// The latest finished conversion.
+    fn read_latest(&mut self) -> anyhow::Result<i16> {
+        Ok(self.read_register(CONVERSION)? as i16)
+    }
+
+    /// Runs a single conversion, sleeping through it instead of polling the config register on the bus.
+    fn read_one_shot(&mut self, input: Input, sps: u32) -> anyhow::Result<i16> {
+        self.set(MUX | MODE_SINGLE_SHOT, input.mux() | MODE_SINGLE_SHOT, true)?;
+        FreeRtos::delay_ms(conversion_ms(sps));
+
+        // Normally ready on the first check, poll in short steps only if the oscillator runs slow
+        for poll in 0..MAX_READY_POLLS {
+            if self.read_register(CONFIG)? & OS != 0 {
+                return self.read_latest();
+            }
+            if poll == 0 {
+                READY_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            }
+            FreeRtos::delay_ms(1);
+        }
+
+        Err(anyhow!("ADS1115 conversion timeout"))
+    }
+
+    /// Converts `input` continuously.
+    fn select(&mut self, input: Input) -> anyhow::Result<()> {
+        self.set(MUX | MODE_SINGLE_SHOT, input.mux(), false)
+    }
+
+    /// Waits for a continuous conversion to finish after this call, by the ALERT/RDY pulse when the pin is
+    /// wired and by the nominal conversion time otherwise.
+    fn wait_ready(&mut self, sps: u32) -> anyhow::Result<()> {
+        let Some(pin) = self.ready.as_mut() else {
+            FreeRtos::delay_ms(conversion_ms(sps));
+            return Ok(());
+        };
+
+        // The driver disables the interrupt after each one, so only pulses from here on are counted
+        let seen = READY_PULSES.load(Ordering::Acquire);
+        pin.enable_interrupt()?;
+        FreeRtos::delay_ms(conversion_ms(sps));
+
+        for poll in 0..MAX_READY_POLLS {
+            if READY_PULSES.load(Ordering::Acquire) != seen {
+                return Ok(());
+            }
+            if poll == 0 {
+                READY_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            }
+            FreeRtos::delay_ms(1);
+        }
+
+        Err(anyhow!("No ADS1115 ready pulse, check board.pin.adc_ready"))
+    }
+}
+
+trait Acquire {
+    const NAME: &'static str;
+
+    fn read<I2C>(&mut self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>;
+}
+
+/// Starts a single conversion per read and lets the ADC power down in between.
+struct OneShot {
+    sps: u32,
+    selected: Option<Input>,
+}
+
+impl Acquire for OneShot {
+    const NAME: &'static str = "oneshot";
+
+    fn read<I2C>(&mut self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+    {
+        // The first conversion after switching the multiplexer still carries charge from the previous input,
+        // so it is discarded and only the second one is used
+        if self.selected != Some(input) {
+            device.read_one_shot(input, self.sps)?;
+            self.selected = Some(input);
+        }
+
+        device.read_one_shot(input, self.sps)
+    }
+}
+
+/// Keeps the ADC free-running and reads the latest finished conversion.
+struct Continuous {
+    sps: u32,
+    selected: Option<Input>,
+}
+
+impl Acquire for Continuous {
+    const NAME: &'static str = "continuous";
+
+    fn read<I2C>(&mut self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+    {
+        if self.selected != Some(input) {
+            device.select(input)?;
+            self.selected = Some(input);
+
+            // Let the conversion in flight and the first one on the new input pass
+            device.wait_ready(self.sps)?;
+            device.wait_ready(self.sps)?;
+        }
+
+        device.read_latest()
+    }
+}
+
+/// Switches to continuous mode at the highest rate, averages a burst of samples and powers down again.
+struct Burst {
+    samples: u32,
+    /// Restored after the burst.
+    sps: u32,
+}
+
+impl Burst {
+    fn average<I2C>(&self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+    {
+        device.select(input)?;
+        device.wait_ready(BURST_SPS)?;
+        device.wait_ready(BURST_SPS)?;
+
+        let mut sum = 0_i32;
+        for _ in 0..self.samples {
+            device.wait_ready(BURST_SPS)?;
+            sum += i32::from(device.read_latest()?);
+        }
+
+        Ok((sum / self.samples as i32) as i16)
+    }
+}
+
+impl Acquire for Burst {
+    const NAME: &'static str = "burst";
+
+    fn read<I2C>(&mut self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+    {
+        device.set_data_rate(BURST_SPS)?;
+        let average = self.average(device, input);
+
+        // Back to power-down at the configured rate, also after a failed burst
+        device.power_down()?;
+        device.set_data_rate(self.sps)?;
+
+        average
+    }
+}
+
+enum Strategy {
+    OneShot(OneShot),
+    Continuous(Continuous),
+    Burst(Burst),
+}
+
+impl Strategy {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::OneShot(_) => OneShot::NAME,
+            Self::Continuous(_) => Continuous::NAME,
+            Self::Burst(_) => Burst::NAME,
+        }
+    }
+
+    // Forgets the selected input, for a device that may have been power cycled or replaced. Configuring it
+    // leaves the multiplexer on the TDS input, so a one-shot TDS read needs no conversion discarded unless
+    // the supply is read in between
+    fn reset(&mut self) {
+        match self {
+            Self::OneShot(s) => s.selected = Some(Input::Tds),
+            Self::Continuous(s) => s.selected = None,
+            Self::Burst(_) => {}
+        }
+    }
+
+    fn read<I2C>(&mut self, device: &mut Device<I2C>, input: Input) -> anyhow::Result<i16>
+    where
+        I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+    {
+        match self {
+            Self::OneShot(s) => s.read(device, input),
+            Self::Continuous(s) => s.read(device, input),
+            Self::Burst(s) => s.read(device, input),
+        }
+    }
+}
+
+pub(crate) struct Adc<I2C> {
+    device: Device<I2C>,
+    strategy: Strategy,
+    sps: u32,
+    timing: Timing,
+}
+
+impl<I2C> Adc<I2C>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    /// Reads the ADC settings, leaving the device itself alone until `configure` is called. `ready` is the
+    /// GPIO wired to ALERT/RDY, if any.
+    pub fn new(i2c: I2C, ready: Option<ReadyPin>) -> anyhow::Result<Self> {
+        let sps = nvs::get_parsed_or("sensor.adc.sps", DEFAULT_SPS, parse_sps)?;
+        let strategy = match nvs::get_parsed_or("sensor.adc.mode", Mode::OneShot, parse_mode)? {
+            Mode::OneShot => Strategy::OneShot(OneShot { sps, selected: None }),
+            Mode::Continuous => Strategy::Continuous(Continuous { sps, selected: None }),
+            Mode::Burst => Strategy::Burst(Burst {
+                samples: nvs::get_parsed_or("sensor.adc.burst_samples", DEFAULT_BURST_SAMPLES, parse_burst_samples)?,
+                sps,
+            }),
+        };
+
+        let timing = Timing {
+            strategy: strategy.name(),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            device: Device::new(i2c, ready.map(listen).transpose()?),
+            strategy,
+            sps,
+            timing,
+        })
+    }
+
+    /// Sets the range and data rate, which also probes whether the ADS1115 answers at all.
+    pub fn configure(&mut self) -> anyhow::Result<()> {
+        self.strategy.reset();
+        self.device.configure(self.sps)
+    }
+
+    /// Reads the voltage on an input using the configured acquisition strategy.
+    pub fn read_voltage(&mut self, input: Input) -> anyhow::Result<f32> {
+        let started = Instant::now();
+        let raw_value = self.strategy.read(&mut self.device, input)?;
+        self.record_timing(started.elapsed());
+
+        Ok(f32::from(raw_value) * MAX_VOLTAGE / MAX_RAW_VALUE)
+    }
+
+    fn record_timing(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_micros() as u64;
+
+        let timing = &mut self.timing;
+        timing.reads = timing.reads.wrapping_add(1);
+        timing.last_us = elapsed;
+        timing.max_us = timing.max_us.max(elapsed);
+        timing.total_us = timing.total_us.wrapping_add(elapsed);
+
+        *TIMING.lock().unwrap() = Some(*timing);
+    }
+}
+
+// Counts the pulses on ALERT/RDY, an open-drain output
+fn listen(mut pin: ReadyPin) -> anyhow::Result<ReadyPin> {
+    pin.set_pull(Pull::Up)?;
+    pin.set_interrupt_type(InterruptType::NegEdge)?;
+    // The callback runs in the ISR, it only touches an atomic
+    unsafe {
+        pin.subscribe(|| {
+            READY_PULSES.fetch_add(1, Ordering::Release);
+        })?;
+    }
+
+    Ok(pin)
+}
+
+/// Parses `sensor.adc.sps`, one of the data rates the ADS1115 supports.
+pub(crate) fn parse_sps(v: &str) -> anyhow::Result<u32> {
+    let sps = v.parse()?;
+    data_rate(sps)?;
+
+    Ok(sps)
+}
+
+/// Parses `sensor.adc.mode`.
+pub(crate) fn parse_mode(v: &str) -> anyhow::Result<Mode> {
+    match v {
+        "oneshot" => Ok(Mode::OneShot),
+        "continuous" => Ok(Mode::Continuous),
+        "burst" => Ok(Mode::Burst),
+        _ => Err(anyhow!("ADC mode must be oneshot, continuous or burst: {v}")),
+    }
+}
+
+/// Parses `sensor.adc.burst_samples`.
+pub(crate) fn parse_burst_samples(v: &str) -> anyhow::Result<u32> {
+    let samples = v.parse()?;
+    if !(1..=MAX_BURST_SAMPLES).contains(&samples) {
+        return Err(anyhow!(
+            "Burst samples must be within 1..={MAX_BURST_SAMPLES}: {samples}"
+        ));
+    }
+
+    Ok(samples)
+}
+
+// The DR field of the config register
+fn data_rate(sps: u32) -> anyhow::Result<u16> {
+    let index = match sps {
+        8 => 0,
+        16 => 1,
+        32 => 2,
+        64 => 3,
+        128 => 4,
+        250 => 5,
+        475 => 6,
+        860 => 7,
+        _ => return Err(anyhow!("Unsupported ADC data rate: {sps}")),
+    };
+
+    Ok(index << 5)
+}
+
+/// Duration of a single conversion at the given data rate, rounded up.
+fn conversion_ms(sps: u32) -> u32 {
+    1000_u32.div_ceil(sps) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    // Starting a conversion is a single config write from the copy in memory. Collecting it takes one
+    // status check and the conversion read, any more means the bus was polled.
+    const TRANSACTIONS_PER_CONVERSION: usize = 3;
+
+    fn i2c(raw_value: i16) -> mock::I2c {
+        mock::I2c::new(&[ADDRESS]).with_register(ADDRESS, CONVERSION, &raw_value.to_be_bytes())
+    }
+
+    fn written(i2c: &mock::I2c) -> Vec<&[u8]> {
+        i2c.transactions.iter().map(|t| t.written.as_slice()).collect()
+    }
+
+    #[test]
+    fn configuring_writes_the_whole_register_once() {
+        let mut configured = i2c(0);
+        Device::new(&mut configured, None).configure(128).unwrap();
+        assert_eq!(written(&configured), [[CONFIG, 0x43, 0x83]]);
+
+        let mut unsupported = i2c(0);
+        assert!(Device::new(&mut unsupported, None).configure(100).is_err());
+        assert!(unsupported.transactions.is_empty());
+
+        // Nothing answering is an error, not a device configured
+        let mut absent = mock::I2c::new(&[]);
+        assert!(Device::new(&mut absent, None).configure(128).is_err());
+    }
+
+    #[test]
+    fn fields_change_without_reading_the_register_back() {
+        let mut i2c = i2c(0);
+        {
+            let mut device = Device::new(&mut i2c, None);
+            device.configure(128).unwrap();
+            device.set_data_rate(860).unwrap();
+            device.select(Input::Supply).unwrap();
+            device.power_down().unwrap();
+        }
+
+        assert_eq!(
+            written(&i2c),
+            [
+                [CONFIG, 0x43, 0x83],
+                [CONFIG, 0x43, 0xe3],
+                [CONFIG, 0x72, 0xe3],
+                [CONFIG, 0x73, 0xe3],
+            ]
+        );
+        assert!(i2c.transactions.iter().all(|t| t.read == 0));
+    }
+
+    #[test]
+    fn one_shot_conversion_checks_ready_once() {
+        let mut i2c = i2c(0x4000);
+        assert_eq!(
+            Device::new(&mut i2c, None).read_one_shot(Input::Supply, 860).unwrap(),
+            0x4000
+        );
+
+        assert_eq!(i2c.transactions.len(), TRANSACTIONS_PER_CONVERSION);
+        assert!(i2c.transactions.iter().all(|t| t.address == ADDRESS));
+        // The start with OS set on the power-on config, the single ready check and the result
+        let conversion = &i2c.transactions;
+        assert_eq!(conversion[0].written, [CONFIG, 0xf5, 0x83]);
+        assert_eq!(
+            (conversion[1].written.as_slice(), conversion[1].read),
+            (&[CONFIG][..], 2)
+        );
+        assert_eq!(
+            (conversion[2].written.as_slice(), conversion[2].read),
+            (&[CONVERSION][..], 2)
+        );
+    }
+
+    #[test]
+    fn one_shot_discards_only_the_first_conversion_after_switching() {
+        let mut i2c = i2c(-0x100);
+        {
+            let mut device = Device::new(&mut i2c, None);
+            let mut strategy = OneShot {
+                sps: 860,
+                selected: None,
+            };
+
+            assert_eq!(strategy.read(&mut device, Input::Supply).unwrap(), -0x100);
+            assert_eq!(strategy.read(&mut device, Input::Supply).unwrap(), -0x100);
+        }
+
+        assert_eq!(i2c.transactions.len(), 3 * TRANSACTIONS_PER_CONVERSION);
+    }
+
+    #[test]
+    fn one_shot_converts_once_per_read_without_the_supply_monitor() {
+        let mut monitor_off = i2c(0x2000);
+        {
+            let mut device = Device::new(&mut monitor_off, None);
+            let mut strategy = Strategy::OneShot(OneShot {
+                sps: 860,
+                selected: None,
+            });
+            strategy.reset();
+            device.configure(860).unwrap();
+
+            for _ in 0..3 {
+                assert_eq!(strategy.read(&mut device, Input::Tds).unwrap(), 0x2000);
+            }
+        }
+        assert_eq!(monitor_off.transactions.len(), 1 + 3 * TRANSACTIONS_PER_CONVERSION);
+
+        // With the monitor on, switching to the supply and back discards a conversion each way
+        let mut monitor_on = i2c(0x2000);
+        {
+            let mut device = Device::new(&mut monitor_on, None);
+            let mut strategy = Strategy::OneShot(OneShot {
+                sps: 860,
+                selected: None,
+            });
+            strategy.reset();
+            device.configure(860).unwrap();
+
+            strategy.read(&mut device, Input::Supply).unwrap();
+            strategy.read(&mut device, Input::Tds).unwrap();
+        }
+        assert_eq!(monitor_on.transactions.len(), 1 + 4 * TRANSACTIONS_PER_CONVERSION);
+    }
+
+    #[test]
+    fn burst_restores_the_configured_rate() {
+        let mut i2c = i2c(0x1000);
+        {
+            let mut device = Device::new(&mut i2c, None);
+            device.configure(128).unwrap();
+            let mut strategy = Burst { samples: 4, sps: 128 };
+            assert_eq!(strategy.read(&mut device, Input::Supply).unwrap(), 0x1000);
+        }
+
+        let written = written(&i2c);
+        // Powered down at the burst rate, then back to the configured one
+        assert_eq!(
+            written[written.len() - 2..],
+            [[CONFIG, 0x73, 0xe3], [CONFIG, 0x73, 0x83]]
+        );
+        assert_eq!(written.iter().filter(|&&w| w == [CONVERSION]).count(), 4);
+    }
+
+    #[test]
+    fn conversion_time_is_rounded_up() {
+        assert_eq!(conversion_ms(8), 126);
+        assert_eq!(conversion_ms(128), 9);
+        assert_eq!(conversion_ms(860), 3);
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Compares the readings against limits set in NVS, so water running too warm or too hard shows on the
+// display and at `/alerts` before the fish suffer from it.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{
+    journal,
+    measurements::{self, Sensor},
+    nvs, system,
+};
+
+// How often the worker looks for a new measurement, well below the measurement interval
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// An alarm clears only once the reading is back inside its limit by this much, so it doesn't flap
+const TEMPERATURE_HYSTERESIS: f32 = 0.3;
+const TDS_HYSTERESIS: f32 = 10.0;
+// A reading has to stay past a limit this long to raise its alarm, a single odd one never does
+const MIN_DURATION: u32 = 60;
+const MAX_MIN_DURATION: u32 = 24 * 60 * 60;
+
+/// Which side of its limits a reading left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Level {
+    Low,
+    High,
+}
+
+/// How one limit holds off its alarm, set per limit with e.g. `alerts.tds.max.hysteresis`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub(crate) struct Debounce {
+    /// How far back inside the limit a reading has to get to clear the alarm.
+    pub hysteresis: f32,
+    /// How long a reading has to stay past the limit to raise the alarm.
+    pub min_duration_s: u32,
+}
+
+/// Limits and alarm state of one sensor, served at `/alerts`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct Alert {
+    pub sensor: &'static str,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub min_debounce: Debounce,
+    pub max_debounce: Debounce,
+    pub alarm: Option<Level>,
+    /// A limit the reading is past, but not yet for its minimum duration.
+    pub pending: Option<Level>,
+    // Uptime in ms when the reading went past the pending limit
+    #[serde(skip)]
+    pending_since: Option<i64>,
+    /// The latest reading checked against the limits.
+    pub value: Option<f32>,
+    /// When the alarm was raised, in ms since the epoch.
+    pub since: Option<i64>,
+}
+
+static ALERTS: Mutex<Vec<Alert>> = Mutex::new(Vec::new());
+
+/// Limits and alarms of all sensors, in the order of [`Sensor::ALL`].
+pub(crate) fn get() -> Vec<Alert> {
+    ALERTS.lock().unwrap().clone()
+}
+
+/// The alarm of `sensor`, if its reading is out of range.
+pub(crate) fn alarm(sensor: Sensor) -> Option<Level> {
+    let alerts = ALERTS.lock().unwrap();
+    alerts
+        .iter()
+        .find(|alert| alert.sensor == sensor.name())
+        .and_then(|alert| alert.alarm)
+}
+
+/// Whether any reading is out of range.
+pub(crate) fn active() -> bool {
+    ALERTS.lock().unwrap().iter().any(|alert| alert.alarm.is_some())
+}
+
+/// The settings holding the lower and upper limit of `sensor`, e.g. `alerts.tds.max`.
+pub(crate) fn settings(sensor: Sensor) -> [&'static str; 2] {
+    match sensor {
+        Sensor::Temperature => ["alerts.temperature.min", "alerts.temperature.max"],
+        Sensor::Tds => ["alerts.tds.min", "alerts.tds.max"],
+    }
+}
+
+/// The settings holding the hysteresis and minimum duration of the lower and upper limit of `sensor`, e.g.
+/// `alerts.tds.max.hysteresis` and `alerts.tds.max.min_duration`.
+pub(crate) fn debounce_settings(sensor: Sensor) -> [[&'static str; 2]; 2] {
+    match sensor {
+        Sensor::Temperature => [
+            [
+                "alerts.temperature.min.hysteresis",
+                "alerts.temperature.min.min_duration",
+            ],
+            [
+                "alerts.temperature.max.hysteresis",
+                "alerts.temperature.max.min_duration",
+            ],
+        ],
+        Sensor::Tds => [
+            ["alerts.tds.min.hysteresis", "alerts.tds.min.min_duration"],
+            ["alerts.tds.max.hysteresis", "alerts.tds.max.min_duration"],
+        ],
+    }
+}
+
+fn default_hysteresis(sensor: Sensor) -> f32 {
+    match sensor {
+        Sensor::Temperature => TEMPERATURE_HYSTERESIS,
+        Sensor::Tds => TDS_HYSTERESIS,
+    }
+}
+
+/// Parses a hysteresis, in the unit of the sensor.
+pub(crate) fn parse_hysteresis(value: &str) -> anyhow::Result<f32> {
+    let value = value.trim();
+    let hysteresis: f32 = value.parse()?;
+    if !hysteresis.is_finite() || hysteresis < 0.0 {
+        return Err(anyhow!("Hysteresis must be a finite number of at least 0: {value}"));
+    }
+    Ok(hysteresis)
+}
+
+/// Parses a minimum duration in seconds, 0 raising the alarm with the first reading past the limit.
+pub(crate) fn parse_min_duration(value: &str) -> anyhow::Result<u32> {
+    let seconds: u32 = value.trim().parse()?;
+    if seconds > MAX_MIN_DURATION {
+        return Err(anyhow!("Minimum duration must be at most {MAX_MIN_DURATION} seconds"));
+    }
+    Ok(seconds)
+}
+
+/// Parses a limit, an empty value meaning there is none.
+pub(crate) fn parse_limit(value: &str) -> anyhow::Result<Option<f32>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let limit: f32 = value.parse()?;
+    if !limit.is_finite() {
+        return Err(anyhow!("Limit must be a finite number: {value}"));
+    }
+    Ok(Some(limit))
+}
+
+pub(crate) fn init() -> anyhow::Result<()> {
+    task::block_in_place(reload)
+}
+
+/// Re-reads the limits, so a change made through `/config` applies without a reboot. An alarm whose
+/// limit was lifted clears with the next measurement.
+pub(crate) fn reload() -> anyhow::Result<()> {
+    // All are parsed before any is applied, so an invalid value changes nothing
+    let mut limits = Vec::with_capacity(Sensor::ALL.len());
+    for sensor in Sensor::ALL {
+        let [min, max] = settings(sensor).map(|name| {
+            let value = nvs::get_opt(name)?;
+            value.as_deref().map_or(Ok(None), parse_limit)
+        });
+        let (min, max) = (min?, max?);
+        let [min_debounce, max_debounce] = debounce_settings(sensor).map(|[hysteresis, min_duration]| {
+            let hysteresis = nvs::get_opt(hysteresis)?
+                .as_deref()
+                .map_or(Ok(default_hysteresis(sensor)), parse_hysteresis)?;
+            let min_duration_s = nvs::get_opt(min_duration)?
+                .as_deref()
+                .map_or(Ok(MIN_DURATION), parse_min_duration)?;
+            anyhow::Ok(Debounce {
+                hysteresis,
+                min_duration_s,
+            })
+        });
+        let (min_debounce, max_debounce) = (min_debounce?, max_debounce?);
+        if let (Some(min), Some(max)) = (min, max) {
+            if min >= max {
+                warn!(
+                    "Lower {} limit {min} is not below the upper one {max}, its alarm never clears",
+                    sensor.name()
+                );
+            }
+        }
+        limits.push((min, max, min_debounce, max_debounce));
+    }
+
+    let mut alerts = ALERTS.lock().unwrap();
+    alerts.resize_with(Sensor::ALL.len(), Alert::default);
+    for ((sensor, alert), (min, max, min_debounce, max_debounce)) in
+        Sensor::ALL.into_iter().zip(alerts.iter_mut()).zip(limits)
+    {
+        alert.sensor = sensor.name();
+        alert.min = min;
+        alert.max = max;
+        alert.min_debounce = min_debounce;
+        alert.max_debounce = max_debounce;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut checked_seq = None;
+
+    loop {
+        interval.tick().await;
+
+        // Warm-up readings are still settling, an alarm on them would be a false one
+        let Some(values) = measurements::get().filter(|v| !v.warming_up && checked_seq != Some(v.seq)) else {
+            continue;
+        };
+        checked_seq = Some(values.seq);
+
+        task::block_in_place(|| update(&values));
+    }
+}
+
+fn update(values: &measurements::Values) {
+    let now = Utc::now().timestamp_millis();
+    let uptime_ms = system::uptime_ms();
+    let mut alerts = ALERTS.lock().unwrap();
+
+    for (sensor, alert) in Sensor::ALL.into_iter().zip(alerts.iter_mut()) {
+        let (value, enabled) = match sensor {
+            Sensor::Temperature => (values.temperature, values.temperature_enabled),
+            Sensor::Tds => (values.tds, values.tds_enabled),
+        };
+        // A sensor switched off says nothing about the water anymore, a failed read keeps the alarm as is
+        let previous = alert.alarm;
+        if !enabled {
+            alert.alarm = None;
+            alert.pending = None;
+            alert.pending_since = None;
+        } else if value.is_finite() {
+            step(alert, value, uptime_ms);
+        } else {
+            continue;
+        }
+        alert.value = Some(value).filter(|v| v.is_finite());
+
+        // Only debounced transitions get any further than `/alerts`
+        let alarm = alert.alarm;
+        if alarm == previous {
+            continue;
+        }
+        alert.since = alarm.map(|_| now);
+
+        // A raised alarm always has the limit it crossed
+        match alarm {
+            Some(Level::High) => warn!(
+                "Alert: {} {value} above {}",
+                sensor.name(),
+                alert.max.unwrap_or_default()
+            ),
+            Some(Level::Low) => warn!(
+                "Alert: {} {value} below {}",
+                sensor.name(),
+                alert.min.unwrap_or_default()
+            ),
+            None => info!("Alert cleared: {} back in range", sensor.name()),
+        }
+        journal::record(journal::Event::Alert {
+            sensor: sensor.name().to_owned(),
+            alarm,
+            value: alert.value,
+        });
+    }
+}
+
+/// Moves the alarm of `alert` on with a reading of `value` taken at `uptime_ms`. A limit has to be crossed
+/// for its minimum duration to raise its alarm, while clearing is immediate once past the hysteresis.
+fn step(alert: &mut Alert, value: f32, uptime_ms: i64) {
+    let debounce = |level| match level {
+        Level::Low => alert.min_debounce,
+        Level::High => alert.max_debounce,
+    };
+    let hysteresis = alert.alarm.map_or(0.0, |level| debounce(level).hysteresis);
+
+    let (alarm, pending) = match check(alert.alarm, value, alert.min, alert.max, hysteresis) {
+        None => (None, None),
+        Some(level) if alert.alarm == Some(level) => (Some(level), None),
+        Some(level) => {
+            // The clock starts at the first reading past the limit, and again after any reading back inside
+            let since = alert
+                .pending
+                .zip(alert.pending_since)
+                .filter(|&(pending, _)| pending == level)
+                .map_or(uptime_ms, |(_, since)| since);
+            if uptime_ms - since >= i64::from(debounce(level).min_duration_s) * 1000 {
+                (Some(level), None)
+            } else {
+                (None, Some((level, since)))
+            }
+        }
+    };
+    alert.alarm = alarm;
+    alert.pending = pending.map(|(level, _)| level);
+    alert.pending_since = pending.map(|(_, since)| since);
+}
+
+/// The alarm for `value` given the one currently raised, which holds until the reading is back inside
+/// its limit by `hysteresis`.
+fn check(alarm: Option<Level>, value: f32, min: Option<f32>, max: Option<f32>, hysteresis: f32) -> Option<Level> {
+    match alarm {
+        _ if max.is_some_and(|max| value > max) => Some(Level::High),
+        _ if min.is_some_and(|min| value < min) => Some(Level::Low),
+        Some(Level::High) if max.is_some_and(|max| value > max - hysteresis) => Some(Level::High),
+        Some(Level::Low) if min.is_some_and(|min| value < min + hysteresis) => Some(Level::Low),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_parse_with_empty_meaning_none() {
+        assert_eq!(parse_limit("").unwrap(), None);
+        assert_eq!(parse_limit("  ").unwrap(), None);
+        assert_eq!(parse_limit(" 28.5 ").unwrap(), Some(28.5));
+        assert_eq!(parse_limit("-2").unwrap(), Some(-2.0));
+
+        for v in ["warm", "28,5", "inf", "-inf", "NaN"] {
+            assert!(parse_limit(v).is_err(), "{v:?}");
+        }
+    }
+
+    fn alert(min_duration_s: u32) -> Alert {
+        let debounce = Debounce {
+            hysteresis: 0.3,
+            min_duration_s,
+        };
+        Alert {
+            sensor: "temperature",
+            min: Some(24.0),
+            max: Some(28.0),
+            min_debounce: debounce,
+            max_debounce: debounce,
+            ..Default::default()
+        }
+    }
+
+    // Drives readings at uptimes in seconds through the state machine, returning the alarm after each
+    fn drive(alert: &mut Alert, readings: &[(f64, f32)]) -> Vec<Option<Level>> {
+        readings
+            .iter()
+            .map(|&(seconds, value)| {
+                step(alert, value, (seconds * 1000.0) as i64);
+                alert.alarm
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debounce_settings_parse() {
+        assert_eq!(parse_hysteresis(" 0.3 ").unwrap(), 0.3);
+        assert_eq!(parse_hysteresis("0").unwrap(), 0.0);
+        assert_eq!(parse_min_duration(" 60 ").unwrap(), 60);
+        assert_eq!(parse_min_duration("0").unwrap(), 0);
+        assert_eq!(parse_min_duration("86400").unwrap(), MAX_MIN_DURATION);
+
+        for v in ["", "-0.1", "inf", "NaN", "0,3"] {
+            assert!(parse_hysteresis(v).is_err(), "{v:?}");
+        }
+        for v in ["", "-1", "86401", "1.5", "1m"] {
+            assert!(parse_min_duration(v).is_err(), "{v:?}");
+        }
+    }
+
+    #[test]
+    fn alarms_raise_once_held_for_their_minimum_duration() {
+        let mut alert = alert(60);
+
+        let alarms = drive(&mut alert, &[(0.0, 28.5), (30.0, 28.5), (59.999, 28.6)]);
+        assert_eq!(alarms, [None, None, None]);
+        assert_eq!(alert.pending, Some(Level::High));
+
+        assert_eq!(drive(&mut alert, &[(60.0, 28.5)]), [Some(Level::High)]);
+        assert_eq!(alert.pending, None);
+    }
+
+    #[test]
+    fn readings_back_inside_restart_the_minimum_duration() {
+        let mut alert = alert(60);
+
+        // Inside by less than the hysteresis still counts, hysteresis only holds a raised alarm
+        let alarms = drive(&mut alert, &[(0.0, 28.5), (30.0, 27.9), (40.0, 28.5), (99.999, 28.5)]);
+        assert_eq!(alarms, [None; 4]);
+        assert_eq!(drive(&mut alert, &[(100.0, 28.5)]), [Some(Level::High)]);
+
+        // Past the other limit starts its own clock
+        let mut alert = self::alert(60);
+        let alarms = drive(&mut alert, &[(0.0, 28.5), (50.0, 23.5), (70.0, 23.5), (110.0, 23.5)]);
+        assert_eq!(alarms, [None, None, None, Some(Level::Low)]);
+    }
+
+    #[test]
+    fn alarms_clear_without_waiting() {
+        let mut alert = alert(60);
+
+        let alarms = drive(&mut alert, &[(0.0, 28.5), (60.0, 28.5), (61.0, 27.8), (62.0, 27.6)]);
+        assert_eq!(alarms, [None, Some(Level::High), Some(Level::High), None]);
+    }
+
+    #[test]
+    fn chatter_around_a_limit_raises_nothing() {
+        let mut alert = alert(60);
+
+        // A reading every 10 s flipping across the limit, as evaporation does over an evening
+        let readings: Vec<_> = (0..100)
+            .map(|i| (f64::from(i) * 10.0, if i % 2 == 0 { 28.1 } else { 27.9 }))
+            .collect();
+        assert!(drive(&mut alert, &readings).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn each_limit_has_its_own_hysteresis() {
+        let mut alert = alert(0);
+        alert.min_debounce.hysteresis = 1.0;
+
+        assert_eq!(drive(&mut alert, &[(0.0, 23.9), (1.0, 24.5)]), [Some(Level::Low); 2]);
+        assert_eq!(drive(&mut alert, &[(2.0, 25.0)]), [None]);
+
+        assert_eq!(
+            drive(&mut alert, &[(3.0, 28.1), (4.0, 27.8), (5.0, 27.6)]),
+            [Some(Level::High), Some(Level::High), None]
+        );
+    }
+
+    #[test]
+    fn alarms_raise_past_their_limit() {
+        let (min, max) = (Some(24.0), Some(28.0));
+
+        assert_eq!(check(None, 26.0, min, max, 0.3), None);
+        assert_eq!(check(None, 28.0, min, max, 0.3), None);
+        assert_eq!(check(None, 28.1, min, max, 0.3), Some(Level::High));
+        assert_eq!(check(None, 24.0, min, max, 0.3), None);
+        assert_eq!(check(None, 23.9, min, max, 0.3), Some(Level::Low));
+
+        assert_eq!(check(None, 1000.0, None, None, 0.3), None);
+        assert_eq!(check(None, 1000.0, min, None, 0.3), None);
+    }
+
+    #[test]
+    fn alarms_clear_only_past_the_hysteresis() {
+        let (min, max) = (Some(24.0), Some(28.0));
+
+        assert_eq!(check(Some(Level::High), 27.9, min, max, 0.3), Some(Level::High));
+        assert_eq!(check(Some(Level::High), 27.8, min, max, 0.3), Some(Level::High));
+        assert_eq!(check(Some(Level::High), 27.6, min, max, 0.3), None);
+
+        assert_eq!(check(Some(Level::Low), 24.1, min, max, 0.3), Some(Level::Low));
+        assert_eq!(check(Some(Level::Low), 24.2, min, max, 0.3), Some(Level::Low));
+        assert_eq!(check(Some(Level::Low), 24.4, min, max, 0.3), None);
+
+        // Hysteresis only holds an alarm, it never raises the other one
+        assert_eq!(check(Some(Level::High), 24.1, min, max, 0.3), None);
+        assert_eq!(check(Some(Level::High), 23.9, min, max, 0.3), Some(Level::Low));
+    }
+
+    #[test]
+    fn lifted_limits_clear_their_alarm() {
+        assert_eq!(check(Some(Level::High), 30.0, Some(24.0), None, 0.3), None);
+        assert_eq!(check(Some(Level::Low), 20.0, None, Some(28.0), 0.3), None);
+    }
+
+    #[test]
+    fn crossed_limits_favour_the_upper_one() {
+        // See the warning in `reload`, a value above the upper limit wins even when it is also below the lower one
+        assert_eq!(check(None, 25.0, Some(30.0), Some(20.0), 0.3), Some(Level::High));
+        assert_eq!(
+            check(Some(Level::High), 19.0, Some(30.0), Some(20.0), 0.3),
+            Some(Level::Low)
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{journal, lifecycle, measurements, nvs};
+
+const NVS_KEY: &str = "assistant";
+const NVS_VERSION: u32 = 1;
+const TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Measurement interval while the water-change assistant is active.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(2);
+
+/// A running water-change session.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Session {
+    pub started: Instant,
+    pub started_at: i64,
+    pub before: Option<f32>,
+}
+
+// The persisted part of a session, so one interrupted by a reboot still gets recorded
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    started_at: i64,
+    before: Option<f32>,
+}
+
+/// How far TDS has risen above the level right after the last water change.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Rise {
+    pub baseline: f32,
+    pub absolute: f32,
+    pub percent: f32,
+}
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+static BASELINE: Mutex<Option<f32>> = Mutex::new(None);
+
+pub(crate) fn get() -> Option<Session> {
+    *SESSION.lock().unwrap()
+}
+
+/// TDS rise relative to the last completed water change, if one is on record.
+pub(crate) fn rise(tds: f32) -> Option<Rise> {
+    let baseline = (*BASELINE.lock().unwrap()).filter(|&v| v > 0.0)?;
+    let absolute = tds - baseline;
+
+    Some(Rise {
+        baseline,
+        absolute,
+        percent: absolute / baseline * 100.0,
+    })
+}
+
+/// Restores the TDS baseline from the journal and closes a session left behind by a reboot. The
+/// measurement cadence itself is never persisted, so it is back to normal already.
+pub(crate) fn init() -> anyhow::Result<()> {
+    // Sessions interrupted by a reboot have no after value and leave the baseline as it was
+    let baseline = journal::query(Some("water_change"), None, usize::MAX)
+        .into_iter()
+        .rev()
+        .find_map(|entry| match entry.event {
+            journal::Event::WaterChange { after, .. } => after,
+            _ => None,
+        });
+    *BASELINE.lock().unwrap() = baseline;
+
+    if let Ok(Some(persisted)) = nvs::load_state::<Persisted>(NVS_KEY, NVS_VERSION) {
+        info!("Closing water-change session interrupted by a reboot");
+        journal::record(journal::Event::WaterChange {
+            started_at: persisted.started_at,
+            before: persisted.before,
+            after: None,
+        });
+    }
+    nvs::remove_blob(NVS_KEY)?;
+
+    Ok(())
+}
+
+pub(crate) fn start(latest: Option<measurements::Values>) -> anyhow::Result<()> {
+    let mut session = SESSION.lock().unwrap();
+    if session.is_some() {
+        return Ok(());
+    }
+
+    let before = latest.filter(|v| v.tds_enabled).map(|v| v.tds);
+    let started_at = Utc::now().timestamp_millis();
+    let blob = nvs::encode_state(NVS_VERSION, &Persisted { started_at, before })?;
+    nvs::persist(NVS_KEY, Some(blob));
+
+    *session = Some(Session {
+        started: Instant::now(),
+        started_at,
+        before,
+    });
+    lifecycle::transition(lifecycle::State::Maintenance);
+    info!("Water-change assistant started");
+
+    Ok(())
+}
+
+/// Ends the session and records it as a water change with before/after values.
+pub(crate) fn stop(latest: Option<measurements::Values>) -> anyhow::Result<()> {
+    let Some(session) = SESSION.lock().unwrap().take() else {
+        return Ok(());
+    };
+
+    let after = latest.filter(|v| v.tds_enabled).map(|v| v.tds);
+    journal::record(journal::Event::WaterChange {
+        started_at: session.started_at,
+        before: session.before,
+        after,
+    });
+    if after.is_some() {
+        *BASELINE.lock().unwrap() = after;
+    }
+    nvs::persist(NVS_KEY, None);
+    lifecycle::resume();
+    info!("Water-change assistant stopped");
+
+    Ok(())
+}
+
+/// Ends a session that ran into the timeout. Called after every measurement.
+pub(crate) fn check_timeout(latest: Option<measurements::Values>) {
+    if !get().is_some_and(|session| session.started.elapsed() >= TIMEOUT) {
+        return;
+    }
+
+    if let Err(e) = stop(latest) {
+        error!("Failed to stop water-change assistant: {e:?}");
+    }
+}
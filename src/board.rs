@@ -0,0 +1,95 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use log::warn;
+use serde::Serialize;
+
+use crate::nvs;
+
+// Wiring of the reference board
+const DEFAULT_PINS: Pins = Pins {
+    sda: 6,
+    scl: 7,
+    onewire: 5,
+    adc_ready: None,
+};
+
+// ESP32-C3 GPIOs, of which 11..=17 are taken by the SPI flash
+const MAX_GPIO: i32 = 21;
+const FLASH_PINS: std::ops::RangeInclusive<i32> = 11..=17;
+// Sampled at reset, usable as long as nothing pulls them the wrong way during boot
+const STRAPPING_PINS: [i32; 3] = [2, 8, 9];
+
+/// GPIO assignments for the sensor and display buses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct Pins {
+    pub sda: i32,
+    pub scl: i32,
+    pub onewire: i32,
+    /// ALERT/RDY of the ADS1115, signalling finished conversions when wired.
+    pub adc_ready: Option<i32>,
+}
+
+static PINS: OnceLock<Pins> = OnceLock::new();
+
+pub(crate) fn pins() -> Option<Pins> {
+    PINS.get().copied()
+}
+
+/// Reads `board.pin.sda`, `board.pin.scl`, `board.pin.onewire` and `board.pin.adc_ready`. Any invalid or conflicting assignment falls back to the
+/// reference wiring as a whole, so a typo never keeps the device from booting.
+pub(crate) fn init() -> Pins {
+    let pins = match load() {
+        Ok(pins) => pins,
+        Err(e) => {
+            warn!("Invalid pin configuration, using defaults: {e:?}");
+            DEFAULT_PINS
+        }
+    };
+
+    PINS.get_or_init(|| pins);
+    pins
+}
+
+fn load() -> anyhow::Result<Pins> {
+    let check = |key: &str, pin: i32| -> anyhow::Result<i32> {
+        if !(0..=MAX_GPIO).contains(&pin) || FLASH_PINS.contains(&pin) {
+            return Err(anyhow!("GPIO{pin} is not usable for {key}"));
+        }
+        if STRAPPING_PINS.contains(&pin) {
+            warn!("{key} is on strapping pin GPIO{pin}, make sure it is pulled high at boot");
+        }
+
+        Ok(pin)
+    };
+    let pin = |key: &str, default: i32| -> anyhow::Result<i32> {
+        let pin = match nvs::get_opt(key)? {
+            Some(v) => v.parse()?,
+            None => default,
+        };
+        check(key, pin)
+    };
+    // Unassigned unless set, the reference board leaves ALERT/RDY unconnected
+    let optional_pin =
+        |key: &str| -> anyhow::Result<Option<i32>> { nvs::get_opt(key)?.map(|v| check(key, v.parse()?)).transpose() };
+
+    let pins = Pins {
+        sda: pin("board.pin.sda", DEFAULT_PINS.sda)?,
+        scl: pin("board.pin.scl", DEFAULT_PINS.scl)?,
+        onewire: pin("board.pin.onewire", DEFAULT_PINS.onewire)?,
+        adc_ready: optional_pin("board.pin.adc_ready")?,
+    };
+    let mut assigned = vec![pins.sda, pins.scl, pins.onewire];
+    assigned.extend(pins.adc_ready);
+    assigned.sort_unstable();
+    if assigned.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(anyhow!("Pins must be distinct: {pins:?}"));
+    }
+
+    Ok(pins)
+}
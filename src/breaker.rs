@@ -0,0 +1,177 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Stops an outbound integration from trying again and again when it keeps failing, e.g. with a mistyped
+// collector URL. After a streak of failures the breaker opens and the integration is skipped for a
+// cool-off that doubles every time it opens again; once that passes, a single attempt probes whether the
+// other end is back. The caller owns the clock, so the breaker is a plain state machine.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Consecutive failures that open the breaker.
+const THRESHOLD: u32 = 3;
+const MIN_COOL_OFF: Duration = Duration::from_secs(30);
+const MAX_COOL_OFF: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum State {
+    /// Attempts go through.
+    Closed,
+    /// Attempts are skipped until the cool-off ends.
+    Open,
+    /// The cool-off ended, one attempt is under way to find out whether to close again.
+    HalfOpen,
+}
+
+/// State of a breaker for `/diagnostics`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Status {
+    pub state: State,
+    pub failure_streak: u32,
+    /// Seconds until the next attempt while open.
+    pub retry_in: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    streak: u32,
+    /// Times it opened without closing in between, each doubling the cool-off.
+    trips: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl Breaker {
+    pub const fn new() -> Self {
+        Self {
+            streak: 0,
+            trips: 0,
+            open_until: None,
+            probing: false,
+        }
+    }
+
+    /// Whether an attempt may go out at `now`. After a cool-off, the first call lets one through and the
+    /// breaker turns half-open until it hears how that went.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            None => true,
+            Some(_) if self.probing => false,
+            Some(until) if now < until => false,
+            Some(_) => {
+                self.probing = true;
+                true
+            }
+        }
+    }
+
+    pub fn success(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Counts a failed attempt at `now` and returns whether it opened the breaker.
+    pub fn failure(&mut self, now: Instant) -> bool {
+        self.streak = self.streak.saturating_add(1);
+        if !self.probing && self.streak < THRESHOLD {
+            return false;
+        }
+
+        self.trips = self.trips.saturating_add(1);
+        self.probing = false;
+        self.open_until = Some(now + self.cool_off());
+
+        true
+    }
+
+    /// The current cool-off, doubling with every trip.
+    pub fn cool_off(&self) -> Duration {
+        let doublings = self.trips.saturating_sub(1).min(16);
+        (MIN_COOL_OFF * (1 << doublings)).min(MAX_COOL_OFF)
+    }
+
+    pub fn status(&self, now: Instant) -> Status {
+        let state = match self.open_until {
+            None => State::Closed,
+            Some(_) if self.probing => State::HalfOpen,
+            Some(_) => State::Open,
+        };
+        let retry_in = self
+            .open_until
+            .filter(|_| !self.probing)
+            .map(|until| until.saturating_duration_since(now).as_secs());
+
+        Status {
+            state,
+            failure_streak: self.streak,
+            retry_in,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_a_streak_of_failures() {
+        let now = Instant::now();
+        let mut breaker = Breaker::new();
+
+        assert!(!breaker.failure(now));
+        assert!(!breaker.failure(now));
+        // A success in between starts the streak over
+        breaker.success();
+        assert!(!breaker.failure(now));
+        assert!(!breaker.failure(now));
+        assert!(breaker.allow(now));
+        assert!(breaker.failure(now));
+
+        assert!(!breaker.allow(now));
+        let status = breaker.status(now);
+        assert_eq!(status.state, State::Open);
+        assert_eq!(status.failure_streak, 3);
+        assert_eq!(status.retry_in, Some(MIN_COOL_OFF.as_secs()));
+    }
+
+    #[test]
+    fn probes_once_after_the_cool_off() {
+        let now = Instant::now();
+        let mut breaker = Breaker::new();
+        for _ in 0..THRESHOLD {
+            breaker.failure(now);
+        }
+
+        let later = now + MIN_COOL_OFF;
+        assert!(breaker.allow(later));
+        assert_eq!(breaker.status(later).state, State::HalfOpen);
+        // Only the one probe until it is answered
+        assert!(!breaker.allow(later));
+
+        breaker.success();
+        assert_eq!(breaker.status(later).state, State::Closed);
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn a_failed_probe_doubles_the_cool_off() {
+        let mut now = Instant::now();
+        let mut breaker = Breaker::new();
+        for _ in 0..THRESHOLD {
+            breaker.failure(now);
+        }
+
+        let mut cool_offs = Vec::new();
+        for _ in 0..10 {
+            cool_offs.push(breaker.cool_off().as_secs());
+            now += breaker.cool_off();
+            assert!(breaker.allow(now));
+            assert!(breaker.failure(now), "a failed probe opens it again");
+        }
+        assert_eq!(cool_offs, [30, 60, 120, 240, 480, 960, 1920, 3600, 3600, 3600]);
+    }
+}
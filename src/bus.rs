@@ -0,0 +1,434 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use log::info;
+use serde::Serialize;
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::{journal, nvs};
+
+// Bus utilization is reported over windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+
+// Error rates are taken over the last 30 samples, one per window
+const RATE_SAMPLES: usize = 30;
+
+// Fewer transactions than this in the rolling window give no meaningful rate
+const MIN_TRANSACTIONS: u32 = 1000;
+
+// Errors per 1000 transactions on the display that suggest moisture on its connector
+const DEFAULT_THRESHOLD: f32 = 5.0;
+
+// The alarm clears once the display is back under this share of the threshold, so it doesn't flap
+const CLEAR_RATIO: f32 = 0.5;
+
+// Condensation shows up on the display's long ribbon first; the ADC on the board is the control
+const SUSPECT: &str = "display";
+const REFERENCE: &str = "adc";
+
+/// Transactions of one device sharing the I2C bus.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DeviceStats {
+    pub name: &'static str,
+    pub transactions: u32,
+    pub errors: u32,
+    pub total_us: u64,
+    pub max_us: u32,
+    /// Errors per 1000 transactions over the last 30 minutes, once there are enough of them.
+    pub error_rate: Option<f32>,
+}
+
+/// Bus time attributed per device, served at `/diagnostics/i2c`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Profile {
+    pub devices: Vec<DeviceStats>,
+    /// Share of the last complete window the bus spent in transactions, in percent.
+    pub utilization: Option<f32>,
+    /// Whether display errors are climbing while the ADC's stay low, see [`worker`].
+    pub degradation_suspected: bool,
+}
+
+/// Transaction and error counts of one device at a sampling point.
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    transactions: u32,
+    errors: u32,
+}
+
+pub(crate) struct Context {
+    threshold: f32,
+    last: Vec<Counts>,
+    // Per device, the counts added during each of the last `RATE_SAMPLES` windows
+    samples: Vec<VecDeque<Counts>>,
+}
+
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+
+struct State {
+    devices: Vec<DeviceStats>,
+    window_started: Option<Instant>,
+    window_busy: Duration,
+    utilization: Option<f32>,
+}
+
+impl State {
+    // Closes the window once it is over, so an idle bus still reports its utilization
+    fn roll_window(&mut self, now: Instant) {
+        let Some(started) = self.window_started else {
+            self.window_started = Some(now);
+            return;
+        };
+
+        let elapsed = now - started;
+        if elapsed >= WINDOW {
+            self.utilization = Some(self.window_busy.as_secs_f32() / elapsed.as_secs_f32() * 100.0);
+            self.window_started = Some(now);
+            self.window_busy = Duration::ZERO;
+        }
+    }
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    devices: Vec::new(),
+    window_started: None,
+    window_busy: Duration::ZERO,
+    utilization: None,
+});
+
+/// Wraps a device on the shared bus to account its transactions under `name`. Costs two timestamps
+/// per transaction.
+pub(crate) struct Profiled<D> {
+    inner: D,
+    index: usize,
+}
+
+impl<D> Profiled<D> {
+    pub fn new(name: &'static str, inner: D) -> Self {
+        let mut state = STATE.lock().unwrap();
+        state.devices.push(DeviceStats {
+            name,
+            transactions: 0,
+            errors: 0,
+            total_us: 0,
+            max_us: 0,
+            error_rate: None,
+        });
+
+        Self {
+            inner,
+            index: state.devices.len() - 1,
+        }
+    }
+
+    fn timed<E>(&mut self, op: impl FnOnce(&mut D) -> Result<(), E>) -> Result<(), E> {
+        let started = Instant::now();
+        let result = op(&mut self.inner);
+        let now = Instant::now();
+
+        let elapsed = now - started;
+        let mut state = STATE.lock().unwrap();
+        let device = &mut state.devices[self.index];
+        device.transactions = device.transactions.wrapping_add(1);
+        if result.is_err() {
+            device.errors = device.errors.wrapping_add(1);
+        }
+        device.total_us += elapsed.as_micros() as u64;
+        device.max_us = device.max_us.max(elapsed.as_micros() as u32);
+        state.window_busy += elapsed;
+        state.roll_window(now);
+
+        result
+    }
+}
+
+impl<D: ErrorType> ErrorType for Profiled<D> {
+    type Error = D::Error;
+}
+
+impl<D: I2c> I2c for Profiled<D> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.timed(|inner| inner.read(address, read))
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.timed(|inner| inner.write(address, write))
+    }
+
+    fn write_read(&mut self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), Self::Error> {
+        self.timed(|inner| inner.write_read(address, write, read))
+    }
+
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.timed(|inner| inner.transaction(address, operations))
+    }
+}
+
+pub(crate) fn profile() -> Profile {
+    let mut state = STATE.lock().unwrap();
+    state.roll_window(Instant::now());
+
+    Profile {
+        devices: state.devices.clone(),
+        utilization: state.utilization,
+        degradation_suspected: DEGRADED.load(Ordering::Relaxed),
+    }
+}
+
+/// Zeroes the counters and starts a new utilization window.
+pub(crate) fn reset() {
+    let mut state = STATE.lock().unwrap();
+    for device in &mut state.devices {
+        device.transactions = 0;
+        device.errors = 0;
+        device.total_us = 0;
+        device.max_us = 0;
+    }
+    state.window_started = Some(Instant::now());
+    state.window_busy = Duration::ZERO;
+    state.utilization = None;
+}
+
+/// Parses `system.i2c_error_threshold`, in errors per 1000 transactions.
+pub(crate) fn parse_threshold(v: &str) -> anyhow::Result<f32> {
+    let threshold: f32 = v.parse()?;
+    if !(threshold > 0.0 && threshold <= 1000.0) {
+        return Err(anyhow!(
+            "I2C error threshold must be above 0 and at most 1000: {threshold}"
+        ));
+    }
+
+    Ok(threshold)
+}
+
+pub(crate) fn init() -> anyhow::Result<Box<Context>> {
+    let threshold = nvs::get_parsed_or("system.i2c_error_threshold", DEFAULT_THRESHOLD, parse_threshold)?;
+
+    Ok(Box::new(Context {
+        threshold,
+        last: Vec::new(),
+        samples: Vec::new(),
+    }))
+}
+
+/// Tracks error rates per device and raises an alarm when the display degrades on its own, which in
+/// practice precedes a dead display by hours when condensation forms in the enclosure.
+pub(crate) async fn worker(ctx: &mut Box<Context>) -> anyhow::Result<()> {
+    let mut interval = interval(WINDOW);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        sample(ctx);
+        check_degradation(ctx);
+    }
+}
+
+fn sample(ctx: &mut Context) {
+    let mut state = STATE.lock().unwrap();
+    let devices = state.devices.len();
+    ctx.last.resize(devices, Counts::default());
+    ctx.samples.resize_with(devices, VecDeque::new);
+
+    for (i, device) in state.devices.iter_mut().enumerate() {
+        // Counters going backwards were reset, everything since then is new
+        let last = ctx.last[i];
+        let added = if device.transactions >= last.transactions {
+            Counts {
+                transactions: device.transactions - last.transactions,
+                errors: device.errors.saturating_sub(last.errors),
+            }
+        } else {
+            Counts {
+                transactions: device.transactions,
+                errors: device.errors,
+            }
+        };
+        ctx.last[i] = Counts {
+            transactions: device.transactions,
+            errors: device.errors,
+        };
+
+        let samples = &mut ctx.samples[i];
+        if samples.len() == RATE_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(added);
+        device.error_rate = error_rate(samples);
+    }
+}
+
+fn error_rate(samples: &VecDeque<Counts>) -> Option<f32> {
+    let (transactions, errors) = samples.iter().fold((0u32, 0u32), |(t, e), c| {
+        (t.saturating_add(c.transactions), e.saturating_add(c.errors))
+    });
+    (transactions >= MIN_TRANSACTIONS).then(|| errors as f32 * 1000.0 / transactions as f32)
+}
+
+fn check_degradation(ctx: &Context) {
+    let state = STATE.lock().unwrap();
+    let rate = |name| {
+        state
+            .devices
+            .iter()
+            .find(|device| device.name == name)
+            .and_then(|device| device.error_rate)
+    };
+    let (Some(suspect), Some(reference)) = (rate(SUSPECT), rate(REFERENCE)) else {
+        return;
+    };
+    drop(state);
+
+    let degraded = DEGRADED.load(Ordering::Relaxed);
+    let now_degraded = degradation(ctx.threshold, degraded, suspect, reference);
+    if now_degraded && !degraded {
+        DEGRADED.store(true, Ordering::Relaxed);
+        info!(
+            "Possible condensation or wiring degradation on the {SUSPECT} bus: {suspect:.1} errors per 1000 \
+             transactions, {reference:.1} on the {REFERENCE}"
+        );
+        journal::record(journal::Event::BusDegradation {
+            device: SUSPECT.to_owned(),
+            error_rate: suspect,
+            reference: REFERENCE.to_owned(),
+            reference_rate: reference,
+        });
+    } else if degraded && !now_degraded {
+        DEGRADED.store(false, Ordering::Relaxed);
+        info!("Errors on the {SUSPECT} bus back to {suspect:.1} per 1000 transactions");
+    }
+}
+
+// Whether the suspect counts as degraded given its and the reference's error rates, raised only while the
+// reference stays clean and cleared once the suspect is well below the threshold
+fn degradation(threshold: f32, degraded: bool, suspect: f32, reference: f32) -> bool {
+    let clear = threshold * CLEAR_RATIO;
+    if degraded {
+        suspect > clear
+    } else {
+        suspect >= threshold && reference < clear
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::I2c as _;
+
+    use super::*;
+    use crate::mock;
+
+    fn counts(index: usize) -> (u32, u32) {
+        let state = STATE.lock().unwrap();
+        let device = &state.devices[index];
+        (device.transactions, device.errors)
+    }
+
+    fn set_counts(index: usize, transactions: u32, errors: u32) {
+        let mut state = STATE.lock().unwrap();
+        state.devices[index].transactions = transactions;
+        state.devices[index].errors = errors;
+    }
+
+    fn context() -> Context {
+        Context {
+            threshold: DEFAULT_THRESHOLD,
+            last: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn every_transaction_and_failure_is_counted() {
+        let mut device = Profiled::new("counted", mock::I2c::new(&[0x48]));
+
+        for _ in 0..3 {
+            device.write(0x48, &[0x01]).unwrap();
+        }
+        device.write_read(0x48, &[0x00], &mut [0; 2]).unwrap();
+        // Nothing answers at 0x49
+        device.read(0x49, &mut [0; 2]).unwrap_err();
+        device.write(0x49, &[0x01]).unwrap_err();
+
+        assert_eq!(counts(device.index), (6, 2));
+    }
+
+    #[test]
+    fn samples_take_what_was_added_since_the_last_one() {
+        let device = Profiled::new("sampled", mock::I2c::new(&[]));
+        let mut ctx = context();
+        let rate = |ctx: &Context| error_rate(&ctx.samples[device.index]);
+
+        set_counts(device.index, 600, 3);
+        sample(&mut ctx);
+        assert_eq!(rate(&ctx), None, "fewer than {MIN_TRANSACTIONS} transactions");
+
+        set_counts(device.index, 1200, 6);
+        sample(&mut ctx);
+        assert_eq!(rate(&ctx), Some(5.0));
+        assert_eq!(STATE.lock().unwrap().devices[device.index].error_rate, Some(5.0));
+
+        // Counters going backwards were reset, and count from zero
+        set_counts(device.index, 100, 10);
+        sample(&mut ctx);
+        assert_eq!(rate(&ctx), Some(16.0 * 1000.0 / 1300.0));
+    }
+
+    #[test]
+    fn old_samples_leave_the_rate() {
+        let device = Profiled::new("rolled", mock::I2c::new(&[]));
+        let mut ctx = context();
+
+        set_counts(device.index, 2000, 40);
+        sample(&mut ctx);
+        for _ in 1..RATE_SAMPLES {
+            sample(&mut ctx);
+        }
+        assert_eq!(error_rate(&ctx.samples[device.index]), Some(20.0));
+
+        sample(&mut ctx);
+        assert_eq!(ctx.samples[device.index].len(), RATE_SAMPLES);
+        assert_eq!(error_rate(&ctx.samples[device.index]), None);
+    }
+
+    #[test]
+    fn error_rates_saturate_instead_of_wrapping() {
+        let samples = VecDeque::from([
+            Counts {
+                transactions: u32::MAX,
+                errors: 10,
+            },
+            Counts {
+                transactions: 10,
+                errors: u32::MAX,
+            },
+        ]);
+        assert_eq!(error_rate(&samples), Some(1000.0));
+    }
+
+    #[test]
+    fn degradation_needs_a_clean_reference_and_clears_with_hysteresis() {
+        let threshold = 5.0;
+
+        assert!(!degradation(threshold, false, 4.9, 0.0));
+        assert!(degradation(threshold, false, 5.0, 2.4));
+        // Both erring points at something other than the display
+        assert!(!degradation(threshold, false, 8.0, 2.5));
+
+        assert!(degradation(threshold, true, 4.0, 0.0), "still above half the threshold");
+        assert!(degradation(threshold, true, 2.6, 9.0));
+        assert!(!degradation(threshold, true, 2.5, 0.0));
+    }
+}
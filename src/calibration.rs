@@ -0,0 +1,321 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// The calibration of this unit as one document, for moving it to a spare board fitted to the same
+// probes: `GET /calibration/export` serves it, `POST /calibration/import` takes it. The K-factor and the
+// pinned probe stay where they always were; how the factor was captured and the fields of later firmware
+// are kept here, so a document passes through older firmware unchanged.
+
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{journal, measurements, nvs, probes};
+
+const NVS_KEY: &str = "calibration";
+const VERSION: u32 = 1;
+
+/// Layout of the document. Fields are only ever added, and kept by firmware that doesn't know them;
+/// anything else bumps it.
+pub(crate) const FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Document {
+    pub format: u32,
+    pub tds: Tds,
+    /// ROM code of the DS18B20 the calibration was made with.
+    pub temperature_probe: Option<String>,
+    /// Fields of later firmware, carried along as they are.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Tds {
+    pub k_factor: f32,
+    /// How the factor was found, unknown for one set through `/config`.
+    pub capture: Option<Capture>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The reading a calibration was made from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Capture {
+    /// TDS of the reference solution in ppm
+    pub reference: f32,
+    /// The reading as the plain Keyestudio curve has it
+    pub measured: f32,
+    /// Probe voltage after the supply correction
+    pub voltage: Option<f32>,
+    pub temperature: f32,
+    pub captured_at: i64,
+    /// Firmware the calibration was made with
+    pub firmware: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Capture {
+    pub fn new(reference: f32, measured: f32, voltage: Option<f32>, temperature: f32) -> Self {
+        Self {
+            reference,
+            measured,
+            voltage,
+            temperature,
+            captured_at: Utc::now().timestamp_millis(),
+            firmware: env!("CARGO_PKG_VERSION").to_owned(),
+            extra: Map::new(),
+        }
+    }
+}
+
+/// What the document holds beyond the K-factor and the pinned probe.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    capture: Option<Capture>,
+    extra: Map<String, Value>,
+    tds_extra: Map<String, Value>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The document is unusable, nothing was changed
+    Rejected(String),
+    /// The document is for another temperature probe than the one attached, nothing was changed
+    ProbeMismatch(String),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Failed(e)
+    }
+}
+
+/// Result of importing a document.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Imported {
+    pub k_factor: f32,
+    pub previous_k_factor: f32,
+    pub temperature_probe: Option<String>,
+    /// Whether the document was for another probe than the one attached, and forced.
+    pub forced: bool,
+}
+
+pub(crate) fn export() -> anyhow::Result<Document> {
+    let stored = load()?;
+
+    Ok(Document {
+        format: FORMAT,
+        tds: Tds {
+            k_factor: measurements::tds_k_factor(),
+            capture: stored.capture,
+            extra: stored.tds_extra,
+        },
+        temperature_probe: probes::pinned(),
+        extra: stored.extra,
+    })
+}
+
+/// Takes over the calibration in `document`. One made with another temperature probe than the one
+/// attached is refused unless `force` is set.
+pub(crate) fn import(document: Document, force: bool) -> Result<Imported, Error> {
+    if document.format != FORMAT {
+        return Err(Error::Rejected(format!(
+            "Unsupported calibration format {}, expected {FORMAT}",
+            document.format
+        )));
+    }
+    let probe = document
+        .temperature_probe
+        .as_deref()
+        .map(probes::parse_address)
+        .transpose()
+        .map_err(|e| Error::Rejected(e.to_string()))?;
+    let mismatch = check_probe(probe, measurements::probe_address());
+    if let Some(message) = &mismatch {
+        if !force {
+            return Err(Error::ProbeMismatch(format!(
+                "{message}, import with force to take it over"
+            )));
+        }
+        warn!("{message}, importing the calibration anyway");
+    }
+
+    // The only write that can be refused goes first, so nothing is left half imported
+    let previous_k_factor = match measurements::import_tds_k_factor(document.tds.k_factor) {
+        Ok(previous_k_factor) => previous_k_factor,
+        Err(measurements::CalibrationError::Rejected(message)) => return Err(Error::Rejected(message)),
+        Err(measurements::CalibrationError::Failed(e)) => return Err(Error::Failed(e)),
+    };
+    if let Some(probe) = probe {
+        probes::pin(probe)?;
+    }
+    save(&Stored {
+        capture: document.tds.capture,
+        extra: document.extra,
+        tds_extra: document.tds.extra,
+    })?;
+
+    let k_factor = document.tds.k_factor;
+    info!("Calibration imported, K-factor {previous_k_factor:.4} -> {k_factor:.4}");
+    journal::record(journal::Event::CalibrationImported {
+        k_factor,
+        temperature_probe: document.temperature_probe.clone(),
+        forced: mismatch.is_some(),
+    });
+
+    Ok(Imported {
+        k_factor,
+        previous_k_factor,
+        temperature_probe: document.temperature_probe,
+        forced: mismatch.is_some(),
+    })
+}
+
+/// Records a calibration just made on this unit. Fields a later firmware added to the TDS section
+/// described the previous calibration, so they go with it.
+pub(crate) fn captured(capture: Capture) {
+    replace_tds(Some(capture));
+}
+
+/// Forgets how the TDS probe was calibrated, after it was replaced.
+pub(crate) fn cleared() {
+    replace_tds(None);
+}
+
+fn replace_tds(capture: Option<Capture>) {
+    let result = load().and_then(|stored| {
+        save(&Stored {
+            capture,
+            tds_extra: Map::new(),
+            ..stored
+        })
+    });
+    if let Err(e) = result {
+        warn!("Failed to store the calibration details: {e:?}");
+    }
+}
+
+/// Why the probe the document was made with doesn't fit the one attached, if it doesn't.
+fn check_probe(document: Option<u64>, attached: Option<u64>) -> Option<String> {
+    let document = document?;
+    match attached {
+        Some(attached) if attached == document => None,
+        Some(attached) => Some(format!(
+            "Calibrated with temperature probe {}, {} is attached",
+            probes::format_address(document),
+            probes::format_address(attached)
+        )),
+        None => Some(format!(
+            "Calibrated with temperature probe {}, none is attached",
+            probes::format_address(document)
+        )),
+    }
+}
+
+fn load() -> anyhow::Result<Stored> {
+    Ok(nvs::load_state(NVS_KEY, VERSION)?.unwrap_or_default())
+}
+
+fn save(stored: &Stored) -> anyhow::Result<()> {
+    nvs::store_blob(NVS_KEY, &nvs::encode_state(VERSION, stored)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn document() -> Document {
+        Document {
+            format: FORMAT,
+            tds: Tds {
+                k_factor: 1.0734,
+                capture: Some(Capture {
+                    reference: 500.0,
+                    measured: 465.8,
+                    voltage: Some(0.912),
+                    temperature: 24.6,
+                    captured_at: 1_700_000_000_000,
+                    firmware: "1.4.0".to_owned(),
+                    extra: Map::new(),
+                }),
+                extra: Map::new(),
+            },
+            temperature_probe: Some("28ff000000000001".to_owned()),
+            extra: Map::new(),
+        }
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let document = document();
+        let json = serde_json::to_vec(&document).unwrap();
+        assert_eq!(serde_json::from_slice::<Document>(&json).unwrap(), document);
+    }
+
+    #[test]
+    fn fields_of_later_firmware_are_preserved() {
+        let later = json!({
+            "format": FORMAT,
+            "tds": {
+                "k_factor": 0.98,
+                "capture": {
+                    "reference": 1413.0,
+                    "measured": 1441.0,
+                    "voltage": null,
+                    "temperature": 25.0,
+                    "captured_at": 1_700_000_000_000_i64,
+                    "firmware": "2.0.0",
+                    "humidity": 40
+                },
+                "two_point": { "low": 84.0, "high": 1413.0 }
+            },
+            "temperature_probe": null,
+            "ph": { "slope": 59.16, "offset": 0.02 },
+            "temperature_offsets": { "28ff000000000001": -0.3 }
+        });
+
+        let document: Document = serde_json::from_value(later.clone()).unwrap();
+        assert_eq!(document.tds.k_factor, 0.98);
+        assert!(document.extra.contains_key("ph"));
+        // Through the text, a float read into an f32 would not widen back to the same f64
+        let exported: Value = serde_json::from_slice(&serde_json::to_vec(&document).unwrap()).unwrap();
+        assert_eq!(exported, later);
+    }
+
+    #[test]
+    fn only_the_k_factor_is_required() {
+        let document: Document = serde_json::from_value(json!({ "format": 1, "tds": { "k_factor": 1.0 } })).unwrap();
+        assert_eq!(document.tds.capture, None);
+        assert_eq!(document.temperature_probe, None);
+
+        for missing in [json!({ "format": 1 }), json!({ "tds": { "k_factor": 1.0 } })] {
+            assert!(
+                serde_json::from_value::<Document>(missing.clone()).is_err(),
+                "{missing}"
+            );
+        }
+    }
+
+    #[test]
+    fn another_probe_is_a_mismatch() {
+        const PINNED: u64 = 0x28ff_0000_0000_0001;
+
+        assert_eq!(check_probe(Some(PINNED), Some(PINNED)), None);
+        // A document without a probe has nothing to check
+        assert_eq!(check_probe(None, Some(PINNED)), None);
+        assert_eq!(check_probe(None, None), None);
+        assert_eq!(
+            check_probe(Some(PINNED), Some(0x28ff_0000_0000_0002)).unwrap(),
+            "Calibrated with temperature probe 28ff000000000001, 28ff000000000002 is attached"
+        );
+        assert!(check_probe(Some(PINNED), None).unwrap().ends_with("none is attached"));
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Answers every DNS query with the address of the setup access point, so a phone joining it finds its
+// connectivity check landing on the setup form and opens it by itself.
+
+use std::net::{Ipv4Addr, UdpSocket};
+
+use log::{info, warn};
+
+const DNS_PORT: u16 = 53;
+// Anything cached now is wrong once the device leaves setup mode, so answers expire quickly
+const TTL_SECONDS: u32 = 10;
+// Plain DNS over UDP never exceeds this without EDNS, which the answers don't advertise
+const MAX_PACKET: usize = 512;
+const STACK_SIZE: usize = 4 * 1024;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// Starts answering DNS on the access point for as long as the device runs, which in setup mode is
+/// until the reboot into station mode.
+pub(crate) fn start(address: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+    std::thread::Builder::new()
+        .name("captive-dns".to_owned())
+        .stack_size(STACK_SIZE)
+        .spawn(move || serve(socket, address))?;
+    info!("Answering DNS queries with {address}");
+
+    Ok(())
+}
+
+fn serve(socket: UdpSocket, address: Ipv4Addr) {
+    let mut buf = [0_u8; MAX_PACKET];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive a DNS query: {e}");
+                continue;
+            }
+        };
+
+        if let Some(reply) = answer(&buf[..len], address) {
+            let _ = socket.send_to(&reply, peer);
+        }
+    }
+}
+
+/// Builds the response to a standard query, pointing an A question at `address` and answering any other
+/// type with no records. Anything else is ignored.
+fn answer(query: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    // A set QR bit is a response, a nonzero opcode anything but a standard query
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    if flags & 0x8000 != 0 || flags & 0x7800 != 0 {
+        return None;
+    }
+    if u16::from_be_bytes([query[4], query[5]]) == 0 {
+        return None;
+    }
+
+    // Only the first question is answered, resolvers never send more than one
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        // Questions come uncompressed, a pointer here means a malformed query
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    // Type and class
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let answers = u16::from(qtype == TYPE_A);
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // Authoritative response echoing the recursion desired bit, with recursion available
+    reply.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x80]);
+    reply.extend_from_slice(&1_u16.to_be_bytes());
+    reply.extend_from_slice(&answers.to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(&query[HEADER_LEN..end]);
+
+    if answers > 0 {
+        // The name is a pointer back to the question's
+        reply.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL_SECONDS.to_be_bytes());
+        reply.extend_from_slice(&4_u16.to_be_bytes());
+        reply.extend_from_slice(&address.octets());
+    }
+
+    Some(reply)
+}
@@ -0,0 +1,176 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Splits a list of records into JSON arrays of bounded size, for transports that cap message sizes.
+// Chunks are serialized one at a time, so the whole export is never held in memory. The summary that
+// follows the last chunk lets the receiver tell whether it got all of them.
+
+use serde::Serialize;
+
+use crate::recovery;
+
+/// Sent after the last chunk: how many there were, and a CRC-32 (IEEE) over their payloads in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct Summary {
+    pub chunks: u32,
+    pub records: u32,
+    pub crc32: u32,
+}
+
+/// Chunks of `records`, each a JSON array of at most `max_len` bytes unless a single record is longer.
+pub(crate) struct Chunks<I> {
+    records: I,
+    max_len: usize,
+    // Serialized ahead, it didn't fit the previous chunk
+    carry: Option<Vec<u8>>,
+    crc: u32,
+    summary: Summary,
+}
+
+impl<I, T> Chunks<I>
+where
+    I: Iterator<Item = T>,
+    T: Serialize,
+{
+    pub fn new(records: impl IntoIterator<IntoIter = I>, max_len: usize) -> Self {
+        Self {
+            records: records.into_iter(),
+            max_len,
+            carry: None,
+            crc: !0,
+            summary: Summary {
+                chunks: 0,
+                records: 0,
+                crc32: 0,
+            },
+        }
+    }
+
+    /// The next chunk, `None` once all records went out.
+    pub fn next_chunk(&mut self) -> serde_json::Result<Option<Vec<u8>>> {
+        let mut chunk = vec![b'['];
+        let mut records = 0;
+        loop {
+            let record = match self.carry.take() {
+                Some(record) => record,
+                None => match self.records.next() {
+                    Some(record) => serde_json::to_vec(&record)?,
+                    None => break,
+                },
+            };
+            // Room for the separator and the closing bracket
+            if records > 0 && chunk.len() + 1 + record.len() + 1 > self.max_len {
+                self.carry = Some(record);
+                break;
+            }
+            if records > 0 {
+                chunk.push(b',');
+            }
+            chunk.extend_from_slice(&record);
+            records += 1;
+        }
+        if records == 0 {
+            return Ok(None);
+        }
+        chunk.push(b']');
+
+        self.crc = recovery::crc32_update(self.crc, &chunk);
+        self.summary.chunks += 1;
+        self.summary.records += records;
+        Ok(Some(chunk))
+    }
+
+    /// What went out so far, complete once `next_chunk` returned `None`.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            crc32: !self.crc,
+            ..self.summary
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::compact;
+
+    /// What a receiver does: checks the chunks against the summary and joins their records.
+    fn reassemble(chunks: &[Vec<u8>], summary: &Summary) -> Result<Vec<Value>, String> {
+        if chunks.len() != summary.chunks as usize {
+            return Err(format!("Got {} of {} chunks", chunks.len(), summary.chunks));
+        }
+        if compact::crc32(&chunks.concat()) != summary.crc32 {
+            return Err("CRC mismatch".to_owned());
+        }
+        let mut records = Vec::new();
+        for chunk in chunks {
+            let values: Vec<Value> = serde_json::from_slice(chunk).map_err(|e| e.to_string())?;
+            records.extend(values);
+        }
+        if records.len() != summary.records as usize {
+            return Err(format!("Got {} of {} records", records.len(), summary.records));
+        }
+
+        Ok(records)
+    }
+
+    fn export(records: &[Value], max_len: usize) -> (Vec<Vec<u8>>, Summary) {
+        let mut chunks = Chunks::new(records, max_len);
+        let mut out = Vec::new();
+        while let Some(chunk) = chunks.next_chunk().unwrap() {
+            out.push(chunk);
+        }
+        (out, chunks.summary())
+    }
+
+    fn records(len: usize) -> Vec<Value> {
+        (0..len)
+            .map(|i| json!({ "timestamp": 1_700_000_000_000_i64 + i as i64 * 5000, "temperature": 25.0, "tds": i }))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        for (len, max_len) in [(0, 512), (1, 512), (100, 512), (720, 4096), (50, 1)] {
+            let records = records(len);
+            let (chunks, summary) = export(&records, max_len);
+
+            assert_eq!(reassemble(&chunks, &summary).unwrap(), records, "{len} by {max_len}");
+            assert_eq!(summary.records as usize, len);
+            // Oversized records go alone rather than being split or dropped
+            for chunk in &chunks {
+                let count = serde_json::from_slice::<Vec<Value>>(chunk).unwrap().len();
+                assert!(chunk.len() <= max_len || count == 1, "{len} by {max_len}");
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_are_filled_up() {
+        let (chunks, summary) = export(&records(100), 512);
+        assert_eq!(summary.chunks as usize, chunks.len());
+        for pair in chunks.windows(2) {
+            // The next record would not have fit
+            let next: Vec<Value> = serde_json::from_slice(&pair[1]).unwrap();
+            let next = serde_json::to_vec(&next[0]).unwrap();
+            assert!(pair[0].len() + 1 + next.len() > 512);
+        }
+    }
+
+    #[test]
+    fn missing_or_altered_chunks_are_detected() {
+        let (mut chunks, summary) = export(&records(100), 512);
+        assert!(chunks.len() > 2);
+
+        let mut missing = chunks.clone();
+        missing.remove(1);
+        assert!(reassemble(&missing, &summary).is_err());
+
+        chunks.swap(0, 1);
+        assert!(reassemble(&chunks, &summary).is_err());
+    }
+}
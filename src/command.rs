@@ -0,0 +1,285 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{assistant, display, factory, measurements, troubleshoot};
+
+/// A command pushed by home automation through `POST /command`.
+struct Command {
+    name: &'static str,
+    run: fn(Value) -> Result<Value, Error>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "start_water_change",
+        run: start_water_change,
+    },
+    Command {
+        name: "stop_water_change",
+        run: stop_water_change,
+    },
+    Command {
+        name: "measure_now",
+        run: measure_now,
+    },
+    Command {
+        name: "display_test",
+        run: display_test,
+    },
+    Command {
+        name: "factory_test",
+        run: factory_test,
+    },
+    Command {
+        name: "factory_confirm",
+        run: factory_confirm,
+    },
+    Command {
+        name: "troubleshoot_network",
+        run: troubleshoot_network,
+    },
+    Command {
+        name: "tds_probe_replaced",
+        run: tds_probe_replaced,
+    },
+];
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The command or its arguments are invalid, nothing was done
+    Rejected(String),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Failed(e)
+    }
+}
+
+/// Outcome of a command, echoing its name.
+#[derive(Debug, Serialize)]
+pub(crate) struct Outcome {
+    pub cmd: &'static str,
+    pub result: Value,
+}
+
+pub(crate) fn dispatch(name: &str, args: Value) -> Result<Outcome, Error> {
+    let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+        let supported: Vec<_> = COMMANDS.iter().map(|command| command.name).collect();
+        return Err(Error::Rejected(format!(
+            "Unknown command {name}, supported: {}",
+            supported.join(", ")
+        )));
+    };
+
+    let result = (command.run)(args)?;
+    Ok(Outcome {
+        cmd: command.name,
+        result,
+    })
+}
+
+// Commands without arguments accept a missing, null or empty object
+fn parse_args<T: DeserializeOwned>(args: Value) -> Result<T, Error> {
+    let args = if args.is_null() {
+        Value::Object(Default::default())
+    } else {
+        args
+    };
+    serde_json::from_value(args).map_err(|e| Error::Rejected(format!("Invalid arguments: {e}")))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NoArgs {}
+
+fn start_water_change(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    let latest = measurements::get();
+    assistant::start(latest)?;
+    Ok(Value::Null)
+}
+
+fn stop_water_change(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    let latest = measurements::get();
+    assistant::stop(latest)?;
+    Ok(Value::Null)
+}
+
+fn measure_now(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    measurements::trigger();
+    Ok(Value::Null)
+}
+
+fn display_test(args: Value) -> Result<Value, Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Args {
+        pattern: display::Pattern,
+        seconds: Option<u64>,
+    }
+
+    let args: Args = parse_args(args)?;
+    let duration = display::test_duration(args.seconds).ok_or(Error::Rejected(format!(
+        "seconds must be between 1 and {}",
+        display::MAX_TEST_SECONDS
+    )))?;
+
+    display::start_test(args.pattern, duration);
+    Ok(serde_json::json!({ "seconds": duration.as_secs() }))
+}
+
+fn factory_test(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    factory::start().map_err(|e| Error::Rejected(e.to_string()))?;
+    Ok(Value::Null)
+}
+
+/// The operator's verdict on the test pattern shown during the factory test.
+fn factory_confirm(args: Value) -> Result<Value, Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Args {
+        passed: bool,
+    }
+
+    let args: Args = parse_args(args)?;
+    factory::confirm(args.passed).map_err(|e| Error::Rejected(e.to_string()))?;
+    Ok(Value::Null)
+}
+
+/// Walks through the network checks on the display.
+fn troubleshoot_network(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    troubleshoot::start().map_err(|e| Error::Rejected(e.to_string()))?;
+    Ok(Value::Null)
+}
+
+/// Clears the calibration of the TDS probe after fitting a new one.
+fn tds_probe_replaced(args: Value) -> Result<Value, Error> {
+    let NoArgs {} = parse_args(args)?;
+
+    let previous_k_factor = measurements::reset_tds_calibration()?;
+    Ok(serde_json::json!({ "previous_k_factor": previous_k_factor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rejection(name: &str, args: Value) -> String {
+        match dispatch(name, args) {
+            Err(Error::Rejected(message)) => message,
+            other => panic!("{name} was not rejected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected_with_the_supported_ones() {
+        let message = rejection("reboot", Value::Null);
+        assert!(message.starts_with("Unknown command reboot"), "{message}");
+        for command in COMMANDS {
+            assert!(message.contains(command.name), "{message}");
+        }
+    }
+
+    #[test]
+    fn every_command_rejects_unknown_arguments() {
+        for command in COMMANDS {
+            let message = rejection(command.name, json!({ "bogus": 1 }));
+            assert!(message.starts_with("Invalid arguments"), "{}: {message}", command.name);
+        }
+        let message = rejection("measure_now", json!([1]));
+        assert!(message.starts_with("Invalid arguments"), "{message}");
+    }
+
+    #[test]
+    fn commands_without_arguments_accept_null_or_an_empty_object() {
+        for args in [Value::Null, json!({})] {
+            let outcome = dispatch("measure_now", args).unwrap();
+            assert_eq!(outcome.cmd, "measure_now");
+            assert_eq!(outcome.result, Value::Null);
+        }
+    }
+
+    #[test]
+    fn water_change_starts_and_stops() {
+        assert_eq!(
+            dispatch("start_water_change", Value::Null).unwrap().cmd,
+            "start_water_change"
+        );
+        assert!(assistant::get().is_some());
+        // Starting twice keeps the running session
+        dispatch("start_water_change", json!({})).unwrap();
+
+        assert_eq!(
+            dispatch("stop_water_change", Value::Null).unwrap().cmd,
+            "stop_water_change"
+        );
+        assert!(assistant::get().is_none());
+        // Nothing to stop is not an error
+        dispatch("stop_water_change", Value::Null).unwrap();
+    }
+
+    #[test]
+    fn display_test_reports_its_duration() {
+        let outcome = dispatch("display_test", json!({ "pattern": "all_on", "seconds": 5 })).unwrap();
+        assert_eq!(outcome.result, json!({ "seconds": 5 }));
+
+        let outcome = dispatch("display_test", json!({ "pattern": "checkerboard" })).unwrap();
+        assert_eq!(outcome.result, json!({ "seconds": 10 }));
+        display::stop_test();
+    }
+
+    #[test]
+    fn display_test_rejects_bad_patterns_and_durations() {
+        assert!(rejection("display_test", Value::Null).starts_with("Invalid arguments"));
+        assert!(rejection("display_test", json!({ "pattern": "stripes" })).starts_with("Invalid arguments"));
+
+        let out_of_range = format!("seconds must be between 1 and {}", display::MAX_TEST_SECONDS);
+        for seconds in [0, display::MAX_TEST_SECONDS + 1] {
+            let args = json!({ "pattern": "all_off", "seconds": seconds });
+            assert_eq!(rejection("display_test", args), out_of_range);
+        }
+        let args = json!({ "pattern": "all_off", "seconds": -1 });
+        assert!(rejection("display_test", args).starts_with("Invalid arguments"));
+    }
+
+    #[test]
+    fn factory_test_starts_when_idle() {
+        assert_eq!(dispatch("factory_test", Value::Null).unwrap().result, Value::Null);
+    }
+
+    #[test]
+    fn factory_confirm_needs_a_verdict_and_a_test_waiting_for_it() {
+        assert!(rejection("factory_confirm", Value::Null).starts_with("Invalid arguments"));
+        assert!(rejection("factory_confirm", json!({ "passed": "yes" })).starts_with("Invalid arguments"));
+        assert_eq!(
+            rejection("factory_confirm", json!({ "passed": true })),
+            "No factory test waiting for confirmation"
+        );
+    }
+
+    #[test]
+    fn troubleshoot_network_starts_when_idle() {
+        assert_eq!(
+            dispatch("troubleshoot_network", json!({})).unwrap().cmd,
+            "troubleshoot_network"
+        );
+    }
+}
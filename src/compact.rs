@@ -0,0 +1,306 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Compact binary history served at `/history.bin`, for links too slow for JSON. A few bytes per record
+// instead of about a hundred. The decoder here is the reference for readers elsewhere.
+//
+// Version 1, integers little-endian:
+//
+//   magic           "CBH"
+//   version         u8, 1
+//   base timestamp  i64, ms since the epoch
+//   count           u32, records that follow
+//   fields          u8, value fields per record, 2 in this version
+//   records         count times:
+//     time delta    zigzag LEB128 varint, ms since the previous record or the base timestamp
+//     temperature   i16 delta of the temperature in 0.1 °C
+//     tds           i16 delta of the TDS in ppm
+//     ...           fields - 2 more i16 deltas, skipped by readers that don't know them
+//   crc             u32, CRC-32 (IEEE) of everything before it
+//
+// Values are deltas from the previous record, wrapping, starting from 0. A missing reading, e.g. a
+// sensor switched off, is the absolute value -32768. New fields are only ever appended behind the
+// known ones and announced through `fields`, so older readers carry on; anything else bumps `version`,
+// which readers must check.
+
+use anyhow::anyhow;
+
+use crate::recovery;
+
+const MAGIC: &[u8; 3] = b"CBH";
+pub(crate) const VERSION: u8 = 1;
+const FIELDS: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8 + 4 + 1;
+const CRC_LEN: usize = 4;
+
+const MISSING: i16 = i16::MIN;
+const TEMPERATURE_SCALE: f32 = 10.0;
+
+/// One reading as the format holds it, temperature to 0.1 °C and TDS to 1 ppm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Record {
+    pub timestamp: i64,
+    pub temperature: Option<f32>,
+    pub tds: Option<f32>,
+}
+
+pub(crate) fn encode(records: &[Record]) -> Vec<u8> {
+    let base = records.first().map_or(0, |record| record.timestamp);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + records.len() * 6 + CRC_LEN);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&base.to_le_bytes());
+    out.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    out.push(FIELDS);
+
+    let mut previous = (base, 0_i16, 0_i16);
+    for record in records {
+        let temperature = quantize(record.temperature, TEMPERATURE_SCALE);
+        let tds = quantize(record.tds, 1.0);
+        write_varint(&mut out, zigzag(record.timestamp.wrapping_sub(previous.0)));
+        out.extend_from_slice(&temperature.wrapping_sub(previous.1).to_le_bytes());
+        out.extend_from_slice(&tds.wrapping_sub(previous.2).to_le_bytes());
+        previous = (record.timestamp, temperature, tds);
+    }
+
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> anyhow::Result<Vec<Record>> {
+    if bytes.len() < HEADER_LEN + CRC_LEN {
+        return Err(anyhow!("Too short for a binary history"));
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - CRC_LEN);
+    if crc32(body) != u32::from_le_bytes(crc.try_into()?) {
+        return Err(anyhow!("CRC mismatch"));
+    }
+    let mut reader = Reader(body);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(anyhow!("Not a binary history"));
+    }
+    let version = reader.take(1)?[0];
+    if version != VERSION {
+        return Err(anyhow!("Unsupported binary history version {version}"));
+    }
+    let base = i64::from_le_bytes(reader.take(8)?.try_into()?);
+    let count = u32::from_le_bytes(reader.take(4)?.try_into()?);
+    let fields = reader.take(1)?[0];
+    if fields < FIELDS {
+        return Err(anyhow!("Expected at least {FIELDS} fields per record, got {fields}"));
+    }
+
+    // Bounded by the bytes there are, a corrupt count can't make this allocate
+    let mut records = Vec::with_capacity((count as usize).min(reader.0.len()));
+    let mut previous = (base, 0_i16, 0_i16);
+    for _ in 0..count {
+        let timestamp = previous.0.wrapping_add(unzigzag(reader.varint()?));
+        let temperature = previous.1.wrapping_add(reader.i16()?);
+        let tds = previous.2.wrapping_add(reader.i16()?);
+        // Fields of a later version
+        reader.take(2 * usize::from(fields - FIELDS))?;
+
+        records.push(Record {
+            timestamp,
+            temperature: dequantize(temperature, TEMPERATURE_SCALE),
+            tds: dequantize(tds, 1.0),
+        });
+        previous = (timestamp, temperature, tds);
+    }
+    if !reader.0.is_empty() {
+        return Err(anyhow!("{} bytes left after the last record", reader.0.len()));
+    }
+
+    Ok(records)
+}
+
+fn quantize(value: Option<f32>, scale: f32) -> i16 {
+    match value.filter(|v| v.is_finite()) {
+        Some(value) => (value * scale)
+            .round()
+            .clamp(f32::from(MISSING + 1), f32::from(i16::MAX)) as i16,
+        None => MISSING,
+    }
+}
+
+fn dequantize(value: i16, scale: f32) -> Option<f32> {
+    (value != MISSING).then(|| f32::from(value) / scale)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow!("Truncated binary history"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> anyhow::Result<i16> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Varint longer than 64 bits"))
+    }
+}
+
+/// CRC-32 as used by zlib and Ethernet.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !recovery::crc32_update(!0, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64*, like the simulator's, so failures reproduce
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn history(rng: &mut Rng, len: usize) -> Vec<Record> {
+        let mut timestamp = 1_700_000_000_000 + rng.below(1 << 40) as i64;
+        (0..len)
+            .map(|_| {
+                // Mostly regular, now and then a gap or a clock stepped backwards
+                timestamp += match rng.below(20) {
+                    0 => -(rng.below(3_600_000) as i64),
+                    1 => rng.below(86_400_000) as i64,
+                    _ => 5000 + rng.below(200) as i64,
+                };
+                let temperature = (rng.below(10) != 0).then(|| (rng.below(700) as f32 - 200.0) / 10.0);
+                let tds = (rng.below(10) != 0).then(|| rng.below(3000) as f32);
+                Record {
+                    timestamp,
+                    temperature,
+                    tds,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_histories_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for len in [0, 1, 2, 10, 720, 5000] {
+            let records = history(&mut rng, len);
+            let bytes = encode(&records);
+            assert_eq!(decode(&bytes).unwrap(), records, "{len} records");
+        }
+    }
+
+    #[test]
+    fn regular_records_take_six_bytes() {
+        let records: Vec<_> = (0..720)
+            .map(|i| Record {
+                timestamp: 1_700_000_000_000 + i * 5000,
+                temperature: Some(25.0 + (i % 3) as f32 / 10.0),
+                tds: Some(300.0),
+            })
+            .collect();
+        // The first record is at the base timestamp, its delta a single byte
+        assert_eq!(encode(&records).len(), HEADER_LEN + 5 + 719 * 6 + CRC_LEN);
+    }
+
+    #[test]
+    fn values_are_rounded_and_clamped() {
+        let records = [Record {
+            timestamp: 0,
+            temperature: Some(25.04),
+            tds: Some(99_999.0),
+        }];
+        let decoded = decode(&encode(&records)).unwrap();
+        assert_eq!(decoded[0].temperature, Some(25.0));
+        assert_eq!(decoded[0].tds, Some(f32::from(i16::MAX)));
+
+        let nan = [Record {
+            timestamp: 0,
+            temperature: Some(f32::NAN),
+            tds: None,
+        }];
+        assert_eq!(decode(&encode(&nan)).unwrap()[0].temperature, None);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let bytes = encode(&history(&mut Rng(7), 50));
+        for i in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert!(decode(&corrupt).is_err(), "byte {i}");
+        }
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn fields_of_later_versions_are_skipped() {
+        let records = history(&mut Rng(11), 3);
+        let mut bytes = encode(&records);
+        bytes.truncate(bytes.len() - CRC_LEN);
+
+        // The same records with one more field each, as a later firmware would write them
+        let mut extended = bytes[..HEADER_LEN].to_vec();
+        extended[HEADER_LEN - 1] = FIELDS + 1;
+        let mut reader = Reader(&bytes[HEADER_LEN..]);
+        for _ in 0..records.len() {
+            let start = reader.0;
+            reader.varint().unwrap();
+            reader.take(4).unwrap();
+            extended.extend_from_slice(&start[..start.len() - reader.0.len()]);
+            extended.extend_from_slice(&7_i16.to_le_bytes());
+        }
+        let crc = crc32(&extended);
+        extended.extend_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(decode(&extended).unwrap(), records);
+    }
+
+    #[test]
+    fn crc_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
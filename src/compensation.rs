@@ -0,0 +1,40 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Pure conversion helpers shared by the probes. Temperatures are in °C, pressures in kPa.
+
+const STANDARD_PRESSURE: f32 = 101.325;
+
+/// Divisor bringing a TDS probe voltage measured at `temperature` back to 25 °C.
+///
+/// See https://wiki.keyestudio.com/KS0429_keyestudio_TDS_Meter_V1.0
+pub(crate) fn tds_temperature_coefficient(temperature: f32) -> f32 {
+    1.0 + 0.02 * (temperature - 25.0)
+}
+
+/// Standard-atmosphere pressure at `altitude` meters above sea level.
+pub(crate) fn pressure_at_altitude(altitude: f32) -> f32 {
+    STANDARD_PRESSURE * (1.0 - 2.25577e-5 * altitude).powf(5.25588)
+}
+
+/// Dissolved oxygen in mg/L of fresh water saturated with air at `temperature` and `pressure`.
+///
+/// Benson & Krause (1984) with the pressure correction from USGS OFR 2011-1091.
+#[allow(dead_code)]
+pub(crate) fn do_saturation(temperature: f32, pressure: f32) -> f32 {
+    let t = temperature as f64;
+    let k = t + 273.15;
+    let p = (pressure / STANDARD_PRESSURE) as f64;
+
+    let ln_do =
+        -139.34411 + 1.575701e5 / k - 6.642308e7 / k.powi(2) + 1.243800e10 / k.powi(3) - 8.621949e11 / k.powi(4);
+
+    // Water vapor pressure in atm, and the oxygen second virial coefficient term
+    let vapor = (11.8571 - 3840.70 / k - 216961.0 / k.powi(2)).exp();
+    let theta = 0.000975 - 1.426e-5 * t + 6.436e-8 * t.powi(2);
+    let correction = p * (1.0 - vapor / p) * (1.0 - theta * p) / ((1.0 - vapor) * (1.0 - theta));
+
+    (ln_do.exp() * correction) as f32
+}
@@ -3,27 +3,66 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use std::{borrow::Cow, time::Duration};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
-use chrono::Utc;
+use chrono::{Timelike, Utc};
 use chrono_tz::Tz;
 use embedded_graphics::{
     Drawable, geometry,
     image::ImageRaw,
-    mono_font::{DecorationDimensions, MonoFont, MonoTextStyle, mapping},
+    mono_font::{DecorationDimensions, MonoFont, MonoTextStyle, ascii::FONT_5X7, mapping},
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder},
+    primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Triangle},
     text::{Baseline, Text},
 };
 use esp_idf_svc::hal::i2c::I2cError;
-use log::error;
+use log::{error, info, warn};
+use serde::Deserialize;
 use sh1106::{mode::GraphicsMode, prelude::*};
 use tokio::time::MissedTickBehavior;
-use tokio::{task, time::interval};
+use tokio::{
+    task,
+    time::{self, interval, interval_at},
+};
+
+use crate::{
+    alerts, assistant, factory, latency, lifecycle, lockdown, measurements, network, nvs, ota, probes, registry,
+    system, thermal, troubleshoot,
+};
+
+/// Controller of the OLED module, reported in `/manifest`.
+pub(crate) const DRIVER: &str = "sh1106";
+
+// Addresses SH1106 modules are strapped to, the first being by far the most common
+const ADDRESSES: [u8; 2] = [0x3c, 0x3d];
 
-use crate::{measurements, network, nvs};
+// Width in characters of the large value fields
+const VALUE_WIDTH: usize = 7;
+
+const INTERVAL: Duration = Duration::from_secs(1);
+const THROTTLED_INTERVAL: Duration = Duration::from_secs(5);
+// Faster redraws while a test pattern runs, so the sweep moves smoothly
+const TEST_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) const WIDTH: i32 = 128;
+pub(crate) const HEIGHT: i32 = 64;
+const SWEEP_WIDTH: u32 = 8;
+const HEARTBEAT_SIZE: u32 = 2;
+
+const DEFAULT_TEST_SECONDS: u64 = 10;
+pub(crate) const MAX_TEST_SECONDS: u64 = 60;
+// Distance between the two spots the heartbeat alternates between
+const HEARTBEAT_STEP: i32 = 3;
+
+// Below the floor where HTTP sheds even light requests, drawing switches to a layout that never
+// allocates, and only switches back once the heap has recovered well above it
+const EMERGENCY_HEAP: u32 = 20 * 1024;
+const RECOVERY_HEAP: u32 = 32 * 1024;
 
 const STYLE_LINE: PrimitiveStyle<BinaryColor> = PrimitiveStyleBuilder::new()
     .stroke_width(1)
@@ -39,6 +78,8 @@ const FONT_TER_14: MonoFont = MonoFont {
     underline: DecorationDimensions::new(11 + 2, 1),
     strikethrough: DecorationDimensions::new(14 / 2, 1),
 };
+const STYLE_FILL: PrimitiveStyle<BinaryColor> = PrimitiveStyle::with_fill(BinaryColor::On);
+
 const STYLE_TER_14: MonoTextStyle<BinaryColor> = MonoTextStyle::new(&FONT_TER_14, BinaryColor::On);
 
 const FONT_TER_24: MonoFont = MonoFont {
@@ -52,31 +93,376 @@ const FONT_TER_24: MonoFont = MonoFont {
 };
 const STYLE_TER_24: MonoTextStyle<BinaryColor> = MonoTextStyle::new(&FONT_TER_24, BinaryColor::On);
 
+const STYLE_SMALL: MonoTextStyle<BinaryColor> = MonoTextStyle::new(&FONT_5X7, BinaryColor::On);
+// Characters of the small font fitting across the panel, each 5 pixels and a space wide
+const SMALL_COLUMNS: usize = WIDTH as usize / 6;
+
+/// Test patterns for spotting dead pixels and columns.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Pattern {
+    Checkerboard,
+    AllOn,
+    AllOff,
+    GradientSweep,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Test {
+    pattern: Pattern,
+    started: Instant,
+    duration: Duration,
+}
+
+static TEST: Mutex<Option<Test>> = Mutex::new(None);
+static SCREENSHOT: Mutex<Option<Frame>> = Mutex::new(None);
+static LAST_ACTIVITY: Mutex<Option<Instant>> = Mutex::new(None);
+static ADDRESS: OnceLock<u8> = OnceLock::new();
+
+/// A monochrome frame, rows top to bottom packed MSB first, a set bit being a lit pixel.
+#[derive(Clone)]
+pub(crate) struct Frame([u8; (WIDTH * HEIGHT / 8) as usize]);
+
+impl Frame {
+    const BLANK: Self = Self([0; (WIDTH * HEIGHT / 8) as usize]);
+
+    pub fn bits(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn set(&mut self, Pixel(point, color): Pixel<BinaryColor>) {
+        if !(0..WIDTH).contains(&point.x) || !(0..HEIGHT).contains(&point.y) {
+            return;
+        }
+
+        let index = (point.y * WIDTH + point.x) as usize;
+        let mask = 0x80 >> (index % 8);
+        if color.is_on() {
+            self.0[index / 8] |= mask;
+        } else {
+            self.0[index / 8] &= !mask;
+        }
+    }
+
+    fn get(&self, point: Point) -> bool {
+        if !(0..WIDTH).contains(&point.x) || !(0..HEIGHT).contains(&point.y) {
+            return false;
+        }
+
+        let index = (point.y * WIDTH + point.x) as usize;
+        self.0[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+/// Draws to the panel while keeping a copy of the frame, published for screenshots once flushed.
+struct Shadowed<D> {
+    inner: D,
+    frame: Frame,
+}
+
+impl<I2C> Shadowed<GraphicsMode<I2cInterface<I2C>>>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.frame = Frame::BLANK;
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush().map_err(|e| anyhow!("{e:?}"))?;
+
+        // Copied only after a complete flush, so a screenshot never shows a half-drawn frame
+        *SCREENSHOT.lock().unwrap() = Some(self.frame.clone());
+        lifecycle::reach(lifecycle::Milestone::Displayed);
+
+        Ok(())
+    }
+}
+
+impl<D> Shadowed<D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    /// Flips every pixel drawn so far within `area`, read back from the frame.
+    fn invert(&mut self, area: Rectangle) -> Result<(), D::Error> {
+        // Drawing updates the frame, so the pixels are read from a copy of it
+        let frame = self.frame.clone();
+        self.draw_iter(
+            area.points()
+                .map(|point| Pixel(point, BinaryColor::from(!frame.get(point)))),
+        )
+    }
+}
+
+impl<D> DrawTarget for Shadowed<D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    type Color = BinaryColor;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let frame = &mut self.frame;
+        self.inner
+            .draw_iter(pixels.into_iter().inspect(|&pixel| frame.set(pixel)))
+    }
+}
+
+impl<D> OriginDimensions for Shadowed<D>
+where
+    D: OriginDimensions,
+{
+    fn size(&self) -> Size {
+        self.inner.size()
+    }
+}
+
+/// The last frame flushed to the panel.
+pub(crate) fn screenshot() -> Option<Frame> {
+    SCREENSHOT.lock().unwrap().clone()
+}
+
 pub(crate) struct Context<I2C>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    graphics: GraphicsMode<I2cInterface<I2C>>,
+    graphics: Shadowed<GraphicsMode<I2cInterface<I2C>>>,
     timezone: Tz,
+    decimal_separator: char,
+    heartbeat: Option<Heartbeat>,
+    /// Blank the panel after this long without activity, never if unset.
+    idle_timeout: Option<Duration>,
+    blanked: bool,
+    /// Drawing the reduced layout because the heap is nearly exhausted.
+    emergency: Option<Emergency>,
+}
+
+/// Kept while in the emergency layout and logged once out of it, since logging on the way in could be the
+/// allocation that fails.
+#[derive(Debug, Clone, Copy)]
+struct Emergency {
+    since: Instant,
+    lowest_heap: u32,
+}
+
+/// Fixed-capacity text on the stack, for drawing without touching the heap. Overflow is truncated.
+struct StackText<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StackText<N> {
+    const fn new() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &b in s.as_bytes() {
+            if self.len == N {
+                return;
+            }
+            self.bytes[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_char(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Appends a non-negative integer, zero-padded to `min_digits`.
+    fn push_uint(&mut self, mut value: u32, min_digits: usize) {
+        let mut digits = [0_u8; 10];
+        let mut count = 0;
+        while value > 0 || count < min_digits.clamp(1, digits.len()) {
+            digits[count] = b'0' + (value % 10) as u8;
+            value /= 10;
+            count += 1;
+        }
+        for &digit in digits[..count].iter().rev() {
+            self.push_char(digit as char);
+        }
+    }
+
+    /// Appends `value` rounded to `decimals`, scaled and split in integer arithmetic.
+    fn push_fixed(&mut self, value: f32, decimals: u32, separator: char) {
+        let scale = 10_u32.pow(decimals);
+        let scaled = (value * scale as f32).round() as i32;
+        if scaled < 0 {
+            self.push_char('-');
+        }
+        let scaled = scaled.unsigned_abs();
+        self.push_uint(scaled / scale, 1);
+        if decimals > 0 {
+            self.push_char(separator);
+            self.push_uint(scaled % scale, decimals as usize);
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Truncation may have split a multi-byte character, drop what is left of it
+        match std::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(s) => s,
+            Err(e) => std::str::from_utf8(&self.bytes[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+
+/// A block toggling between two spots whenever a new measurement made it to the panel.
+struct Heartbeat {
+    origin: Point,
+    seq: Option<u32>,
+    phase: bool,
+}
+
+/// I2C address the display answered on, absent when running headless.
+pub(crate) fn address() -> Option<u8> {
+    ADDRESS.get().copied()
 }
 
-pub(crate) fn init<I2C>(i2c: I2C) -> anyhow::Result<Box<Context<I2C>>>
+/// Finds and sets up the display, or returns `None` to run headless when nothing answers.
+pub(crate) fn init<I2C>(mut i2c: I2C) -> anyhow::Result<Option<Box<Context<I2C>>>>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
     task::block_in_place(move || {
-        let mut graphics: GraphicsMode<_> = sh1106::Builder::new().connect_i2c(i2c).into();
-        graphics.init().unwrap();
+        let address = nvs::get_parsed_or("display.address", None, |v| parse_address(v).map(Some))?;
+        let address = address.or_else(|| detect(&mut i2c));
+        let Some(address) = address else {
+            warn!("No display found at {ADDRESSES:02x?}, running headless");
+            lifecycle::reach(lifecycle::Milestone::Displayed);
+            return Ok(None);
+        };
+
+        let mut graphics: GraphicsMode<_> = sh1106::Builder::new().with_i2c_addr(address).connect_i2c(i2c).into();
+        if let Err(e) = graphics.init() {
+            warn!("Display at 0x{address:02x} failed to initialize, running headless: {e:?}");
+            lifecycle::reach(lifecycle::Milestone::Displayed);
+            return Ok(None);
+        }
         graphics.clear();
-        graphics.flush().unwrap();
+        graphics.flush().map_err(|e| anyhow!("{e:?}"))?;
+        let _ = ADDRESS.set(address);
+        info!("Display found at 0x{address:02x}");
+
+        // A board fresh out of the box has no timezone yet and shows UTC until set up
+        let timezone = nvs::get_parsed_or("display.timezone", Tz::UTC, parse_timezone)?;
+        let decimal_separator = nvs::get_parsed_or("display.decimal_separator", '.', parse_decimal_separator)?;
+
+        let heartbeat = if nvs::get_flag("display.heartbeat")? {
+            let origin = nvs::get_parsed_or("display.heartbeat_corner", heartbeat_origin(Corner::BottomRight), |v| {
+                parse_heartbeat_corner(v).map(heartbeat_origin)
+            })?;
+            Some(Heartbeat {
+                origin,
+                seq: None,
+                phase: false,
+            })
+        } else {
+            None
+        };
 
-        let timezone = nvs::get("timezone")?;
-        let timezone: Tz = timezone.parse()?;
+        let idle_timeout = nvs::get_parsed_or("display.idle_timeout", None, |v| parse_idle_timeout(v).map(Some))?;
 
-        Ok(Box::new(Context { graphics, timezone }))
+        Ok(Some(Box::new(Context {
+            graphics: Shadowed {
+                inner: graphics,
+                frame: Frame::BLANK,
+            },
+            timezone,
+            decimal_separator,
+            heartbeat,
+            idle_timeout,
+            blanked: false,
+            emergency: None,
+        })))
     })
 }
 
+// Probes the usual addresses with an empty command, taking the first that acknowledges
+fn detect<I2C>(i2c: &mut I2C) -> Option<u8>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    const COMMAND: u8 = 0x00;
+
+    ADDRESSES
+        .into_iter()
+        .find(|&address| i2c.write(address, &[COMMAND]).is_ok())
+}
+
+/// Parses an override of the display address, in hex with a `0x` prefix or decimal.
+pub(crate) fn parse_address(v: &str) -> anyhow::Result<u8> {
+    let address = match v.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16)?,
+        None => v.parse()?,
+    };
+    if address > 0x7f {
+        return Err(anyhow!("Invalid display address: {v}"));
+    }
+
+    Ok(address)
+}
+
+/// Parses `display.timezone`, an IANA name such as `Asia/Tokyo`.
+pub(crate) fn parse_timezone(v: &str) -> anyhow::Result<Tz> {
+    v.parse().map_err(|_| anyhow!("Unknown timezone: {v}"))
+}
+
+/// Parses `display.decimal_separator`, a point or a comma.
+pub(crate) fn parse_decimal_separator(v: &str) -> anyhow::Result<char> {
+    match v {
+        "." => Ok('.'),
+        "," => Ok(','),
+        _ => Err(anyhow!("Decimal separator must be . or ,: {v}")),
+    }
+}
+
+/// Corner of the panel holding the heartbeat block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Parses `display.heartbeat_corner`, e.g. `top_left`.
+pub(crate) fn parse_heartbeat_corner(v: &str) -> anyhow::Result<Corner> {
+    match v {
+        "top_left" => Ok(Corner::TopLeft),
+        "top_right" => Ok(Corner::TopRight),
+        "bottom_left" => Ok(Corner::BottomLeft),
+        "bottom_right" => Ok(Corner::BottomRight),
+        _ => Err(anyhow!("Invalid heartbeat corner: {v}")),
+    }
+}
+
+// The origin is the spot nearer the corner, the second one lies inwards
+fn heartbeat_origin(corner: Corner) -> Point {
+    let (right, bottom) = (WIDTH - HEARTBEAT_SIZE as i32, HEIGHT - HEARTBEAT_SIZE as i32);
+    match corner {
+        Corner::TopLeft => Point::new(0, 0),
+        Corner::TopRight => Point::new(right, 0),
+        Corner::BottomLeft => Point::new(0, bottom),
+        Corner::BottomRight => Point::new(right, bottom),
+    }
+}
+
+/// Parses `display.idle_timeout`, in seconds.
+pub(crate) fn parse_idle_timeout(v: &str) -> anyhow::Result<Duration> {
+    let seconds: u64 = v.parse()?;
+    if seconds == 0 {
+        return Err(anyhow!("Idle timeout must be at least a second"));
+    }
+
+    Ok(Duration::from_secs(seconds))
+}
+
 pub(crate) async fn greet<I2C>(ctx: &mut Box<Context<I2C>>) -> anyhow::Result<()>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
@@ -89,25 +475,97 @@ where
         Text::with_baseline("Cobitis v1.2", Point::new(16, 18), STYLE_TER_14, Baseline::Top).draw(graphics)?;
         Text::with_baseline("Starting...", Point::new(20, 36), STYLE_TER_14, Baseline::Top).draw(graphics)?;
 
-        ctx.graphics.flush().map_err(|e| anyhow!("{e:?}"))?;
+        ctx.graphics.flush()?;
 
         Ok(())
     })
 }
 
-pub(crate) async fn worker<I2C>(ctx: &mut Box<Context<I2C>>) -> anyhow::Result<()>
+pub(crate) async fn worker<I2C>(ctx: Option<&mut Box<Context<I2C>>>) -> anyhow::Result<()>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    let mut interval = interval(Duration::from_secs(1));
+    // Headless, there is nothing to draw
+    let Some(ctx) = ctx else {
+        return std::future::pending().await;
+    };
+
+    let mut period = cadence();
+    let mut interval = interval(period);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
     loop {
         interval.tick().await;
 
+        let started = Instant::now();
         if let Err(e) = draw(ctx).await {
             error!("Failed to draw: {e:?}");
         }
+        latency::DISPLAY_DRAW.record(started.elapsed());
+
+        if cadence() != period {
+            period = cadence();
+            interval = interval_at(time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        }
+    }
+}
+
+/// Records someone interacting with the device, waking the panel if it was blanked for being idle.
+pub(crate) fn wake() {
+    *LAST_ACTIVITY.lock().unwrap() = Some(Instant::now());
+}
+
+// Whether nobody has interacted with the device for `timeout`, counting from boot until someone does
+fn idle(timeout: Duration) -> bool {
+    let last = *LAST_ACTIVITY.lock().unwrap();
+    last.map_or_else(
+        || system::uptime() >= timeout.as_secs(),
+        |last| last.elapsed() >= timeout,
+    )
+}
+
+/// Validates a requested test length in seconds, defaulting to 10 when unset.
+pub(crate) fn test_duration(seconds: Option<u64>) -> Option<Duration> {
+    let seconds = seconds.unwrap_or(DEFAULT_TEST_SECONDS);
+    (1..=MAX_TEST_SECONDS)
+        .contains(&seconds)
+        .then(|| Duration::from_secs(seconds))
+}
+
+/// Replaces the normal screens with `pattern` for `duration`, after which they resume by themselves.
+pub(crate) fn start_test(pattern: Pattern, duration: Duration) {
+    wake();
+    *TEST.lock().unwrap() = Some(Test {
+        pattern,
+        started: Instant::now(),
+        duration,
+    });
+    info!("Showing {pattern:?} test pattern for {duration:?}");
+}
+
+/// Ends the running test pattern early.
+pub(crate) fn stop_test() {
+    *TEST.lock().unwrap() = None;
+}
+
+// The running test, dropping it once expired
+fn test() -> Option<Test> {
+    let mut test = TEST.lock().unwrap();
+    if test.is_some_and(|t| t.started.elapsed() >= t.duration) {
+        *test = None;
+    }
+
+    *test
+}
+
+fn cadence() -> Duration {
+    if test().is_some() {
+        TEST_INTERVAL
+    } else if thermal::throttled() {
+        THROTTLED_INTERVAL
+    } else {
+        INTERVAL
     }
 }
 
@@ -115,60 +573,894 @@ async fn draw<I2C>(ctx: &mut Context<I2C>) -> anyhow::Result<()>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    let (temp, tds) = {
-        let m = measurements::get().await;
-        (m.map(|m| m.temperature), m.map(|m| m.tds))
-    };
+    // Decided before anything below gets a chance to allocate
+    let free_heap = system::free_heap();
+    match &mut ctx.emergency {
+        None if free_heap < EMERGENCY_HEAP => {
+            ctx.emergency = Some(Emergency {
+                since: Instant::now(),
+                lowest_heap: free_heap,
+            });
+        }
+        Some(emergency) if free_heap >= RECOVERY_HEAP => {
+            warn!(
+                "Drew the emergency layout for {} s with the free heap down to {} bytes, back to {free_heap} bytes",
+                emergency.since.elapsed().as_secs(),
+                emergency.lowest_heap
+            );
+            ctx.emergency = None;
+        }
+        Some(emergency) => emergency.lowest_heap = emergency.lowest_heap.min(free_heap),
+        None => {}
+    }
+    if ctx.emergency.is_some() {
+        let values = measurements::get();
+        return task::block_in_place(move || draw_emergency(ctx, values));
+    }
+
+    let values = measurements::get();
     let signal_level: i32 = {
         let v = network::get().await;
         v.map(|v| v.signal_quality).unwrap_or_default().into()
     };
+    let session = assistant::get();
+    let test = test();
+    let factory = factory::state();
+    let troubleshooting = troubleshoot::state();
+    let update = ota::progress();
+    let new_probe = probes::banner();
+
+    // Tests, water changes, troubleshooting and updates mean someone is watching, an alarm that someone should be.
+    // Either keeps the panel on regardless
+    let watched = test.is_some()
+        || update.is_some()
+        || alerts::active()
+        || new_probe
+        || session.is_some()
+        || !matches!(factory, factory::State::Idle)
+        || !matches!(troubleshooting, troubleshoot::State::Idle);
+    let blank = !watched && ctx.idle_timeout.is_some_and(idle);
+    if blank && ctx.blanked {
+        return Ok(());
+    }
 
     task::block_in_place(move || {
-        let graphics = &mut ctx.graphics;
+        ctx.graphics.clear();
 
-        graphics.clear();
+        if blank {
+            ctx.graphics.flush()?;
+            ctx.blanked = true;
+            return Ok(());
+        }
+        ctx.blanked = false;
 
-        // Draw date & time
-        let text = Utc::now()
-            .with_timezone(&ctx.timezone)
-            .format("%m/%d %H:%M")
-            .to_string();
-        Text::with_baseline(&text, Point::new(10, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
-
-        // Draw signal quality bars
-        for i in 1..=signal_level {
-            let x = 107 + i * 2;
-            let y = 12 - i * 2;
-            Line::new(Point::new(x, y), Point::new(x, 11))
-                .into_styled(STYLE_LINE)
-                .draw(graphics)?;
+        match (test, session, network::setup()) {
+            _ if update.is_some() => draw_ota(ctx, update)?,
+            (Some(test), _, _) => draw_test(ctx, &test)?,
+            _ if !matches!(factory, factory::State::Idle) => draw_factory(ctx, &factory)?,
+            _ if !matches!(troubleshooting, troubleshoot::State::Idle) => draw_troubleshoot(ctx, &troubleshooting)?,
+            _ if new_probe => draw_new_probe(ctx)?,
+            (None, _, Some(setup)) => draw_setup(ctx, setup)?,
+            (None, Some(session), None) => draw_water_change(ctx, &session, values)?,
+            (None, None, None) => draw_main(ctx, values, signal_level)?,
         }
 
-        // Draw temperature
-        let text: Cow<_> = if let Some(v) = temp {
-            format!("{v:>7.1}").into()
-        } else {
-            "    -.-".into()
+        // Only toggles once a new measurement is flushed, so it freezes if either side stalls
+        let seq = values.map(|v| v.seq);
+        let advanced = ctx.heartbeat.as_ref().is_some_and(|h| h.seq != seq);
+        if test.is_none() {
+            draw_heartbeat(ctx, advanced)?;
+        }
+
+        ctx.graphics.flush()?;
+
+        if let Some(heartbeat) = ctx.heartbeat.as_mut().filter(|_| advanced) {
+            heartbeat.seq = seq;
+            heartbeat.phase = !heartbeat.phase;
+        }
+
+        Ok(())
+    })
+}
+
+fn draw_main<I2C>(
+    ctx: &mut Context<I2C>,
+    values: Option<measurements::Values>,
+    signal_level: i32,
+) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let now = Utc::now().timestamp_millis();
+    // A sensor just switched back on shows the placeholder until its first reading
+    let temp = values
+        .filter(|m| m.temperature_enabled)
+        .map(|m| (m.temperature, m.temperature_age(now)));
+    let tds = values.filter(|m| m.tds_enabled).map(|m| (m.tds, m.tds_age(now)));
+    let warming_up = values.is_some_and(|v| v.warming_up);
+    let graphics = &mut ctx.graphics;
+
+    // Draw date & time, with the date giving way to a tag when the device is not running normally or
+    // the values are synthetic. Without a valid clock, the uptime is the only useful time there is
+    let text = if !system::clock_valid() {
+        format_uptime(system::uptime())
+    } else {
+        let format = match lifecycle::state() {
+            lifecycle::State::Connecting => "WAIT  %H:%M",
+            lifecycle::State::Throttled => "HOT   %H:%M",
+            lifecycle::State::Ota => "OTA   %H:%M",
+            _ if values.is_some_and(|v| v.simulated) => "SIM   %H:%M",
+            _ => "%m/%d %H:%M",
         };
+        Utc::now().with_timezone(&ctx.timezone).format(format).to_string()
+    };
+    Text::with_baseline(&text, Point::new(10, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Draw a padlock left of the clock while changes over the network are disabled
+    if lockdown::enabled() {
+        Rectangle::new(Point::new(2, 2), Size::new(5, 5))
+            .into_styled(STYLE_LINE)
+            .draw(graphics)?;
+        Rectangle::new(Point::new(1, 6), Size::new(7, 6))
+            .into_styled(STYLE_FILL)
+            .draw(graphics)?;
+    }
+
+    // Draw signal quality bars
+    for i in 1..=signal_level {
+        let x = 107 + i * 2;
+        let y = 12 - i * 2;
+        Line::new(Point::new(x, y), Point::new(x, 11))
+            .into_styled(STYLE_LINE)
+            .draw(graphics)?;
+    }
+
+    // Draw temperature
+    let metric = &registry::TEMPERATURE;
+    let placeholder = format!("-{}-", ctx.decimal_separator);
+    if measurements::enabled(measurements::Sensor::Temperature) {
+        draw_reading(
+            graphics,
+            Point::new(0, 16),
+            metric,
+            temp,
+            &placeholder,
+            ctx.decimal_separator,
+        )?;
+    } else {
+        draw_disabled(graphics, Point::new(0, 16))?;
+    }
+    if flash(measurements::Sensor::Temperature) {
+        graphics.invert(value_field(Point::new(0, 16)))?;
+    }
+    Text::with_baseline(metric.label, Point::new(89, 23), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Draw TDS
+    let metric = &registry::TDS;
+    if measurements::enabled(measurements::Sensor::Tds) {
+        draw_reading(graphics, Point::new(0, 40), metric, tds, "-", ctx.decimal_separator)?;
+    } else {
+        draw_disabled(graphics, Point::new(0, 40))?;
+    }
+    if flash(measurements::Sensor::Tds) {
+        graphics.invert(value_field(Point::new(0, 40)))?;
+    }
+    Text::with_baseline(metric.label, Point::new(90, 47), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Draw the rise since the last water change above the unit, e.g. "+23%"
+    let rise = tds
+        .filter(|&(_, age)| measurements::freshness(age) < measurements::Freshness::Stale)
+        .and_then(|(v, _)| assistant::rise(v));
+    if let Some(rise) = rise.filter(|_| !warming_up) {
+        let text = format!("{:+.0}%", rise.percent.clamp(-999.0, 999.0));
+        Text::with_baseline(&text, Point::new(90, 39), STYLE_SMALL, Baseline::Top).draw(graphics)?;
+    }
+
+    // Draw an hourglass right of the unit while the probe is still settling
+    if warming_up {
+        Triangle::new(Point::new(116, 48), Point::new(122, 48), Point::new(119, 52))
+            .into_styled(STYLE_FILL)
+            .draw(graphics)?;
+        Triangle::new(Point::new(119, 53), Point::new(116, 57), Point::new(122, 57))
+            .into_styled(STYLE_FILL)
+            .draw(graphics)?;
+    }
+
+    Ok(())
+}
+
+fn draw_water_change<I2C>(
+    ctx: &mut Context<I2C>,
+    session: &assistant::Session,
+    values: Option<measurements::Values>,
+) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let now = Utc::now().timestamp_millis();
+    let reading = values.filter(|m| m.tds_enabled).map(|m| (m.tds, m.tds_age(now)));
+    let graphics = &mut ctx.graphics;
+
+    // Draw title & elapsed time
+    let elapsed = session.started.elapsed().as_secs();
+    let text = format!("Water chg {:>2}:{:02}", elapsed / 60, elapsed % 60);
+    Text::with_baseline(&text, Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Draw live TDS
+    let metric = &registry::TDS;
+    if measurements::enabled(measurements::Sensor::Tds) {
+        draw_reading(graphics, Point::new(0, 16), metric, reading, "-", ctx.decimal_separator)?;
+    } else {
+        draw_disabled(graphics, Point::new(0, 16))?;
+    }
+    Text::with_baseline(metric.label, Point::new(90, 23), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Draw change since the session started
+    let tds = reading
+        .filter(|&(_, age)| measurements::freshness(age) != measurements::Freshness::Expired)
+        .map(|(v, _)| v);
+    let text = match (session.before, tds) {
+        (Some(before), Some(now)) => {
+            let delta = registry::round(now - before, metric.display_precision);
+            format!("Change {delta:>+6.prec$}", prec = metric.display_precision)
+        }
+        _ => "Change      -".to_owned(),
+    };
+    Text::with_baseline(&text, Point::new(0, 46), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    Ok(())
+}
+
+/// Draws a reading into a large value field at `origin`, toned down as it ages: bold while live, regular
+/// once aging, small with its age once stale, and `placeholder` once expired.
+fn draw_reading<D>(
+    graphics: &mut D,
+    origin: Point,
+    metric: &registry::Metric,
+    reading: Option<(f32, Duration)>,
+    placeholder: &str,
+    separator: char,
+) -> anyhow::Result<()>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    // Width in small characters of the value in front of the age, e.g. `  24.5 40s`
+    const SMALL_VALUE_WIDTH: usize = 6;
+
+    let freshness = reading.map_or(measurements::Freshness::Expired, |(_, age)| {
+        measurements::freshness(age)
+    });
+    let value = reading
+        .map(|(v, _)| v)
+        .filter(|_| freshness != measurements::Freshness::Expired);
+
+    match (value, freshness) {
+        (Some(v), measurements::Freshness::Stale) => {
+            let age = reading.map_or(Duration::ZERO, |(_, age)| age);
+            let text = format!(
+                "{} {:>3}",
+                format_value(metric, v, SMALL_VALUE_WIDTH, separator),
+                format_age(age.as_secs())
+            );
+            // Sits on the same baseline as the unit next to it
+            Text::with_baseline(&text, origin + Point::new(0, 7), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+        }
+        (Some(v), freshness) => {
+            let text = format_value(metric, v, VALUE_WIDTH, separator);
+            Text::with_baseline(&text, origin, STYLE_TER_24, Baseline::Top).draw(graphics)?;
+            if freshness == measurements::Freshness::Live {
+                Text::with_baseline(&text, origin + Point::new(1, 0), STYLE_TER_24, Baseline::Top).draw(graphics)?;
+            }
+        }
+        (None, _) => {
+            let text = format!("{placeholder:>VALUE_WIDTH$}");
+            Text::with_baseline(&text, origin, STYLE_TER_24, Baseline::Top).draw(graphics)?;
+            Text::with_baseline(&text, origin + Point::new(1, 0), STYLE_TER_24, Baseline::Top).draw(graphics)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the value field of `sensor` is shown inverted this tick, blinking while its alarm is raised. The
+// uptime steps an odd number of seconds per tick, at the throttled cadence as well
+fn flash(sensor: measurements::Sensor) -> bool {
+    alerts::alarm(sensor).is_some() && system::uptime() % 2 == 0
+}
+
+/// Area of the large value field at `origin`.
+fn value_field(origin: Point) -> Rectangle {
+    let cell = FONT_TER_24.character_size;
+    Rectangle::new(origin, Size::new(VALUE_WIDTH as u32 * cell.width, cell.height))
+}
+
+/// Draws a struck-through dash into a large value field at `origin`, for a sensor switched off by the user.
+fn draw_disabled<D>(graphics: &mut D, origin: Point) -> anyhow::Result<()>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    let text = format!("{:>VALUE_WIDTH$}", "-");
+    Text::with_baseline(&text, origin, STYLE_TER_24, Baseline::Top).draw(graphics)?;
+
+    // Slashed through the cell of the dash, which sits last in the field
+    let cell = FONT_TER_24.character_size;
+    let cell_origin = origin + Point::new(((VALUE_WIDTH - 1) as u32 * cell.width) as i32, 0);
+    Line::new(
+        cell_origin + Point::new(1, cell.height as i32 - 5),
+        cell_origin + Point::new(cell.width as i32 - 2, 4),
+    )
+    .into_styled(STYLE_LINE)
+    .draw(graphics)?;
+
+    Ok(())
+}
+
+/// Formats an age in seconds compactly, e.g. `45s`, `12m` or `3h`.
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..100 => format!("{seconds}s"),
+        100..6000 => format!("{}m", seconds / 60),
+        _ => format!("{}h", (seconds / 3600).min(99)),
+    }
+}
+
+fn draw_heartbeat<I2C>(ctx: &mut Context<I2C>, advanced: bool) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let Some(heartbeat) = &ctx.heartbeat else {
+        return Ok(());
+    };
+
+    // Spots step inwards from the corner, towards the middle of the panel
+    let inwards = Point::new(
+        if heartbeat.origin.x == 0 {
+            HEARTBEAT_STEP
+        } else {
+            -HEARTBEAT_STEP
+        },
+        0,
+    );
+    let position = if heartbeat.phase != advanced {
+        heartbeat.origin + inwards
+    } else {
+        heartbeat.origin
+    };
+    Rectangle::new(position, Size::new(HEARTBEAT_SIZE, HEARTBEAT_SIZE))
+        .into_styled(STYLE_FILL)
+        .draw(&mut ctx.graphics)?;
+
+    Ok(())
+}
+
+fn draw_test<I2C>(ctx: &mut Context<I2C>, test: &Test) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    let area = Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32));
+
+    match test.pattern {
+        Pattern::Checkerboard => {
+            let pixels = area.points().map(|p| Pixel(p, BinaryColor::from((p.x + p.y) % 2 == 0)));
+            graphics.draw_iter(pixels)?;
+        }
+        Pattern::AllOn => area.into_styled(STYLE_FILL).draw(graphics)?,
+        Pattern::AllOff => {}
+        Pattern::GradientSweep => {
+            // The bar crosses the full width once over the duration of the test
+            let progress = test.started.elapsed().as_secs_f32() / test.duration.as_secs_f32();
+            let x = ((WIDTH + SWEEP_WIDTH as i32) as f32 * progress) as i32 - SWEEP_WIDTH as i32;
+            Rectangle::new(Point::new(x, 0), Size::new(SWEEP_WIDTH, HEIGHT as u32))
+                .into_styled(STYLE_FILL)
+                .draw(graphics)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Clock, temperature and a status line, formatted into stack buffers so drawing keeps working when
+/// the heap is nearly exhausted. Every frame starts from a cleared buffer, so switching layouts in
+/// either direction leaves nothing behind.
+fn draw_emergency<I2C>(ctx: &mut Context<I2C>, values: Option<measurements::Values>) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    graphics.clear();
+
+    let mut clock = StackText::<5>::new();
+    if system::clock_valid() {
+        let now = Utc::now().with_timezone(&ctx.timezone);
+        clock.push_uint(now.hour(), 2);
+        clock.push_char(':');
+        clock.push_uint(now.minute(), 2);
+    } else {
+        clock.push_str("--:--");
+    }
+    Text::with_baseline(clock.as_str(), Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    let metric = &registry::TEMPERATURE;
+    let now = Utc::now().timestamp_millis();
+    let mut temperature = StackText::<16>::new();
+    let fresh = |v: &measurements::Values| {
+        v.temperature_enabled && measurements::freshness(v.temperature_age(now)) != measurements::Freshness::Expired
+    };
+    match values.filter(fresh) {
+        Some(v) => temperature.push_fixed(v.temperature, metric.display_precision as u32, ctx.decimal_separator),
+        None => temperature.push_char('-'),
+    }
+    temperature.push_char(' ');
+    temperature.push_str(metric.label);
+    Text::with_baseline(temperature.as_str(), Point::new(0, 18), STYLE_TER_24, Baseline::Top).draw(graphics)?;
+
+    Text::with_baseline("LOW MEMORY", Point::new(0, 48), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    graphics.flush()?;
+    ctx.blanked = false;
+
+    Ok(())
+}
+
+/// Tells the user which access point to join and where to point the browser.
+fn draw_setup<I2C>(ctx: &mut Context<I2C>, setup: &network::SetupAp) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    Text::with_baseline("Setup mode", Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    // Counts down to trying the stored network again
+    if let Some(remaining) = network::setup_remaining() {
+        let seconds = remaining.as_secs();
+        let text = format!("{}:{:02}", seconds / 60, seconds % 60);
+        let origin = Point::new(WIDTH - 6 * text.len() as i32, 3);
+        Text::with_baseline(&text, origin, STYLE_SMALL, Baseline::Top).draw(graphics)?;
+    }
+
+    let lines = [
+        setup.ssid.clone(),
+        format!("pw {}", setup.password),
+        format!("http://{}/", setup.address),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        let origin = Point::new(0, 22 + 12 * i as i32);
+        Text::with_baseline(line, origin, STYLE_SMALL, Baseline::Top).draw(graphics)?;
+    }
+
+    Ok(())
+}
+
+/// Shows the factory test progress, then its verdict with the failed checks for the operator.
+fn draw_factory<I2C>(ctx: &mut Context<I2C>, state: &factory::State) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    Text::with_baseline("Factory test", Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    let checks = match state {
+        factory::State::Idle => return Ok(()),
+        factory::State::Running { step, checks } => {
+            Text::with_baseline(step.name(), Point::new(0, 20), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+            checks
+        }
+        factory::State::AwaitingConfirmation { checks } => {
+            Text::with_baseline("confirm display", Point::new(0, 20), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+            checks
+        }
+        factory::State::Done { passed, checks } => {
+            let verdict = if *passed { "PASS" } else { "FAIL" };
+            Text::with_baseline(verdict, Point::new(0, 16), STYLE_TER_24, Baseline::Top).draw(graphics)?;
+            checks
+        }
+    };
+
+    let failed: Vec<_> = checks.iter().filter(|c| !c.passed).map(|c| c.name.as_str()).collect();
+    if !failed.is_empty() {
+        let text = format!("failed: {}", failed.join(" "));
+        Text::with_baseline(&text, Point::new(0, 56), STYLE_SMALL, Baseline::Top).draw(graphics)?;
+    }
+
+    Ok(())
+}
+
+/// Asks about a temperature probe other than the one calibrated with, for a while after it was found.
+fn draw_new_probe<I2C>(ctx: &mut Context<I2C>) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    Text::with_baseline("New sensor?", Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
 
-        Text::with_baseline(&text, Point::new(0, 16), STYLE_TER_24, Baseline::Top).draw(graphics)?;
-        Text::with_baseline(&text, Point::new(1, 16), STYLE_TER_24, Baseline::Top).draw(graphics)?;
-        Text::with_baseline("°C", Point::new(89, 23), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+    let text = "Calibration was made with another probe. Confirm at /sensors/confirm or recalibrate.";
+    for (i, line) in wrap(text, SMALL_COLUMNS).iter().take(5).enumerate() {
+        let origin = Point::new(0, 20 + 9 * i as i32);
+        Text::with_baseline(line, origin, STYLE_SMALL, Baseline::Top).draw(graphics)?;
+    }
+
+    Ok(())
+}
+
+/// Shows how far an update got, then that the device is about to reboot into it.
+fn draw_ota<I2C>(ctx: &mut Context<I2C>, progress: Option<ota::Progress>) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+    Text::with_baseline("Updating", Point::new(0, 0), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+
+    let (text, percent) = match progress {
+        None => return Ok(()),
+        Some(ota::Progress::Installed) => ("Rebooting...".to_owned(), Some(100)),
+        Some(progress @ ota::Progress::Receiving { received, .. }) => match progress.percent() {
+            Some(percent) => (format!("{percent}%"), Some(percent)),
+            // Without a Content-Length there is no end to show, only the amount so far
+            None => (format!("{} kB", received / 1000), None),
+        },
+    };
+    Text::with_baseline(&text, Point::new(0, 18), STYLE_TER_24, Baseline::Top).draw(graphics)?;
+
+    if let Some(percent) = percent {
+        let bar = Rectangle::new(Point::new(0, 48), Size::new(128, 10));
+        bar.into_styled(STYLE_LINE).draw(graphics)?;
+        let filled = (bar.size.width - 4) * u32::from(percent) / 100;
+        Rectangle::new(Point::new(2, 50), Size::new(filled, 6))
+            .into_styled(STYLE_FILL)
+            .draw(graphics)?;
+    }
+
+    Ok(())
+}
+
+/// Shows the network check in progress one question per screen, then what to do about the first failure.
+fn draw_troubleshoot<I2C>(ctx: &mut Context<I2C>, state: &troubleshoot::State) -> anyhow::Result<()>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let graphics = &mut ctx.graphics;
+
+    match state {
+        troubleshoot::State::Idle => {}
+        troubleshoot::State::Running { step, verdicts } => {
+            let index = troubleshoot::STEPS.iter().position(|s| s == step).unwrap_or_default();
+            let title = format!("Network check {}/{}", index + 1, troubleshoot::STEPS.len());
+            Text::with_baseline(&title, Point::new(0, 0), STYLE_SMALL, Baseline::Top).draw(graphics)?;
+            draw_check_progress(graphics, verdicts, Point::new(0, 10))?;
+
+            Text::with_baseline(step.question(), Point::new(0, 20), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+            let verdict = verdicts.get(index).map_or("checking...".to_owned(), |v| v.summary());
+            Text::with_baseline(&verdict, Point::new(0, 40), STYLE_SMALL, Baseline::Top).draw(graphics)?;
+        }
+        troubleshoot::State::Done { verdicts, advice } => {
+            Text::with_baseline("Network check", Point::new(0, 0), STYLE_SMALL, Baseline::Top).draw(graphics)?;
+            draw_check_progress(graphics, verdicts, Point::new(0, 10))?;
+
+            for (i, line) in wrap(advice, SMALL_COLUMNS).iter().take(5).enumerate() {
+                let origin = Point::new(0, 20 + 9 * i as i32);
+                Text::with_baseline(line, origin, STYLE_SMALL, Baseline::Top).draw(graphics)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// One segment per check across the panel: filled when it passed, crossed out when it failed, a bare
+/// outline while it is pending or was skipped.
+fn draw_check_progress<D>(graphics: &mut D, verdicts: &[troubleshoot::Verdict], origin: Point) -> anyhow::Result<()>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    const GAP: i32 = 3;
+    const SEGMENT_HEIGHT: u32 = 5;
 
-        // Draw TDS
-        let text: Cow<_> = if let Some(v) = tds {
-            format!("{v:>7.0}").into()
+    let count = troubleshoot::STEPS.len() as i32;
+    let segment_width = (WIDTH - GAP * (count - 1)) / count;
+    for i in 0..count {
+        let area = Rectangle::new(
+            origin + Point::new(i * (segment_width + GAP), 0),
+            Size::new(segment_width as u32, SEGMENT_HEIGHT),
+        );
+        let outcome = verdicts.get(i as usize).map(|verdict| verdict.outcome);
+        let style = if outcome == Some(troubleshoot::Outcome::Yes) {
+            STYLE_FILL
         } else {
-            "      -".into()
+            STYLE_LINE
         };
+        area.into_styled(style).draw(graphics)?;
 
-        Text::with_baseline(&text, Point::new(0, 40), STYLE_TER_24, Baseline::Top).draw(graphics)?;
-        Text::with_baseline(&text, Point::new(1, 40), STYLE_TER_24, Baseline::Top).draw(graphics)?;
-        Text::with_baseline("ppm", Point::new(90, 47), STYLE_TER_14, Baseline::Top).draw(graphics)?;
+        if outcome == Some(troubleshoot::Outcome::No) {
+            let corner = area.bottom_right().unwrap_or(area.top_left);
+            Line::new(area.top_left, corner)
+                .into_styled(STYLE_LINE)
+                .draw(graphics)?;
+        }
+    }
 
-        ctx.graphics.flush().map_err(|e| anyhow!("{e:?}"))?;
+    Ok(())
+}
 
-        Ok(())
-    })
+/// Breaks `text` into lines of at most `columns` characters at spaces, splitting words too long to fit.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > columns {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+        while line.chars().count() > columns {
+            let split = line.char_indices().nth(columns).map_or(line.len(), |(i, _)| i);
+            let rest = line.split_off(split);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Formats an uptime in seconds as e.g. `up 3d 04h`, or `up 5h 07m` within the first day.
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("up {days}d {hours:02}h")
+    } else {
+        format!("up {hours}h {minutes:02}m")
+    }
+}
+
+/// Formats a metric's value for the display, or `ERR` if the value cannot be real.
+fn format_value(metric: &registry::Metric, value: f32, width: usize, separator: char) -> String {
+    if metric.is_plausible(value) {
+        format_number(value, metric.display_precision, width, separator)
+    } else {
+        format!("{:>width$}", "ERR")
+    }
+}
+
+/// Formats a number right-aligned in `width` characters using the configured decimal separator.
+///
+/// The sign counts towards the width. Rather than letting the text grow past the field and into the
+/// neighboring label, decimals are dropped first, then thousands are abbreviated (`12.3k`), and a value
+/// that still doesn't fit is shown as out of range. Rounding is shared with the JSON outputs so both
+/// always agree.
+fn format_number(value: f32, precision: usize, width: usize, separator: char) -> String {
+    let candidates = [
+        registry::format(value, precision),
+        registry::format(value, 0),
+        format!("{}k", registry::format(value / 1000.0, 1)),
+        format!("{}k", registry::format(value / 1000.0, 0)),
+    ];
+    let mut text = match candidates.into_iter().find(|text| text.len() <= width) {
+        Some(text) => text,
+        None if value < 0.0 => "<".repeat(width),
+        None => ">".repeat(width),
+    };
+    if separator != '.' {
+        text = text.replace('.', &separator.to_string());
+    }
+
+    format!("{text:>width$}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    // Width of the value in front of the age of a stale reading, see `draw_reading`
+    const SMALL_VALUE_WIDTH: usize = 6;
+
+    #[test]
+    fn plausible_values_fit_both_fields_unabbreviated() {
+        let temperatures = (-200..=600).map(|v| v as f32 / 10.0);
+        let tds = (0..=5000).map(|v| v as f32);
+        for (metric, values) in [
+            (&registry::TEMPERATURE, temperatures.collect::<Vec<_>>()),
+            (&registry::TDS, tds.collect()),
+        ] {
+            for value in values {
+                for width in [VALUE_WIDTH, SMALL_VALUE_WIDTH] {
+                    let text = format_value(metric, value, width, '.');
+                    assert_eq!(text.chars().count(), width, "{value} in {width}: {text:?}");
+                    assert_eq!(text.trim_start(), registry::format(value, metric.display_precision));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn implausible_values_show_as_error() {
+        for (metric, value) in [
+            (&registry::TEMPERATURE, -20.1),
+            (&registry::TEMPERATURE, 85.0),
+            (&registry::TDS, -1.0),
+            (&registry::TDS, 12000.0),
+            (&registry::TDS, f32::NAN),
+        ] {
+            assert_eq!(format_value(metric, value, VALUE_WIDTH, '.'), "    ERR", "{value}");
+        }
+    }
+
+    #[test]
+    fn numbers_too_wide_drop_decimals_then_abbreviate() {
+        assert_eq!(format_number(186.5, 0, 7, '.'), "    187");
+        assert_eq!(format_number(-12.34, 1, 5, '.'), "-12.3");
+        assert_eq!(format_number(-12.34, 1, 4, '.'), " -12");
+        assert_eq!(format_number(12345.0, 0, 5, '.'), "12345");
+        assert_eq!(format_number(12345.0, 0, 4, '.'), " 12k");
+        assert_eq!(format_number(12345.0, 0, 5, ','), "12345");
+        assert_eq!(format_number(123456.0, 0, 5, '.'), " 123k");
+        assert_eq!(format_number(99960.0, 0, 4, '.'), "100k");
+    }
+
+    #[test]
+    fn numbers_that_never_fit_show_their_direction() {
+        assert_eq!(format_number(1.0e7, 0, 4, '.'), ">>>>");
+        assert_eq!(format_number(-1.0e7, 0, 4, '.'), "<<<<");
+        assert_eq!(format_number(-1234.0, 0, 3, '.'), "-1k");
+        assert_eq!(format_number(-12345.0, 0, 3, '.'), "<<<");
+    }
+
+    #[test]
+    fn decimal_separator_replaces_the_point() {
+        assert_eq!(format_number(24.56, 1, 7, ','), "   24,6");
+        assert_eq!(format_number(12345.0, 0, 6, ','), " 12345");
+        assert_eq!(format_number(123456.0, 0, 6, ','), "123,5k");
+    }
+
+    #[test]
+    fn ages_and_uptimes_stay_short() {
+        assert_eq!(format_age(45), "45s");
+        assert_eq!(format_age(99), "99s");
+        assert_eq!(format_age(100), "1m");
+        assert_eq!(format_age(5999), "99m");
+        assert_eq!(format_age(6000), "1h");
+        assert_eq!(format_age(1_000_000), "99h");
+
+        assert_eq!(format_uptime(0), "up 0h 00m");
+        assert_eq!(format_uptime(5 * 3600 + 7 * 60), "up 5h 07m");
+        assert_eq!(format_uptime(3 * 86400 + 4 * 3600), "up 3d 04h");
+    }
+
+    #[test]
+    fn detect_takes_the_first_address_acknowledged() {
+        for (present, expected) in [
+            (&[0x3c, 0x3d][..], Some(0x3c)),
+            (&[0x3d][..], Some(0x3d)),
+            (&[0x3c][..], Some(0x3c)),
+            (&[0x48][..], None),
+            (&[][..], None),
+        ] {
+            let mut i2c = mock::I2c::new(present);
+            assert_eq!(detect(&mut i2c), expected, "{present:02x?}");
+
+            // Probing stops at the first module answering
+            let probed: Vec<_> = i2c.transactions.iter().map(|t| t.address).collect();
+            let expected_probes = match expected {
+                Some(0x3c) => &ADDRESSES[..1],
+                _ => &ADDRESSES[..],
+            };
+            assert_eq!(probed, expected_probes, "{present:02x?}");
+        }
+    }
+
+    #[test]
+    fn address_overrides_parse_as_hex_or_decimal() {
+        assert_eq!(parse_address("0x3d").unwrap(), 0x3d);
+        assert_eq!(parse_address("60").unwrap(), 0x3c);
+        assert_eq!(parse_address("0x7f").unwrap(), 0x7f);
+
+        for v in ["0x80", "128", "256", "0xzz", "3c", "", "0x", "-1"] {
+            assert!(parse_address(v).is_err(), "{v:?}");
+        }
+    }
+
+    #[test]
+    fn wrap_breaks_at_spaces_and_splits_long_words() {
+        assert_eq!(wrap("Probe not found", 10), ["Probe not", "found"]);
+        assert_eq!(wrap("abcdefghijkl", 5), ["abcde", "fghij", "kl"]);
+        assert!(wrap("   ", 5).is_empty());
+    }
+
+    fn context() -> Context<mock::Sink> {
+        let mut graphics: GraphicsMode<_> = sh1106::Builder::new()
+            .with_i2c_addr(0x3c)
+            .connect_i2c(mock::Sink)
+            .into();
+        graphics.init().unwrap();
+        Context {
+            graphics: Shadowed {
+                inner: graphics,
+                frame: Frame::BLANK,
+            },
+            timezone: Tz::UTC,
+            decimal_separator: '.',
+            heartbeat: None,
+            idle_timeout: None,
+            blanked: false,
+            emergency: None,
+        }
+    }
+
+    // A reading taken just now, so neither layout shows it as stale
+    fn values(temperature: f32) -> measurements::Values {
+        let now = Utc::now().timestamp_millis();
+        measurements::Values {
+            timestamp: now,
+            temperature,
+            temperature_at: now,
+            tds: 186.0,
+            tds_at: now,
+            supply_voltage: None,
+            simulated: false,
+            uptime_ms: 60_000,
+            clock_valid: true,
+            seq: 7,
+            compensation: measurements::Compensation {
+                temperature,
+                measured_at: now,
+                fallback: false,
+                k_factor: 1.0,
+                voltage: None,
+            },
+            warming_up: false,
+            temperature_enabled: true,
+            tds_enabled: true,
+        }
+    }
+
+    #[test]
+    fn emergency_layout_draws_without_allocating() {
+        let mut ctx = context();
+        // Statics set up and the milestone logged on the first frame, as happens once at boot
+        draw_emergency(&mut ctx, None).unwrap();
+
+        for values in [Some(values(24.56)), Some(values(-3.2)), None] {
+            let mut result = Ok(());
+            let allocations = mock::count_allocations(|| result = draw_emergency(&mut ctx, values));
+            result.unwrap();
+            assert_eq!(allocations, 0, "{values:?}");
+        }
+    }
+
+    #[test]
+    fn switching_layouts_leaves_nothing_behind() {
+        let v = values(24.56);
+        let expected = |draw: fn(&mut Context<mock::Sink>, measurements::Values)| {
+            let mut ctx = context();
+            draw(&mut ctx, v);
+            ctx.graphics.frame
+        };
+        // As `draw` does, the normal layout starts from a cleared frame and the emergency one clears it itself
+        let main = |ctx: &mut Context<mock::Sink>, v| {
+            ctx.graphics.clear();
+            draw_main(ctx, Some(v), -60).unwrap();
+        };
+        let emergency = |ctx: &mut Context<mock::Sink>, v| draw_emergency(ctx, Some(v)).unwrap();
+        let (main_frame, emergency_frame) = (expected(main), expected(emergency));
+        assert_ne!(main_frame.bits(), emergency_frame.bits());
+
+        let mut ctx = context();
+        main(&mut ctx, v);
+        emergency(&mut ctx, v);
+        assert_eq!(
+            ctx.graphics.frame.bits(),
+            emergency_frame.bits(),
+            "into the emergency layout"
+        );
+        main(&mut ctx, v);
+        assert_eq!(ctx.graphics.frame.bits(), main_frame.bits(), "back out of it");
+    }
 }
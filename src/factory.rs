@@ -0,0 +1,263 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{ops::RangeInclusive, sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::Notify,
+    task,
+    time::{Instant, sleep, timeout},
+};
+
+use crate::{display, journal, measurements, network, nvs, system};
+
+// The operator has this long to look the test pattern over and confirm it
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+// A triggered measurement normally lands within a second, the probe retries take a few more
+const MEASUREMENT_TIMEOUT: Duration = Duration::from_secs(15);
+const MEASUREMENT_POLL: Duration = Duration::from_millis(250);
+
+// Assembled units are tested on the bench, with the probes dry
+const ROOM_TEMPERATURE: RangeInclusive<f32> = 10.0..=40.0;
+const OPEN_CIRCUIT_TDS: RangeInclusive<f32> = 0.0..=20.0;
+
+const NVS_TEST_KEY: &str = "factory.test";
+const NVS_TEST_PATTERN: &[u8] = &[0x55, 0xaa, 0x00, 0xff];
+
+/// Checks in the order they run.
+const STEPS: [Step; 5] = [Step::Display, Step::Temperature, Step::Adc, Step::Wifi, Step::Nvs];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Step {
+    Display,
+    Temperature,
+    Adc,
+    Wifi,
+    Nvs,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Display => "display",
+            Self::Temperature => "temperature",
+            Self::Adc => "adc",
+            Self::Wifi => "wifi",
+            Self::Nvs => "nvs",
+        }
+    }
+}
+
+/// Outcome of one check, journaled and printed as part of the result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Check {
+    pub name: String,
+    pub passed: bool,
+    /// What was measured or why the check failed.
+    pub detail: String,
+}
+
+/// Where the factory test is, served at `/factory` and shown on the display.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum State {
+    Idle,
+    Running {
+        step: Step,
+        checks: Vec<Check>,
+    },
+    /// The test pattern is up and the operator has yet to confirm it.
+    AwaitingConfirmation {
+        checks: Vec<Check>,
+    },
+    Done {
+        passed: bool,
+        checks: Vec<Check>,
+    },
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Idle);
+static START: Notify = Notify::const_new();
+static CONFIRM: Notify = Notify::const_new();
+static VERDICT: Mutex<Option<bool>> = Mutex::new(None);
+
+pub(crate) fn state() -> State {
+    STATE.lock().unwrap().clone()
+}
+
+/// Starts the factory test unless one is already running.
+pub(crate) fn start() -> anyhow::Result<()> {
+    if matches!(
+        *STATE.lock().unwrap(),
+        State::Running { .. } | State::AwaitingConfirmation { .. }
+    ) {
+        return Err(anyhow!("Factory test already running"));
+    }
+
+    START.notify_one();
+    Ok(())
+}
+
+/// Records the operator's verdict on the test pattern.
+pub(crate) fn confirm(passed: bool) -> anyhow::Result<()> {
+    if !matches!(*STATE.lock().unwrap(), State::AwaitingConfirmation { .. }) {
+        return Err(anyhow!("No factory test waiting for confirmation"));
+    }
+
+    *VERDICT.lock().unwrap() = Some(passed);
+    CONFIRM.notify_one();
+    Ok(())
+}
+
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    loop {
+        START.notified().await;
+        info!("Factory test started");
+
+        let mut checks = Vec::new();
+        for step in STEPS {
+            *STATE.lock().unwrap() = State::Running {
+                step,
+                checks: checks.clone(),
+            };
+
+            let check = run(step, &checks).await;
+            if check.passed {
+                info!("Factory test {}: pass, {}", check.name, check.detail);
+            } else {
+                warn!("Factory test {}: fail, {}", check.name, check.detail);
+            }
+            checks.push(check);
+        }
+
+        finish(checks);
+    }
+}
+
+async fn run(step: Step, checks: &[Check]) -> Check {
+    let (passed, detail) = match step {
+        Step::Display => check_display(checks).await,
+        Step::Temperature => match fresh_measurement().await {
+            Some(values) if values.simulated => (false, "simulated".to_owned()),
+            Some(values) if !values.temperature_enabled => (false, "disabled by user".to_owned()),
+            Some(values) => (
+                ROOM_TEMPERATURE.contains(&values.temperature),
+                format!("{:.2} °C", values.temperature),
+            ),
+            None => (false, "no reading".to_owned()),
+        },
+        Step::Adc => match fresh_measurement().await {
+            Some(values) if values.simulated => (false, "simulated".to_owned()),
+            Some(values) if !values.tds_enabled => (false, "disabled by user".to_owned()),
+            Some(values) => (
+                OPEN_CIRCUIT_TDS.contains(&values.tds),
+                format!("{:.0} ppm open circuit", values.tds),
+            ),
+            None => (false, "no reading".to_owned()),
+        },
+        // Being associated proves both the radio and that a network is in range
+        Step::Wifi => match network::get().await {
+            Some(status) if network::is_connected() => (true, format!("{:?}", status.signal_quality)),
+            _ => (false, "not connected".to_owned()),
+        },
+        Step::Nvs => match task::block_in_place(check_nvs) {
+            Ok(()) => (true, "write, read and erase".to_owned()),
+            Err(e) => (false, format!("{e:#}")),
+        },
+    };
+
+    Check {
+        name: step.name().to_owned(),
+        passed,
+        detail,
+    }
+}
+
+async fn check_display(checks: &[Check]) -> (bool, String) {
+    let Some(address) = display::address() else {
+        return (false, "not found".to_owned());
+    };
+
+    *VERDICT.lock().unwrap() = None;
+    *STATE.lock().unwrap() = State::AwaitingConfirmation {
+        checks: checks.to_vec(),
+    };
+    display::start_test(display::Pattern::Checkerboard, CONFIRM_TIMEOUT);
+
+    let confirmed = timeout(CONFIRM_TIMEOUT, CONFIRM.notified()).await;
+    display::stop_test();
+
+    match (confirmed, VERDICT.lock().unwrap().take()) {
+        (Ok(()), Some(true)) => (true, format!("confirmed by operator, at 0x{address:02x}")),
+        (Ok(()), _) => (false, "rejected by operator".to_owned()),
+        (Err(_), _) => (false, format!("not confirmed within {}s", CONFIRM_TIMEOUT.as_secs())),
+    }
+}
+
+// Takes a measurement now and waits for it, so a check never judges a reading from before it started
+async fn fresh_measurement() -> Option<measurements::Values> {
+    let requested = Utc::now().timestamp_millis();
+    let deadline = Instant::now() + MEASUREMENT_TIMEOUT;
+    measurements::trigger();
+
+    while Instant::now() < deadline {
+        if let Some(values) = measurements::get().filter(|v| v.temperature_at >= requested && v.tds_at >= requested) {
+            return Some(values);
+        }
+        sleep(MEASUREMENT_POLL).await;
+    }
+
+    None
+}
+
+fn check_nvs() -> anyhow::Result<()> {
+    nvs::store_blob(NVS_TEST_KEY, NVS_TEST_PATTERN)?;
+    let read = nvs::load_blob(NVS_TEST_KEY)?;
+    if read.as_deref() != Some(NVS_TEST_PATTERN) {
+        return Err(anyhow!("Read back {read:02x?}"));
+    }
+
+    nvs::remove_blob(NVS_TEST_KEY)?;
+    if nvs::load_blob(NVS_TEST_KEY)?.is_some() {
+        return Err(anyhow!("Still present after erase"));
+    }
+
+    Ok(())
+}
+
+// Publishes the result on the display, the console for the label printer, and the journal
+fn finish(checks: Vec<Check>) {
+    let passed = checks.iter().all(|check| check.passed);
+
+    #[derive(Serialize)]
+    struct Report<'a> {
+        device_id: &'static str,
+        boot_id: system::BootId,
+        passed: bool,
+        checks: &'a [Check],
+    }
+    let report = Report {
+        device_id: system::device_id(),
+        boot_id: system::boot_id(),
+        passed,
+        checks: &checks,
+    };
+    if let Ok(json) = serde_json::to_string(&report) {
+        println!("COBITIS_FACTORY {json}");
+    }
+    info!("Factory test {}", if passed { "passed" } else { "failed" });
+
+    journal::record(journal::Event::FactoryTest {
+        passed,
+        checks: checks.clone(),
+    });
+    *STATE.lock().unwrap() = State::Done { passed, checks };
+    display::wake();
+}
@@ -0,0 +1,360 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Incremental parser for a flat JSON object of strings, numbers and booleans, the shape of a settings
+// document. Bytes are pushed one at a time and nothing is kept beyond the key and value in progress, so
+// memory stays bounded however large the document is.
+
+use std::fmt;
+
+/// Longest key or value accepted, anything longer is rejected rather than buffered.
+pub(crate) const MAX_TOKEN_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Before the opening brace.
+    Start,
+    /// After the opening brace, where the object may also end.
+    FirstKey,
+    /// After a comma.
+    Key,
+    InKey,
+    Colon,
+    Value,
+    InString,
+    /// A number, `true` or `false`, which only ends at the next delimiter.
+    InLiteral,
+    /// After a value, expecting a comma or the closing brace.
+    Next,
+    /// After the closing brace, where only whitespace may follow.
+    End,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    Backslash,
+    Unicode { digits: u8, code: u32 },
+}
+
+/// Where and why a document was rejected.
+#[derive(Debug)]
+pub(crate) struct Error {
+    offset: usize,
+    message: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+pub(crate) struct Parser {
+    state: State,
+    escape: Escape,
+    token: Vec<u8>,
+    key: String,
+    offset: usize,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Start,
+            escape: Escape::None,
+            token: Vec::with_capacity(MAX_TOKEN_LEN),
+            key: String::new(),
+            offset: 0,
+        }
+    }
+
+    /// Feeds the next byte, returning a key with its value as text once the value is complete.
+    pub fn push(&mut self, byte: u8) -> Result<Option<(String, String)>, Error> {
+        let result = self.step(byte);
+        self.offset += 1;
+        result
+    }
+
+    /// Checks that the document ended right after the object closed.
+    pub fn finish(&self) -> Result<(), Error> {
+        match self.state {
+            State::End => Ok(()),
+            State::Start => Err(self.error("Empty document")),
+            _ => Err(self.error("Document ends inside the object")),
+        }
+    }
+
+    fn step(&mut self, byte: u8) -> Result<Option<(String, String)>, Error> {
+        let whitespace = matches!(byte, b' ' | b'\t' | b'\n' | b'\r');
+
+        match self.state {
+            State::Start => match byte {
+                _ if whitespace => {}
+                b'{' => self.state = State::FirstKey,
+                _ => return Err(self.error("Expected an object")),
+            },
+            State::FirstKey | State::Key => match byte {
+                _ if whitespace => {}
+                b'"' => {
+                    self.token.clear();
+                    self.state = State::InKey;
+                }
+                b'}' if self.state == State::FirstKey => self.state = State::End,
+                _ => return Err(self.error("Expected a key")),
+            },
+            State::InKey => {
+                if self.string_byte(byte)? {
+                    self.key = self.take_token()?;
+                    self.state = State::Colon;
+                }
+            }
+            State::Colon => match byte {
+                _ if whitespace => {}
+                b':' => self.state = State::Value,
+                _ => return Err(self.error("Expected a colon")),
+            },
+            State::Value => match byte {
+                _ if whitespace => {}
+                b'"' => {
+                    self.token.clear();
+                    self.state = State::InString;
+                }
+                b'-' | b'0'..=b'9' | b't' | b'f' => {
+                    self.token.clear();
+                    self.token.push(byte);
+                    self.state = State::InLiteral;
+                }
+                b'{' | b'[' => return Err(self.error("Nested values are not settings")),
+                b'n' => return Err(self.error("Null is not a setting value")),
+                _ => return Err(self.error("Expected a value")),
+            },
+            State::InString => {
+                if self.string_byte(byte)? {
+                    let value = self.take_token()?;
+                    self.state = State::Next;
+                    return Ok(Some((std::mem::take(&mut self.key), value)));
+                }
+            }
+            State::InLiteral => {
+                if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'+' | b'-') {
+                    self.push_token(byte)?;
+                    return Ok(None);
+                }
+
+                let value = self.take_token()?;
+                let number = value
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+                    && value.parse::<f64>().is_ok();
+                if !number && value != "true" && value != "false" {
+                    return Err(self.error("Invalid literal"));
+                }
+
+                // The delimiter that ended the literal belongs to what follows it
+                self.state = State::Next;
+                self.step(byte)?;
+                return Ok(Some((std::mem::take(&mut self.key), value)));
+            }
+            State::Next => match byte {
+                _ if whitespace => {}
+                b',' => self.state = State::Key,
+                b'}' => self.state = State::End,
+                _ => return Err(self.error("Expected a comma or closing brace")),
+            },
+            State::End => {
+                if !whitespace {
+                    return Err(self.error("Trailing data after the object"));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Takes one byte of a quoted string, returning whether it was the closing quote
+    fn string_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        match self.escape {
+            Escape::None => match byte {
+                b'"' => return Ok(true),
+                b'\\' => self.escape = Escape::Backslash,
+                0x00..=0x1f => return Err(self.error("Control character in string")),
+                _ => self.push_token(byte)?,
+            },
+            Escape::Backslash => {
+                self.escape = Escape::None;
+                let unescaped = match byte {
+                    b'"' | b'\\' | b'/' => byte,
+                    b'b' => 0x08,
+                    b'f' => 0x0c,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'u' => {
+                        self.escape = Escape::Unicode { digits: 0, code: 0 };
+                        return Ok(false);
+                    }
+                    _ => return Err(self.error("Invalid escape")),
+                };
+                self.push_token(unescaped)?;
+            }
+            Escape::Unicode { digits, code } => {
+                let digit = (byte as char)
+                    .to_digit(16)
+                    .ok_or_else(|| self.error("Invalid unicode escape"))?;
+                let code = code * 16 + digit;
+                if digits < 3 {
+                    self.escape = Escape::Unicode {
+                        digits: digits + 1,
+                        code,
+                    };
+                    return Ok(false);
+                }
+
+                // Surrogate pairs are not needed for any setting and rejected along with lone ones
+                self.escape = Escape::None;
+                let c = char::from_u32(code).ok_or_else(|| self.error("Surrogate in unicode escape"))?;
+                for &b in c.encode_utf8(&mut [0; 4]).as_bytes() {
+                    self.push_token(b)?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn push_token(&mut self, byte: u8) -> Result<(), Error> {
+        if self.token.len() >= MAX_TOKEN_LEN {
+            return Err(self.error("Key or value too long"));
+        }
+        self.token.push(byte);
+
+        Ok(())
+    }
+
+    fn take_token(&mut self) -> Result<String, Error> {
+        let text = std::str::from_utf8(&self.token)
+            .map(str::to_owned)
+            .map_err(|_| self.error("Invalid UTF-8"))?;
+        self.token.clear();
+
+        Ok(text)
+    }
+
+    fn error(&self, message: &'static str) -> Error {
+        Error {
+            offset: self.offset,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(doc: &[u8]) -> Result<Vec<(String, String)>, Error> {
+        let mut parser = Parser::new();
+        let mut pairs = vec![];
+        for &byte in doc {
+            pairs.extend(parser.push(byte)?);
+        }
+        parser.finish()?;
+
+        Ok(pairs)
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    #[track_caller]
+    fn assert_rejected(doc: &[u8], message: &str, offset: usize) {
+        let e = parse(doc).unwrap_err();
+        assert_eq!(
+            (e.message, e.offset),
+            (message, offset),
+            "{}",
+            String::from_utf8_lossy(doc)
+        );
+    }
+
+    #[test]
+    fn yields_every_pair_as_it_completes() {
+        let doc = br#" { "a" : "x", "b":12.5,"c": true ,"d":-3e2,
+            "e": false } "#;
+        assert_eq!(
+            parse(doc).unwrap(),
+            pairs(&[("a", "x"), ("b", "12.5"), ("c", "true"), ("d", "-3e2"), ("e", "false")])
+        );
+        assert!(parse(b"{}").unwrap().is_empty());
+
+        // A string value is complete at its closing quote, a literal only at the delimiter after it
+        let mut parser = Parser::new();
+        let results: Vec<_> = br#"{"a":"x","b":1}"#.iter().map(|&b| parser.push(b).unwrap()).collect();
+        assert_eq!(results[7], Some(("a".to_owned(), "x".to_owned())));
+        assert_eq!(results[13], None);
+        assert_eq!(results[14], Some(("b".to_owned(), "1".to_owned())));
+    }
+
+    #[test]
+    fn strings_are_unescaped() {
+        assert_eq!(
+            parse(br#"{"k\"ey": "a\"b\\c\/d\n\t\u00e9\u20ac"}"#).unwrap(),
+            pairs(&[("k\"ey", "a\"b\\c/d\n\té€")])
+        );
+        assert_eq!(parse("{\"k\": \"é\"}".as_bytes()).unwrap(), pairs(&[("k", "é")]));
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected_where_they_go_wrong() {
+        assert_rejected(b"", "Empty document", 0);
+        assert_rejected(b"[1]", "Expected an object", 0);
+        assert_rejected(br#"{"a": 1"#, "Document ends inside the object", 7);
+        assert_rejected(br#"{"a" 1}"#, "Expected a colon", 5);
+        assert_rejected(br#"{"a": 1,}"#, "Expected a key", 8);
+        assert_rejected(br#"{"a": 1 "b": 2}"#, "Expected a comma or closing brace", 8);
+        assert_rejected(br#"{"a": "b"} x"#, "Trailing data after the object", 11);
+        assert_rejected(br#"{"a": "b"}{}"#, "Trailing data after the object", 10);
+    }
+
+    #[test]
+    fn only_flat_scalar_values_are_accepted() {
+        assert_rejected(br#"{"a": {}}"#, "Nested values are not settings", 6);
+        assert_rejected(br#"{"a": [1]}"#, "Nested values are not settings", 6);
+        assert_rejected(br#"{"a": null}"#, "Null is not a setting value", 6);
+        assert_rejected(br#"{"a": 'b'}"#, "Expected a value", 6);
+        assert_rejected(br#"{"a": tru}"#, "Invalid literal", 9);
+        assert_rejected(br#"{"a": 1.2.3}"#, "Invalid literal", 11);
+        assert_rejected(br#"{"a": 0x10}"#, "Invalid literal", 10);
+        assert_rejected(br#"{"a": 1inf}"#, "Invalid literal", 10);
+    }
+
+    #[test]
+    fn invalid_strings_are_rejected() {
+        assert_rejected(b"{\"a\": \"b\nc\"}", "Control character in string", 8);
+        assert_rejected(br#"{"a": "\x"}"#, "Invalid escape", 8);
+        assert_rejected(br#"{"a": "\u00g0"}"#, "Invalid unicode escape", 11);
+        assert_rejected(br#"{"a": "\ud83d"}"#, "Surrogate in unicode escape", 12);
+        assert_rejected(b"{\"a\": \"\xff\"}", "Invalid UTF-8", 8);
+    }
+
+    #[test]
+    fn tokens_are_bounded() {
+        let fits = "k".repeat(MAX_TOKEN_LEN);
+        let doc = format!(r#"{{"{fits}": "{fits}"}}"#);
+        assert_eq!(parse(doc.as_bytes()).unwrap(), pairs(&[(fits.as_str(), fits.as_str())]));
+
+        let doc = format!(r#"{{"a": "{fits}k"}}"#);
+        assert_rejected(doc.as_bytes(), "Key or value too long", 7 + MAX_TOKEN_LEN);
+        let doc = format!(r#"{{"a": 1{}}}"#, "0".repeat(MAX_TOKEN_LEN));
+        assert_rejected(doc.as_bytes(), "Key or value too long", 6 + MAX_TOKEN_LEN);
+    }
+
+    #[test]
+    fn errors_name_the_offset() {
+        assert_eq!(parse(b"  [").unwrap_err().to_string(), "Expected an object at byte 2");
+    }
+}
@@ -0,0 +1,2168 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{Display, Write as _},
+    str::FromStr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use esp_idf_svc::{
+    hal::io::{Read, Write},
+    http::{
+        Headers, Method,
+        server::{Configuration as ServerConfiguration, EspHttpConnection, EspHttpServer, Request},
+    },
+    sys,
+};
+use log::info;
+use serde::{Deserialize, Serialize, Serializer, de::DeserializeOwned, ser::SerializeMap};
+
+use crate::{
+    adc, alerts, assistant, breaker, bus, calibration, command, compact, display, factory, incidents, integrations,
+    journal, labels, latency, lifecycle, lockdown, logging, manifest, measurements, mqtt, network, nvs, ota, probes,
+    push, registry, revision, simulation, site, system, template, thermal,
+};
+
+// The setup form, served at `/` while the device runs its setup access point
+const SETUP_FORM: &str = concat!(
+    "<!DOCTYPE html><title>Cobitis setup</title>",
+    "<form method=post action=/setup>",
+    "<p><label>WiFi SSID <input name=ssid required></label>",
+    "<p><label>WiFi password <input name=psk type=password></label>",
+    "<p><label>NTP server <input name=ntp_server placeholder=pool.ntp.org></label>",
+    "<p><label>Timezone <input name=timezone placeholder=Asia/Tokyo></label>",
+    "<p><button>Save and reboot</button></form>",
+    "<form method=post action=/provision/extend><button>More time</button></form>",
+);
+
+// Connectivity checks of Android, Apple, Windows and Firefox, which the captive DNS sends here while in
+// setup mode. Redirecting them is what makes the phone pop the setup form up.
+const CAPTIVE_PROBES: [&str; 5] = [
+    "/generate_204",
+    "/hotspot-detect.html",
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/canonical.html",
+];
+
+// Handler slots of the server, all routes including the setup mode ones have to fit
+const MAX_ROUTES: usize = 51;
+
+// Leaves the response time to reach the browser before the reboot drops the access point
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+// Bodies of `/config/import` are parsed as they arrive, the limit only bounds how long one can take
+const IMPORT_BODY_LIMIT: usize = 16 * 1024;
+
+// Default size limit for request bodies
+const DEFAULT_BODY_LIMIT: usize = 2048;
+
+pub(crate) struct Context<'a> {
+    #[allow(dead_code)]
+    server: Option<EspHttpServer<'a>>,
+}
+
+// Stands in for the reading of a sensor switched off by the user
+const DISABLED: &str = "disabled";
+
+// Histories of single features, each a view over the event journal taking the parameters of /events
+const EVENT_VIEWS: [(&str, &str, &str); 4] = [
+    (
+        "/alerts/history",
+        "alert",
+        "Alerts raised and cleared, from the event journal",
+    ),
+    (
+        "/assistant/water-change",
+        "water_change",
+        "Past water changes with before and after values, from the event journal",
+    ),
+    (
+        "/factory/history",
+        "factory_test",
+        "Past factory test results, from the event journal",
+    ),
+    (
+        "/ota/history",
+        "ota_verdict",
+        "Whether updated images were kept or rolled back, from the event journal",
+    ),
+];
+
+/// Legacy payload served at `/`, kept with an integer TDS for compatibility.
+#[derive(Debug, Serialize)]
+pub(crate) struct Message {
+    pub timestamp: i64,
+    #[serde(serialize_with = "value_or_disabled")]
+    pub temperature: Option<f32>,
+    #[serde(serialize_with = "value_or_disabled")]
+    pub tds: Option<i32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub simulated: bool,
+}
+
+impl From<measurements::Values> for Message {
+    fn from(value: measurements::Values) -> Self {
+        Self {
+            timestamp: value.oldest_at(),
+            temperature: value
+                .temperature_enabled
+                .then(|| registry::TEMPERATURE.round(value.temperature)),
+            tds: value.tds_enabled.then(|| registry::round(value.tds, 0) as i32),
+            simulated: value.simulated,
+        }
+    }
+}
+
+/// The timestamp of a history sample is when it was taken, rather than its oldest reading.
+impl From<measurements::Sample> for Message {
+    fn from(value: measurements::Sample) -> Self {
+        Self {
+            timestamp: value.timestamp,
+            temperature: value
+                .temperature_enabled
+                .then(|| registry::TEMPERATURE.round(value.temperature)),
+            tds: value.tds_enabled.then(|| registry::round(value.tds, 0) as i32),
+            simulated: value.simulated,
+        }
+    }
+}
+
+fn value_or_disabled<T: Serialize, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => value.serialize(serializer),
+        None => serializer.serialize_str(DISABLED),
+    }
+}
+
+/// Payload served at `/v1/measurements`, keyed and rounded by the metric registry.
+///
+/// Each metric is nested with its own `measured_at`, since a failed sensor keeps its previous reading.
+#[derive(Debug)]
+pub(crate) struct MessageV1(measurements::Values);
+
+impl From<measurements::Values> for MessageV1 {
+    fn from(value: measurements::Values) -> Self {
+        Self(value)
+    }
+}
+
+impl Serialize for MessageV1 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = &self.0;
+        let now = Utc::now().timestamp_millis();
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("id", &values.id())?;
+        map.serialize_entry("device_name", labels::device_name())?;
+        map.serialize_entry("timestamp", &values.timestamp)?;
+        if values.temperature_enabled {
+            map.serialize_entry(
+                registry::TEMPERATURE.name,
+                &Reading {
+                    label: labels::metric(&registry::TEMPERATURE),
+                    value: registry::TEMPERATURE.round(values.temperature),
+                    measured_at: values.temperature_at,
+                    freshness: measurements::freshness(values.temperature_age(now)),
+                },
+            )?;
+        } else {
+            map.serialize_entry(registry::TEMPERATURE.name, DISABLED)?;
+        }
+        if values.tds_enabled {
+            map.serialize_entry(
+                registry::TDS.name,
+                &Reading {
+                    label: labels::metric(&registry::TDS),
+                    value: registry::TDS.round(values.tds),
+                    measured_at: values.tds_at,
+                    freshness: measurements::freshness(values.tds_age(now)),
+                },
+            )?;
+        } else {
+            map.serialize_entry(registry::TDS.name, DISABLED)?;
+        }
+        if values.simulated {
+            map.serialize_entry("simulated", &true)?;
+        }
+        if values.warming_up {
+            map.serialize_entry("warming_up", &true)?;
+        }
+        if let Some(rise) = assistant::rise(values.tds).filter(|_| values.tds_enabled) {
+            map.serialize_entry("tds_rise_since_change", &rise)?;
+        }
+        map.serialize_entry("uptime_ms", &values.uptime_ms)?;
+        map.serialize_entry("clock_valid", &values.clock_valid)?;
+        map.end()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Reading {
+    label: &'static str,
+    value: f32,
+    measured_at: i64,
+    freshness: measurements::Freshness,
+}
+
+/// Payload served at `/v1/debug`, raw values useful for diagnosing the sensors.
+#[derive(Debug, Serialize)]
+pub(crate) struct DebugMessage {
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub simulated: bool,
+    pub supply_voltage: Option<f32>,
+    pub compensation: measurements::Compensation,
+    pub adc: Option<adc::Timing>,
+    pub syslog_dropped: u32,
+}
+
+impl From<measurements::Values> for DebugMessage {
+    fn from(value: measurements::Values) -> Self {
+        Self {
+            timestamp: value.timestamp,
+            simulated: value.simulated,
+            supply_voltage: value.supply_voltage,
+            compensation: value.compensation,
+            adc: adc::timing(),
+            syslog_dropped: logging::dropped(),
+        }
+    }
+}
+
+/// Payload served at `/health`.
+#[derive(Debug, Serialize)]
+pub(crate) struct HealthMessage {
+    pub boot_id: system::BootId,
+    pub state: lifecycle::State,
+    pub uptime: u64,
+    pub wifi_connected: bool,
+    pub wifi_tx_power: Option<network::TxPower>,
+    pub chip_temperature: Option<f32>,
+    pub ntp: Option<network::NtpStatus>,
+    /// Seconds between measurements right now, see `sensor.max_interval`.
+    pub measurement_interval: u64,
+}
+
+impl HealthMessage {
+    pub fn current() -> Self {
+        Self {
+            boot_id: system::boot_id(),
+            state: lifecycle::state(),
+            uptime: system::uptime(),
+            wifi_connected: network::is_connected(),
+            wifi_tx_power: network::tx_power(),
+            chip_temperature: system::chip_temperature(),
+            ntp: network::ntp_status(),
+            measurement_interval: measurements::interval().as_secs(),
+        }
+    }
+}
+
+/// Payload served at `/diagnostics`.
+#[derive(Debug, Serialize)]
+pub(crate) struct DiagnosticsMessage {
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub implausible_readings: u32,
+    /// `enabled` or `disabled by user` per sensor. Failures and implausible readings of a sensor switched
+    /// off are no longer counted, so the counters above may be left over from before.
+    pub sensors: BTreeMap<&'static str, &'static str>,
+    /// I2C address the display answered on, absent when running headless.
+    pub display_address: Option<u8>,
+    pub throttling: &'static [&'static str],
+    pub http: HttpStats,
+    pub nvs_writes: nvs::WriteStats,
+    /// The temperature probe in use against the one the calibration was made with.
+    pub probe: probes::Status,
+    /// Circuit breaker of each enabled outbound integration that has one.
+    pub breakers: BTreeMap<&'static str, breaker::Status>,
+    /// Latency summaries keyed by Prometheus name, heavy handlers by path; full histograms are at `/metrics`.
+    pub latency: BTreeMap<String, latency::Summary>,
+}
+
+/// Requests turned away by admission control, and the lowest free heap seen right after a handler.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct HttpStats {
+    pub shed_light: u32,
+    pub shed_heavy: u32,
+    pub handler_low_water: Option<u32>,
+}
+
+/// How much memory a route needs, checked against the free heap before its handler runs.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Cost {
+    Light,
+    Heavy,
+}
+
+impl Cost {
+    // Below these the request is shed with 503 instead of risking WiFi stability
+    fn min_free_heap(self) -> u32 {
+        match self {
+            Self::Light => 20 * 1024,
+            Self::Heavy => 40 * 1024,
+        }
+    }
+}
+
+static SHED_LIGHT: AtomicU32 = AtomicU32::new(0);
+static SHED_HEAVY: AtomicU32 = AtomicU32::new(0);
+static HANDLER_LOW_WATER: AtomicU32 = AtomicU32::new(u32::MAX);
+// One histogram per heavy route, allocated as routes are registered
+static HANDLER_LATENCY: Mutex<Vec<(String, latency::Histogram)>> = Mutex::new(Vec::new());
+
+pub(crate) fn stats() -> HttpStats {
+    let low_water = HANDLER_LOW_WATER.load(Ordering::Relaxed);
+    HttpStats {
+        shed_light: SHED_LIGHT.load(Ordering::Relaxed),
+        shed_heavy: SHED_HEAVY.load(Ordering::Relaxed),
+        handler_low_water: (low_water != u32::MAX).then_some(low_water),
+    }
+}
+
+/// Thin wrapper over the server that every route is registered through, so policies such as lockdown
+/// and admission control apply to new endpoints without each handler having to remember them.
+struct Router<'a> {
+    /// Without a server the routes are only listed, for checking them without the network up.
+    server: Option<EspHttpServer<'a>>,
+    routes: Vec<Route>,
+    /// Paths whose requests come from a person rather than automation, and wake the display.
+    interactive: Vec<String>,
+}
+
+/// A registered route as listed by `/api`.
+#[derive(Debug, Clone, Serialize)]
+struct Route {
+    method: &'static str,
+    path: String,
+    description: &'static str,
+    cost: Cost,
+    /// Whether the route is refused while the device is in lockdown.
+    mutating: bool,
+}
+
+static ROUTES: OnceLock<Vec<Route>> = OnceLock::new();
+
+impl<'a> Router<'a> {
+    fn get<F>(&mut self, uri: &str, description: &'static str, handler: F) -> anyhow::Result<&mut Self>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> anyhow::Result<()> + Send + 'a,
+    {
+        self.add(uri, description, Method::Get, Cost::Light, handler)
+    }
+
+    /// Registers a route whose response takes a large buffer, e.g. a list of records.
+    fn get_heavy<F>(&mut self, uri: &str, description: &'static str, handler: F) -> anyhow::Result<&mut Self>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> anyhow::Result<()> + Send + 'a,
+    {
+        self.add(uri, description, Method::Get, Cost::Heavy, handler)
+    }
+
+    /// Registers a route that changes state. These are refused while the device is in lockdown.
+    ///
+    /// The handler returns its reply instead of writing it, so a successful one can be replayed when a
+    /// client retries with the same `Idempotency-Key`.
+    fn post<F>(&mut self, uri: &str, description: &'static str, handler: F) -> anyhow::Result<&mut Self>
+    where
+        F: for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> anyhow::Result<Reply> + Send + 'a,
+    {
+        const BAD_REQUEST: u16 = 400;
+        const MAX_KEY_LEN: usize = 64;
+
+        let route = uri.to_owned();
+        self.add(uri, description, Method::Post, Cost::Light, move |mut request| {
+            let key = request.header("Idempotency-Key").map(str::to_owned);
+            if key.as_ref().is_some_and(|key| key.len() > MAX_KEY_LEN) {
+                let error = HttpError::new(BAD_REQUEST, format!("Idempotency-Key exceeds {MAX_KEY_LEN} bytes"));
+                return respond_error(request, error);
+            }
+
+            if let Some(reply) = key.as_deref().and_then(|key| cached_reply(&route, key)) {
+                return respond_reply(request, &reply);
+            }
+
+            let reply = handler(&mut request)?;
+            if let Some(key) = key {
+                cache_reply(&route, key, &reply);
+            }
+            respond_reply(request, &reply)
+        })
+    }
+
+    fn add<F>(
+        &mut self,
+        uri: &str,
+        description: &'static str,
+        method: Method,
+        cost: Cost,
+        handler: F,
+    ) -> anyhow::Result<&mut Self>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> anyhow::Result<()> + Send + 'a,
+    {
+        const SERVICE_UNAVAILABLE: u16 = 503;
+
+        // Descriptions are what keeps /api useful, so a route without one is a bug
+        if description.trim().is_empty() {
+            return Err(anyhow!("Route {uri} has no description"));
+        }
+
+        let mutating = !matches!(method, Method::Get);
+        let interactive = self.interactive.iter().any(|path| path == uri);
+        let route = Route {
+            method: if mutating { "POST" } else { "GET" },
+            path: uri.to_owned(),
+            description,
+            cost,
+            mutating,
+        };
+        self.routes.push(route.clone());
+        let Some(server) = self.server.as_mut() else {
+            return Ok(self);
+        };
+
+        let timed = matches!(cost, Cost::Heavy).then(|| {
+            let mut handlers = HANDLER_LATENCY.lock().unwrap();
+            handlers.push((uri.to_owned(), latency::Histogram::new()));
+            handlers.len() - 1
+        });
+        server.fn_handler(uri, method, move |request| {
+            if let Some(error) = refusal(&route, lockdown::enabled(), thermal::throttled(), system::free_heap()) {
+                if error.status == SERVICE_UNAVAILABLE {
+                    match cost {
+                        Cost::Light => SHED_LIGHT.fetch_add(1, Ordering::Relaxed),
+                        Cost::Heavy => SHED_HEAVY.fetch_add(1, Ordering::Relaxed),
+                    };
+                }
+                return respond_error(request, error);
+            }
+
+            if interactive {
+                display::wake();
+            }
+
+            let started = Instant::now();
+            let result = handler(request);
+            if let Some(index) = timed {
+                HANDLER_LATENCY.lock().unwrap()[index].1.record(started.elapsed());
+            }
+            HANDLER_LOW_WATER.fetch_min(system::free_heap(), Ordering::Relaxed);
+            result
+        })?;
+        Ok(self)
+    }
+}
+
+/// Why a request for `route` is turned away before reaching its handler, if it is.
+fn refusal(route: &Route, lockdown: bool, throttled: bool, free_heap: u32) -> Option<HttpError> {
+    const METHOD_NOT_ALLOWED: u16 = 405;
+    const SERVICE_UNAVAILABLE: u16 = 503;
+    const RETRY_AFTER: u32 = 5;
+    const THROTTLED_RETRY_AFTER: u32 = 60;
+
+    if route.mutating && lockdown {
+        return Some(HttpError::new(
+            METHOD_NOT_ALLOWED,
+            "Device is in lockdown, changes are disabled",
+        ));
+    }
+
+    if matches!(route.cost, Cost::Heavy) && throttled {
+        let error = HttpError::new(SERVICE_UNAVAILABLE, "Throttled while the chip is hot, try again later");
+        return Some(error.retry_after(THROTTLED_RETRY_AFTER));
+    }
+
+    // The server runs one handler at a time, so free heap is the only thing worth gating on
+    if free_heap < route.cost.min_free_heap() {
+        let error = HttpError::new(SERVICE_UNAVAILABLE, "Low on memory, try again later");
+        return Some(error.retry_after(RETRY_AFTER));
+    }
+
+    None
+}
+
+fn latency_summaries() -> BTreeMap<String, latency::Summary> {
+    let mut summaries: BTreeMap<_, _> = latency::LATENCIES
+        .iter()
+        .map(|latency| (latency.name.to_owned(), latency.snapshot().summary()))
+        .collect();
+    for (path, histogram) in HANDLER_LATENCY.lock().unwrap().iter() {
+        summaries.insert(path.clone(), histogram.summary());
+    }
+
+    summaries
+}
+
+// Renders `/metrics`; the readings are left out while there are none or once they expire
+fn prometheus() -> String {
+    let mut out = String::new();
+
+    let now = Utc::now().timestamp_millis();
+    if let Some(values) = measurements::get() {
+        let readings = [
+            (
+                &registry::TEMPERATURE,
+                values.temperature_enabled,
+                values.temperature,
+                values.temperature_age(now),
+            ),
+            (&registry::TDS, values.tds_enabled, values.tds, values.tds_age(now)),
+        ];
+        for (metric, enabled, value, age) in readings {
+            if !enabled || measurements::freshness(age) == measurements::Freshness::Expired {
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                "# HELP {} Water {} in {}",
+                metric.prometheus, metric.name, metric.unit
+            );
+            let _ = writeln!(out, "# TYPE {} gauge", metric.prometheus);
+            let _ = writeln!(out, "{} {}", metric.prometheus, metric.round(value));
+        }
+    }
+
+    for latency in latency::LATENCIES {
+        let _ = writeln!(out, "# HELP {} {}", latency.name, latency.help);
+        let _ = writeln!(out, "# TYPE {} histogram", latency.name);
+        latency.snapshot().write_prometheus(&mut out, latency.name, "");
+    }
+
+    let handlers = HANDLER_LATENCY.lock().unwrap().clone();
+    if !handlers.is_empty() {
+        let _ = writeln!(out, "# HELP {} {}", latency::HANDLER_NAME, latency::HANDLER_HELP);
+        let _ = writeln!(out, "# TYPE {} histogram", latency::HANDLER_NAME);
+        for (path, histogram) in handlers {
+            histogram.write_prometheus(&mut out, latency::HANDLER_NAME, &format!("path=\"{path}\""));
+        }
+    }
+
+    out
+}
+
+pub(crate) fn init<'a>() -> anyhow::Result<Box<Context<'a>>> {
+    let mut router = Router {
+        server: Some(EspHttpServer::new(&ServerConfiguration {
+            max_uri_handlers: MAX_ROUTES,
+            // Bounds every blocking socket read and send, an OTA upload feeds the watchdog in between
+            session_timeout: ota::READ_TIMEOUT,
+            ..Default::default()
+        })?),
+        routes: vec![],
+        interactive: nvs::get_opt("display.interactive")?
+            .map(|v| v.split(',').map(|path| path.trim().to_owned()).collect())
+            .unwrap_or_default(),
+    };
+    register_routes(&mut router)?;
+    ROUTES
+        .set(router.routes)
+        .map_err(|_| anyhow!("HTTP server already initialized"))?;
+
+    Ok(Box::new(Context { server: router.server }))
+}
+
+fn register_routes(router: &mut Router<'_>) -> anyhow::Result<()> {
+    router.get(
+        "/api",
+        "This list of routes, as JSON or as HTML for browsers",
+        move |request| {
+            let routes = ROUTES.get().map_or(&[][..], Vec::as_slice);
+
+            let html = request.header("Accept").is_some_and(|v| v.contains("text/html"));
+            if !html {
+                return respond_json(request, Some(&routes));
+            }
+
+            let mut body = String::from("<!DOCTYPE html><title>Cobitis API</title><ul>");
+            for route in routes {
+                body.push_str(&format!(
+                    "<li><code>{} {}</code> {}</li>",
+                    route.method, route.path, route.description
+                ));
+            }
+            body.push_str("</ul>");
+            let mut res = request.into_response(200, None, &[("Content-Type", "text/html")])?;
+            res.write_all(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+    router.get(
+        "/",
+        "Latest measurements in the legacy format, or the setup form",
+        move |request| {
+            if network::setup().is_some() {
+                let mut res = request.into_response(200, None, &[("Content-Type", "text/html")])?;
+                res.write_all(SETUP_FORM.as_bytes())?;
+                return Ok(());
+            }
+
+            let values = measurements::get();
+            let version = values.map(|v| v.seq.to_string());
+            respond_versioned(request, version, values.map(Message::from).as_ref())
+        },
+    )?;
+    // Only registered in setup mode, they would take up handler slots for nothing otherwise
+    if let Some(setup) = network::setup() {
+        let location = format!("http://{}/", setup.address);
+        for probe in CAPTIVE_PROBES {
+            let location = location.clone();
+            router.get(
+                probe,
+                "Connectivity check, redirected to the setup form",
+                move |request| {
+                    const FOUND: u16 = 302;
+
+                    request.into_response(FOUND, None, &[("Location", location.as_str())])?;
+                    Ok(())
+                },
+            )?;
+        }
+    }
+    router.post(
+        "/setup",
+        "Stores WiFi credentials from the setup form and reboots",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const FIELDS: [&str; 4] = ["ssid", "psk", "ntp_server", "timezone"];
+
+            if network::setup().is_none() {
+                return Ok(HttpError::new(BAD_REQUEST, "Not in setup mode, use /config").into());
+            }
+            let fields = match read_form(request, DEFAULT_BODY_LIMIT) {
+                Ok(fields) => fields,
+                Err(e) => return Ok(e.into()),
+            };
+
+            // Optional fields left blank keep their defaults
+            let mut changes = Vec::new();
+            for (name, value) in fields
+                .iter()
+                .filter(|(name, value)| FIELDS.contains(&name.as_str()) && !value.is_empty())
+            {
+                // Checked like /config does, a timezone typo would otherwise only show after the reboot
+                match validate_setting(name, value.clone()) {
+                    Ok(change) => changes.push(change),
+                    Err(e) => return Ok(e.into()),
+                }
+            }
+            if !changes.iter().any(|&(name, _)| name == "net.wifi.ssid") {
+                return Ok(HttpError::new(BAD_REQUEST, "ssid is required").into());
+            }
+
+            for (name, value) in &changes {
+                nvs::set(name, value)?;
+            }
+            info!("WiFi credentials stored, rebooting into station mode");
+            system::restart_after(RESTART_DELAY);
+
+            Ok(Reply::no_content())
+        },
+    )?;
+    router.post(
+        "/provision/extend",
+        "Restarts the timeout of the setup access point and returns to the setup form",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const SEE_OTHER: u16 = 303;
+
+            let Some(setup) = network::setup() else {
+                return Ok(HttpError::new(BAD_REQUEST, "Not in setup mode").into());
+            };
+            if let Some(timeout) = network::extend_setup() {
+                info!("Setup timeout extended to {} minutes from now", timeout.as_secs() / 60);
+            }
+
+            let location = format!("http://{}/", setup.address);
+            request.into_response(SEE_OTHER, None, &[("Location", location.as_str())])?;
+            Ok(())
+        },
+    )?;
+    router.post(
+        "/ota",
+        "Installs the firmware image in the body and reboots into it, rolled back unless it proves healthy",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const REQUEST_TIMEOUT: u16 = 408;
+
+            let len = request.content_len();
+            let installed = ota::install(len, |buf| request.read(buf).map_err(|e| e.0));
+            let installed = match installed {
+                Ok(installed) => installed,
+                Err(ota::InstallError::Rejected(message)) => return Ok(HttpError::new(BAD_REQUEST, message).into()),
+                Err(ota::InstallError::TimedOut) => {
+                    return Ok(HttpError::new(REQUEST_TIMEOUT, "Upload stalled, update aborted").into());
+                }
+                Err(ota::InstallError::Failed(e)) => return Err(e),
+            };
+
+            // The journal entry has to make it to flash before the reboot
+            nvs::flush();
+            info!("Rebooting into {}", installed.partition);
+            system::restart_after(RESTART_DELAY);
+
+            #[derive(Serialize)]
+            struct Body {
+                partition: String,
+                bytes: usize,
+            }
+            Reply::json(&Body {
+                partition: installed.partition,
+                bytes: installed.bytes,
+            })
+        },
+    )?;
+    router.get(
+        "/v1/measurements",
+        "Latest measurements with labels, timestamps and freshness",
+        move |request| {
+            let values = measurements::get();
+            // Freshness changes as the snapshot ages, so it is part of the version
+            let now = Utc::now().timestamp_millis();
+            let version = values.map(|v| {
+                let temperature = measurements::freshness(v.temperature_age(now));
+                let tds = measurements::freshness(v.tds_age(now));
+                format!("{}.{}{}", v.seq, temperature as u8, tds as u8)
+            });
+            respond_versioned(request, version, values.map(MessageV1::from).as_ref())
+        },
+    )?;
+    router.get(
+        "/v1/debug",
+        "Raw sensor diagnostics of the latest measurement",
+        move |request| {
+            let msg = measurements::get().map(DebugMessage::from);
+            respond_json(request, msg.as_ref())
+        },
+    )?;
+    router.get(
+        "/health",
+        "Lifecycle state, uptime, WiFi and NTP status",
+        move |request| respond_json(request, Some(&HealthMessage::current())),
+    )?;
+    router.get("/alerts", "Alert limits and alarms of each sensor", move |request| {
+        respond_json(request, Some(&alerts::get()))
+    })?;
+    router.get(
+        "/incidents",
+        "Recent alarm incidents with the history around them",
+        move |request| respond_json(request, Some(&incidents::get())),
+    )?;
+    router.get("/factory", "Progress and result of the factory test", move |request| {
+        respond_json(request, Some(&factory::state()))
+    })?;
+    router.get(
+        "/lifecycle",
+        "Current lifecycle state, recent transitions and boot milestones",
+        move |request| {
+            #[derive(Serialize)]
+            struct Body {
+                state: lifecycle::State,
+                history: Vec<lifecycle::Transition>,
+                milestones: Vec<lifecycle::Progress>,
+            }
+
+            let msg = Body {
+                state: lifecycle::state(),
+                history: lifecycle::history(),
+                milestones: lifecycle::progress(),
+            };
+            respond_json(request, Some(&msg))
+        },
+    )?;
+    router.get("/version", "Firmware identity and pin map", move |request| {
+        respond_json(request, Some(&system::Version::current()))
+    })?;
+    router.get(
+        "/display/screenshot",
+        "Last frame shown on the display, as PBM or JSON",
+        move |request| {
+            const SERVICE_UNAVAILABLE: u16 = 503;
+
+            let uri = request.uri().to_owned();
+            let Some(frame) = display::screenshot() else {
+                return respond_error(request, HttpError::new(SERVICE_UNAVAILABLE, "Nothing drawn yet"));
+            };
+
+            if query_param(&uri, "format") == Some("json") {
+                #[derive(Serialize)]
+                struct Body {
+                    width: i32,
+                    height: i32,
+                    bits: String,
+                }
+
+                let msg = Body {
+                    width: display::WIDTH,
+                    height: display::HEIGHT,
+                    bits: base64(frame.bits()),
+                };
+                return respond_json(request, Some(&msg));
+            }
+
+            // Binary PBM, where a set bit is black, so lit pixels are flipped to come out white like on the panel
+            let mut body = format!("P4\n{} {}\n", display::WIDTH, display::HEIGHT).into_bytes();
+            body.extend(frame.bits().iter().map(|b| !b));
+            let mut res = request.into_response(200, None, &[("Content-Type", "image/x-portable-bitmap")])?;
+            res.write_all(&body)?;
+
+            Ok(())
+        },
+    )?;
+    router.get("/config", "Current settings, secrets left out", move |request| {
+        let mut msg = BTreeMap::new();
+        for name in nvs::names().filter(|name| !nvs::is_secret(name)) {
+            if let Some(value) = nvs::get_opt(name)? {
+                msg.insert(name, value);
+            }
+        }
+        respond_json(request, Some(&msg))
+    })?;
+    router.post(
+        "/config",
+        "Changes settings, taking effect after a reboot except for sensor switches, alert limits and WiFi TX power",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+
+            let body: BTreeMap<String, serde_json::Value> = match read_json(request, DEFAULT_BODY_LIMIT) {
+                Ok(body) => body,
+                Err(e) => return Ok(e.into()),
+            };
+
+            // Everything is validated before anything is written, so a bad key doesn't leave half a change
+            let mut changes = Vec::new();
+            for (name, value) in &body {
+                let value = match value {
+                    serde_json::Value::String(v) => v.clone(),
+                    serde_json::Value::Number(v) => v.to_string(),
+                    serde_json::Value::Bool(v) => v.to_string(),
+                    _ => {
+                        let message = format!("Value of {name} must be a string, number or boolean");
+                        return Ok(HttpError::new(BAD_REQUEST, message).into());
+                    }
+                };
+                match validate_setting(name, value) {
+                    Ok(change) => changes.push(change),
+                    Err(e) => return Ok(e.into()),
+                }
+            }
+
+            apply_settings(changes)
+        },
+    )?;
+    router.post(
+        "/config/import",
+        "Changes settings from a document of any size, parsed as it arrives, all or nothing",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const PAYLOAD_TOO_LARGE: u16 = 413;
+
+            if request.content_len().is_some_and(|len| len > IMPORT_BODY_LIMIT as u64) {
+                let message = format!("Body exceeds {IMPORT_BODY_LIMIT} bytes");
+                return Ok(HttpError::new(PAYLOAD_TOO_LARGE, message).into());
+            }
+
+            // Staged per setting, so a long document with repeated keys needs no more memory than a short one
+            let mut staged = BTreeMap::new();
+            let mut parser = flatjson::Parser::new();
+            let mut chunk = [0_u8; 256];
+            let mut total = 0;
+            loop {
+                let len = match request.read(&mut chunk) {
+                    Ok(len) => len,
+                    Err(e) => return Ok(HttpError::new(BAD_REQUEST, format!("Failed to read body: {e:?}")).into()),
+                };
+                if len == 0 {
+                    break;
+                }
+                total += len;
+                if total > IMPORT_BODY_LIMIT {
+                    let message = format!("Body exceeds {IMPORT_BODY_LIMIT} bytes");
+                    return Ok(HttpError::new(PAYLOAD_TOO_LARGE, message).into());
+                }
+
+                for &byte in &chunk[..len] {
+                    let (name, value) = match parser.push(byte) {
+                        Ok(Some(entry)) => entry,
+                        Ok(None) => continue,
+                        Err(e) => return Ok(HttpError::new(BAD_REQUEST, format!("Malformed JSON: {e}")).into()),
+                    };
+                    match validate_setting(&name, value) {
+                        Ok((name, value)) => staged.insert(name, value),
+                        Err(e) => return Ok(e.into()),
+                    };
+                }
+            }
+            if let Err(e) = parser.finish() {
+                return Ok(HttpError::new(BAD_REQUEST, format!("Malformed JSON: {e}")).into());
+            }
+
+            apply_settings(staged.into_iter().collect())
+        },
+    )?;
+    router.post(
+        "/config/rollback",
+        "Restores the settings from before the latest change, a second call undoing the rollback",
+        move |_| {
+            const CONFLICT: u16 = 409;
+
+            let rollback = match revision::roll_back() {
+                Ok(rollback) => rollback,
+                Err(e) => return Ok(HttpError::new(CONFLICT, e.to_string()).into()),
+            };
+            journal::record(journal::Event::ConfigRollback {
+                revision: rollback.revision,
+                automatic: false,
+                restored: rollback.restored.clone(),
+            });
+            Reply::json(&rollback)
+        },
+    )?;
+    router.get(
+        "/manifest",
+        "Everything identifying this unit, for fleet scripts",
+        move |request| respond_json(request, Some(&manifest::Manifest::current())),
+    )?;
+    router.get(
+        "/diagnostics",
+        "Heap, throttling, HTTP and NVS write statistics",
+        move |request| {
+            let msg = DiagnosticsMessage {
+                free_heap: system::free_heap(),
+                min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+                implausible_readings: measurements::implausible(),
+                sensors: measurements::Sensor::ALL
+                    .into_iter()
+                    .map(|sensor| {
+                        let state = if measurements::enabled(sensor) {
+                            "enabled"
+                        } else {
+                            "disabled by user"
+                        };
+                        (sensor.name(), state)
+                    })
+                    .collect(),
+                display_address: display::address(),
+                throttling: thermal::actions(),
+                http: stats(),
+                nvs_writes: nvs::write_stats(),
+                probe: probes::status(),
+                breakers: [("push", push::breaker()), ("mqtt", mqtt::breaker())]
+                    .into_iter()
+                    .filter_map(|(name, status)| Some((name, status?)))
+                    .collect(),
+                latency: latency_summaries(),
+            };
+            respond_json(request, Some(&msg))
+        },
+    )?;
+    router.get(
+        "/metrics",
+        "Readings and latency histograms in the Prometheus text format",
+        move |request| {
+            let body = prometheus();
+            let headers = [("Content-Type", "text/plain; version=0.0.4")];
+            let mut res = request.into_response(200, None, &headers)?;
+            res.write_all(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+    router.get(
+        "/diagnostics/i2c",
+        "Bus time per I2C device and bus utilization",
+        move |request| respond_json(request, Some(&bus::profile())),
+    )?;
+    router.post(
+        "/diagnostics/i2c/reset",
+        "Zeroes the I2C bus profile",
+        move |_request| {
+            bus::reset();
+            Ok(Reply::no_content())
+        },
+    )?;
+    router.get("/integrations", "Status of the outbound integrations", move |request| {
+        let mut msg = serde_json::Map::new();
+        for integration in integrations::INTEGRATIONS {
+            msg.insert(
+                integration.name.to_owned(),
+                serde_json::to_value((integration.status)())?,
+            );
+        }
+        respond_json(request, Some(&msg))
+    })?;
+    router.get_heavy(
+        "/history",
+        "Recent measurements in the legacy format, optionally since a timestamp or record ID, or paged by cursor",
+        move |request| {
+            // Serialized samples are sent in chunks of about this size, never as one large body
+            const CHUNK_LEN: usize = 1024;
+            const BAD_REQUEST: u16 = 400;
+            const MAX_PAGE_SIZE: usize = 200;
+
+            let uri = request.uri().to_owned();
+            let query = (
+                parse_query(&uri, "since"),
+                parse_query::<measurements::Cursor>(&uri, "cursor"),
+                parse_query::<usize>(&uri, "page_size"),
+            );
+            let (since, cursor, page_size) = match query {
+                (Ok(since), Ok(cursor), Ok(page_size)) => (since, cursor, page_size),
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return respond_error(request, e),
+            };
+            let since = since.unwrap_or(measurements::Since::Start);
+
+            // Paged, the records are wrapped in an object with the cursor to the next page
+            let page = if cursor.is_some() || page_size.is_some() {
+                if cursor.is_some() && since != measurements::Since::Start {
+                    return respond_error(request, HttpError::new(BAD_REQUEST, "since and cursor are exclusive"));
+                }
+                let page_size = page_size.unwrap_or(MAX_PAGE_SIZE);
+                if !(1..=MAX_PAGE_SIZE).contains(&page_size) {
+                    let message = format!("page_size must be within 1..={MAX_PAGE_SIZE}");
+                    return respond_error(request, HttpError::new(BAD_REQUEST, message));
+                }
+                match measurements::get_history_page(since, cursor, page_size) {
+                    Ok(page) => Some(page),
+                    Err(e) => return respond_error(request, HttpError::new(BAD_REQUEST, format!("{e:#}"))),
+                }
+            } else {
+                None
+            };
+            let (samples, page) = match page {
+                Some(mut page) => (std::mem::take(&mut page.samples), Some(page)),
+                None => (measurements::get_history(since), None),
+            };
+
+            let mut res = request.into_response(200, None, &[("Content-Type", "application/json")])?;
+            let mut chunk = Vec::with_capacity(CHUNK_LEN + 128);
+            if page.is_some() {
+                chunk.extend_from_slice(br#"{"records":"#);
+            }
+            chunk.push(b'[');
+            for (i, sample) in samples.into_iter().enumerate() {
+                if i > 0 {
+                    chunk.push(b',');
+                }
+                serde_json::to_writer(&mut chunk, &Message::from(sample))?;
+                if chunk.len() >= CHUNK_LEN {
+                    res.write_all(&chunk)?;
+                    chunk.clear();
+                }
+            }
+            chunk.push(b']');
+            if let Some(page) = page {
+                chunk.extend_from_slice(br#","next_cursor":"#);
+                serde_json::to_writer(&mut chunk, &page.next_cursor)?;
+                chunk.extend_from_slice(br#","more":"#);
+                serde_json::to_writer(&mut chunk, &page.more)?;
+                chunk.push(b'}');
+            }
+            res.write_all(&chunk)?;
+
+            Ok(())
+        },
+    )?;
+    router.get_heavy(
+        "/history.bin",
+        "Recent measurements in the compact binary format, optionally since a timestamp or record ID",
+        move |request| {
+            let uri = request.uri().to_owned();
+            let query = (parse_query(&uri, "since"), parse_query::<usize>(&uri, "limit"));
+            let (since, limit) = match query {
+                (Ok(since), Ok(limit)) => (since, limit),
+                (Err(e), _) | (_, Err(e)) => return respond_error(request, e),
+            };
+
+            let records: Vec<_> = measurements::get_history(since.unwrap_or(measurements::Since::Start))
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|sample| {
+                    let value = |enabled: bool, value: f32| Some(value).filter(|v| enabled && v.is_finite());
+                    compact::Record {
+                        timestamp: sample.timestamp,
+                        temperature: value(sample.temperature_enabled, sample.temperature),
+                        tds: value(sample.tds_enabled, sample.tds),
+                    }
+                })
+                .collect();
+            let body = compact::encode(&records);
+
+            let version = compact::VERSION.to_string();
+            let headers = [
+                ("Content-Type", "application/octet-stream"),
+                ("X-Format-Version", version.as_str()),
+            ];
+            let mut res = request.into_response(200, None, &headers)?;
+            res.write_all(&body)?;
+
+            Ok(())
+        },
+    )?;
+    router.get_heavy(
+        "/events",
+        "Event journal, filterable by type and sequence",
+        move |request| {
+            let uri = request.uri().to_owned();
+            respond_events(request, query_param(&uri, "type"))
+        },
+    )?;
+    for (uri, kind, description) in EVENT_VIEWS {
+        router.get_heavy(uri, description, move |request| respond_events(request, Some(kind)))?;
+    }
+    router.post(
+        "/assistant/water-change",
+        "Starts or stops the water-change assistant",
+        move |request| {
+            #[derive(Deserialize)]
+            #[serde(rename_all = "snake_case")]
+            enum Action {
+                Start,
+                Stop,
+            }
+            #[derive(Deserialize)]
+            struct Body {
+                action: Action,
+            }
+
+            let body: Body = match read_json(request, DEFAULT_BODY_LIMIT) {
+                Ok(body) => body,
+                Err(e) => return Ok(e.into()),
+            };
+
+            let latest = measurements::get();
+            match body.action {
+                Action::Start => assistant::start(latest)?,
+                Action::Stop => assistant::stop(latest)?,
+            }
+            Ok(Reply::no_content())
+        },
+    )?;
+    router.post("/display/test", "Shows a test pattern on the display", move |request| {
+        #[derive(Deserialize)]
+        struct Body {
+            pattern: display::Pattern,
+            seconds: Option<u64>,
+        }
+
+        let body: Body = match read_json(request, DEFAULT_BODY_LIMIT) {
+            Ok(body) => body,
+            Err(e) => return Ok(e.into()),
+        };
+        let Some(duration) = display::test_duration(body.seconds) else {
+            let message = format!("seconds must be between 1 and {}", display::MAX_TEST_SECONDS);
+            return Ok(HttpError::new(400, message).into());
+        };
+
+        display::start_test(body.pattern, duration);
+        Ok(Reply::no_content())
+    })?;
+    router.post(
+        "/calibrate/tds",
+        "Fits TDS readings to the reference solution the probe sits in",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const CONFLICT: u16 = 409;
+
+            #[derive(Deserialize)]
+            #[serde(deny_unknown_fields)]
+            struct Body {
+                /// TDS of the solution in ppm
+                reference: f32,
+            }
+
+            let body: Body = match read_json(request, DEFAULT_BODY_LIMIT) {
+                Ok(body) => body,
+                Err(e) => return Ok(e.into()),
+            };
+            if !(body.reference.is_finite() && body.reference > 0.0) {
+                return Ok(HttpError::new(BAD_REQUEST, "reference must be a positive number of ppm").into());
+            }
+
+            match measurements::calibrate_tds(body.reference) {
+                Ok(calibration) => Reply::json(&calibration),
+                Err(measurements::CalibrationError::Rejected(message)) => Ok(HttpError::new(CONFLICT, message).into()),
+                Err(measurements::CalibrationError::Failed(e)) => Err(e),
+            }
+        },
+    )?;
+    router.get(
+        "/calibration/export",
+        "All calibration data, to take it over on another unit fitted to the same probes",
+        move |request| respond_json(request, Some(&calibration::export()?)),
+    )?;
+    router.post(
+        "/calibration/import",
+        "Takes over calibration data exported from another unit, force to accept another temperature probe",
+        move |request| {
+            const BAD_REQUEST: u16 = 400;
+            const CONFLICT: u16 = 409;
+
+            let force = match parse_query::<bool>(request.uri(), "force") {
+                Ok(force) => force.unwrap_or(false),
+                Err(e) => return Ok(e.into()),
+            };
+            let document: calibration::Document = match read_json(request, DEFAULT_BODY_LIMIT) {
+                Ok(document) => document,
+                Err(e) => return Ok(e.into()),
+            };
+
+            match calibration::import(document, force) {
+                Ok(imported) => Reply::json(&imported),
+                Err(calibration::Error::Rejected(message)) => Ok(HttpError::new(BAD_REQUEST, message).into()),
+                Err(calibration::Error::ProbeMismatch(message)) => Ok(HttpError::new(CONFLICT, message).into()),
+                Err(calibration::Error::Failed(e)) => Err(e),
+            }
+        },
+    )?;
+    router.post(
+        "/sensors/confirm",
+        "Confirms the calibration fits the temperature probe in use",
+        move |_| {
+            const CONFLICT: u16 = 409;
+
+            #[derive(Serialize)]
+            struct Body {
+                address: String,
+            }
+
+            match probes::confirm() {
+                Ok(address) => Reply::json(&Body { address }),
+                Err(e) => Ok(HttpError::new(CONFLICT, e.to_string()).into()),
+            }
+        },
+    )?;
+    router.post("/command", "Runs a command from the command registry", move |request| {
+        const BAD_REQUEST: u16 = 400;
+
+        #[derive(Deserialize)]
+        struct Body {
+            cmd: String,
+            #[serde(default)]
+            args: serde_json::Value,
+        }
+
+        let body: Body = match read_json(request, DEFAULT_BODY_LIMIT) {
+            Ok(body) => body,
+            Err(e) => return Ok(e.into()),
+        };
+
+        match command::dispatch(&body.cmd, body.args) {
+            Ok(result) => Ok(Reply::json(&result)?),
+            Err(command::Error::Rejected(message)) => Ok(HttpError::new(BAD_REQUEST, message).into()),
+            Err(command::Error::Failed(e)) => Err(e),
+        }
+    })?;
+    for integration in integrations::INTEGRATIONS {
+        const BAD_GATEWAY: u16 = 502;
+
+        router.post(
+            &format!("/integrations/{}/test", integration.name),
+            "Sends one test delivery through the integration",
+            move |_request| match (integration.test)() {
+                Ok(()) => Ok(Reply::no_content()),
+                Err(e) => Ok(HttpError::new(BAD_GATEWAY, format!("{e:#}")).into()),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Journal entries of `kind`, or of every kind, after the `since` sequence number of the query and up to
+/// its `limit`.
+fn respond_events(request: Request<&mut EspHttpConnection>, kind: Option<&str>) -> anyhow::Result<()> {
+    const MAX_LIMIT: usize = 32;
+
+    let uri = request.uri().to_owned();
+    let (since, limit) = match (parse_query(&uri, "since"), parse_query(&uri, "limit")) {
+        (Ok(since), Ok(limit)) => (since, limit.unwrap_or(MAX_LIMIT)),
+        (Err(e), _) | (_, Err(e)) => return respond_error(request, e),
+    };
+
+    let entries = journal::query(kind, since, limit.min(MAX_LIMIT));
+    respond_json(request, Some(&entries))
+}
+
+fn query_param<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(k, v)| (k == key).then_some(v))
+}
+
+/// Parses the query parameter `key`, a malformed value being rejected as the client's mistake.
+fn parse_query<T: FromStr>(uri: &str, key: &str) -> Result<Option<T>, HttpError>
+where
+    T::Err: Display,
+{
+    const BAD_REQUEST: u16 = 400;
+
+    query_param(uri, key)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| HttpError::new(BAD_REQUEST, format!("Invalid {key} {value:?}: {e}")))
+        })
+        .transpose()
+}
+
+/// A request that is rejected before reaching the handler logic.
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+    retry_after: Option<u32>,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    fn retry_after(mut self, seconds: u32) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+/// Reads and parses a JSON body of at most `limit` bytes.
+///
+/// Bodies without a Content-Length are read until the end of the stream, still bounded by `limit`.
+fn read_json<T: DeserializeOwned>(
+    request: &mut Request<&mut EspHttpConnection>,
+    limit: usize,
+) -> Result<T, HttpError> {
+    const BAD_REQUEST: u16 = 400;
+
+    read_body(request, limit, |text| {
+        serde_json::from_str(text).map_err(|e| HttpError::new(BAD_REQUEST, format!("Malformed JSON: {e}")))
+    })
+}
+
+/// Resolves a setting and checks its value the way `/config` and `/config/import` accept them.
+fn validate_setting(name: &str, value: String) -> Result<(&'static str, String), HttpError> {
+    const BAD_REQUEST: u16 = 400;
+    const FORBIDDEN: u16 = 403;
+
+    let Some(canonical) = nvs::canonical_name(name) else {
+        return Err(HttpError::new(BAD_REQUEST, format!("Unknown setting: {name}")));
+    };
+    if nvs::is_local_only(canonical) {
+        let message = format!("{name} can only be changed over the serial port");
+        return Err(HttpError::new(FORBIDDEN, message));
+    }
+    if value.len() > nvs::MAX_VALUE_LEN {
+        let message = format!("Value of {name} exceeds {} bytes", nvs::MAX_VALUE_LEN);
+        return Err(HttpError::new(BAD_REQUEST, message));
+    }
+
+    // Each value goes through the parser that reads it back, so nothing is stored that the device would
+    // then have to ignore, and switches and alert limits don't fail their reload after being written
+    let flag = |v: &str| nvs::parse_flag(v).map(drop).ok_or(anyhow!("Expected on or off"));
+    let switch = measurements::Sensor::ALL
+        .iter()
+        .any(|sensor| sensor.setting() == canonical);
+    let limit = measurements::Sensor::ALL
+        .iter()
+        .any(|&sensor| alerts::settings(sensor).contains(&canonical));
+    // Each limit has its hysteresis first and its minimum duration second
+    let debounce = |field: usize| {
+        measurements::Sensor::ALL.iter().any(|&sensor| {
+            alerts::debounce_settings(sensor)
+                .iter()
+                .any(|settings| settings[field] == canonical)
+        })
+    };
+    let (hysteresis, min_duration) = (debounce(0), debounce(1));
+    let parsed = match canonical {
+        _ if switch => flag(&value),
+        _ if limit => alerts::parse_limit(&value).map(drop),
+        _ if hysteresis => alerts::parse_hysteresis(&value).map(drop),
+        _ if min_duration => alerts::parse_min_duration(&value).map(drop),
+        "net.wifi.tx_power_dbm" => network::parse_tx_power(&value).map(drop),
+        "net.ntp.servers" => network::parse_ntp_servers(&value).map(drop),
+        "net.ntp.sync_mode" => network::parse_ntp_sync_mode(&value).map(drop),
+        "net.ntp.sync_interval" => network::parse_ntp_interval(&value).map(drop),
+        "net.mdns.hostname" => network::parse_hostname(&value).map(drop),
+        "net.setup.timeout" => network::parse_setup_timeout(&value).map(drop),
+        "net.push.template" | "net.mqtt.state_template" => template::validate(&value),
+        "net.syslog.port" => logging::parse_syslog_port(&value).map(drop),
+        "net.syslog.level" => logging::parse_syslog_level(&value).map(drop),
+        "display.timezone" => display::parse_timezone(&value).map(drop),
+        "display.decimal_separator" => display::parse_decimal_separator(&value).map(drop),
+        "display.heartbeat_corner" => display::parse_heartbeat_corner(&value).map(drop),
+        "display.address" => display::parse_address(&value).map(drop),
+        "display.idle_timeout" => display::parse_idle_timeout(&value).map(drop),
+        "sensor.adc.sps" => adc::parse_sps(&value).map(drop),
+        "sensor.adc.mode" => adc::parse_mode(&value).map(drop),
+        "sensor.adc.burst_samples" => adc::parse_burst_samples(&value).map(drop),
+        "sensor.warmup" => measurements::parse_warmup(&value).map(drop),
+        "sensor.max_interval" => measurements::parse_max_interval(&value).map(drop),
+        "sensor.tds.k_factor" => measurements::parse_k_factor(&value).map(drop),
+        "history.capacity" => measurements::parse_history_capacity(&value).map(drop),
+        "history.interval" => measurements::parse_history_interval(&value).map(drop),
+        "site.altitude" => site::parse_altitude(&value).map(drop),
+        "site.pressure" => site::parse_pressure(&value).map(drop),
+        "sim.seed" => simulation::parse_seed(&value).map(drop),
+        "system.thermal_limit" => thermal::parse_limit(&value).map(drop),
+        "system.i2c_error_threshold" => bus::parse_threshold(&value).map(drop),
+        "net.syslog.enabled"
+        | "net.mqtt.discovery.enabled"
+        | "net.push.enabled"
+        | "net.push.template_json"
+        | "net.mqtt.state_template_json"
+        | "display.heartbeat"
+        | "sensor.vref_monitor"
+        | "sim.enabled" => flag(&value),
+        _ => Ok(()),
+    };
+    if let Err(e) = parsed {
+        return Err(HttpError::new(BAD_REQUEST, format!("Invalid value of {name}: {e:#}")));
+    }
+
+    Ok((canonical, value))
+}
+
+// Writes settings validated in full beforehand, and replies with their names
+fn apply_settings(changes: Vec<(&'static str, String)>) -> anyhow::Result<Reply> {
+    let before = revision::snapshot()?;
+    for (name, value) in &changes {
+        nvs::set(name, value)?;
+        info!("Setting {name} changed");
+    }
+    let revision = revision::applied(before)?;
+    measurements::reload_switches()?;
+    alerts::reload()?;
+    network::reload_tx_power()?;
+
+    #[derive(Serialize)]
+    struct Body {
+        updated: Vec<&'static str>,
+        revision: u32,
+    }
+    Reply::json(&Body {
+        updated: changes.into_iter().map(|(name, _)| name).collect(),
+        revision,
+    })
+}
+
+/// Reads an `application/x-www-form-urlencoded` body, as sent by an HTML form, into its decoded fields.
+fn read_form(request: &mut Request<&mut EspHttpConnection>, limit: usize) -> Result<Vec<(String, String)>, HttpError> {
+    read_body(request, limit, |text| {
+        Ok(text
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (decode_form(k), decode_form(v)))
+            .collect())
+    })
+}
+
+fn decode_form(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                // A truncated or non-hex escape shows as `?` rather than as some other byte
+                let digit = |b: Option<u8>| b.and_then(|b| (b as char).to_digit(16));
+                let decoded = digit(input.next())
+                    .zip(digit(input.next()))
+                    .map(|(high, low)| (high * 16 + low) as u8);
+                bytes.push(decoded.unwrap_or(b'?'));
+            }
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn read_body<T>(
+    request: &mut Request<&mut EspHttpConnection>,
+    limit: usize,
+    parse: impl FnOnce(&str) -> Result<T, HttpError>,
+) -> Result<T, HttpError> {
+    const BAD_REQUEST: u16 = 400;
+
+    // Handlers run one at a time on the server task, so a single buffer is reused between requests
+    static BUFFER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    let mut buf = BUFFER.lock().unwrap();
+
+    let content_len = request.content_len();
+    read_limited(request, content_len, limit, &mut buf)?;
+
+    let text = std::str::from_utf8(&buf).map_err(|_| HttpError::new(BAD_REQUEST, "Body is not valid UTF-8"))?;
+    parse(text)
+}
+
+/// Reads a body of at most `limit` bytes into `buf`, giving up as soon as it is known to be larger.
+///
+/// Without a Content-Length, e.g. for a chunked body, it reads until the end of the stream.
+fn read_limited<R: Read>(
+    reader: &mut R,
+    content_len: Option<u64>,
+    limit: usize,
+    buf: &mut Vec<u8>,
+) -> Result<(), HttpError> {
+    const PAYLOAD_TOO_LARGE: u16 = 413;
+    const BAD_REQUEST: u16 = 400;
+
+    buf.clear();
+
+    let too_large = || HttpError::new(PAYLOAD_TOO_LARGE, format!("Body exceeds {limit} bytes"));
+    if content_len.is_some_and(|len| len > limit as u64) {
+        return Err(too_large());
+    }
+
+    let mut chunk = [0_u8; 256];
+    loop {
+        let len = reader
+            .read(&mut chunk)
+            .map_err(|e| HttpError::new(BAD_REQUEST, format!("Failed to read body: {e:?}")))?;
+        if len == 0 {
+            break;
+        }
+        if buf.len() + len > limit {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok(())
+}
+
+/// Response of a mutating handler, kept whole so it can be replayed for a repeated idempotency key.
+#[derive(Debug, Clone)]
+struct Reply {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn no_content() -> Self {
+        const NO_CONTENT: u16 = 204;
+
+        Self {
+            status: NO_CONTENT,
+            body: vec![],
+        }
+    }
+
+    fn json<T: Serialize>(msg: &T) -> anyhow::Result<Self> {
+        const OK: u16 = 200;
+
+        Ok(Self {
+            status: OK,
+            body: serde_json::to_vec(msg)?,
+        })
+    }
+
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl From<HttpError> for Reply {
+    fn from(error: HttpError) -> Self {
+        Self {
+            status: error.status,
+            body: error_body(&error.message),
+        }
+    }
+}
+
+struct CachedReply {
+    route: String,
+    key: String,
+    stored: Instant,
+    reply: Reply,
+}
+
+// Replies to requests with an Idempotency-Key, least recently used first. RAM only, so a reboot clears it
+static IDEMPOTENCY: Mutex<VecDeque<CachedReply>> = Mutex::new(VecDeque::new());
+
+const IDEMPOTENCY_CAPACITY: usize = 16;
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(10 * 60);
+
+fn cached_reply(route: &str, key: &str) -> Option<Reply> {
+    let mut cache = IDEMPOTENCY.lock().unwrap();
+    cache.retain(|cached| cached.stored.elapsed() < IDEMPOTENCY_TTL);
+
+    // Keys are scoped to the route, the same key on another endpoint is a different request
+    let index = cache
+        .iter()
+        .position(|cached| cached.route == route && cached.key == key)?;
+    let cached = cache.remove(index)?;
+    let reply = cached.reply.clone();
+    cache.push_back(cached);
+
+    Some(reply)
+}
+
+fn cache_reply(route: &str, key: String, reply: &Reply) {
+    // Failures are not remembered, so a retry after one runs the handler again
+    if !reply.is_success() {
+        return;
+    }
+
+    let mut cache = IDEMPOTENCY.lock().unwrap();
+    cache.retain(|cached| !(cached.route == route && cached.key == key));
+    if cache.len() >= IDEMPOTENCY_CAPACITY {
+        cache.pop_front();
+    }
+
+    cache.push_back(CachedReply {
+        route: route.to_owned(),
+        key,
+        stored: Instant::now(),
+        reply: reply.clone(),
+    });
+}
+
+fn error_body(message: &str) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body<'a> {
+        error: &'a str,
+    }
+
+    serde_json::to_vec(&Body { error: message }).unwrap_or_default()
+}
+
+fn respond_reply(request: Request<&mut EspHttpConnection>, reply: &Reply) -> anyhow::Result<()> {
+    let headers: &[_] = if reply.body.is_empty() {
+        &[]
+    } else {
+        &[("Content-Type", "application/json")]
+    };
+    let mut res = request.into_response(reply.status, None, headers)?;
+    res.write_all(&reply.body)?;
+
+    Ok(())
+}
+
+fn respond_error(request: Request<&mut EspHttpConnection>, error: HttpError) -> anyhow::Result<()> {
+    let msg = error_body(&error.message);
+    let retry_after = error.retry_after.map(|v| v.to_string());
+    let mut headers = vec![("Content-Type", "application/json")];
+    if let Some(v) = &retry_after {
+        headers.push(("Retry-After", v.as_str()));
+    }
+    let mut res = request.into_response(error.status, None, &headers)?;
+    res.write_all(&msg)?;
+
+    Ok(())
+}
+
+fn respond_json<T: Serialize>(request: Request<&mut EspHttpConnection>, msg: Option<&T>) -> anyhow::Result<()> {
+    const NO_CONTENT: u16 = 204;
+
+    let Some(msg) = msg else {
+        request.into_status_response(NO_CONTENT)?;
+        return Ok(());
+    };
+
+    let body = serde_json::to_string(msg)?.into_bytes();
+    let etag = format!("\"{:08x}\"", system::fnv1a(&body));
+    respond_tagged(request, &etag, || Ok(body))
+}
+
+/// Like `respond_json`, but derives the ETag from a version of the underlying data, so an unchanged
+/// response is answered without serializing it.
+fn respond_versioned<T: Serialize>(
+    request: Request<&mut EspHttpConnection>,
+    version: Option<String>,
+    msg: Option<&T>,
+) -> anyhow::Result<()> {
+    let (Some(version), Some(msg)) = (version, msg) else {
+        return respond_json(request, msg);
+    };
+
+    // Versions restart after a reboot, the boot ID keeps them from matching tags handed out before
+    let etag = format!("\"{}-{version}\"", system::boot_id());
+    respond_tagged(request, &etag, || Ok(serde_json::to_string(msg)?.into_bytes()))
+}
+
+/// Answers 304 without a body if the client already holds `etag`, otherwise 200 with the body.
+fn respond_tagged<F>(request: Request<&mut EspHttpConnection>, etag: &str, body: F) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<Vec<u8>>,
+{
+    const NOT_MODIFIED: u16 = 304;
+
+    let cached = request
+        .header("If-None-Match")
+        .is_some_and(|v| v.split(',').map(str::trim).any(|tag| tag == etag || tag == "*"));
+
+    // no-cache lets clients keep the body but makes them revalidate every time
+    let headers = [("ETag", etag), ("Cache-Control", "no-cache")];
+    if cached {
+        request.into_response(NOT_MODIFIED, None, &headers)?;
+    } else {
+        let body = body()?;
+        let mut res = request.into_response(200, None, &headers)?;
+        res.write_all(&body)?;
+    }
+
+    Ok(())
+}
+
+/// Standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn values(temperature: f32, tds: f32) -> measurements::Values {
+        measurements::Values {
+            timestamp: 1_700_000_000_000,
+            temperature,
+            temperature_at: 1_700_000_000_000,
+            tds,
+            tds_at: 1_700_000_000_000,
+            supply_voltage: None,
+            simulated: false,
+            uptime_ms: 60_000,
+            clock_valid: true,
+            seq: 7,
+            compensation: measurements::Compensation {
+                temperature,
+                measured_at: 1_700_000_000_000,
+                fallback: false,
+                k_factor: 1.0,
+                voltage: None,
+            },
+            warming_up: false,
+            temperature_enabled: true,
+            tds_enabled: true,
+        }
+    }
+
+    #[test]
+    fn payloads_round_the_same_values_to_their_own_precision() {
+        let v = values(24.56, 186.46);
+
+        let legacy = serde_json::to_value(Message::from(v)).unwrap();
+        assert_eq!(legacy["temperature"], json!(24.6_f32));
+        assert_eq!(legacy["tds"], json!(186));
+        assert_eq!(legacy["timestamp"], json!(1_700_000_000_000_i64));
+
+        let v1 = serde_json::to_value(MessageV1::from(v)).unwrap();
+        assert_eq!(v1["temperature"]["value"], json!(24.6_f32));
+        assert_eq!(v1["tds"]["value"], json!(186.5_f32));
+        assert_eq!(v1["tds"]["measured_at"], json!(1_700_000_000_000_i64));
+        assert_eq!(v1["uptime_ms"], json!(60_000));
+        assert_eq!(v1["clock_valid"], json!(true));
+    }
+
+    #[test]
+    fn payloads_mark_disabled_sensors() {
+        let v = measurements::Values {
+            tds: f32::NAN,
+            tds_enabled: false,
+            ..values(24.56, 0.0)
+        };
+
+        let legacy = serde_json::to_value(Message::from(v)).unwrap();
+        assert_eq!(legacy["tds"], json!(DISABLED));
+        assert_eq!(legacy["temperature"], json!(24.6_f32));
+
+        let v1 = serde_json::to_value(MessageV1::from(v)).unwrap();
+        assert_eq!(v1["tds"], json!(DISABLED));
+        assert_eq!(v1["temperature"]["value"], json!(24.6_f32));
+    }
+
+    #[test]
+    fn payloads_leave_out_flags_that_are_not_set() {
+        let v = values(24.56, 186.46);
+
+        let legacy = serde_json::to_value(Message::from(v)).unwrap();
+        assert!(legacy.get("simulated").is_none());
+        let v1 = serde_json::to_value(MessageV1::from(v)).unwrap();
+        assert!(v1.get("simulated").is_none());
+        assert!(v1.get("warming_up").is_none());
+
+        let v = measurements::Values {
+            simulated: true,
+            warming_up: true,
+            ..v
+        };
+        let legacy = serde_json::to_value(Message::from(v)).unwrap();
+        assert_eq!(legacy["simulated"], json!(true));
+        let v1 = serde_json::to_value(MessageV1::from(v)).unwrap();
+        assert_eq!(v1["simulated"], json!(true));
+        assert_eq!(v1["warming_up"], json!(true));
+    }
+
+    #[test]
+    fn query_param_finds_the_value_of_a_key() {
+        let uri = "/events?type=alert&since=12&flag&limit=";
+        assert_eq!(query_param(uri, "type"), Some("alert"));
+        assert_eq!(query_param(uri, "since"), Some("12"));
+        assert_eq!(query_param(uri, "limit"), Some(""));
+        assert_eq!(query_param(uri, "flag"), None);
+        assert_eq!(query_param(uri, "missing"), None);
+        assert_eq!(query_param("/events", "type"), None);
+    }
+
+    #[test]
+    fn parse_query_rejects_malformed_values_with_400() {
+        assert_eq!(parse_query::<u32>("/events?since=12", "since").unwrap(), Some(12));
+        assert_eq!(parse_query::<u32>("/events", "since").unwrap(), None);
+
+        let error = parse_query::<usize>("/events?limit=ten", "limit").unwrap_err();
+        assert_eq!(error.status, 400);
+        assert!(error.message.contains("limit"));
+        assert_eq!(parse_query::<u32>("/events?since=-1", "since").unwrap_err().status, 400);
+    }
+
+    // Hands out a body in pieces, the way a chunked request arrives
+    struct Chunked<'a> {
+        body: &'a [u8],
+        piece: usize,
+    }
+
+    impl esp_idf_svc::hal::io::ErrorType for Chunked<'_> {
+        type Error = std::convert::Infallible;
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = self.piece.min(buf.len()).min(self.body.len());
+            buf[..len].copy_from_slice(&self.body[..len]);
+            self.body = &self.body[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn body_at_the_limit_is_read() {
+        let body = [b'x'; DEFAULT_BODY_LIMIT];
+        let mut buf = vec![];
+
+        read_limited(&mut &body[..], Some(body.len() as u64), DEFAULT_BODY_LIMIT, &mut buf).unwrap();
+        assert_eq!(buf.len(), DEFAULT_BODY_LIMIT);
+    }
+
+    #[test]
+    fn body_over_the_limit_is_refused_before_reading() {
+        let body = [b'x'; DEFAULT_BODY_LIMIT + 1];
+        let mut reader = &body[..];
+        let mut buf = vec![];
+
+        let error = read_limited(&mut reader, Some(body.len() as u64), DEFAULT_BODY_LIMIT, &mut buf).unwrap_err();
+        assert_eq!(error.status, 413);
+        assert_eq!(reader.len(), body.len());
+    }
+
+    #[test]
+    fn body_longer_than_its_content_length_is_refused() {
+        let body = [b'x'; DEFAULT_BODY_LIMIT + 1];
+        let mut buf = vec![];
+
+        let error = read_limited(&mut &body[..], Some(10), DEFAULT_BODY_LIMIT, &mut buf).unwrap_err();
+        assert_eq!(error.status, 413);
+    }
+
+    #[test]
+    fn chunked_body_is_bounded_by_the_limit() {
+        let body = [b'x'; DEFAULT_BODY_LIMIT + 1];
+        let mut buf = vec![];
+
+        let mut at_limit = Chunked {
+            body: &body[..DEFAULT_BODY_LIMIT],
+            piece: 100,
+        };
+        read_limited(&mut at_limit, None, DEFAULT_BODY_LIMIT, &mut buf).unwrap();
+        assert_eq!(buf.len(), DEFAULT_BODY_LIMIT);
+
+        let mut over = Chunked {
+            body: &body,
+            piece: 100,
+        };
+        let error = read_limited(&mut over, None, DEFAULT_BODY_LIMIT, &mut buf).unwrap_err();
+        assert_eq!(error.status, 413);
+    }
+
+    fn routes() -> Vec<Route> {
+        let mut router = Router {
+            server: None,
+            routes: vec![],
+            interactive: vec![],
+        };
+        register_routes(&mut router).unwrap();
+        router.routes
+    }
+
+    fn route<'r>(routes: &'r [Route], method: &str, path: &str) -> &'r Route {
+        routes
+            .iter()
+            .find(|route| route.method == method && route.path == path)
+            .unwrap_or_else(|| panic!("No route {method} {path}"))
+    }
+
+    #[test]
+    fn lockdown_refuses_changes_but_not_reads() {
+        let routes = routes();
+
+        for path in [
+            "/ota",
+            "/config",
+            "/config/import",
+            "/config/rollback",
+            "/setup",
+            "/provision/extend",
+            "/command",
+            "/calibrate/tds",
+            "/calibration/import",
+            "/sensors/confirm",
+            "/display/test",
+            "/assistant/water-change",
+            "/diagnostics/i2c/reset",
+        ] {
+            let error = refusal(route(&routes, "POST", path), true, false, u32::MAX);
+            assert_eq!(error.map(|error| error.status), Some(405), "POST {path}");
+        }
+        for path in ["/config", "/health", "/version", "/events", "/lifecycle", "/api"] {
+            assert!(
+                refusal(route(&routes, "GET", path), true, false, u32::MAX).is_none(),
+                "GET {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn health_names_the_boot() {
+        let health = serde_json::to_value(HealthMessage::current()).unwrap();
+        assert_eq!(health["boot_id"], system::boot_id().to_string());
+    }
+
+    #[test]
+    fn routes_are_admitted_outside_lockdown() {
+        for route in routes() {
+            assert!(refusal(&route, false, false, u32::MAX).is_none(), "{}", route.path);
+        }
+    }
+
+    #[test]
+    fn every_route_is_described_once() {
+        let routes = routes();
+        for route in &routes {
+            assert!(!route.description.trim().is_empty(), "{}", route.path);
+            let count = routes
+                .iter()
+                .filter(|other| (other.method, &other.path) == (route.method, &route.path))
+                .count();
+            assert_eq!(count, 1, "{} {}", route.method, route.path);
+        }
+        // Outside setup mode, so the connectivity checks are not among them
+        assert!(routes.len() + CAPTIVE_PROBES.len() <= MAX_ROUTES);
+    }
+
+    #[test]
+    fn route_listing_round_trips() {
+        // What a client of /api reads back
+        #[derive(Deserialize)]
+        struct Listed {
+            method: String,
+            path: String,
+            description: String,
+            cost: String,
+            mutating: bool,
+        }
+
+        let routes = routes();
+        let listed: Vec<Listed> = serde_json::from_slice(&serde_json::to_vec(&routes).unwrap()).unwrap();
+
+        assert_eq!(listed.len(), routes.len());
+        for (listed, route) in listed.iter().zip(&routes) {
+            assert_eq!(listed.method, route.method);
+            assert_eq!(listed.path, route.path);
+            assert_eq!(listed.description, route.description);
+            assert_eq!(listed.mutating, route.mutating);
+            let cost = match route.cost {
+                Cost::Light => "light",
+                Cost::Heavy => "heavy",
+            };
+            assert_eq!(listed.cost, cost);
+        }
+    }
+
+    #[test]
+    fn heavy_routes_are_shed_first() {
+        let routes = routes();
+        let light = routes.iter().find(|route| matches!(route.cost, Cost::Light)).unwrap();
+        let heavy = routes.iter().find(|route| matches!(route.cost, Cost::Heavy)).unwrap();
+        let free_heap = Cost::Light.min_free_heap();
+
+        assert!(refusal(light, false, true, free_heap).is_none());
+        let error = refusal(heavy, false, true, u32::MAX).unwrap();
+        assert_eq!((error.status, error.retry_after), (503, Some(60)));
+        let error = refusal(heavy, false, false, free_heap).unwrap();
+        assert_eq!((error.status, error.retry_after), (503, Some(5)));
+    }
+
+    // The cache is shared by all tests, each one uses routes of its own
+    fn reply(status: u16, body: &str) -> Reply {
+        Reply {
+            status,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn retry_after_success_gets_the_same_reply() {
+        cache_reply("/test/success", "key-1".to_owned(), &reply(200, "first"));
+
+        let cached = cached_reply("/test/success", "key-1").unwrap();
+        assert_eq!((cached.status, cached.body), (200, b"first".to_vec()));
+        assert!(cached_reply("/test/success", "key-2").is_none());
+    }
+
+    #[test]
+    fn retry_after_failure_runs_again() {
+        cache_reply("/test/failure", "key-1".to_owned(), &reply(400, "bad"));
+        cache_reply("/test/failure", "key-2".to_owned(), &reply(500, "failed"));
+
+        assert!(cached_reply("/test/failure", "key-1").is_none());
+        assert!(cached_reply("/test/failure", "key-2").is_none());
+    }
+
+    #[test]
+    fn same_key_on_another_endpoint_is_another_request() {
+        cache_reply("/test/collision/a", "key".to_owned(), &reply(200, "a"));
+        assert!(cached_reply("/test/collision/b", "key").is_none());
+
+        cache_reply("/test/collision/b", "key".to_owned(), &reply(204, ""));
+        assert_eq!(cached_reply("/test/collision/a", "key").unwrap().body, b"a");
+        assert_eq!(cached_reply("/test/collision/b", "key").unwrap().status, 204);
+    }
+
+    #[test]
+    fn settings_the_network_may_not_change_are_refused() {
+        for name in [
+            "system.lockdown",
+            "lockdown",
+            "board.pin.sda",
+            "pin_scl",
+            "board.pin.onewire",
+            "board.pin.adc_ready",
+        ] {
+            let e = validate_setting(name, "1".to_owned()).unwrap_err();
+            assert_eq!(e.status, 403, "{name}");
+        }
+    }
+
+    #[test]
+    fn settings_are_checked_with_the_parser_reading_them_at_boot() {
+        for (name, value) in [
+            ("display.timezone", "Asia/Tokio"),
+            ("timezone", "Mars/Olympus"),
+            ("display.decimal_separator", ";"),
+            ("display.heartbeat", "yes"),
+            ("display.heartbeat_corner", "center"),
+            ("display.address", "0x80"),
+            ("display.idle_timeout", "0"),
+            ("sensor.adc.sps", "100"),
+            ("sensor.adc.mode", "fast"),
+            ("sensor.adc.burst_samples", "0"),
+            ("sensor.max_interval", "1"),
+            ("history.capacity", "100000"),
+            ("system.thermal_limit", "hot"),
+            ("net.syslog.level", "loud"),
+        ] {
+            let e = validate_setting(name, value.to_owned()).unwrap_err();
+            assert_eq!(e.status, 400, "{name} {value:?}");
+        }
+
+        for (name, value) in [
+            ("display.timezone", "Asia/Tokyo"),
+            ("display.decimal_separator", ","),
+            ("display.heartbeat", "on"),
+            ("display.heartbeat_corner", "top_left"),
+            ("display.address", "0x3d"),
+            ("display.idle_timeout", "300"),
+            ("sensor.adc.sps", "860"),
+            ("sensor.adc.mode", "burst"),
+            ("sensor.adc.burst_samples", "32"),
+            ("system.thermal_limit", "75"),
+            ("device.name", "Living room"),
+        ] {
+            let (canonical, stored) = validate_setting(name, value.to_owned()).unwrap();
+            assert_eq!((canonical, stored.as_str()), (name, value));
+        }
+    }
+
+    #[test]
+    fn base64_matches_the_rfc_vectors() {
+        for (bytes, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(bytes.as_bytes()), encoded, "{bytes:?}");
+        }
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+        assert_eq!(base64(&[0; 1024]).len(), 1368);
+    }
+
+    #[test]
+    fn form_values_are_percent_decoded() {
+        assert_eq!(decode_form("my+tank%21"), "my tank!");
+        assert_eq!(decode_form("a%2Bb%3d%26"), "a+b=&");
+        assert_eq!(decode_form("%E3%81%93%E3%82%93"), "こん");
+        assert_eq!(decode_form(""), "");
+
+        // A malformed escape decodes as a single `?`, taking the two characters after the `%` with it
+        assert_eq!(decode_form("100%zz"), "100?");
+        assert_eq!(decode_form("50%"), "50?");
+        assert_eq!(decode_form("%4"), "?");
+        assert_eq!(decode_form("%+5x"), "?x");
+        assert_eq!(decode_form("%FF"), "\u{fffd}");
+    }
+}
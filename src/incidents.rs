@@ -0,0 +1,450 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Captures what led up to an alarm and what followed it, for looking into an incident after the fact at
+// `/incidents`. Samples stay in the history ring while the capture runs, an incident only remembers
+// where its range starts; they are copied out once, when the capture ends and the incident is written to
+// NVS. Alarms raised while one is open join it rather than opening another.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{alerts, measurements, network, nvs, system};
+
+const CAPACITY: usize = 5;
+const NVS_KEY: &str = "incidents";
+const VERSION: u32 = 1;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// History captured on either side of the first alarm
+const BEFORE: Duration = Duration::from_secs(600);
+const AFTER: Duration = Duration::from_secs(600);
+// Samples kept per incident, about one every 30 s, thinned out evenly so five incidents fit in NVS
+const MAX_SAMPLES: usize = 41;
+
+/// An alarm as it was raised, with the limit it crossed and how that limit was debounced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Alarm {
+    pub sensor: String,
+    pub level: alerts::Level,
+    pub limit: Option<f32>,
+    pub hysteresis: f32,
+    pub min_duration_s: u32,
+    pub value: Option<f32>,
+    /// When it was raised, in ms since the epoch.
+    pub raised_at: Option<i64>,
+}
+
+impl Alarm {
+    fn raised() -> Vec<Self> {
+        alerts::get()
+            .into_iter()
+            .filter_map(|alert| {
+                let level = alert.alarm?;
+                let (limit, debounce) = match level {
+                    alerts::Level::Low => (alert.min, alert.min_debounce),
+                    alerts::Level::High => (alert.max, alert.max_debounce),
+                };
+                Some(Self {
+                    sensor: alert.sensor.to_owned(),
+                    level,
+                    limit,
+                    hysteresis: debounce.hysteresis,
+                    min_duration_s: debounce.min_duration_s,
+                    value: alert.value,
+                    raised_at: alert.since,
+                })
+            })
+            .collect()
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        self.sensor == other.sensor && self.level == other.level
+    }
+}
+
+/// A history sample as `[timestamp, temperature, tds]`, null for a sensor switched off or failing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Sample(pub i64, pub Option<f32>, pub Option<f32>);
+
+impl From<measurements::Sample> for Sample {
+    fn from(sample: measurements::Sample) -> Self {
+        let value = |enabled: bool, value: f32| Some(value).filter(|v| enabled && v.is_finite());
+        Self(
+            sample.timestamp,
+            value(sample.temperature_enabled, sample.temperature),
+            value(sample.tds_enabled, sample.tds),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Incident {
+    pub id: u32,
+    pub boot_id: system::BootId,
+    /// When the first alarm was raised, in ms since the epoch.
+    pub opened_at: i64,
+    /// When the last alarm cleared, absent while any is still raised.
+    pub closed_at: Option<i64>,
+    /// Every alarm raised while the incident was open, in order.
+    pub alarms: Vec<Alarm>,
+    /// WiFi signal and free heap when it opened.
+    pub rssi: Option<i32>,
+    pub free_heap: u32,
+    /// History from `BEFORE` the first alarm to `AFTER` it, empty until the capture ends.
+    pub samples: Vec<Sample>,
+}
+
+/// Conditions on the device when an incident opens.
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    rssi: Option<i32>,
+    free_heap: u32,
+}
+
+struct Capture {
+    incident: Incident,
+    /// Sequence number of the first history sample in the capture, `None` before there was any.
+    first_seq: Option<u32>,
+    until: i64,
+    /// Whether the capture ended and the incident is among the stored ones.
+    stored: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Stored {
+    next_id: u32,
+    incidents: VecDeque<Incident>,
+}
+
+struct Tracker {
+    capture: Option<Capture>,
+    stored: Stored,
+}
+
+impl Tracker {
+    /// Moves the incident on with the alarms raised at `now`. `context` and `first_seq` are only asked for
+    /// when an incident opens, `samples` when its capture ends. Returns whether the stored incidents
+    /// changed.
+    fn update(
+        &mut self,
+        raised: &[Alarm],
+        now: i64,
+        context: impl FnOnce() -> (Context, Option<u32>),
+        samples: impl FnOnce(Option<u32>, i64, i64) -> Vec<Sample>,
+    ) -> bool {
+        let Some(capture) = &mut self.capture else {
+            if !raised.is_empty() {
+                let (context, first_seq) = context();
+                self.capture = Some(Capture {
+                    incident: Incident {
+                        id: self.stored.next_id,
+                        boot_id: system::boot_id(),
+                        opened_at: now,
+                        closed_at: None,
+                        alarms: raised.to_vec(),
+                        rssi: context.rssi,
+                        free_heap: context.free_heap,
+                        samples: Vec::new(),
+                    },
+                    first_seq,
+                    until: now + AFTER.as_millis() as i64,
+                    stored: false,
+                });
+                self.stored.next_id = self.stored.next_id.wrapping_add(1);
+            }
+            return false;
+        };
+
+        let incident = &mut capture.incident;
+        let mut changed = false;
+        for alarm in raised {
+            if !incident.alarms.iter().any(|known| known.same(alarm)) {
+                incident.alarms.push(alarm.clone());
+                changed = true;
+            }
+        }
+        // Raised again before the capture ended, it is still the same incident
+        let closed_at = if raised.is_empty() {
+            incident.closed_at.or(Some(now))
+        } else {
+            None
+        };
+        changed |= closed_at != incident.closed_at;
+        incident.closed_at = closed_at;
+
+        if !capture.stored && now >= capture.until {
+            let from = incident.opened_at - BEFORE.as_millis() as i64;
+            incident.samples = thin(samples(capture.first_seq, from, capture.until), MAX_SAMPLES);
+            capture.stored = true;
+            if self.stored.incidents.len() >= CAPACITY {
+                self.stored.incidents.pop_front();
+            }
+            self.stored.incidents.push_back(incident.clone());
+            changed = true;
+        } else if capture.stored && changed {
+            if let Some(stored) = self.stored.incidents.back_mut() {
+                stored.alarms.clone_from(&incident.alarms);
+                stored.closed_at = incident.closed_at;
+            }
+        } else {
+            // Nothing stored yet, changes so far go out with the capture
+            changed = false;
+        }
+
+        // Done once captured and recovered, the next alarm opens a new one
+        if capture.stored && capture.incident.closed_at.is_some() {
+            self.capture = None;
+        }
+
+        changed
+    }
+
+    /// The stored incidents, oldest first, followed by the one still capturing.
+    fn incidents(&self) -> Vec<Incident> {
+        let capturing = self.capture.as_ref().filter(|capture| !capture.stored);
+        self.stored
+            .incidents
+            .iter()
+            .chain(capturing.map(|capture| &capture.incident))
+            .cloned()
+            .collect()
+    }
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    capture: None,
+    stored: Stored {
+        next_id: 0,
+        incidents: VecDeque::new(),
+    },
+});
+
+/// Stored incidents, oldest first, followed by the one being captured, which has no samples yet.
+pub(crate) fn get() -> Vec<Incident> {
+    TRACKER.lock().unwrap().incidents()
+}
+
+pub(crate) fn init() {
+    match nvs::load_state::<Stored>(NVS_KEY, VERSION) {
+        Ok(Some(stored)) => TRACKER.lock().unwrap().stored = stored,
+        Ok(None) => {}
+        Err(e) => warn!("Discarding unreadable incidents: {e:?}"),
+    }
+}
+
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        task::block_in_place(update);
+    }
+}
+
+fn update() {
+    let raised = Alarm::raised();
+    let now = Utc::now().timestamp_millis();
+    let mut tracker = TRACKER.lock().unwrap();
+
+    let capturing = tracker.capture.is_some();
+    let changed = tracker.update(
+        &raised,
+        now,
+        || {
+            let context = Context {
+                rssi: network::rssi(),
+                free_heap: system::free_heap(),
+            };
+            let from = now - BEFORE.as_millis() as i64;
+            (context, measurements::history_seq_at(from))
+        },
+        |first_seq, from, until| {
+            let since = match first_seq {
+                Some(0) => measurements::Since::Start,
+                Some(seq) => measurements::Since::Seq(seq - 1),
+                None => measurements::Since::Timestamp(from - 1),
+            };
+            measurements::get_history(since)
+                .into_iter()
+                .filter(|sample| sample.timestamp <= until)
+                .map(Sample::from)
+                .collect()
+        },
+    );
+    if !capturing {
+        if let Some(capture) = &tracker.capture {
+            info!("Incident {} opened, capturing history", capture.incident.id);
+        }
+    }
+
+    if changed {
+        match nvs::encode_state(VERSION, &tracker.stored) {
+            Ok(blob) => nvs::persist(NVS_KEY, Some(blob)),
+            Err(e) => error!("Failed to persist incidents: {e:?}"),
+        }
+    }
+}
+
+/// At most `max` of `samples`, evenly spread and always keeping the first and the last.
+fn thin(samples: Vec<Sample>, max: usize) -> Vec<Sample> {
+    if samples.len() <= max || max < 2 {
+        return samples.into_iter().take(max).collect();
+    }
+
+    let last = samples.len() - 1;
+    (0..max).map(|i| samples[i * last / (max - 1)]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60_000;
+
+    fn alarm(sensor: &str, level: alerts::Level) -> Alarm {
+        Alarm {
+            sensor: sensor.to_owned(),
+            level,
+            limit: Some(28.0),
+            hysteresis: 0.3,
+            min_duration_s: 60,
+            value: Some(28.5),
+            raised_at: None,
+        }
+    }
+
+    fn tracker() -> Tracker {
+        Tracker {
+            capture: None,
+            stored: Stored::default(),
+        }
+    }
+
+    // Moves the tracker on with one sample a minute over the whole capture
+    fn update(tracker: &mut Tracker, raised: &[Alarm], now: i64) -> bool {
+        let context = || {
+            let context = Context {
+                rssi: Some(-60),
+                free_heap: 100_000,
+            };
+            (context, Some(1))
+        };
+        let samples = |_, from: i64, until: i64| -> Vec<Sample> {
+            (from..=until)
+                .step_by(MINUTE as usize)
+                .map(|at| Sample(at, Some(28.5), Some(300.0)))
+                .collect()
+        };
+        tracker.update(raised, now, context, samples)
+    }
+
+    #[test]
+    fn captures_both_sides_of_the_first_alarm() {
+        let mut tracker = tracker();
+        let high = [alarm("temperature", alerts::Level::High)];
+
+        assert!(!update(&mut tracker, &high, 0));
+        assert_eq!(tracker.incidents().len(), 1);
+        assert!(tracker.incidents()[0].samples.is_empty());
+        assert!(!update(&mut tracker, &high, 10 * MINUTE - 1));
+
+        assert!(update(&mut tracker, &high, 10 * MINUTE));
+        let incident = &tracker.incidents()[0];
+        assert_eq!(incident.samples.len(), 21);
+        assert_eq!(incident.samples.first().unwrap().0, -10 * MINUTE);
+        assert_eq!(incident.samples.last().unwrap().0, 10 * MINUTE);
+        assert_eq!(incident.closed_at, None);
+        assert_eq!(incident.rssi, Some(-60));
+    }
+
+    #[test]
+    fn recovery_closes_the_incident() {
+        let mut tracker = tracker();
+        let high = [alarm("temperature", alerts::Level::High)];
+
+        update(&mut tracker, &high, 0);
+        update(&mut tracker, &high, 10 * MINUTE);
+        assert!(update(&mut tracker, &[], 15 * MINUTE));
+        assert_eq!(tracker.incidents()[0].closed_at, Some(15 * MINUTE));
+
+        // The next alarm is a new incident
+        update(&mut tracker, &high, 20 * MINUTE);
+        let incidents = tracker.incidents();
+        assert_eq!(incidents.len(), 2);
+        assert_eq!((incidents[0].id, incidents[1].id), (0, 1));
+    }
+
+    #[test]
+    fn recovery_within_the_capture_waits_for_its_end() {
+        let mut tracker = tracker();
+        let high = [alarm("temperature", alerts::Level::High)];
+
+        update(&mut tracker, &high, 0);
+        assert!(!update(&mut tracker, &[], 2 * MINUTE));
+        assert!(update(&mut tracker, &[], 10 * MINUTE));
+
+        let incidents = tracker.incidents();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].closed_at, Some(2 * MINUTE));
+        assert_eq!(incidents[0].samples.len(), 21);
+        assert!(tracker.capture.is_none());
+    }
+
+    #[test]
+    fn overlapping_alarms_share_one_incident() {
+        let mut tracker = tracker();
+        let high = alarm("temperature", alerts::Level::High);
+        let tds = alarm("tds", alerts::Level::High);
+
+        update(&mut tracker, &[high.clone()], 0);
+        update(&mut tracker, &[high.clone(), tds.clone()], MINUTE);
+        // Cleared and raised again within the capture
+        update(&mut tracker, &[], 2 * MINUTE);
+        update(&mut tracker, &[tds.clone()], 3 * MINUTE);
+        update(&mut tracker, &[tds.clone()], 10 * MINUTE);
+
+        let incidents = tracker.incidents();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].alarms, [high, tds]);
+        assert_eq!(incidents[0].closed_at, None);
+    }
+
+    #[test]
+    fn only_the_last_incidents_are_kept() {
+        let mut tracker = tracker();
+        let high = [alarm("temperature", alerts::Level::High)];
+
+        for i in 0..7 {
+            let start = i * 30 * MINUTE;
+            update(&mut tracker, &high, start);
+            update(&mut tracker, &[], start + 10 * MINUTE);
+        }
+
+        let ids: Vec<_> = tracker.incidents().iter().map(|incident| incident.id).collect();
+        assert_eq!(ids, [2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn thinning_keeps_both_ends() {
+        let samples: Vec<_> = (0..241).map(|i| Sample(i, None, None)).collect();
+
+        let thinned = thin(samples.clone(), 41);
+        assert_eq!(thinned.len(), 41);
+        assert_eq!(thinned.first().unwrap().0, 0);
+        assert_eq!(thinned.last().unwrap().0, 240);
+        assert!(thinned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        assert_eq!(thin(samples[..10].to_vec(), 41).len(), 10);
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{logging, push};
+
+/// Outbound delivery that can be inspected and tested through `/integrations`.
+pub(crate) struct Integration {
+    pub name: &'static str,
+    pub status: fn() -> Status,
+    pub test: fn() -> anyhow::Result<()>,
+}
+
+/// Common view of an integration, each one is enabled by its own `<prefix>.enabled` flag.
+#[derive(Debug, Serialize)]
+pub(crate) struct Status {
+    pub enabled: bool,
+    pub endpoint: Option<String>,
+    pub sent: u32,
+    pub failed: u32,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+}
+
+pub(crate) static INTEGRATIONS: &[Integration] = &[
+    Integration {
+        name: "syslog",
+        status: logging::status,
+        test: logging::test,
+    },
+    Integration {
+        name: "push",
+        status: push::status,
+        test: push::test,
+    },
+];
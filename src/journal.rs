@@ -0,0 +1,293 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::{alerts, factory, lifecycle, nvs, recovery, system};
+
+const CAPACITY: usize = 32;
+const NVS_KEY: &str = "journal";
+const VERSION: u32 = 1;
+// Events that can come in bursts are written out at most this often, the others right away
+const BATCH_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    Boot {
+        /// Absent in entries written before boots were identified
+        #[serde(default)]
+        boot_id: Option<system::BootId>,
+        reset_reason: String,
+        /// Last measurements before an unexpected reset, recovered from RTC memory
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        recovered: Vec<recovery::Record>,
+    },
+    WifiReconnect,
+    WaterChange {
+        started_at: i64,
+        before: Option<f32>,
+        after: Option<f32>,
+    },
+    /// Persisted state written by firmware with a layout this one does not know
+    StateDiscarded {
+        key: String,
+        version: u32,
+    },
+    /// One device's I2C error rate climbing while another on the same bus stays clean
+    BusDegradation {
+        device: String,
+        /// Errors per 1000 transactions
+        error_rate: f32,
+        reference: String,
+        reference_rate: f32,
+    },
+    FactoryTest {
+        passed: bool,
+        checks: Vec<factory::Check>,
+    },
+    /// Whether an image booted for the first time after an update was kept or rolled back
+    OtaVerdict {
+        valid: bool,
+        partition: String,
+        uptime_ms: i64,
+        milestones: Vec<lifecycle::Progress>,
+    },
+    /// The TDS probe fitted to a reference solution
+    TdsCalibration {
+        reference: f32,
+        /// Uncalibrated reading in the solution
+        measured: f32,
+        k_factor: f32,
+    },
+    /// A calibration exported from another unit taken over, `forced` when made with another probe
+    CalibrationImported {
+        k_factor: f32,
+        temperature_probe: Option<String>,
+        forced: bool,
+    },
+    /// The TDS probe swapped for another, its calibration cleared
+    TdsProbeReplaced {
+        previous_k_factor: f32,
+    },
+    /// A temperature probe other than the one calibrated with found on the bus
+    ProbeChanged {
+        pinned: String,
+        found: String,
+    },
+    /// An image written through `/ota`, booted next
+    OtaInstalled {
+        partition: String,
+        bytes: usize,
+    },
+    /// The configuration restored from before its latest revision, `automatic` after repeated invalid
+    /// settings on probation
+    ConfigRollback {
+        revision: u32,
+        automatic: bool,
+        restored: Vec<String>,
+    },
+    /// A reading leaving its limits, or `alarm` absent, coming back inside them
+    Alert {
+        sensor: String,
+        alarm: Option<alerts::Level>,
+        value: Option<f32>,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Boot { .. } => "boot",
+            Self::WifiReconnect => "wifi_reconnect",
+            Self::WaterChange { .. } => "water_change",
+            Self::StateDiscarded { .. } => "state_discarded",
+            Self::BusDegradation { .. } => "bus_degradation",
+            Self::FactoryTest { .. } => "factory_test",
+            Self::OtaVerdict { .. } => "ota_verdict",
+            Self::OtaInstalled { .. } => "ota_installed",
+            Self::TdsCalibration { .. } => "tds_calibration",
+            Self::TdsProbeReplaced { .. } => "tds_probe_replaced",
+            Self::CalibrationImported { .. } => "calibration_imported",
+            Self::ProbeChanged { .. } => "probe_changed",
+            Self::ConfigRollback { .. } => "config_rollback",
+            Self::Alert { .. } => "alert",
+        }
+    }
+
+    /// Whether the event is written to flash right away. Alerts, reconnects and bus trouble can repeat
+    /// every few seconds while something is wrong, so they wait for the next batch instead, and a crash
+    /// loses at most the last `BATCH_INTERVAL` of them.
+    fn durable(&self) -> bool {
+        !matches!(
+            self,
+            Self::WifiReconnect | Self::BusDegradation { .. } | Self::Alert { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// Monotonically increasing across reboots, so pollers can resume after the last one they saw
+    pub seq: u32,
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    next_seq: u32,
+    entries: VecDeque<Entry>,
+}
+
+static JOURNAL: Mutex<Journal> = Mutex::new(Journal {
+    next_seq: 0,
+    entries: VecDeque::new(),
+});
+// Whether entries were recorded since the journal was last queued for writing
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn init() -> anyhow::Result<()> {
+    match nvs::load_state::<Journal>(NVS_KEY, VERSION) {
+        Ok(Some(journal)) => *JOURNAL.lock().unwrap() = journal,
+        Ok(None) => {}
+        Err(e) => warn!("Discarding unreadable journal: {e:?}"),
+    }
+
+    Ok(())
+}
+
+/// Appends an event, evicting the oldest entry when the journal is full. Only durable events are written
+/// out immediately, along with any batched ones before them.
+pub(crate) fn record(event: Event) {
+    let mut journal = JOURNAL.lock().unwrap();
+    let durable = event.durable();
+
+    let seq = journal.next_seq;
+    journal.next_seq = seq.wrapping_add(1);
+    if journal.entries.len() >= CAPACITY {
+        journal.entries.pop_front();
+    }
+    journal.entries.push_back(Entry {
+        seq,
+        timestamp: Utc::now().timestamp_millis(),
+        event,
+    });
+
+    if durable {
+        save(&journal);
+    } else {
+        UNSAVED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn save(journal: &Journal) {
+    UNSAVED.store(false, Ordering::Relaxed);
+    match nvs::encode_state(VERSION, journal) {
+        Ok(blob) => nvs::persist(NVS_KEY, Some(blob)),
+        Err(e) => error!("Failed to persist journal: {e:?}"),
+    }
+}
+
+/// Queues batched entries for writing, for callers about to reboot.
+pub(crate) fn sync() {
+    if UNSAVED.load(Ordering::Relaxed) {
+        save(&JOURNAL.lock().unwrap());
+    }
+}
+
+/// Writes batched entries out every `BATCH_INTERVAL`.
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    let mut interval = interval(BATCH_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        sync();
+    }
+}
+
+/// Returns entries in ascending sequence order, optionally filtered by type and starting after `since`.
+pub(crate) fn query(kind: Option<&str>, since: Option<u32>, limit: usize) -> Vec<Entry> {
+    let journal = JOURNAL.lock().unwrap();
+
+    journal
+        .entries
+        .iter()
+        .filter(|e| since.is_none_or(|since| e.seq > since))
+        .filter(|e| kind.is_none_or(|kind| e.event.kind() == kind))
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn boots_and_their_recovered_measurements_name_their_boot() {
+        let previous = system::BootId(0x5f3e_9a01);
+        let event = Event::Boot {
+            boot_id: Some(system::boot_id()),
+            reset_reason: "panic".to_owned(),
+            recovered: vec![recovery::Record {
+                boot_id: previous,
+                seq: 42,
+                timestamp: 1_700_000_000_000,
+                temperature: 25.5,
+                tds: 300.0,
+            }],
+        };
+
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["type"], "boot");
+        assert_eq!(value["boot_id"], system::boot_id().to_string());
+        assert_eq!(value["recovered"][0]["boot_id"], "5f3e9a01");
+    }
+
+    #[test]
+    fn boots_journaled_before_boot_ids_still_load() {
+        let event: Event = serde_json::from_value(json!({ "type": "boot", "reset_reason": "power_on" })).unwrap();
+        assert!(matches!(event, Event::Boot { boot_id: None, .. }));
+    }
+
+    #[test]
+    fn only_durable_events_are_written_right_away() {
+        record(Event::Alert {
+            sensor: "tds".to_owned(),
+            alarm: None,
+            value: Some(120.0),
+        });
+        record(Event::WifiReconnect);
+        assert!(UNSAVED.load(Ordering::Relaxed));
+
+        // Takes the batched entries along
+        record(Event::OtaInstalled {
+            partition: "ota_1".to_owned(),
+            bytes: 1024,
+        });
+        assert!(!UNSAVED.load(Ordering::Relaxed));
+
+        record(Event::WifiReconnect);
+        sync();
+        assert!(!UNSAVED.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::sync::OnceLock;
+
+use log::warn;
+
+use crate::{nvs, registry, system};
+
+const MAX_LEN: usize = 32;
+
+/// User-assigned names telling identical devices and their metrics apart in outputs.
+#[derive(Debug, Default)]
+struct Labels {
+    device: Option<String>,
+    temperature: Option<String>,
+    tds: Option<String>,
+}
+
+static LABELS: OnceLock<Labels> = OnceLock::new();
+
+/// Reads `device.name`, `label.temperature` and `label.tds`. Invalid names are ignored with a warning.
+pub(crate) fn init() -> anyhow::Result<()> {
+    let labels = Labels {
+        device: load("device.name")?,
+        temperature: load("label.temperature")?,
+        tds: load("label.tds")?,
+    };
+    LABELS.get_or_init(|| labels);
+
+    Ok(())
+}
+
+/// The user-assigned device name, or the device ID when none is set.
+pub(crate) fn device_name() -> &'static str {
+    LABELS
+        .get()
+        .and_then(|labels| labels.device.as_deref())
+        .unwrap_or_else(system::device_id)
+}
+
+/// The user-assigned name of a metric, or its canonical name when none is set.
+pub(crate) fn metric(metric: &registry::Metric) -> &'static str {
+    let labels = LABELS.get();
+    let label = if metric.name == registry::TEMPERATURE.name {
+        labels.and_then(|labels| labels.temperature.as_deref())
+    } else if metric.name == registry::TDS.name {
+        labels.and_then(|labels| labels.tds.as_deref())
+    } else {
+        None
+    };
+
+    label.unwrap_or(metric.name)
+}
+
+fn load(name: &str) -> anyhow::Result<Option<String>> {
+    let Some(value) = nvs::get_opt(name)? else {
+        return Ok(None);
+    };
+
+    let value = value.trim();
+    if value.is_empty() || value.chars().count() > MAX_LEN || value.chars().any(char::is_control) {
+        warn!("Ignoring {name}, expected 1 to {MAX_LEN} printable characters");
+        return Ok(None);
+    }
+
+    Ok(Some(value.to_owned()))
+}
@@ -0,0 +1,220 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{fmt::Write, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+// Upper bounds of the buckets in milliseconds, doubling from 1 ms; slower samples overflow into +Inf
+const BOUNDS_MS: [u32; 12] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Latency distribution over fixed exponential buckets, cheap enough to record on every cycle.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Histogram {
+    // Non-cumulative, the last one counting samples above every bound
+    counts: [u32; BOUNDS_MS.len() + 1],
+    sum_us: u64,
+    count: u32,
+}
+
+/// Compact view of a histogram for `/diagnostics`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Summary {
+    pub count: u32,
+    pub mean_ms: Option<f32>,
+    /// Upper bound of the bucket holding the 99th percentile, `None` when it overflowed.
+    pub p99_ms: Option<u32>,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: [0; BOUNDS_MS.len() + 1],
+            sum_us: 0,
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        // A sample exactly on a bound belongs to that bucket, as Prometheus' `le` says
+        let bucket = BOUNDS_MS.iter().take_while(|&&bound| us > bound as u64 * 1000).count();
+
+        self.counts[bucket] = self.counts[bucket].saturating_add(1);
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.count = self.count.saturating_add(1);
+    }
+
+    pub fn summary(&self) -> Summary {
+        let rank = (self.count as u64 * 99).div_ceil(100);
+        let mut seen = 0;
+        let p99 = self.counts.iter().position(|&count| {
+            seen += count as u64;
+            seen >= rank
+        });
+
+        Summary {
+            count: self.count,
+            mean_ms: (self.count > 0).then(|| self.sum_us as f32 / self.count as f32 / 1000.0),
+            p99_ms: p99.filter(|_| self.count > 0).and_then(|i| BOUNDS_MS.get(i).copied()),
+        }
+    }
+
+    /// Appends the `_bucket`, `_sum` and `_count` series, with `labels` (e.g. `path="/x"`) on each.
+    pub fn write_prometheus(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+
+        let mut cumulative = 0;
+        for (bound, count) in BOUNDS_MS.iter().zip(self.counts) {
+            cumulative += count;
+            let le = *bound as f32 / 1000.0;
+            let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}", self.count);
+
+        let braces = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{braces} {}", self.sum_us as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{name}_count{braces} {}", self.count);
+    }
+}
+
+/// A histogram of one operation, shared between the task timing it and the endpoints reporting it.
+pub(crate) struct Latency {
+    /// Prometheus metric name, also the key in `/diagnostics`.
+    pub name: &'static str,
+    pub help: &'static str,
+    histogram: Mutex<Histogram>,
+}
+
+impl Latency {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            histogram: Mutex::new(Histogram::new()),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        self.histogram.lock().unwrap().record(elapsed);
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        *self.histogram.lock().unwrap()
+    }
+}
+
+pub(crate) static MEASUREMENT_CYCLE: Latency = Latency::new(
+    "cobitis_measurement_cycle_seconds",
+    "Duration of a full measurement cycle",
+);
+pub(crate) static DS18B20_READ: Latency = Latency::new(
+    "cobitis_ds18b20_read_seconds",
+    "Duration of a DS18B20 conversion and read",
+);
+pub(crate) static ADC_READ: Latency = Latency::new("cobitis_adc_read_seconds", "Duration of a TDS read from the ADC");
+pub(crate) static DISPLAY_DRAW: Latency =
+    Latency::new("cobitis_display_draw_seconds", "Duration of a display draw and flush");
+
+pub(crate) static LATENCIES: [&Latency; 4] = [&MEASUREMENT_CYCLE, &DS18B20_READ, &ADC_READ, &DISPLAY_DRAW];
+
+/// Prometheus name of the per-endpoint handler histograms, labeled by path.
+pub(crate) const HANDLER_NAME: &str = "cobitis_http_handler_seconds";
+pub(crate) const HANDLER_HELP: &str = "Duration of heavy HTTP handlers";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(samples: &[Duration]) -> Histogram {
+        let mut histogram = Histogram::new();
+        for &sample in samples {
+            histogram.record(sample);
+        }
+        histogram
+    }
+
+    #[test]
+    fn samples_on_a_bound_belong_to_its_bucket() {
+        let ms = Duration::from_millis;
+        let us = Duration::from_micros;
+
+        for (sample, bucket) in [
+            (Duration::ZERO, 0),
+            (ms(1), 0),
+            (ms(1) + us(1), 1),
+            (ms(2), 1),
+            (ms(3), 2),
+            (ms(1024) + us(1), 11),
+            (ms(2048), 11),
+        ] {
+            let histogram = histogram(&[sample]);
+            assert_eq!(histogram.counts[bucket], 1, "{sample:?}");
+            assert_eq!(histogram.counts.iter().sum::<u32>(), 1);
+        }
+    }
+
+    #[test]
+    fn samples_above_every_bound_overflow() {
+        let histogram = histogram(&[Duration::from_millis(2049), Duration::from_secs(60), Duration::MAX]);
+
+        assert_eq!(histogram.counts[BOUNDS_MS.len()], 3);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.sum_us, u64::MAX);
+        assert_eq!(histogram.summary().p99_ms, None);
+    }
+
+    #[test]
+    fn summary_reports_the_bucket_of_the_99th_percentile() {
+        let mut samples = vec![Duration::from_millis(1); 99];
+        samples.push(Duration::from_millis(100));
+        let summary = histogram(&samples).summary();
+
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.p99_ms, Some(1));
+        assert_eq!(summary.mean_ms, Some(1.99));
+
+        samples.push(Duration::from_millis(100));
+        assert_eq!(histogram(&samples).summary().p99_ms, Some(128));
+    }
+
+    #[test]
+    fn empty_summary_has_no_statistics() {
+        let summary = Histogram::new().summary();
+
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.mean_ms, None);
+        assert_eq!(summary.p99_ms, None);
+    }
+
+    #[test]
+    fn prometheus_buckets_are_cumulative_with_overflow_in_inf() {
+        let histogram = histogram(&[
+            Duration::from_millis(1),
+            Duration::from_millis(3),
+            Duration::from_secs(5),
+        ]);
+        let mut out = String::new();
+        histogram.write_prometheus(&mut out, "x_seconds", "path=\"/history\"");
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines.len(), BOUNDS_MS.len() + 3);
+        assert_eq!(lines[0], "x_seconds_bucket{path=\"/history\",le=\"0.001\"} 1");
+        assert_eq!(lines[2], "x_seconds_bucket{path=\"/history\",le=\"0.004\"} 2");
+        assert_eq!(lines[11], "x_seconds_bucket{path=\"/history\",le=\"2.048\"} 2");
+        assert_eq!(lines[12], "x_seconds_bucket{path=\"/history\",le=\"+Inf\"} 3");
+        assert_eq!(lines[13], "x_seconds_sum{path=\"/history\"} 5.004");
+        assert_eq!(lines[14], "x_seconds_count{path=\"/history\"} 3");
+
+        let mut out = String::new();
+        histogram.write_prometheus(&mut out, "x_seconds", "");
+        assert!(out.starts_with("x_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.ends_with("x_seconds_count 3\n"));
+    }
+}
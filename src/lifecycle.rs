@@ -0,0 +1,406 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::system;
+
+const HISTORY: usize = 20;
+
+/// Where the device is in its life, as rendered by the display and the API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum State {
+    Booting,
+    Connecting,
+    Running,
+    Throttled,
+    /// Serving the setup access point until the device is given WiFi credentials and reboots.
+    Setup,
+    /// A water change is in progress, the readings don't describe the tank.
+    Maintenance,
+    /// A firmware image is being written.
+    Ota,
+    /// About to restart, nothing leaves this state.
+    Rebooting,
+}
+
+impl State {
+    fn can_become(self, next: State) -> bool {
+        use State::*;
+
+        matches!(
+            (self, next),
+            (Booting, Connecting | Setup | Rebooting)
+                | (Connecting, Running | Throttled | Setup | Rebooting)
+                | (Running, Connecting | Throttled | Maintenance | Ota | Rebooting)
+                | (Throttled, Connecting | Running | Maintenance | Ota | Rebooting)
+                | (Setup, Rebooting)
+                | (Maintenance | Ota, Connecting | Running | Throttled | Rebooting)
+        )
+    }
+}
+
+/// What a healthy boot gets done early on. An updated image is only kept once it reached all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Milestone {
+    /// A measurement was stored.
+    Measured,
+    /// A frame was flushed to the panel, or the device runs headless.
+    Displayed,
+    /// WiFi connected, or no network is configured.
+    Online,
+}
+
+impl Milestone {
+    const ALL: [Self; 3] = [Self::Measured, Self::Displayed, Self::Online];
+}
+
+/// When a milestone was first reached this boot, if it was.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Progress {
+    pub milestone: Milestone,
+    pub uptime_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Transition {
+    pub from: State,
+    pub to: State,
+    pub timestamp: i64,
+    pub uptime_ms: i64,
+}
+
+struct Lifecycle {
+    state: State,
+    /// Whether WiFi is up. Kept apart from the state, which is also left by maintenance and updates.
+    connected: bool,
+    /// Whether the die is too hot, with the same reasoning.
+    throttled: bool,
+    history: VecDeque<Transition>,
+    /// Uptime each milestone was reached at, in the order of `Milestone::ALL`.
+    reached: [Option<i64>; Milestone::ALL.len()],
+}
+
+impl Lifecycle {
+    const fn new() -> Self {
+        Self {
+            state: State::Booting,
+            connected: false,
+            throttled: false,
+            history: VecDeque::new(),
+            reached: [None; Milestone::ALL.len()],
+        }
+    }
+
+    /// The state the connection and the die temperature call for.
+    fn settled(&self) -> State {
+        match (self.connected, self.throttled) {
+            (false, _) => State::Connecting,
+            (true, true) => State::Throttled,
+            (true, false) => State::Running,
+        }
+    }
+
+    fn transition(&mut self, next: State) -> bool {
+        let from = self.state;
+        if from == next {
+            return true;
+        }
+        // Throttling is reported by the thermal worker whether or not WiFi is up
+        if !from.can_become(next) || (matches!(next, State::Running | State::Throttled) && !self.connected) {
+            warn!("Rejected lifecycle transition {from:?} -> {next:?}");
+            return false;
+        }
+
+        if self.history.len() >= HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(Transition {
+            from,
+            to: next,
+            timestamp: Utc::now().timestamp_millis(),
+            uptime_ms: system::uptime_ms(),
+        });
+        self.state = next;
+        info!("Lifecycle {from:?} -> {next:?}");
+
+        true
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        if connected {
+            self.reach(Milestone::Online);
+        }
+        if matches!(
+            self.state,
+            State::Booting | State::Connecting | State::Running | State::Throttled
+        ) {
+            self.transition(self.settled());
+        }
+    }
+
+    fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+        if matches!(self.state, State::Running | State::Throttled) {
+            self.transition(self.settled());
+        }
+    }
+
+    fn resume(&mut self) {
+        if matches!(self.state, State::Maintenance | State::Ota) {
+            self.transition(self.settled());
+        }
+    }
+
+    fn reach(&mut self, milestone: Milestone) {
+        if cfg!(feature = "fail-health") && milestone == Milestone::Online {
+            return;
+        }
+        let Some(index) = Milestone::ALL.iter().position(|&m| m == milestone) else {
+            return;
+        };
+        if self.reached[index].is_none() {
+            self.reached[index] = Some(system::uptime_ms());
+            info!("Lifecycle milestone {milestone:?} reached");
+        }
+    }
+}
+
+static LIFECYCLE: Mutex<Lifecycle> = Mutex::new(Lifecycle::new());
+
+pub(crate) fn state() -> State {
+    LIFECYCLE.lock().unwrap().state
+}
+
+/// Transitions so far, oldest first and bounded to the last 20.
+pub(crate) fn history() -> Vec<Transition> {
+    LIFECYCLE.lock().unwrap().history.iter().copied().collect()
+}
+
+/// Records reaching `milestone`, keeping the time it was first reached.
+pub(crate) fn reach(milestone: Milestone) {
+    LIFECYCLE.lock().unwrap().reach(milestone);
+}
+
+/// Every milestone and when it was reached.
+pub(crate) fn progress() -> Vec<Progress> {
+    let lifecycle = LIFECYCLE.lock().unwrap();
+
+    Milestone::ALL
+        .into_iter()
+        .zip(lifecycle.reached)
+        .map(|(milestone, uptime_ms)| Progress { milestone, uptime_ms })
+        .collect()
+}
+
+/// Whether every milestone has been reached, the definition of a healthy boot.
+pub(crate) fn healthy() -> bool {
+    LIFECYCLE.lock().unwrap().reached.iter().all(Option::is_some)
+}
+
+/// Moves to `next`. Transitions the state machine doesn't allow are logged and ignored, as are `Running`
+/// and `Throttled` while WiFi is down.
+pub(crate) fn transition(next: State) {
+    LIFECYCLE.lock().unwrap().transition(next);
+}
+
+/// Records WiFi going up or down, moving between `Connecting` and the running states unless the device
+/// is in setup, maintenance or an update. Coming up is what reaches `Milestone::Online`.
+pub(crate) fn set_connected(connected: bool) {
+    LIFECYCLE.lock().unwrap().set_connected(connected);
+}
+
+/// Records the die getting too hot or cooling down, which only changes the state while running.
+pub(crate) fn set_throttled(throttled: bool) {
+    LIFECYCLE.lock().unwrap().set_throttled(throttled);
+}
+
+/// Leaves maintenance or an update for whatever the connection and the temperature call for.
+pub(crate) fn resume() {
+    LIFECYCLE.lock().unwrap().resume();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [State; 8] = [
+        State::Booting,
+        State::Connecting,
+        State::Running,
+        State::Throttled,
+        State::Setup,
+        State::Maintenance,
+        State::Ota,
+        State::Rebooting,
+    ];
+
+    fn at(state: State, connected: bool) -> Lifecycle {
+        let mut lifecycle = Lifecycle::new();
+        lifecycle.state = state;
+        lifecycle.connected = connected;
+        lifecycle
+    }
+
+    fn online(lifecycle: &Lifecycle) -> bool {
+        let index = Milestone::ALL.iter().position(|&m| m == Milestone::Online).unwrap();
+        lifecycle.reached[index].is_some()
+    }
+
+    #[test]
+    fn every_allowed_transition_is_taken_and_recorded() {
+        let allowed = [
+            (State::Booting, State::Connecting),
+            (State::Booting, State::Setup),
+            (State::Booting, State::Rebooting),
+            (State::Connecting, State::Running),
+            (State::Connecting, State::Throttled),
+            (State::Connecting, State::Setup),
+            (State::Connecting, State::Rebooting),
+            (State::Running, State::Connecting),
+            (State::Running, State::Throttled),
+            (State::Running, State::Maintenance),
+            (State::Running, State::Ota),
+            (State::Running, State::Rebooting),
+            (State::Throttled, State::Connecting),
+            (State::Throttled, State::Running),
+            (State::Throttled, State::Maintenance),
+            (State::Throttled, State::Ota),
+            (State::Throttled, State::Rebooting),
+            (State::Setup, State::Rebooting),
+            (State::Maintenance, State::Connecting),
+            (State::Maintenance, State::Running),
+            (State::Maintenance, State::Throttled),
+            (State::Maintenance, State::Rebooting),
+            (State::Ota, State::Connecting),
+            (State::Ota, State::Running),
+            (State::Ota, State::Throttled),
+            (State::Ota, State::Rebooting),
+        ];
+
+        for from in ALL {
+            for to in ALL.into_iter().filter(|&to| to != from) {
+                assert_eq!(from.can_become(to), allowed.contains(&(from, to)), "{from:?} -> {to:?}");
+            }
+        }
+        for (from, to) in allowed {
+            let mut lifecycle = at(from, true);
+            assert!(lifecycle.transition(to), "{from:?} -> {to:?}");
+            assert_eq!(lifecycle.state, to);
+            let last = lifecycle.history.back().unwrap();
+            assert_eq!((last.from, last.to), (from, to));
+        }
+    }
+
+    #[test]
+    fn obvious_disallowed_transitions_are_rejected() {
+        for (from, to) in [
+            (State::Booting, State::Running),
+            (State::Booting, State::Ota),
+            (State::Setup, State::Running),
+            (State::Setup, State::Connecting),
+            (State::Connecting, State::Ota),
+            (State::Maintenance, State::Ota),
+            (State::Ota, State::Maintenance),
+            (State::Rebooting, State::Booting),
+            (State::Rebooting, State::Running),
+        ] {
+            let mut lifecycle = at(from, true);
+            assert!(!lifecycle.transition(to), "{from:?} -> {to:?}");
+            assert_eq!(lifecycle.state, from);
+            assert!(lifecycle.history.is_empty());
+        }
+    }
+
+    #[test]
+    fn nothing_leaves_rebooting() {
+        for to in ALL.into_iter().filter(|&to| to != State::Rebooting) {
+            assert!(!State::Rebooting.can_become(to), "{to:?}");
+        }
+    }
+
+    #[test]
+    fn running_and_throttled_need_a_connection() {
+        for to in [State::Running, State::Throttled] {
+            let mut lifecycle = at(State::Connecting, false);
+            assert!(!lifecycle.transition(to), "{to:?}");
+            assert_eq!(lifecycle.state, State::Connecting);
+        }
+    }
+
+    #[test]
+    fn throttling_while_connecting_neither_changes_state_nor_goes_online() {
+        let mut lifecycle = Lifecycle::new();
+        lifecycle.set_connected(false);
+        assert_eq!(lifecycle.state, State::Connecting);
+
+        lifecycle.set_throttled(true);
+        assert_eq!(lifecycle.state, State::Connecting);
+        lifecycle.set_throttled(false);
+        assert_eq!(lifecycle.state, State::Connecting);
+        assert!(!online(&lifecycle));
+
+        // Connecting while hot goes straight to throttled
+        lifecycle.set_throttled(true);
+        lifecycle.set_connected(true);
+        assert_eq!(lifecycle.state, State::Throttled);
+        assert!(online(&lifecycle));
+        lifecycle.set_throttled(false);
+        assert_eq!(lifecycle.state, State::Running);
+    }
+
+    #[test]
+    fn losing_the_connection_goes_back_to_connecting() {
+        let mut lifecycle = at(State::Throttled, true);
+        lifecycle.throttled = true;
+        lifecycle.set_connected(false);
+        assert_eq!(lifecycle.state, State::Connecting);
+    }
+
+    #[test]
+    fn maintenance_and_updates_outlast_connection_and_temperature_changes() {
+        for state in [State::Maintenance, State::Ota] {
+            let mut lifecycle = at(state, true);
+            lifecycle.set_connected(false);
+            lifecycle.set_throttled(true);
+            assert_eq!(lifecycle.state, state);
+
+            lifecycle.resume();
+            assert_eq!(lifecycle.state, State::Connecting);
+
+            let mut lifecycle = at(state, true);
+            lifecycle.set_throttled(true);
+            lifecycle.resume();
+            assert_eq!(lifecycle.state, State::Throttled);
+        }
+    }
+
+    #[test]
+    fn setup_is_left_only_by_rebooting() {
+        let mut lifecycle = at(State::Setup, false);
+        lifecycle.set_connected(true);
+        lifecycle.set_throttled(true);
+        lifecycle.resume();
+        assert_eq!(lifecycle.state, State::Setup);
+    }
+
+    #[test]
+    fn history_keeps_the_last_20_transitions() {
+        let mut lifecycle = at(State::Running, true);
+        for i in 0..25 {
+            lifecycle.set_throttled(i % 2 == 0);
+        }
+
+        assert_eq!(lifecycle.history.len(), HISTORY);
+        assert_eq!(lifecycle.history.back().unwrap().to, State::Throttled);
+    }
+}
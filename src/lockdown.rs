@@ -0,0 +1,54 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+use crate::nvs;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the device refuses every change from the network, as configured by the `system.lockdown` flag.
+///
+/// `/config` refuses the flag like any other local-only setting, so it can only be changed by flashing the
+/// NVS partition over the serial port.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn init() -> anyhow::Result<()> {
+    if parse(nvs::get_opt("system.lockdown")?.as_deref()) {
+        ENABLED.store(true, Ordering::Relaxed);
+        warn!("Lockdown is active, changes over the network are disabled");
+    }
+
+    Ok(())
+}
+
+// An unreadable flag locks down, falling back to open would hand the device to anyone who garbled it
+fn parse(value: Option<&str>) -> bool {
+    match value {
+        Some(v) => nvs::parse_flag(v).unwrap_or_else(|| {
+            warn!("Invalid system.lockdown {v:?}, locking down");
+            true
+        }),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_missing_or_off_flag_leaves_the_device_open() {
+        assert!(!parse(None));
+        assert!(!parse(Some("off")));
+        assert!(parse(Some("on")));
+        assert!(parse(Some("")));
+        assert!(parse(Some("no")));
+    }
+}
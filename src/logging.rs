@@ -0,0 +1,229 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use esp_idf_svc::log::EspLogger;
+use log::{Level, Log, Metadata, Record, info};
+
+use crate::{integrations, network, nvs, system};
+
+const DEFAULT_SYSLOG_PORT: u16 = 514;
+const FACILITY_LOCAL0: u8 = 16;
+// Structured data under the enterprise number RFC 5424 reserves for examples, there is no registered one
+const SD_ID: &str = "cobitis@32473";
+
+/// Logs to the console through `EspLogger` and forwards records to syslog once configured.
+struct Logger {
+    console: EspLogger,
+}
+
+static LOGGER: Logger = Logger {
+    console: EspLogger::new(),
+};
+
+struct Syslog {
+    socket: UdpSocket,
+    target: SocketAddr,
+    min_level: Level,
+    hostname: String,
+}
+
+static SYSLOG: OnceLock<Syslog> = OnceLock::new();
+static SENT: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static LAST_SUCCESS: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+static LAST_FAILURE: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+
+        if let Some(syslog) = SYSLOG.get() {
+            syslog.forward(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+// RFC 5424: <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+fn format_message(
+    level: Level,
+    now: DateTime<Utc>,
+    hostname: &str,
+    app_name: &str,
+    args: &std::fmt::Arguments,
+) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let timestamp = now.to_rfc3339_opts(SecondsFormat::Millis, true);
+
+    format!(
+        "<{}>1 {timestamp} {hostname} {app_name} - - [{SD_ID} boot_id=\"{}\"] {}",
+        FACILITY_LOCAL0 * 8 + severity,
+        system::boot_id(),
+        args
+    )
+}
+
+impl Syslog {
+    fn forward(&self, record: &Record) {
+        // Forwarding pauses while WiFi is down, nothing is buffered for later
+        if record.level() > self.min_level || !network::is_connected() {
+            return;
+        }
+
+        // Failures are counted but not reported, logging them here would recurse
+        let _ = self.send(record.level(), record.module_path().unwrap_or("-"), record.args());
+    }
+
+    fn send(&self, level: Level, app_name: &str, args: &std::fmt::Arguments) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let message = format_message(level, now, &self.hostname, app_name, args);
+
+        // The socket is non-blocking, so a full send buffer drops the record instead of stalling the caller
+        match self.socket.send_to(message.as_bytes(), self.target) {
+            Ok(_) => {
+                SENT.fetch_add(1, Ordering::Relaxed);
+                *LAST_SUCCESS.lock().unwrap() = Some(now);
+                Ok(())
+            }
+            Err(e) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                *LAST_FAILURE.lock().unwrap() = Some(now);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Installs the logger. Must be called before anything logs.
+pub(crate) fn init() {
+    log::set_logger(&LOGGER).map(|()| LOGGER.console.initialize()).unwrap();
+}
+
+/// Parses `net.syslog.port`.
+pub(crate) fn parse_syslog_port(v: &str) -> anyhow::Result<u16> {
+    match v.parse::<u16>()? {
+        0 => Err(anyhow!("Syslog port must not be 0")),
+        port => Ok(port),
+    }
+}
+
+/// Parses `net.syslog.level`, the least severe level forwarded, e.g. `warn`.
+pub(crate) fn parse_syslog_level(v: &str) -> anyhow::Result<Level> {
+    v.parse()
+        .map_err(|_| anyhow!("Syslog level must be error, warn, info, debug or trace: {v}"))
+}
+
+/// Starts forwarding to syslog if `net.syslog.host` is configured and `net.syslog.enabled` is not off.
+/// Needs the network to resolve the host.
+pub(crate) fn init_syslog() -> anyhow::Result<()> {
+    let Some(host) = nvs::get_opt("net.syslog.host")? else {
+        return Ok(());
+    };
+    if !nvs::get_flag_or("net.syslog.enabled", true)? {
+        info!("Syslog forwarding to {host} is disabled");
+        return Ok(());
+    }
+    let port = nvs::get_parsed_or("net.syslog.port", DEFAULT_SYSLOG_PORT, parse_syslog_port)?;
+    let min_level = nvs::get_parsed_or("net.syslog.level", Level::Info, parse_syslog_level)?;
+
+    let target = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Failed to resolve {host}"))?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+
+    let syslog = Syslog {
+        socket,
+        target,
+        min_level,
+        hostname: system::device_id().to_owned(),
+    };
+    SYSLOG.set(syslog).map_err(|_| anyhow!("Syslog already initialized"))?;
+    info!("Forwarding logs to syslog at {target}");
+
+    Ok(())
+}
+
+/// Number of records dropped because the socket would have blocked.
+pub(crate) fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Delivery state of the syslog forwarder for `/integrations`.
+pub(crate) fn status() -> integrations::Status {
+    integrations::Status {
+        enabled: SYSLOG.get().is_some(),
+        endpoint: SYSLOG.get().map(|syslog| syslog.target.to_string()),
+        sent: SENT.load(Ordering::Relaxed),
+        failed: dropped(),
+        last_success: *LAST_SUCCESS.lock().unwrap(),
+        last_failure: *LAST_FAILURE.lock().unwrap(),
+    }
+}
+
+/// Sends one test record regardless of the configured level.
+pub(crate) fn test() -> anyhow::Result<()> {
+    let syslog = SYSLOG.get().ok_or(anyhow!("Syslog is not enabled"))?;
+    if !network::is_connected() {
+        return Err(anyhow!("WiFi is not connected"));
+    }
+
+    syslog.send(
+        Level::Info,
+        module_path!(),
+        &format_args!("Test message from {}", system::device_id()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn messages_carry_the_boot_id_as_structured_data() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        let message = format_message(
+            Level::Warn,
+            now,
+            "cobitis-a1b2c3",
+            "cobitis::network",
+            &format_args!("Lost WiFi"),
+        );
+
+        assert_eq!(
+            message,
+            format!(
+                "<132>1 2025-01-02T03:04:05.000Z cobitis-a1b2c3 cobitis::network - - \
+                 [cobitis@32473 boot_id=\"{}\"] Lost WiFi",
+                system::boot_id()
+            )
+        );
+    }
+}
@@ -8,51 +8,158 @@ use std::cell::RefCell;
 use embedded_hal_bus::i2c as i2c_bus;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::{gpio::PinDriver, i2c, prelude::*},
+    hal::{
+        gpio::{AnyIOPin, AnyInputPin, PinDriver},
+        i2c,
+        prelude::*,
+    },
     nvs::EspDefaultNvsPartition,
 };
+use log::error;
 use tokio::select;
 
+mod adc;
+mod alerts;
+mod assistant;
+mod board;
+mod breaker;
+mod bus;
+mod calibration;
+mod captive;
+mod chunks;
+mod command;
+mod compact;
+mod compensation;
 mod display;
+mod factory;
+mod flatjson;
+mod http;
+mod incidents;
+mod integrations;
+mod journal;
+mod labels;
+mod latency;
+mod lifecycle;
+mod lockdown;
+mod logging;
+mod manifest;
 mod measurements;
+#[cfg(test)]
+mod mock;
+mod mqtt;
 mod network;
 mod nvs;
+mod ota;
+mod probes;
+mod push;
+mod recovery;
+mod registry;
+mod retry;
+mod revision;
+mod simulation;
+mod site;
+mod system;
+mod template;
+mod thermal;
+mod troubleshoot;
+
+const RECOVERED_IN_JOURNAL: usize = 4;
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: mock::CountingAllocator = mock::CountingAllocator;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize ESP32 and its peripherals
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    logging::init();
 
     let peripherals = Box::new(Peripherals::take()?);
     let event_loop = Box::new(EspSystemEventLoop::take()?);
     let partition = Box::new(EspDefaultNvsPartition::take()?);
     nvs::init(*partition)?;
+    let pins = board::init();
+    labels::init()?;
+    site::init()?;
+
+    // Announce the firmware and its wiring early, for provisioning jigs scraping the console
+    let reset_reason = system::reset_reason();
+    system::print_boot_banner(reset_reason);
+    journal::init()?;
+    lockdown::init()?;
+    revision::init()?;
+
+    // Attach the sensor context leading up to a crash to the boot record
+    let recovered = recovery::init(reset_reason);
+    journal::record(journal::Event::Boot {
+        boot_id: Some(system::boot_id()),
+        reset_reason: reset_reason.to_owned(),
+        recovered: recovered[recovered.len().saturating_sub(RECOVERED_IN_JOURNAL)..].to_vec(),
+    });
 
-    let one_wire_pin = Box::new(PinDriver::input_output(peripherals.pins.gpio5)?);
+    // The pins come from NVS, nothing else takes them out of `peripherals.pins`
+    let one_wire_pin = Box::new(PinDriver::input_output(unsafe { AnyIOPin::new(pins.onewire) })?);
+    let adc_ready = pins
+        .adc_ready
+        .map(|pin| PinDriver::input(unsafe { AnyInputPin::new(pin) }))
+        .transpose()?;
     let i2c = Box::new(i2c::I2cDriver::new(
         peripherals.i2c0,
-        peripherals.pins.gpio6,
-        peripherals.pins.gpio7,
+        unsafe { AnyIOPin::new(pins.sda) },
+        unsafe { AnyIOPin::new(pins.scl) },
         &i2c::config::Config::new()
             .baudrate(400.kHz().into())
             .scl_enable_pullup(true)
             .sda_enable_pullup(true),
     )?);
     let i2c = Box::new(RefCell::new(*i2c));
-    let i2c_display = Box::new(i2c_bus::RefCellDevice::new(&i2c));
-    let i2c_adc = Box::new(i2c_bus::RefCellDevice::new(&i2c));
+    let i2c_display = Box::new(bus::Profiled::new("display", i2c_bus::RefCellDevice::new(&i2c)));
+    let i2c_adc = Box::new(bus::Profiled::new("adc", i2c_bus::RefCellDevice::new(&i2c)));
 
     // Start workers
     let mut display_ctx = display::init(*i2c_display)?;
-    display::greet(&mut display_ctx).await?;
+    if let Some(display_ctx) = display_ctx.as_mut() {
+        display::greet(display_ctx).await?;
+    }
 
     let mut network_ctx = network::init(peripherals.modem, *event_loop)?;
-    let mut measurements_ctx = measurements::init(*one_wire_pin, *i2c_adc)?;
+    if let Err(e) = logging::init_syslog() {
+        error!("Failed to start syslog forwarding: {e:?}");
+    }
+    let mut mqtt_ctx = mqtt::init().unwrap_or_else(|e| {
+        error!("Failed to start MQTT publishing: {e:?}");
+        None
+    });
+    if let Err(e) = push::init() {
+        error!("Failed to start pushing readings: {e:?}");
+    }
+    let _http_ctx = http::init()?;
+    assistant::init()?;
+    probes::init()?;
+    let mut measurements_ctx = measurements::init(*one_wire_pin, *i2c_adc, adc_ready)?;
+    if let Err(e) = alerts::init() {
+        error!("Failed to load alert limits: {e:?}");
+    }
+    incidents::init();
+    let mut thermal_ctx = thermal::init()?;
+    let mut bus_ctx = bus::init()?;
+    let mut ota_ctx = ota::init()?;
 
     select! {
-        result = display::worker(&mut display_ctx) => result,
+        result = display::worker(display_ctx.as_mut()) => result,
         result = network::worker(&mut network_ctx) => result,
+        result = mqtt::worker(mqtt_ctx.as_mut()) => result,
         result = measurements::worker(&mut measurements_ctx) => result,
+        result = alerts::worker() => result,
+        result = incidents::worker() => result,
+        result = thermal::worker(&mut thermal_ctx) => result,
+        result = bus::worker(&mut bus_ctx) => result,
+        result = nvs::worker() => result,
+        result = journal::worker() => result,
+        result = revision::worker() => result,
+        result = factory::worker() => result,
+        result = troubleshoot::worker() => result,
+        result = ota::worker(ota_ctx.as_mut()) => result,
     }
 }
@@ -0,0 +1,218 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::ffi::CStr;
+
+use esp_idf_svc::sys;
+use serde::Serialize;
+
+use crate::{adc, display, integrations, measurements, simulation, site, system};
+
+// Bumped whenever a field is renamed or removed, adding fields keeps the version
+const MANIFEST_VERSION: u32 = 1;
+
+/// Everything a fleet script needs to identify a unit, served at `/manifest`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Manifest {
+    pub manifest_version: u32,
+    #[serde(flatten)]
+    pub version: system::Version,
+    pub app: App,
+    pub partitions: Vec<Partition>,
+    pub features: Vec<&'static str>,
+    pub integrations: Vec<&'static str>,
+    pub sensors: Sensors,
+    pub site: Site,
+}
+
+/// Fields of the ESP-IDF app descriptor not already covered by `system::Version`.
+#[derive(Debug, Serialize)]
+pub(crate) struct App {
+    pub build_date: String,
+    pub build_time: String,
+    pub idf_version: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Partition {
+    pub label: String,
+    #[serde(rename = "type")]
+    pub kind: u32,
+    pub subtype: u32,
+    pub address: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Sensors {
+    /// ROM code of the DS18B20, absent in simulation or before it is found.
+    pub ds18b20: Option<String>,
+    pub adc_address: u8,
+    pub display: &'static str,
+    /// I2C address the display answered on, absent when running headless.
+    pub display_address: Option<u8>,
+    pub simulated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Site {
+    #[serde(flatten)]
+    pub config: site::Site,
+    /// Pressure in kPa that conversions use, fixed or derived from the altitude.
+    pub effective_pressure: f32,
+}
+
+impl Manifest {
+    pub fn current() -> Self {
+        Self {
+            manifest_version: MANIFEST_VERSION,
+            version: system::Version::current(),
+            app: app(),
+            partitions: partitions(),
+            features: features(),
+            integrations: integrations::INTEGRATIONS.iter().map(|i| i.name).collect(),
+            sensors: Sensors {
+                ds18b20: measurements::probe_address().map(|address| format!("{address:016x}")),
+                adc_address: adc::ADDRESS,
+                display: display::DRIVER,
+                display_address: display::address(),
+                simulated: simulation::enabled(),
+            },
+            site: Site {
+                config: site::get(),
+                effective_pressure: site::get().pressure(),
+            },
+        }
+    }
+}
+
+fn app() -> App {
+    let text = |field: &[std::ffi::c_char]| unsafe { CStr::from_ptr(field.as_ptr()) }.to_string_lossy().into_owned();
+
+    let desc = unsafe { &*sys::esp_app_get_description() };
+    App {
+        build_date: text(&desc.date),
+        build_time: text(&desc.time),
+        idf_version: text(&desc.idf_ver),
+    }
+}
+
+fn partitions() -> Vec<Partition> {
+    let mut partitions = vec![];
+
+    unsafe {
+        let mut it = sys::esp_partition_find(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+            sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            std::ptr::null(),
+        );
+        while !it.is_null() {
+            let partition = &*sys::esp_partition_get(it);
+            partitions.push(Partition {
+                label: CStr::from_ptr(partition.label.as_ptr()).to_string_lossy().into_owned(),
+                kind: partition.type_ as u32,
+                subtype: partition.subtype as u32,
+                address: partition.address,
+                size: partition.size,
+            });
+            it = sys::esp_partition_next(it);
+        }
+        // esp_partition_next releases the iterator once it runs off the end
+    }
+
+    partitions
+}
+
+fn features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "experimental") {
+        features.push("experimental");
+    }
+    if cfg!(feature = "fail-health") {
+        features.push("fail-health");
+    }
+
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::board;
+
+    // Fleet scripts parse this, a change here that isn't only an added field needs MANIFEST_VERSION bumped
+    #[test]
+    fn schema_is_pinned() {
+        let manifest = Manifest {
+            manifest_version: MANIFEST_VERSION,
+            version: system::Version {
+                device_id: "a1b2c3",
+                device_name: "cobitis-a1b2c3",
+                version: "0.1.0",
+                git_hash: "0123abc",
+                partition: "ota_0".to_owned(),
+                pins: Some(board::Pins {
+                    sda: 5,
+                    scl: 6,
+                    onewire: 4,
+                    adc_ready: None,
+                }),
+            },
+            app: App {
+                build_date: "Oct 16 2026".to_owned(),
+                build_time: "12:00:00".to_owned(),
+                idf_version: "v5.3.3".to_owned(),
+            },
+            partitions: vec![Partition {
+                label: "nvs".to_owned(),
+                kind: 1,
+                subtype: 2,
+                address: 0x9000,
+                size: 0x6000,
+            }],
+            features: vec!["experimental"],
+            integrations: vec!["syslog", "push"],
+            sensors: Sensors {
+                ds18b20: Some("28ff0123456789ab".to_owned()),
+                adc_address: 0x48,
+                display: "sh1106",
+                display_address: None,
+                simulated: false,
+            },
+            site: Site {
+                config: site::Site {
+                    altitude: 100.0,
+                    pressure: None,
+                },
+                effective_pressure: 100.0,
+            },
+        };
+
+        let expected = json!({
+            "manifest_version": 1,
+            "device_id": "a1b2c3",
+            "device_name": "cobitis-a1b2c3",
+            "version": "0.1.0",
+            "git_hash": "0123abc",
+            "partition": "ota_0",
+            "pins": { "sda": 5, "scl": 6, "onewire": 4, "adc_ready": null },
+            "app": { "build_date": "Oct 16 2026", "build_time": "12:00:00", "idf_version": "v5.3.3" },
+            "partitions": [{ "label": "nvs", "type": 1, "subtype": 2, "address": 36864, "size": 24576 }],
+            "features": ["experimental"],
+            "integrations": ["syslog", "push"],
+            "sensors": {
+                "ds18b20": "28ff0123456789ab",
+                "adc_address": 72,
+                "display": "sh1106",
+                "display_address": null,
+                "simulated": false,
+            },
+            "site": { "altitude": 100.0, "pressure": null, "effective_pressure": 100.0 },
+        });
+        assert_eq!(serde_json::to_value(&manifest).unwrap(), expected);
+    }
+}
@@ -3,185 +3,1669 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::Duration,
+};
 
-use ads1x1x::{Ads1x1x, FullScaleRange, TargetAddr, channel};
 use anyhow::anyhow;
 use chrono::Utc;
 use ds18b20::{Ds18b20, InputPin, OneWire, OutputPin, Resolution};
-use esp_idf_svc::hal::{
-    delay::{Delay, FreeRtos},
-    gpio::GpioError,
-    i2c::I2cError,
-};
-use log::error;
+use esp_idf_svc::hal::{delay::Delay, gpio::GpioError, i2c::I2cError};
+use log::{error, info, warn};
+use serde::{Serialize, Serializer};
 use tokio::{
-    sync::RwLock,
+    select,
+    sync::Notify,
     task,
-    time::{MissedTickBehavior, interval},
+    time::{Instant, MissedTickBehavior, interval, interval_at},
 };
 
-type Ads1115<I2C> = Ads1x1x<I2C, ads1x1x::ic::Ads1115, ads1x1x::ic::Resolution16Bit, ads1x1x::mode::OneShot>;
+use crate::{
+    adc::{self, Adc, Input},
+    alerts, assistant, calibration, compensation, journal, latency, lifecycle, nvs, probes, recovery, registry,
+    retry::retry_blocking,
+    simulation::{self, Simulator},
+    system,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Values {
     pub timestamp: i64,
     pub temperature: f32,
+    pub temperature_at: i64,
     pub tds: f32,
+    pub tds_at: i64,
+    pub supply_voltage: Option<f32>,
+    pub simulated: bool,
+    /// Monotonic time of the snapshot, usable for ordering when the wall clock is not set.
+    pub uptime_ms: i64,
+    pub clock_valid: bool,
+    /// Counts stored snapshots, so consumers can tell a new one from a re-read.
+    pub seq: u32,
+    pub compensation: Compensation,
+    /// Taken while the probe board is still settling after boot, shown live but kept out of history.
+    pub warming_up: bool,
+    /// Whether each sensor was switched on, the reading of one switched off being NaN.
+    pub temperature_enabled: bool,
+    pub tds_enabled: bool,
+}
+
+/// A snapshot trimmed down for `/history`, which holds an hour of them unless configured otherwise.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sample {
+    pub timestamp: i64,
+    /// NaN for a sensor switched off, like in `Values`.
+    pub temperature: f32,
+    pub tds: f32,
+    pub temperature_enabled: bool,
+    pub tds_enabled: bool,
+    pub simulated: bool,
+    /// Sequence number of the snapshot it was taken from, see [`RecordId`].
+    pub seq: u32,
+}
+
+/// Where `/history` picks up from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Since {
+    /// The oldest sample kept.
+    Start,
+    /// After a time, in ms since the epoch.
+    Timestamp(i64),
+    /// After the snapshot with this sequence number, taken during this boot.
+    Seq(u32),
+}
+
+impl Since {
+    fn includes(&self, sample: &Sample) -> bool {
+        match *self {
+            Self::Start => true,
+            Self::Timestamp(timestamp) => sample.timestamp > timestamp,
+            Self::Seq(seq) => sample.seq > seq,
+        }
+    }
+}
+
+/// Parses a timestamp, or a record ID as served in `/v1/measurements`. The sequence restarts every boot,
+/// so an ID from an earlier boot or another device starts from the oldest sample.
+impl FromStr for Since {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Ok(timestamp) = s.parse() {
+            return Ok(Self::Timestamp(timestamp));
+        }
+        let id = RecordId::parse(s).ok_or_else(|| anyhow!("Expected a timestamp or a record ID"))?;
+
+        if id.device_id == system::device_id() && id.boot_id == system::boot_id() {
+            Ok(Self::Seq(id.seq))
+        } else {
+            Ok(Self::Start)
+        }
+    }
+}
+
+/// Where a paged `/history` response left off, handed to the client as an opaque token to resume after.
+/// It names the boot, sequence numbers restart with every one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    boot_id: system::BootId,
+    seq: u32,
+}
+
+impl Cursor {
+    fn new(seq: u32) -> Self {
+        Self {
+            boot_id: system::boot_id(),
+            seq,
+        }
+    }
+
+    // Keyed with the device ID, so a cursor from another device fails it like an altered one
+    fn check(&self) -> u32 {
+        let mut bytes = Vec::with_capacity(8 + system::device_id().len());
+        bytes.extend_from_slice(&self.boot_id.0.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(system::device_id().as_bytes());
+        system::fnv1a(&bytes)
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{:08x}{:08x}", self.boot_id, self.seq, self.check())
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let field = |range: std::ops::Range<usize>| {
+            s.get(range)
+                .and_then(|v| u32::from_str_radix(v, 16).ok())
+                .ok_or_else(|| anyhow!("Not a cursor"))
+        };
+        if s.len() != 24 {
+            return Err(anyhow!("Not a cursor"));
+        }
+        let cursor = Self {
+            boot_id: system::BootId(field(0..8)?),
+            seq: field(8..16)?,
+        };
+        if field(16..24)? != cursor.check() {
+            return Err(anyhow!("Cursor was altered or is from another device"));
+        }
+
+        Ok(cursor)
+    }
+}
+
+/// Records of a paged `/history` response.
+#[derive(Debug, Clone)]
+pub(crate) struct Page {
+    pub samples: Vec<Sample>,
+    /// After the last record of the page, or where the request started when it had none.
+    pub next_cursor: Option<Cursor>,
+    /// Whether more records were already waiting past this page.
+    pub more: bool,
+}
+
+/// Up to `page_size` of `samples` after `after`, which must be from this boot and not have had anything
+/// after it evicted since. `evicted` is the sequence number of the latest sample evicted.
+fn page<'a>(
+    samples: impl IntoIterator<Item = &'a Sample>,
+    evicted: Option<u32>,
+    after: Option<Cursor>,
+    page_size: usize,
+) -> anyhow::Result<Page> {
+    if let Some(after) = after {
+        if after.boot_id != system::boot_id() {
+            return Err(anyhow!(
+                "Cursor is from an earlier boot, whose history is gone; start over"
+            ));
+        }
+        if evicted.is_some_and(|evicted| evicted > after.seq) {
+            return Err(anyhow!("Records after the cursor were evicted meanwhile; start over"));
+        }
+    }
+
+    let mut samples = samples
+        .into_iter()
+        .filter(|sample| after.is_none_or(|after| sample.seq > after.seq))
+        .take(page_size + 1)
+        .copied()
+        .collect::<Vec<_>>();
+    let more = samples.len() > page_size;
+    samples.truncate(page_size);
+    let next_cursor = samples.last().map(|sample| Cursor::new(sample.seq)).or(after);
+
+    Ok(Page {
+        samples,
+        next_cursor,
+        more,
+    })
+}
+
+/// Sensors that can be switched off, e.g. to silence a dead probe until its replacement arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sensor {
+    Temperature,
+    Tds,
+}
+
+impl Sensor {
+    pub const ALL: [Self; 2] = [Self::Temperature, Self::Tds];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Temperature => registry::TEMPERATURE.name,
+            Self::Tds => registry::TDS.name,
+        }
+    }
+
+    /// The on/off setting switching the sensor, e.g. `sensor.tds.enabled`.
+    pub fn setting(&self) -> &'static str {
+        match self {
+            Self::Temperature => "sensor.temperature.enabled",
+            Self::Tds => "sensor.tds.enabled",
+        }
+    }
+
+    fn switch(&self) -> &'static AtomicBool {
+        match self {
+            Self::Temperature => &TEMPERATURE_ENABLED,
+            Self::Tds => &TDS_ENABLED,
+        }
+    }
+}
+
+/// Identifies a snapshot across devices and reboots, for aggregators to deduplicate records received
+/// more than once.
+///
+/// Formatted as `<device_id>:<boot_id>:<seq>`, e.g. `cobitis-a1b2c3:5f3e9a01:42`. The sequence starts
+/// at 0 every boot and increases by one per stored snapshot, including warm-up ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordId<'a> {
+    pub device_id: &'a str,
+    pub boot_id: system::BootId,
+    pub seq: u32,
+}
+
+impl fmt::Display for RecordId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.device_id, self.boot_id, self.seq)
+    }
+}
+
+impl Serialize for RecordId<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'a> RecordId<'a> {
+    /// Reverses the `Display` format, e.g. for a client resuming `/history` after the last record it saw.
+    pub fn parse(s: &'a str) -> Option<Self> {
+        let mut fields = s.rsplitn(3, ':');
+        let seq = fields.next()?.parse().ok()?;
+        let boot_id = fields.next().filter(|v| v.len() == 8)?;
+        let boot_id = system::BootId(u32::from_str_radix(boot_id, 16).ok()?);
+        let device_id = fields.next().filter(|v| !v.is_empty())?;
+
+        Some(Self {
+            device_id,
+            boot_id,
+            seq,
+        })
+    }
+}
+
+/// What went into turning the TDS probe voltage into ppm, kept to diagnose TDS shifting silently.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Compensation {
+    pub temperature: f32,
+    pub measured_at: i64,
+    /// Whether the temperature read failed and the previous reading was used instead.
+    pub fallback: bool,
+    /// Per-probe factor from the last calibration, 1 for the plain Keyestudio curve.
+    pub k_factor: f32,
+    /// Probe voltage after the supply correction, unknown until the TDS was read with this compensation.
+    pub voltage: Option<f32>,
+}
+
+#[derive(Debug)]
+pub(crate) enum CalibrationError {
+    /// The latest reading can't be trusted for calibration, nothing was changed
+    Rejected(String),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for CalibrationError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Failed(e)
+    }
+}
+
+/// Result of calibrating the TDS probe in a reference solution.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Calibration {
+    pub reference: f32,
+    /// The reading as the plain Keyestudio curve has it.
+    pub measured: f32,
+    pub k_factor: f32,
+    pub previous_k_factor: f32,
 }
 
 pub(crate) struct Context<PIN, I2C>
+where
+    PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    source: Source<PIN, I2C>,
+    warmup: Duration,
+    /// Longest interval the adaptive cadence stretches to, `None` keeping the regular interval.
+    max_interval: Option<Duration>,
+    interval: Duration,
+    // When a reading last moved fast, holding the regular interval for a while
+    fast_at: Option<Instant>,
+}
+
+enum Source<PIN, I2C>
+where
+    PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    Hardware(Sensors<PIN, I2C>),
+    Simulated(Simulator),
+}
+
+struct Sensors<PIN, I2C>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
     one_wire: OneWire<PIN>,
-    ds18b20: Ds18b20,
-    ads1115: Ads1115<I2C>,
+    // Dropped while the temperature sensor is switched off, so switching it on searches the bus again
+    ds18b20: Option<Ds18b20>,
+    adc: Adc<I2C>,
+    // Likewise cleared while the TDS sensor is switched off, so switching it on probes the ADC again
+    adc_configured: bool,
+    vref_monitor: bool,
+}
+
+const RETRY_COUNT: u32 = 3;
+const INTERVAL: Duration = Duration::from_secs(5);
+
+// TDS reads 10-20 ppm high for the first few readings after power-on
+const DEFAULT_WARMUP: Duration = Duration::from_secs(60);
+
+// The probe board output is ratiometric to its supply, measured on A3 through a 1:1 divider
+const NOMINAL_SUPPLY: f32 = 3.3;
+const SUPPLY_DIVIDER: f32 = 2.0;
+const SUPPLY_RANGE: std::ops::RangeInclusive<f32> = 3.2..=3.4;
+
+// Ages in measurement intervals at which a reading moves on to the next freshness tier
+const AGING_INTERVALS: u32 = 2;
+const STALE_INTERVALS: u32 = 5;
+const EXPIRED_INTERVALS: u32 = 10;
+
+// Adaptive cadence: a reading moving more than this since the previous one is back to the regular interval
+const FAST_TEMPERATURE_DELTA: f32 = 0.2;
+const FAST_TDS_DELTA: f32 = 5.0;
+
+// The regular interval is kept this long after the last fast change before stretching again
+const FAST_HOLD: Duration = Duration::from_secs(600);
+
+// The TDS probe is calibrated at this temperature, used for compensation when nothing was ever measured
+const REFERENCE_TEMPERATURE: f32 = 25.0;
+
+// A probe this far off the curve is more likely dry, fouled or broken than in need of calibration
+const K_FACTOR_RANGE: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+// Below this the probe is taken to be out of the solution, where no factor makes sense
+const MIN_CALIBRATION_TDS: f32 = 10.0;
+
+// Samples kept for `/history` by default, an hour at the regular interval
+const DEFAULT_HISTORY_LEN: usize = 720;
+// About 35 KiB of samples, e.g. a day at one a minute
+const MAX_HISTORY_LEN: usize = 1440;
+
+// Largest acceptable gap between the TDS and the one recomputed at the reported temperature, about what
+// compensating with a temperature 0.5 °C off shifts it by
+const MAX_COMPENSATION_SHIFT: f32 = 0.01;
+
+// A plain mutex held only to copy the snapshot, so httpd threads never wait on the tokio runtime
+static VALUES: Mutex<Option<Values>> = Mutex::new(None);
+static HISTORY: Mutex<History> = Mutex::new(History::new());
+static HISTORY_CONFIG: OnceLock<HistoryConfig> = OnceLock::new();
+static IMPLAUSIBLE: AtomicU32 = AtomicU32::new(0);
+static TRIGGER: Notify = Notify::const_new();
+static PROBE_ADDRESS: Mutex<Option<u64>> = Mutex::new(None);
+static ACTIVE_INTERVAL_MS: AtomicU32 = AtomicU32::new(INTERVAL.as_millis() as u32);
+static TEMPERATURE_ENABLED: AtomicBool = AtomicBool::new(true);
+static TDS_ENABLED: AtomicBool = AtomicBool::new(true);
+static TDS_K_FACTOR: AtomicU32 = AtomicU32::new(1.0_f32.to_bits());
+
+struct History {
+    samples: VecDeque<Sample>,
+    /// Sequence number of the latest sample evicted, for cursors to tell they missed something.
+    evicted: Option<u32>,
+    /// When the window of the latest sample opened. Until it closes, newer snapshots replace that sample
+    /// rather than being appended.
+    window_start: Option<i64>,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            evicted: None,
+            window_start: None,
+        }
+    }
+
+    /// Keeps at most one sample per `config.interval`, or per regular measurement interval when that is
+    /// shorter, so bursts of fast measurements don't flood the buffer. The latest sample of a window is
+    /// provisional, each snapshot within the window replacing it.
+    fn record(&mut self, config: HistoryConfig, sample: Sample) {
+        // Half an interval of slack, so jitter in the measurement ticks doesn't push every sample one late. A
+        // clock stepped backwards doesn't hold samples back until it catches up
+        let spacing = config.interval.max(INTERVAL).saturating_sub(INTERVAL / 2).as_millis() as i64;
+        let open = self
+            .window_start
+            .is_some_and(|start| (0..spacing).contains(&(sample.timestamp - start)));
+        if let Some(provisional) = self.samples.back_mut().filter(|_| open) {
+            *provisional = sample;
+            return;
+        }
+
+        if self.samples.len() >= config.capacity {
+            self.evicted = self.samples.pop_front().map(|sample| sample.seq);
+        }
+        self.samples.push_back(sample);
+        self.window_start = Some(sample.timestamp);
+    }
+}
+
+/// How much `/history` holds, set from `history.capacity` and `history.interval` at boot.
+#[derive(Debug, Clone, Copy)]
+struct HistoryConfig {
+    capacity: usize,
+    /// Shortest time between kept samples, zero keeping one per regular measurement interval.
+    interval: Duration,
+}
+
+impl Values {
+    pub fn id(&self) -> RecordId<'static> {
+        RecordId {
+            device_id: system::device_id(),
+            boot_id: system::boot_id(),
+            seq: self.seq,
+        }
+    }
+
+    /// Time of the oldest reading in the snapshot.
+    pub fn oldest_at(&self) -> i64 {
+        self.temperature_at.min(self.tds_at)
+    }
+
+    pub fn temperature_age(&self, now: i64) -> Duration {
+        age(self.temperature_at, now)
+    }
+
+    pub fn tds_age(&self, now: i64) -> Duration {
+        age(self.tds_at, now)
+    }
+}
+
+fn age(measured_at: i64, now: i64) -> Duration {
+    Duration::from_millis((now - measured_at).max(0) as u64)
+}
+
+/// How far a reading can still be trusted, judged by its age.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Freshness {
+    Live,
+    Aging,
+    Stale,
+    /// Too old to show at all.
+    Expired,
+}
+
+impl Freshness {
+    /// Tier of a reading `age` old when a new one is due every `interval`.
+    pub fn for_age(age: Duration, interval: Duration) -> Self {
+        if age > interval * EXPIRED_INTERVALS {
+            Self::Expired
+        } else if age > interval * STALE_INTERVALS {
+            Self::Stale
+        } else if age > interval * AGING_INTERVALS {
+            Self::Aging
+        } else {
+            Self::Live
+        }
+    }
+}
+
+/// Tier of a reading `age` old at the measurement interval in effect.
+pub(crate) fn freshness(age: Duration) -> Freshness {
+    Freshness::for_age(age, interval())
+}
+
+/// Measurement interval in effect, stretched by the adaptive cadence or shortened by the water-change
+/// assistant.
+pub(crate) fn interval() -> Duration {
+    Duration::from_millis(ACTIVE_INTERVAL_MS.load(Ordering::Relaxed) as u64)
+}
+
+pub(crate) fn get() -> Option<Values> {
+    *VALUES.lock().unwrap()
+}
+
+/// Recent samples, oldest first, from `since` on.
+pub(crate) fn get_history(since: Since) -> Vec<Sample> {
+    let history = HISTORY.lock().unwrap();
+
+    history
+        .samples
+        .iter()
+        .filter(|sample| since.includes(sample))
+        .copied()
+        .collect()
+}
+
+/// Sequence number of the first sample kept at or after `timestamp`, `None` while there is none.
+pub(crate) fn history_seq_at(timestamp: i64) -> Option<u32> {
+    let history = HISTORY.lock().unwrap();
+
+    history
+        .samples
+        .iter()
+        .find(|sample| sample.timestamp >= timestamp)
+        .map(|sample| sample.seq)
+}
+
+/// Up to `page_size` samples, oldest first, after `cursor` or else from `since` on.
+pub(crate) fn get_history_page(since: Since, cursor: Option<Cursor>, page_size: usize) -> anyhow::Result<Page> {
+    let history = HISTORY.lock().unwrap();
+
+    let samples = history.samples.iter().filter(|sample| since.includes(sample));
+    page(samples, history.evicted, cursor, page_size)
+}
+
+/// Takes a measurement right away instead of waiting for the next tick.
+pub(crate) fn trigger() {
+    TRIGGER.notify_one();
+}
+
+/// ROM code of the DS18B20 in use, once it has been found on the bus.
+pub(crate) fn probe_address() -> Option<u64> {
+    *PROBE_ADDRESS.lock().unwrap()
+}
+
+/// Whether `sensor` is switched on, see `sensor.temperature.enabled` and `sensor.tds.enabled`.
+pub(crate) fn enabled(sensor: Sensor) -> bool {
+    sensor.switch().load(Ordering::Relaxed)
+}
+
+/// Re-reads the sensor switches and measures right away, so a change made through `/config` applies
+/// without a reboot. A sensor switched back on is searched for or probed again before it is read.
+pub(crate) fn reload_switches() -> anyhow::Result<()> {
+    if load_switches()? {
+        trigger();
+    }
+
+    Ok(())
+}
+
+// Returns whether any switch changed
+fn load_switches() -> anyhow::Result<bool> {
+    // Both are read before either is applied, so a failed read changes nothing
+    let mut switches = [true; Sensor::ALL.len()];
+    for (sensor, enabled) in Sensor::ALL.iter().zip(&mut switches) {
+        *enabled = nvs::get_flag_or(sensor.setting(), true)?;
+    }
+
+    let mut changed = false;
+    for (sensor, enabled) in Sensor::ALL.into_iter().zip(switches) {
+        if sensor.switch().swap(enabled, Ordering::Relaxed) == enabled {
+            continue;
+        }
+        changed = true;
+        if enabled {
+            info!("Sensor {} enabled", sensor.name());
+        } else {
+            warn!("Sensor {} disabled by user", sensor.name());
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Factor applied to TDS readings, from `sensor.tds.k_factor`.
+pub(crate) fn tds_k_factor() -> f32 {
+    f32::from_bits(TDS_K_FACTOR.load(Ordering::Relaxed))
+}
+
+fn load_k_factor() -> anyhow::Result<()> {
+    let k_factor = nvs::get_parsed_or("sensor.tds.k_factor", tds_k_factor(), parse_k_factor)?;
+
+    TDS_K_FACTOR.store(k_factor.to_bits(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Parses `sensor.tds.k_factor`.
+pub(crate) fn parse_k_factor(v: &str) -> anyhow::Result<f32> {
+    let k_factor = v.parse()?;
+    if !K_FACTOR_RANGE.contains(&k_factor) {
+        return Err(anyhow!("TDS K-factor must be within {K_FACTOR_RANGE:?}: {k_factor}"));
+    }
+
+    Ok(k_factor)
+}
+
+/// Parses `sensor.warmup`, in seconds.
+pub(crate) fn parse_warmup(v: &str) -> anyhow::Result<Duration> {
+    Ok(Duration::from_secs(v.parse()?))
+}
+
+/// Parses `sensor.max_interval`, in seconds, no shorter than the regular interval.
+pub(crate) fn parse_max_interval(v: &str) -> anyhow::Result<Duration> {
+    let max = Duration::from_secs(v.parse()?);
+    if max < INTERVAL {
+        return Err(anyhow!("Maximum interval shorter than {INTERVAL:?}: {max:?}"));
+    }
+
+    Ok(max)
+}
+
+/// Parses `history.capacity`, in samples.
+pub(crate) fn parse_history_capacity(v: &str) -> anyhow::Result<usize> {
+    let capacity = v.parse()?;
+    if !(1..=MAX_HISTORY_LEN).contains(&capacity) {
+        return Err(anyhow!(
+            "History capacity must be within 1..={MAX_HISTORY_LEN}: {capacity}"
+        ));
+    }
+
+    Ok(capacity)
+}
+
+/// Parses `history.interval`, in seconds, 0 keeping every measurement.
+pub(crate) fn parse_history_interval(v: &str) -> anyhow::Result<Duration> {
+    Ok(Duration::from_secs(v.parse()?))
+}
+
+/// Scales TDS readings so the latest one matches `reference` ppm, the solution the probe sits in, and
+/// stores the factor.
+pub(crate) fn calibrate_tds(reference: f32) -> Result<Calibration, CalibrationError> {
+    let Some(values) = get() else {
+        return Err(CalibrationError::Rejected("No measurement yet".to_owned()));
+    };
+    if !values.tds_enabled {
+        return Err(CalibrationError::Rejected("TDS sensor is disabled".to_owned()));
+    }
+    if values.simulated {
+        return Err(CalibrationError::Rejected("Readings are simulated".to_owned()));
+    }
+    if values.warming_up {
+        return Err(CalibrationError::Rejected("Probe is still warming up".to_owned()));
+    }
+    // The reading must come from the probe sitting in the solution now, not from before it was dipped
+    if freshness(values.tds_age(Utc::now().timestamp_millis())) != Freshness::Live {
+        return Err(CalibrationError::Rejected(
+            "Latest TDS reading is not fresh, try again after the next measurement".to_owned(),
+        ));
+    }
+
+    let measured = values.tds / values.compensation.k_factor;
+    if measured < MIN_CALIBRATION_TDS {
+        return Err(CalibrationError::Rejected(format!(
+            "Probe reads {measured:.0} ppm, is it in the solution?"
+        )));
+    }
+    let k_factor = reference / measured;
+    if !K_FACTOR_RANGE.contains(&k_factor) {
+        return Err(CalibrationError::Rejected(format!(
+            "Factor {k_factor:.3} is outside {K_FACTOR_RANGE:?}, check the probe and the reference"
+        )));
+    }
+
+    let previous_k_factor = store_k_factor(k_factor)?;
+    info!("TDS calibrated against {reference} ppm, K-factor {previous_k_factor:.4} -> {k_factor:.4}");
+    journal::record(journal::Event::TdsCalibration {
+        reference,
+        measured,
+        k_factor,
+    });
+    // Calibrated with the probe in use, whatever was pinned before
+    if let Err(e) = probes::confirm() {
+        warn!("Failed to pin the temperature probe: {e:?}");
+    }
+    calibration::captured(calibration::Capture::new(
+        reference,
+        measured,
+        values.compensation.voltage,
+        values.compensation.temperature,
+    ));
+    trigger();
+
+    Ok(Calibration {
+        reference,
+        measured,
+        k_factor,
+        previous_k_factor,
+    })
+}
+
+/// Drops the calibration of a TDS probe that was swapped for another, going back to the plain curve.
+/// Returns the factor it had.
+pub(crate) fn reset_tds_calibration() -> anyhow::Result<f32> {
+    let previous_k_factor = store_k_factor(1.0)?;
+    info!("TDS probe replaced, K-factor {previous_k_factor:.4} cleared");
+    journal::record(journal::Event::TdsProbeReplaced { previous_k_factor });
+    calibration::cleared();
+    trigger();
+
+    Ok(previous_k_factor)
 }
 
-const RETRY_COUNT: i32 = 3;
+/// Takes over a factor found by calibrating on another board, returning the one it replaces.
+pub(crate) fn import_tds_k_factor(k_factor: f32) -> Result<f32, CalibrationError> {
+    if !K_FACTOR_RANGE.contains(&k_factor) {
+        return Err(CalibrationError::Rejected(format!(
+            "TDS K-factor must be within {K_FACTOR_RANGE:?}: {k_factor}"
+        )));
+    }
+    let previous_k_factor = store_k_factor(k_factor)?;
+    trigger();
+
+    Ok(previous_k_factor)
+}
 
-static VALUES: RwLock<Option<Values>> = RwLock::const_new(None);
+fn store_k_factor(k_factor: f32) -> anyhow::Result<f32> {
+    nvs::set("sensor.tds.k_factor", &format!("{k_factor:.4}"))?;
+    Ok(f32::from_bits(TDS_K_FACTOR.swap(k_factor.to_bits(), Ordering::Relaxed)))
+}
 
-pub(crate) async fn get() -> Option<Values> {
-    *VALUES.read().await
+/// Number of snapshots holding a value outside its metric's plausible range.
+pub(crate) fn implausible() -> u32 {
+    IMPLAUSIBLE.load(Ordering::Relaxed)
 }
 
-pub(crate) fn init<PIN, I2C>(one_wire_pin: PIN, i2c: I2C) -> anyhow::Result<Box<Context<PIN, I2C>>>
+pub(crate) fn init<PIN, I2C>(
+    one_wire_pin: PIN,
+    i2c: I2C,
+    adc_ready: Option<adc::ReadyPin>,
+) -> anyhow::Result<Box<Context<PIN, I2C>>>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
     task::block_in_place(move || {
-        let (one_wire, ds18b20) = init_ds18b20(one_wire_pin)?;
-        let ads1115 = init_ads1115(i2c)?;
+        load_switches()?;
+        load_k_factor()?;
+        init_history()?;
+
+        let max_interval = nvs::get_parsed_or("sensor.max_interval", None, |v| parse_max_interval(v).map(Some))?;
+
+        if let Some(simulator) = simulation::init()? {
+            warn!("Simulation mode, sensor readings are synthetic");
+            return Ok(Box::new(Context {
+                source: Source::Simulated(simulator),
+                warmup: Duration::ZERO,
+                max_interval,
+                interval: INTERVAL,
+                fast_at: None,
+            }));
+        }
+
+        // A sensor switched off is left alone, so a dead one cannot keep the device from booting
+        let mut one_wire = OneWire::new(one_wire_pin).unwrap();
+        let ds18b20 = if enabled(Sensor::Temperature) {
+            Some(find_ds18b20(&mut one_wire)?)
+        } else {
+            None
+        };
+        let mut adc = Adc::new(i2c, adc_ready)?;
+        let adc_configured = enabled(Sensor::Tds);
+        if adc_configured {
+            adc.configure()?;
+        }
+        let vref_monitor = nvs::get_flag("sensor.vref_monitor")?;
+        let warmup = nvs::get_parsed_or("sensor.warmup", DEFAULT_WARMUP, parse_warmup)?;
 
         Ok(Box::new(Context {
-            one_wire,
-            ds18b20,
-            ads1115,
+            source: Source::Hardware(Sensors {
+                one_wire,
+                ds18b20,
+                adc,
+                adc_configured,
+                vref_monitor,
+            }),
+            warmup,
+            max_interval,
+            interval: INTERVAL,
+            fast_at: None,
         }))
     })
 }
 
-fn init_ds18b20<PIN>(pin: PIN) -> anyhow::Result<(OneWire<PIN>, Ds18b20)>
+fn find_ds18b20<PIN>(one_wire: &mut OneWire<PIN>) -> anyhow::Result<Ds18b20>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
 {
-    let mut one_wire = OneWire::new(pin).unwrap();
     let mut delay = Delay::new_default();
 
     // Retry to initialize DS18B20 up to 3 times
-    for _ in 0..RETRY_COUNT {
-        for address in one_wire.devices(false, &mut delay).flatten() {
-            if address.family_code() != ds18b20::FAMILY_CODE {
-                continue;
-            }
+    let address = retry_blocking(RETRY_COUNT, Duration::from_secs(1), || {
+        one_wire
+            .devices(false, &mut delay)
+            .flatten()
+            .find(|address| address.family_code() == ds18b20::FAMILY_CODE)
+            .ok_or(anyhow!("DS18B20 not found"))
+    })?;
 
-            let ds18b20 = Ds18b20::new::<GpioError>(address).map_err(|e| anyhow!("{e:?}"))?;
-            ds18b20
-                .set_config(-128, 127, Resolution::Bits12, &mut one_wire, &mut delay)
-                .unwrap();
+    let ds18b20 = Ds18b20::new::<GpioError>(address).map_err(|e| anyhow!("{e:?}"))?;
+    *PROBE_ADDRESS.lock().unwrap() = Some(address.0);
+    probes::discovered(address.0);
+    ds18b20
+        .set_config(-128, 127, Resolution::Bits12, one_wire, &mut delay)
+        .map_err(|e| anyhow!("{e:?}"))?;
+
+    Ok(ds18b20)
+}
+
+pub(crate) async fn worker<PIN, I2C>(ctx: &mut Box<Context<PIN, I2C>>) -> anyhow::Result<()>
+where
+    PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let mut period = cadence(ctx.interval);
+    ACTIVE_INTERVAL_MS.store(period.as_millis() as u32, Ordering::Relaxed);
+    let mut interval = interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        select! {
+            _ = interval.tick() => {}
+            _ = TRIGGER.notified() => {}
+        }
+
+        let previous = get();
+        let started = Instant::now();
+        let result = update(ctx).await;
+        latency::MEASUREMENT_CYCLE.record(started.elapsed());
+        if let Err(e) = &result {
+            error!("Failed to update measurements: {e:?}");
+        }
+        let latest = get();
+        assistant::check_timeout(latest);
+
+        if let Some(max) = ctx.max_interval {
+            // A failed read is treated as a fast change, so the next attempt comes soon, and so is an
+            // active alarm, which is watched closely until it clears
+            let fast = match (result, latest) {
+                (Ok(()), Some(latest)) => is_fast(previous, &latest) || alerts::active(),
+                _ => true,
+            };
+            if fast {
+                ctx.fast_at = Some(Instant::now());
+            }
+            ctx.interval = adapt(ctx.interval, max, ctx.fast_at.map(|at| at.elapsed()));
+        }
 
-            return Ok((one_wire, ds18b20));
+        // Follow cadence changes, e.g. when the water-change assistant starts or ends
+        if cadence(ctx.interval) != period {
+            period = cadence(ctx.interval);
+            ACTIVE_INTERVAL_MS.store(period.as_millis() as u32, Ordering::Relaxed);
+            interval = interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         }
+    }
+}
 
-        FreeRtos::delay_ms(1000);
+fn cadence(adaptive: Duration) -> Duration {
+    if assistant::get().is_some() {
+        assistant::INTERVAL
+    } else {
+        adaptive
     }
+}
 
-    Err(anyhow!("DS18B20 not found"))
+/// Next interval of the adaptive cadence given the time since readings last moved fast: the regular one
+/// until `FAST_HOLD` has passed, otherwise doubling up to `max`.
+fn adapt(current: Duration, max: Duration, since_fast: Option<Duration>) -> Duration {
+    if since_fast.is_some_and(|elapsed| elapsed < FAST_HOLD) {
+        INTERVAL
+    } else {
+        (current * 2).min(max)
+    }
 }
 
-fn init_ads1115<I2C>(i2c: I2C) -> anyhow::Result<Ads1115<I2C>>
+/// Whether `latest` moved enough since `previous`, or is doubtful enough, to watch it closely.
+fn is_fast(previous: Option<Values>, latest: &Values) -> bool {
+    let Some(previous) = previous else {
+        return true;
+    };
+
+    // Readings of a sensor switched off are NaN, so they never count as moving
+    latest.warming_up
+        || latest.compensation.fallback
+        || latest.temperature_enabled != previous.temperature_enabled
+        || latest.tds_enabled != previous.tds_enabled
+        || !is_plausible(latest)
+        || (latest.temperature - previous.temperature).abs() > FAST_TEMPERATURE_DELTA
+        || (latest.tds - previous.tds).abs() > FAST_TDS_DELTA
+}
+
+// Whether the readings of the sensors switched on are within their metric's plausible range
+fn is_plausible(values: &Values) -> bool {
+    (!values.temperature_enabled || registry::TEMPERATURE.is_plausible(values.temperature))
+        && (!values.tds_enabled || registry::TDS.is_plausible(values.tds))
+}
+
+async fn update<PIN, I2C>(ctx: &mut Context<PIN, I2C>) -> anyhow::Result<()>
 where
+    PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    let mut ads1115 = Ads1x1x::new_ads1115(i2c, TargetAddr::default());
-    ads1115.set_full_scale_range(FullScaleRange::Within4_096V).unwrap();
+    let previous = get();
+    let warmup = ctx.warmup;
+    // Taken once, so a switch flipped mid-cycle applies from the next one
+    let switches = (enabled(Sensor::Temperature), enabled(Sensor::Tds));
+    let values = task::block_in_place(move || match &mut ctx.source {
+        Source::Hardware(sensors) => read_sensors(sensors, previous, switches),
+        Source::Simulated(simulator) => {
+            let timestamp = Utc::now().timestamp_millis();
+            let (temperature, tds) = simulator.step(interval().as_secs_f32());
+            let (temperature_enabled, tds_enabled) = switches;
 
-    Ok(ads1115)
+            anyhow::Ok(Values {
+                timestamp,
+                temperature: if temperature_enabled { temperature } else { f32::NAN },
+                temperature_at: timestamp,
+                tds: if tds_enabled { tds } else { f32::NAN },
+                tds_at: timestamp,
+                supply_voltage: None,
+                simulated: true,
+                uptime_ms: system::uptime_ms(),
+                clock_valid: system::clock_valid(),
+                seq: 0,
+                // The simulator models calibrated readings
+                compensation: Compensation {
+                    temperature,
+                    measured_at: timestamp,
+                    fallback: false,
+                    k_factor: 1.0,
+                    voltage: None,
+                },
+                warming_up: false,
+                temperature_enabled,
+                tds_enabled,
+            })
+        }
+    })?;
+    let values = Values {
+        seq: previous.map_or(0, |v| v.seq.wrapping_add(1)),
+        warming_up: values.uptime_ms < warmup.as_millis() as i64,
+        ..values
+    };
+
+    if !is_plausible(&values) {
+        IMPLAUSIBLE.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Implausible reading: {:.1} °C, {:.0} ppm",
+            values.temperature, values.tds
+        );
+    }
+
+    *VALUES.lock().unwrap() = Some(values);
+    lifecycle::reach(lifecycle::Milestone::Measured);
+    if !values.warming_up {
+        recovery::push(&values);
+        push_history(&values);
+    }
+
+    Ok(())
 }
 
-pub(crate) async fn worker<PIN, I2C>(ctx: &mut Box<Context<PIN, I2C>>) -> anyhow::Result<()>
+fn init_history() -> anyhow::Result<()> {
+    let capacity = nvs::get_parsed_or("history.capacity", DEFAULT_HISTORY_LEN, parse_history_capacity)?;
+    let interval = nvs::get_parsed_or("history.interval", Duration::ZERO, parse_history_interval)?;
+
+    // Allocated in full up front, rather than doubling past the length later
+    HISTORY.lock().unwrap().samples.reserve_exact(capacity);
+    HISTORY_CONFIG
+        .set(HistoryConfig { capacity, interval })
+        .map_err(|_| anyhow!("History already initialized"))?;
+    if !interval.is_zero() {
+        info!("Keeping {capacity} samples of history, one every {interval:?}");
+    }
+
+    Ok(())
+}
+
+fn push_history(values: &Values) {
+    let Some(config) = HISTORY_CONFIG.get() else {
+        return;
+    };
+
+    HISTORY.lock().unwrap().record(
+        *config,
+        Sample {
+            timestamp: values.timestamp,
+            temperature: values.temperature,
+            tds: values.tds,
+            temperature_enabled: values.temperature_enabled,
+            tds_enabled: values.tds_enabled,
+            simulated: values.simulated,
+            seq: values.seq,
+        },
+    );
+}
+
+/// Reads the sensors switched on in `switches`, temperature first. A failed sensor keeps its previous
+/// reading and timestamp, so one dead probe doesn't take the other one down with it.
+fn read_sensors<PIN, I2C>(
+    sensors: &mut Sensors<PIN, I2C>,
+    previous: Option<Values>,
+    switches: (bool, bool),
+) -> anyhow::Result<Values>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    let mut interval = interval(Duration::from_secs(5));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let timestamp = Utc::now().timestamp_millis();
+    let (temperature_enabled, tds_enabled) = switches;
 
-    loop {
-        interval.tick().await;
+    let temperature = if temperature_enabled {
+        let started = std::time::Instant::now();
+        let temperature = read_temperature_sensor(sensors);
+        latency::DS18B20_READ.record(started.elapsed());
+        match temperature {
+            Ok(temperature) => Some((temperature, timestamp)),
+            Err(e) => {
+                let previous = previous.filter(|v| v.temperature_enabled).ok_or(e)?;
+                error!("Failed to read temperature, keeping the previous value");
+                Some((previous.temperature, previous.temperature_at))
+            }
+        }
+    } else {
+        sensors.ds18b20 = None;
+        None
+    };
+    let compensation = match temperature {
+        Some((temperature, temperature_at)) => Compensation {
+            temperature,
+            measured_at: temperature_at,
+            fallback: temperature_at != timestamp,
+            k_factor: tds_k_factor(),
+            voltage: None,
+        },
+        // Switched off, TDS goes on with the last temperature that was measured
+        None => previous.map_or(
+            Compensation {
+                temperature: REFERENCE_TEMPERATURE,
+                measured_at: timestamp,
+                fallback: false,
+                k_factor: tds_k_factor(),
+                voltage: None,
+            },
+            |v| Compensation {
+                fallback: false,
+                k_factor: tds_k_factor(),
+                ..v.compensation
+            },
+        ),
+    };
 
-        if let Err(e) = update(ctx).await {
-            error!("Failed to update measurements: {e:?}");
+    let tds = if tds_enabled {
+        match read_tds_sensor(sensors, &compensation) {
+            Ok((tds, voltage, supply_voltage)) => {
+                let compensation = Compensation {
+                    voltage: Some(voltage),
+                    ..compensation
+                };
+                Some((tds, timestamp, compensation, supply_voltage))
+            }
+            Err(e) => {
+                let previous = previous.filter(|v| v.tds_enabled).ok_or(e)?;
+                error!("Failed to read TDS, keeping the previous value");
+                Some((
+                    previous.tds,
+                    previous.tds_at,
+                    previous.compensation,
+                    previous.supply_voltage,
+                ))
+            }
+        }
+    } else {
+        sensors.adc_configured = false;
+        None
+    };
+
+    let fresh_temperature = temperature.is_some_and(|(_, at)| at == timestamp);
+    let fresh_tds = tds.is_some_and(|(_, at, ..)| at == timestamp);
+    if (temperature_enabled || tds_enabled) && !fresh_temperature && !fresh_tds {
+        return Err(anyhow!("All sensors failed"));
+    }
+
+    let (temperature, temperature_at) = temperature.unwrap_or((f32::NAN, timestamp));
+    let (tds, tds_at, compensation, supply_voltage) = tds.unwrap_or((f32::NAN, timestamp, compensation, None));
+    let values = Values {
+        timestamp,
+        temperature,
+        temperature_at,
+        tds,
+        tds_at,
+        supply_voltage,
+        simulated: false,
+        uptime_ms: system::uptime_ms(),
+        clock_valid: system::clock_valid(),
+        seq: 0,
+        compensation,
+        warming_up: false,
+        temperature_enabled,
+        tds_enabled,
+    };
+    if fresh_tds && temperature_enabled {
+        if let Some(mismatch) = compensation_mismatch(&values, interval().as_millis() as i64) {
+            warn!("{mismatch}");
         }
     }
+
+    Ok(values)
 }
 
-async fn update<PIN, I2C>(ctx: &mut Context<PIN, I2C>) -> anyhow::Result<()>
+// Searches the bus first when the temperature sensor was just switched back on
+fn read_temperature_sensor<PIN, I2C>(sensors: &mut Sensors<PIN, I2C>) -> anyhow::Result<f32>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    let values = task::block_in_place(move || {
-        let timestamp = Utc::now().timestamp_millis();
-        let temperature = read_temperature(&mut ctx.one_wire, &ctx.ds18b20)?;
-        let tds = read_tds(&mut ctx.ads1115, temperature)?;
+    let ds18b20 = match sensors.ds18b20.take() {
+        Some(ds18b20) => ds18b20,
+        None => {
+            let ds18b20 = find_ds18b20(&mut sensors.one_wire)?;
+            info!("DS18B20 found at {:016x}", probe_address().unwrap_or_default());
+            ds18b20
+        }
+    };
+    let temperature = read_temperature(&mut sensors.one_wire, &ds18b20);
+    sensors.ds18b20 = Some(ds18b20);
 
-        anyhow::Ok(Values {
-            timestamp,
-            temperature,
-            tds,
-        })
-    })?;
+    temperature
+}
 
-    *VALUES.write().await = Some(values);
+// Configures the ADC first when the TDS sensor was just switched back on, the reading comes with the probe
+// voltage it was converted from
+fn read_tds_sensor<PIN, I2C>(
+    sensors: &mut Sensors<PIN, I2C>,
+    compensation: &Compensation,
+) -> anyhow::Result<(f32, f32, Option<f32>)>
+where
+    PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    if !sensors.adc_configured {
+        sensors.adc.configure()?;
+        sensors.adc_configured = true;
+    }
 
-    Ok(())
+    let supply_voltage = if sensors.vref_monitor {
+        Some(read_supply(&mut sensors.adc)?)
+    } else {
+        None
+    };
+    let started = std::time::Instant::now();
+    let voltage = read_tds_voltage(&mut sensors.adc, supply_voltage);
+    latency::ADC_READ.record(started.elapsed());
+
+    let voltage = voltage?;
+    let tds = tds_from_voltage(voltage, compensation.temperature, compensation.k_factor);
+    Ok((tds, voltage, supply_voltage))
+}
+
+/// Why a fresh TDS reading disagrees with the temperature reported alongside it, if it does.
+///
+/// The TDS is recomputed from the probe voltage at the reported temperature, so compensating with any
+/// other one, e.g. from a stale fallback or another sensor, shows up as a shift.
+fn compensation_mismatch(values: &Values, max_age: i64) -> Option<String> {
+    let compensation = &values.compensation;
+
+    let age = values.tds_at - compensation.measured_at;
+    if age > max_age {
+        return Some(format!("TDS compensated with a temperature {age} ms old"));
+    }
+
+    let expected = tds_from_voltage(compensation.voltage?, values.temperature, compensation.k_factor);
+    let shift = values.tds / expected - 1.0;
+    if expected > 0.0 && shift.abs() > MAX_COMPENSATION_SHIFT {
+        return Some(format!(
+            "TDS compensated at {:.2} °C is {:+.1} % off the {expected:.1} ppm expected at {:.2} °C",
+            compensation.temperature,
+            shift * 100.0,
+            values.temperature
+        ));
+    }
+
+    None
 }
 
 fn read_temperature<PIN>(one_wire: &mut OneWire<PIN>, ds18b20: &Ds18b20) -> anyhow::Result<f32>
 where
     PIN: InputPin<Error = GpioError> + OutputPin<Error = GpioError>,
 {
-    let mut err: Option<_> = None;
-
-    for _ in 0..RETRY_COUNT {
+    retry_blocking(RETRY_COUNT, Duration::ZERO, || {
         let mut delay = Delay::new_default();
         ds18b20
             .start_temp_measurement(one_wire, &mut delay)
             .map_err(|e| anyhow!("{e:?}"))?;
 
         Resolution::Bits12.delay_for_measurement_time(&mut delay);
-        match ds18b20.read_data(one_wire, &mut delay) {
-            Ok(data) => return Ok((data.temperature * 10.0).round() / 10.0),
-            Err(e) => err = Some(e),
-        }
+        let data = ds18b20.read_data(one_wire, &mut delay).map_err(|e| anyhow!("{e:?}"))?;
+
+        Ok(data.temperature)
+    })
+}
+
+fn read_supply<I2C>(adc: &mut Adc<I2C>) -> anyhow::Result<f32>
+where
+    I2C: embedded_hal::i2c::I2c<Error = I2cError>,
+{
+    let supply = adc.read_voltage(Input::Supply)? * SUPPLY_DIVIDER;
+    if !SUPPLY_RANGE.contains(&supply) {
+        warn!("Probe supply voltage out of range: {supply:.3} V");
     }
 
-    Err(anyhow!("{:?}", err.unwrap()))
+    Ok(supply)
 }
 
-fn read_tds<I2C>(ads1115: &mut Ads1115<I2C>, temperature: f32) -> anyhow::Result<f32>
+fn read_tds_voltage<I2C>(adc: &mut Adc<I2C>, supply_voltage: Option<f32>) -> anyhow::Result<f32>
 where
     I2C: embedded_hal::i2c::I2c<Error = I2cError>,
 {
-    const MAX_VOLTAGE: f32 = 4.096;
-    const MAX_RAW_VALUE: f32 = 32767.0;
+    let voltage = adc.read_voltage(Input::Tds)?;
 
-    let raw_value = nb::block!(ads1115.read(channel::SingleA0)).map_err(|e| anyhow!("{e:?}"))?;
-    let voltage = f32::from(raw_value) * MAX_VOLTAGE / MAX_RAW_VALUE;
+    // Compensate supply droop, the probe board output scales with its supply
+    Ok(match supply_voltage {
+        Some(supply) if supply > 0.0 => voltage * NOMINAL_SUPPLY / supply,
+        _ => voltage,
+    })
+}
 
+/// Converts the probe voltage to ppm at 25 °C, given the water temperature it was measured at.
+fn tds_from_voltage(voltage: f32, temperature: f32, k_factor: f32) -> f32 {
     // See https://wiki.keyestudio.com/KS0429_keyestudio_TDS_Meter_V1.0
 
-    // temperature compensation formula: fFinalResult(25^C) = fFinalResult(current)/(1.0+0.02*(fTP-25.0));
-    let coefficient = 1.0 + 0.02 * (temperature - 25.0);
     //temperature compensation
-    let voltage = voltage / coefficient;
+    let voltage = voltage / compensation::tds_temperature_coefficient(temperature);
     //convert voltage value to tds value
     let tds = (133.42 * voltage.powi(3) - 255.86 * voltage.powi(2) + 857.39 * voltage) * 0.5;
 
-    Ok(tds.round())
+    // The curve is generic, the factor fits it to this probe
+    tds * k_factor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL_MS: i64 = 5_000;
+
+    // A reading taken at 1_700_000_000_000 with a probe voltage of 1 V, compensated at 25 °C
+    fn values(temperature: f32) -> Values {
+        let timestamp = 1_700_000_000_000;
+        Values {
+            timestamp,
+            temperature,
+            temperature_at: timestamp,
+            tds: tds_from_voltage(1.0, 25.0, 1.0),
+            tds_at: timestamp,
+            supply_voltage: None,
+            simulated: false,
+            uptime_ms: 60_000,
+            clock_valid: true,
+            seq: 0,
+            compensation: Compensation {
+                temperature: 25.0,
+                measured_at: timestamp,
+                fallback: false,
+                k_factor: 1.0,
+                voltage: Some(1.0),
+            },
+            warming_up: false,
+            temperature_enabled: true,
+            tds_enabled: true,
+        }
+    }
+
+    #[test]
+    fn compensation_at_the_reported_temperature_matches() {
+        assert_eq!(compensation_mismatch(&values(25.0), INTERVAL_MS), None);
+        // Within half a degree is within the tolerance
+        assert_eq!(compensation_mismatch(&values(25.2), INTERVAL_MS), None);
+    }
+
+    #[test]
+    fn compensation_at_another_temperature_is_reported() {
+        assert!(compensation_mismatch(&values(27.0), INTERVAL_MS).is_some());
+        assert!(compensation_mismatch(&values(23.0), INTERVAL_MS).is_some());
+    }
+
+    #[test]
+    fn compensation_with_a_stale_fallback_is_reported() {
+        let mut v = values(25.0);
+        v.temperature_at -= 2 * INTERVAL_MS;
+        v.compensation.measured_at = v.temperature_at;
+        v.compensation.fallback = true;
+
+        let mismatch = compensation_mismatch(&v, INTERVAL_MS).unwrap();
+        assert!(mismatch.contains("old"), "{mismatch}");
+
+        // A fallback from the previous cycle is still fine
+        v.compensation.measured_at = v.tds_at - INTERVAL_MS;
+        assert_eq!(compensation_mismatch(&v, INTERVAL_MS), None);
+    }
+
+    #[test]
+    fn compensation_without_a_voltage_only_checks_the_age() {
+        let mut v = values(30.0);
+        v.compensation.voltage = None;
+        assert_eq!(compensation_mismatch(&v, INTERVAL_MS), None);
+    }
+
+    #[test]
+    fn record_id_round_trips() {
+        let id = values(25.0).id();
+        let text = id.to_string();
+
+        assert_eq!(text, format!("{}:{}:0", system::device_id(), system::boot_id()));
+        assert_eq!(RecordId::parse(&text), Some(id));
+
+        // Only the last two fields are split off, whatever the device ID holds
+        let id = RecordId::parse("tank:2:5f3e9a01:42").unwrap();
+        assert_eq!(
+            (id.device_id, id.boot_id, id.seq),
+            ("tank:2", system::BootId(0x5f3e9a01), 42)
+        );
+        assert_eq!(id.to_string(), "tank:2:5f3e9a01:42");
+    }
+
+    #[test]
+    fn record_id_rejects_malformed_fields() {
+        for text in [
+            "",
+            "cobitis:5f3e9a01",
+            ":5f3e9a01:42",
+            "cobitis:5f3e9a0:42",
+            "cobitis:5f3e9a01a:42",
+            "cobitis:5f3e9g01:42",
+            "cobitis:5f3e9a01:",
+            "cobitis:5f3e9a01:-1",
+        ] {
+            assert_eq!(RecordId::parse(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn history_resumes_after_a_timestamp_or_record() {
+        let mut own = values(25.0);
+        own.seq = 7;
+        let other_boot = RecordId {
+            boot_id: system::BootId(system::boot_id().0.wrapping_add(1)),
+            ..own.id()
+        };
+
+        assert_eq!(
+            "1700000000000".parse::<Since>().unwrap(),
+            Since::Timestamp(1_700_000_000_000)
+        );
+        assert_eq!(own.id().to_string().parse::<Since>().unwrap(), Since::Seq(7));
+        assert_eq!(other_boot.to_string().parse::<Since>().unwrap(), Since::Start);
+        assert!("yesterday".parse::<Since>().is_err());
+
+        let sample = Sample {
+            timestamp: 1_700_000_000_000,
+            temperature: 25.0,
+            tds: 180.0,
+            temperature_enabled: true,
+            tds_enabled: true,
+            simulated: false,
+            seq: 8,
+        };
+        assert!(Since::Start.includes(&sample));
+        assert!(Since::Timestamp(1_699_999_999_999).includes(&sample));
+        assert!(!Since::Timestamp(1_700_000_000_000).includes(&sample));
+        assert!(Since::Seq(7).includes(&sample));
+        assert!(!Since::Seq(8).includes(&sample));
+    }
+
+    fn samples(seqs: std::ops::RangeInclusive<u32>) -> Vec<Sample> {
+        seqs.map(|seq| Sample {
+            timestamp: 1_700_000_000_000 + i64::from(seq) * 5000,
+            temperature: 25.0,
+            tds: 180.0,
+            temperature_enabled: true,
+            tds_enabled: true,
+            simulated: false,
+            seq,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn bursts_update_a_provisional_sample() {
+        let config = HistoryConfig {
+            capacity: DEFAULT_HISTORY_LEN,
+            interval: Duration::from_secs(60),
+        };
+        let mut history = History::new();
+
+        // The water change cadence, every 2 s for 10 minutes
+        for (i, mut sample) in samples(1..=300).into_iter().enumerate() {
+            sample.timestamp = 1_700_000_000_000 + i as i64 * 2000;
+            sample.temperature = 25.0 + i as f32 / 100.0;
+            history.record(config, sample);
+
+            // The latest snapshot is always in, if only provisionally
+            let latest = history.samples.back().unwrap();
+            assert_eq!((latest.seq, latest.temperature), (sample.seq, sample.temperature));
+        }
+
+        // One per window of a little under a minute, each the last snapshot of its window
+        assert_eq!(history.samples.len(), 11);
+        assert_eq!(history.evicted, None);
+        let seqs: Vec<_> = history.samples.iter().map(|sample| sample.seq).collect();
+        assert_eq!(seqs, [29, 58, 87, 116, 145, 174, 203, 232, 261, 290, 300]);
+    }
+
+    #[test]
+    fn regular_measurements_are_all_kept() {
+        let mut history = History::new();
+        let config = HistoryConfig {
+            capacity: 5,
+            interval: Duration::ZERO,
+        };
+
+        for sample in samples(1..=8) {
+            history.record(config, sample);
+        }
+        let seqs: Vec<_> = history.samples.iter().map(|sample| sample.seq).collect();
+        assert_eq!(seqs, [4, 5, 6, 7, 8]);
+        assert_eq!(history.evicted, Some(3));
+    }
+
+    fn seqs(page: &Page) -> Vec<u32> {
+        page.samples.iter().map(|sample| sample.seq).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip_and_reject_tampering() {
+        let cursor = Cursor::new(42);
+        let token = cursor.to_string();
+        assert_eq!(token.len(), 24);
+        assert_eq!(token.parse::<Cursor>().unwrap(), cursor);
+
+        // Any changed digit fails the check
+        for i in 0..token.len() {
+            let mut altered = token.clone().into_bytes();
+            altered[i] = if altered[i] == b'0' { b'1' } else { b'0' };
+            let altered = String::from_utf8(altered).unwrap();
+            assert!(altered.parse::<Cursor>().is_err(), "{altered}");
+        }
+        for token in [
+            "",
+            "42",
+            &token[..23],
+            format!("{token}0").as_str(),
+            "zzzzzzzzzzzzzzzzzzzzzzzz",
+        ] {
+            assert!(token.parse::<Cursor>().is_err(), "{token:?}");
+        }
+    }
+
+    #[test]
+    fn pages_resume_right_after_their_cursor() {
+        let history = samples(1..=10);
+
+        let first = page(&history, None, None, 4).unwrap();
+        assert_eq!(seqs(&first), [1, 2, 3, 4]);
+        assert!(first.more);
+        let second = page(&history, None, first.next_cursor, 4).unwrap();
+        assert_eq!(seqs(&second), [5, 6, 7, 8]);
+        let last = page(&history, None, second.next_cursor, 4).unwrap();
+        assert_eq!(seqs(&last), [9, 10]);
+        assert!(!last.more);
+
+        // Caught up, the cursor stays put until something new arrives
+        let empty = page(&history, None, last.next_cursor, 4).unwrap();
+        assert!(empty.samples.is_empty());
+        assert_eq!(empty.next_cursor, last.next_cursor);
+    }
+
+    #[test]
+    fn appending_between_pages_loses_nothing() {
+        let first = page(&samples(1..=6), None, None, 4).unwrap();
+        assert_eq!(seqs(&first), [1, 2, 3, 4]);
+
+        let grown = samples(1..=9);
+        let second = page(&grown, None, first.next_cursor, 4).unwrap();
+        assert_eq!(seqs(&second), [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn eviction_behind_the_cursor_is_harmless() {
+        let first = page(&samples(1..=10), None, None, 4).unwrap();
+
+        // 1 to 4 went while the client held a cursor after 4, down to the very record it names
+        let second = page(&samples(5..=12), Some(4), first.next_cursor, 4).unwrap();
+        assert_eq!(seqs(&second), [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn eviction_past_the_cursor_is_rejected() {
+        let first = page(&samples(1..=10), None, None, 4).unwrap();
+
+        // 5 went before the client came back for it
+        let error = page(&samples(6..=13), Some(5), first.next_cursor, 4).unwrap_err();
+        assert!(error.to_string().contains("evicted"), "{error}");
+    }
+
+    #[test]
+    fn cursors_from_another_boot_are_rejected() {
+        let stale = Cursor {
+            boot_id: system::BootId(system::boot_id().0.wrapping_add(1)),
+            seq: 4,
+        };
+        // Parses fine, it is only stale
+        assert_eq!(stale.to_string().parse::<Cursor>().unwrap(), stale);
+
+        let error = page(&samples(1..=10), None, Some(stale), 4).unwrap_err();
+        assert!(error.to_string().contains("earlier boot"), "{error}");
+    }
+
+    #[test]
+    fn freshness_tiers_start_past_their_boundaries() {
+        let interval = Duration::from_secs(5);
+        let tier = |age: Duration| Freshness::for_age(age, interval);
+
+        assert_eq!(tier(Duration::ZERO), Freshness::Live);
+        assert_eq!(tier(interval * AGING_INTERVALS), Freshness::Live);
+        assert_eq!(
+            tier(interval * AGING_INTERVALS + Duration::from_millis(1)),
+            Freshness::Aging
+        );
+        assert_eq!(tier(interval * STALE_INTERVALS), Freshness::Aging);
+        assert_eq!(
+            tier(interval * STALE_INTERVALS + Duration::from_millis(1)),
+            Freshness::Stale
+        );
+        assert_eq!(tier(interval * EXPIRED_INTERVALS), Freshness::Stale);
+        assert_eq!(
+            tier(interval * EXPIRED_INTERVALS + Duration::from_millis(1)),
+            Freshness::Expired
+        );
+        assert_eq!(tier(Duration::MAX), Freshness::Expired);
+    }
+
+    #[test]
+    fn freshness_scales_with_the_interval() {
+        let age = Duration::from_secs(30);
+
+        assert_eq!(Freshness::for_age(age, Duration::from_secs(60)), Freshness::Live);
+        assert_eq!(Freshness::for_age(age, Duration::from_secs(10)), Freshness::Aging);
+        assert_eq!(Freshness::for_age(age, Duration::from_secs(5)), Freshness::Stale);
+        assert_eq!(Freshness::for_age(age, Duration::from_secs(1)), Freshness::Expired);
+        // Tiers are ordered, so callers can ask for "at least this fresh"
+        assert!(Freshness::Live < Freshness::Aging && Freshness::Stale < Freshness::Expired);
+    }
+
+    #[test]
+    fn tds_rises_with_the_compensated_voltage() {
+        let at_25 = tds_from_voltage(1.0, 25.0, 1.0);
+        assert!((at_25 - 367.475).abs() < 0.01, "{at_25}");
+        // Warmer water conducts better, the same voltage means less dissolved solids
+        assert!(tds_from_voltage(1.0, 30.0, 1.0) < at_25);
+        assert_eq!(tds_from_voltage(1.0, 25.0, 1.1), at_25 * 1.1);
+    }
+
+    #[test]
+    fn adaptive_interval_backs_off_to_the_maximum_while_readings_are_steady() {
+        let max = Duration::from_secs(60);
+        let mut interval = INTERVAL;
+        let mut seen = vec![];
+        for _ in 0..6 {
+            interval = adapt(interval, max, None);
+            seen.push(interval.as_secs());
+        }
+        assert_eq!(seen, [10, 20, 40, 60, 60, 60]);
+    }
+
+    #[test]
+    fn adaptive_interval_recovers_at_once_and_holds() {
+        let max = Duration::from_secs(60);
+
+        assert_eq!(adapt(max, max, Some(Duration::ZERO)), INTERVAL);
+        assert_eq!(adapt(INTERVAL, max, Some(FAST_HOLD - Duration::from_secs(1))), INTERVAL);
+        // Backs off again once the hold is over
+        assert_eq!(adapt(INTERVAL, max, Some(FAST_HOLD)), INTERVAL * 2);
+    }
+
+    #[test]
+    fn steady_readings_are_not_fast() {
+        let previous = values(25.0);
+        let mut latest = values(25.0 + FAST_TEMPERATURE_DELTA / 2.0);
+        latest.tds += FAST_TDS_DELTA / 2.0;
+        assert!(!is_fast(Some(previous), &latest));
+
+        // A sensor switched off reads NaN, which never counts as moving
+        let mut previous = previous;
+        previous.tds_enabled = false;
+        previous.tds = f32::NAN;
+        let mut latest = values(25.0);
+        latest.tds_enabled = false;
+        latest.tds = f32::NAN;
+        assert!(!is_fast(Some(previous), &latest));
+    }
+
+    #[test]
+    fn moving_or_doubtful_readings_are_fast() {
+        let previous = values(25.0);
+        assert!(is_fast(None, &previous), "first reading");
+        assert!(is_fast(Some(previous), &values(25.0 + FAST_TEMPERATURE_DELTA * 2.0)));
+
+        let mut latest = values(25.0);
+        latest.tds += FAST_TDS_DELTA * 2.0;
+        assert!(is_fast(Some(previous), &latest));
+
+        let mut latest = values(25.0);
+        latest.warming_up = true;
+        assert!(is_fast(Some(previous), &latest), "warming up");
+
+        let mut latest = values(25.0);
+        latest.compensation.fallback = true;
+        assert!(is_fast(Some(previous), &latest), "compensation fallback");
+
+        let mut latest = values(25.0);
+        latest.temperature_enabled = false;
+        latest.temperature = f32::NAN;
+        assert!(is_fast(Some(previous), &latest), "sensor switched off");
+
+        assert!(is_fast(Some(values(150.0)), &values(150.0)), "implausible");
+    }
 }
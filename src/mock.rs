@@ -0,0 +1,167 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// An I2C bus double in the spirit of embedded-hal-mock, for testing drivers against their bus traffic.
+// Devices are modelled as plain register files: a write sets the register pointer and stores any
+// bytes after it, a read returns the bytes stored at the pointer.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    ptr,
+    sync::{
+        Mutex,
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+    },
+};
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+use esp_idf_svc::{
+    hal::i2c::I2cError,
+    sys::{self, EspError},
+};
+
+/// One transaction as the bus saw it, a write followed by a read being a single one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Transaction {
+    pub address: u8,
+    pub written: Vec<u8>,
+    pub read: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct I2c {
+    /// Addresses that acknowledge, any other is answered with a NACK
+    present: Vec<u8>,
+    registers: HashMap<(u8, u8), Vec<u8>>,
+    pointers: HashMap<u8, u8>,
+    /// Every transaction attempted, including the NACKed ones
+    pub transactions: Vec<Transaction>,
+}
+
+impl I2c {
+    pub fn new(present: &[u8]) -> Self {
+        Self {
+            present: present.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_register(mut self, address: u8, register: u8, value: &[u8]) -> Self {
+        self.registers.insert((address, register), value.to_vec());
+        self
+    }
+}
+
+impl ErrorType for I2c {
+    type Error = I2cError;
+}
+
+impl embedded_hal::i2c::I2c for I2c {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        let mut transaction = Transaction {
+            address,
+            written: vec![],
+            read: 0,
+        };
+        let present = self.present.contains(&address);
+
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(bytes) => {
+                    transaction.written.extend_from_slice(bytes);
+                    if let Some((&register, value)) = bytes.split_first().filter(|_| present) {
+                        self.pointers.insert(address, register);
+                        if !value.is_empty() {
+                            self.registers.insert((address, register), value.to_vec());
+                        }
+                    }
+                }
+                Operation::Read(buffer) => {
+                    transaction.read += buffer.len();
+                    let register = self.pointers.get(&address).copied().unwrap_or_default();
+                    let value = self.registers.get(&(address, register)).map_or(&[][..], Vec::as_slice);
+                    for (i, byte) in buffer.iter_mut().enumerate() {
+                        *byte = value.get(i).copied().unwrap_or_default();
+                    }
+                }
+            }
+        }
+        self.transactions.push(transaction);
+
+        if !present {
+            return Err(I2cError::new(
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+                EspError::from_infallible::<{ sys::ESP_FAIL }>(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A bus on which every device acknowledges and nothing is recorded, for when the double itself must not
+/// allocate. Reads leave the buffer untouched.
+pub(crate) struct Sink;
+
+impl ErrorType for Sink {
+    type Error = I2cError;
+}
+
+impl embedded_hal::i2c::I2c for Sink {
+    fn transaction(&mut self, _address: u8, _operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The system allocator, counting allocations made by the task inside `count_allocations`. Installed as
+/// the global allocator of test builds.
+pub(crate) struct CountingAllocator;
+
+static COUNTED_TASK: AtomicPtr<sys::tskTaskControlBlock> = AtomicPtr::new(ptr::null_mut());
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Held while counting, so tests running at the same time take turns
+static COUNTING: Mutex<()> = Mutex::new(());
+
+impl CountingAllocator {
+    fn count(&self) {
+        let task = COUNTED_TASK.load(Ordering::Acquire);
+        // Other tasks allocating meanwhile are not the code under test
+        if !task.is_null() && task == unsafe { sys::xTaskGetCurrentTaskHandle() } {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+/// How many times `f` allocated or grew an allocation on the heap.
+pub(crate) fn count_allocations(f: impl FnOnce()) -> usize {
+    let _counting = COUNTING.lock().unwrap();
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    COUNTED_TASK.store(unsafe { sys::xTaskGetCurrentTaskHandle() }, Ordering::Release);
+    f();
+    COUNTED_TASK.store(ptr::null_mut(), Ordering::Release);
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,684 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    task,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{
+    breaker::{self, Breaker},
+    chunks::Chunks,
+    command, http, labels, lockdown, measurements, network, nvs, registry, system,
+    template::{self, Escape},
+};
+
+// How often the worker looks for a new measurement, well below the measurement interval
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Home Assistant reads the state out of the JSON availability payload with this
+const AVAILABILITY_TEMPLATE: &str = "{{ value_json.state }}";
+
+// History chunks stay far below the 256 KiB brokers commonly accept, each is built in the heap in full
+const HISTORY_CHUNK_LEN: usize = 4096;
+
+// Commands waiting for the worker, further ones are dropped until it caught up
+const MAX_PENDING_COMMANDS: usize = 4;
+// Correlation IDs remembered, a command the broker delivers again within these is not run twice
+const RECENT_COMMAND_IDS: usize = 8;
+const MAX_COMMAND_ID_LEN: usize = 64;
+
+// Home Assistant's default, only changed there for brokers shared by several installations
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const MODEL: &str = "Cobitis";
+
+// Set from the client's event callback, which runs on the MQTT task
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BREAKER: Mutex<Breaker> = Mutex::new(Breaker::new());
+// A history export request waiting for the worker, one at a time
+static HISTORY_REQUEST: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+static HISTORY_REJECTED: AtomicU32 = AtomicU32::new(0);
+// Commands by the name in their topic, with their payload
+static COMMANDS: Mutex<VecDeque<(String, Vec<u8>)>> = Mutex::new(VecDeque::new());
+static COMMANDS_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) struct Context {
+    client: EspMqttClient<'static>,
+    /// Topic prefix without the trailing slash, e.g. `cobitis-a1b2c3`.
+    prefix: String,
+    state_topic: String,
+    rssi_topic: String,
+    availability_topic: String,
+    /// Where history exports and commands are requested, subscribed to on every connection.
+    command_filter: String,
+    /// Commands run lately as their name and correlation ID, oldest first.
+    recent_commands: VecDeque<(String, String)>,
+    /// Shape of the state payload in place of the `/` message, see template.rs.
+    state_template: Option<(String, Escape)>,
+    /// Home Assistant discovery configs by topic, published on every connection.
+    discovery: Vec<(String, Vec<u8>)>,
+    /// Connection `online` was last published on, it has to go out again after every reconnect.
+    announced: Option<u32>,
+    /// Sequence number of the last measurement published.
+    published_seq: Option<u32>,
+}
+
+/// Describes one entity of the device to Home Assistant, see
+/// https://www.home-assistant.io/integrations/sensor.mqtt/
+#[derive(Serialize)]
+struct DiscoveryConfig<'a> {
+    name: &'a str,
+    unique_id: String,
+    state_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<String>,
+    unit_of_measurement: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    state_class: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_display_precision: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    availability_topic: &'a str,
+    availability_template: &'a str,
+    device: &'a DiscoveryDevice<'a>,
+}
+
+/// Groups the entities under one device in Home Assistant.
+#[derive(Serialize)]
+struct DiscoveryDevice<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    model: &'a str,
+    sw_version: &'a str,
+}
+
+/// Connects to the broker in `net.mqtt.url`, or returns `None` when it is unset or the device runs its
+/// setup access point.
+pub(crate) fn init() -> anyhow::Result<Option<Box<Context>>> {
+    task::block_in_place(move || {
+        let Some(url) = nvs::get_opt("net.mqtt.url")? else {
+            return Ok(None);
+        };
+        if network::setup().is_some() {
+            return Ok(None);
+        }
+
+        let user = nvs::get_opt("net.mqtt.user")?;
+        let pass = nvs::get_opt("net.mqtt.pass")?;
+        let prefix = nvs::get_opt("net.mqtt.topic_prefix")?.unwrap_or_else(|| system::device_id().to_owned());
+        let prefix = prefix.trim_end_matches('/');
+        let state_topic = format!("{prefix}/state");
+        let rssi_topic = format!("{prefix}/rssi");
+        let availability_topic = format!("{prefix}/availability");
+        let state_template = template::load("net.mqtt.state_template", "net.mqtt.state_template_json")?;
+        let discovery = if nvs::get_flag_or("net.mqtt.discovery.enabled", true)? {
+            let discovery_prefix = nvs::get_opt("net.mqtt.discovery.prefix")?;
+            let discovery_prefix = discovery_prefix.as_deref().unwrap_or(DEFAULT_DISCOVERY_PREFIX);
+            discovery(
+                discovery_prefix.trim_end_matches('/'),
+                &state_topic,
+                &rssi_topic,
+                &availability_topic,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        // The broker publishes `offline` in our place when the connection drops without a goodbye
+        let offline = availability(false)?;
+        let conf = MqttClientConfiguration {
+            client_id: Some(system::device_id()),
+            username: user.as_deref(),
+            password: pass.as_deref(),
+            lwt: Some(LwtConfiguration {
+                topic: &availability_topic,
+                payload: &offline,
+                qos: QoS::AtMostOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        };
+
+        // The client reconnects by itself, the callback only keeps track for the worker
+        let request_topic = format!("{prefix}/cmd/history");
+        let command_prefix = format!("{prefix}/cmd/");
+        let client = EspMqttClient::new_cb(&url, &conf, move |event| match event.payload() {
+            EventPayload::Connected(_) => {
+                CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                CONNECTED.store(true, Ordering::Relaxed);
+                info!("MQTT connected");
+            }
+            EventPayload::Disconnected => {
+                if CONNECTED.swap(false, Ordering::Relaxed) {
+                    warn!("MQTT disconnected, retrying");
+                }
+            }
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                details: Details::Complete,
+                ..
+            } if topic == request_topic => {
+                if EXPORTING.swap(true, Ordering::Relaxed) {
+                    HISTORY_REJECTED.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    *HISTORY_REQUEST.lock().unwrap() = Some(data.to_vec());
+                }
+            }
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                details: Details::Complete,
+                ..
+            } => {
+                let Some(name) = topic.strip_prefix(command_prefix.as_str()) else {
+                    return;
+                };
+                let mut commands = COMMANDS.lock().unwrap();
+                if commands.len() < MAX_PENDING_COMMANDS {
+                    commands.push_back((name.to_owned(), data.to_vec()));
+                } else {
+                    COMMANDS_DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            EventPayload::Error(e) => warn!("MQTT error: {e:?}"),
+            _ => {}
+        })?;
+        info!("Publishing to {state_topic} on {url}");
+        ENABLED.store(true, Ordering::Relaxed);
+
+        Ok(Some(Box::new(Context {
+            client,
+            prefix: prefix.to_owned(),
+            state_topic,
+            rssi_topic,
+            availability_topic,
+            command_filter: format!("{prefix}/cmd/#"),
+            recent_commands: VecDeque::new(),
+            state_template,
+            discovery,
+            announced: None,
+            published_seq: None,
+        })))
+    })
+}
+
+/// Whether publishing is being skipped after failing repeatedly, for `/diagnostics`.
+pub(crate) fn breaker() -> Option<breaker::Status> {
+    ENABLED
+        .load(Ordering::Relaxed)
+        .then(|| BREAKER.lock().unwrap().status(Instant::now()))
+}
+
+/// Answers a history export request from `<prefix>/cmd/history`, and the ones turned away because an
+/// export was already under way.
+fn answer_history_requests(ctx: &mut Context) -> anyhow::Result<()> {
+    let error_topic = format!("{}/history/error", ctx.prefix);
+    let publish_error = |client: &mut EspMqttClient<'static>, message: &str| {
+        let payload = serde_json::to_vec(&serde_json::json!({ "error": message }))?;
+        client.publish(&error_topic, QoS::AtMostOnce, false, &payload)?;
+        anyhow::Ok(())
+    };
+
+    for _ in 0..HISTORY_REJECTED.swap(0, Ordering::Relaxed) {
+        publish_error(&mut ctx.client, "Another history export is in progress")?;
+    }
+
+    let Some(request) = HISTORY_REQUEST.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let result = export_history(ctx, &request);
+    EXPORTING.store(false, Ordering::Relaxed);
+    if let Err(e) = result {
+        warn!("Failed to export history over MQTT: {e:#}");
+        publish_error(&mut ctx.client, &format!("{e:#}"))?;
+    }
+
+    Ok(())
+}
+
+/// Publishes the history matching `request` in chunks on `<prefix>/history/chunk/<n>`, then their count
+/// and checksum on `<prefix>/history/done`.
+fn export_history(ctx: &mut Context, request: &[u8]) -> anyhow::Result<()> {
+    let (since, limit) = parse_history_request(request)?;
+    let samples = measurements::get_history(since);
+    let records = samples
+        .into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(http::Message::from);
+
+    let mut chunks = Chunks::new(records, HISTORY_CHUNK_LEN);
+    let mut n = 0;
+    while let Some(chunk) = chunks.next_chunk()? {
+        let topic = format!("{}/history/chunk/{n}", ctx.prefix);
+        ctx.client.publish(&topic, QoS::AtMostOnce, false, &chunk)?;
+        n += 1;
+    }
+    let summary = serde_json::to_vec(&chunks.summary())?;
+    ctx.client.publish(
+        &format!("{}/history/done", ctx.prefix),
+        QoS::AtMostOnce,
+        false,
+        &summary,
+    )?;
+    info!("Exported {} history records over MQTT", chunks.summary().records);
+
+    Ok(())
+}
+
+/// Runs the commands received on `<prefix>/cmd/<name>` through the same registry as `POST /command`,
+/// publishing each outcome on `<prefix>/result/<name>/<id>`.
+fn answer_commands(ctx: &mut Context) -> anyhow::Result<()> {
+    let dropped = COMMANDS_DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("Dropped {dropped} MQTT commands, they arrived faster than they could be run");
+    }
+
+    loop {
+        // Not held while the command runs, the client's callback would wait for it
+        let next = COMMANDS.lock().unwrap().pop_front();
+        let Some((name, payload)) = next else {
+            return Ok(());
+        };
+        let (id, reply) = run_command(&name, &payload, lockdown::enabled());
+        if let Some(id) = &id {
+            // QoS 1 delivers a command again when the broker missed the acknowledgement
+            let key = (name.clone(), id.clone());
+            if ctx.recent_commands.contains(&key) {
+                info!("Ignoring MQTT command {name} {id} delivered again");
+                continue;
+            }
+            if ctx.recent_commands.len() == RECENT_COMMAND_IDS {
+                ctx.recent_commands.pop_front();
+            }
+            ctx.recent_commands.push_back(key);
+        }
+
+        let topic = result_topic(&ctx.prefix, &name, id.as_deref());
+        ctx.client
+            .publish(&topic, QoS::AtLeastOnce, false, &serde_json::to_vec(&reply)?)?;
+    }
+}
+
+/// Outcome of a command received over MQTT, as `POST /command` would have answered it.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum CommandReply {
+    Done(command::Outcome),
+    Refused { error: String },
+}
+
+/// Runs the command `name` with a `{"id": ..., "args": ...}` payload, both optional, returning the
+/// correlation ID to answer under along with the reply.
+fn run_command(name: &str, payload: &[u8], lockdown: bool) -> (Option<String>, CommandReply) {
+    #[derive(Default, Deserialize)]
+    struct Request {
+        id: Option<String>,
+        #[serde(default)]
+        args: Value,
+    }
+
+    let refused = |message: String| CommandReply::Refused { error: message };
+    let request: Request = if payload.iter().all(u8::is_ascii_whitespace) {
+        Request::default()
+    } else {
+        match serde_json::from_slice(payload) {
+            Ok(request) => request,
+            Err(e) => return (None, refused(format!("Malformed JSON: {e}"))),
+        }
+    };
+    // Part of the result topic, so it can't contain a level separator or a wildcard
+    if let Some(id) = &request.id {
+        if id.is_empty() || id.len() > MAX_COMMAND_ID_LEN || id.contains(['/', '+', '#']) {
+            let message = format!("id must be 1 to {MAX_COMMAND_ID_LEN} characters without /, + or #");
+            return (None, refused(message));
+        }
+    }
+    if lockdown {
+        return (
+            request.id,
+            refused("Device is in lockdown, changes are disabled".to_owned()),
+        );
+    }
+
+    let reply = match command::dispatch(name, request.args) {
+        Ok(outcome) => CommandReply::Done(outcome),
+        Err(command::Error::Rejected(message)) => refused(message),
+        Err(command::Error::Failed(e)) => {
+            error!("MQTT command {name} failed: {e:?}");
+            refused(format!("{e:#}"))
+        }
+    };
+    (request.id, reply)
+}
+
+fn result_topic(prefix: &str, name: &str, id: Option<&str>) -> String {
+    match id {
+        Some(id) => format!("{prefix}/result/{name}/{id}"),
+        None => format!("{prefix}/result/{name}"),
+    }
+}
+
+/// Parses `{"since": ..., "limit": ...}`, both optional, `since` being a timestamp or a record ID as for
+/// `/history`. An empty payload asks for everything.
+fn parse_history_request(payload: &[u8]) -> anyhow::Result<(measurements::Since, Option<usize>)> {
+    #[derive(Default, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Request {
+        since: Option<Value>,
+        limit: Option<usize>,
+    }
+
+    let request: Request = if payload.iter().all(u8::is_ascii_whitespace) {
+        Request::default()
+    } else {
+        serde_json::from_slice(payload).map_err(|e| anyhow!("Invalid history request: {e}"))?
+    };
+    let since = match request.since {
+        None | Some(Value::Null) => measurements::Since::Start,
+        Some(Value::Number(n)) => n
+            .as_i64()
+            .map(measurements::Since::Timestamp)
+            .ok_or(anyhow!("since must be a timestamp in ms or a record ID"))?,
+        Some(Value::String(s)) => s.parse()?,
+        Some(_) => return Err(anyhow!("since must be a timestamp in ms or a record ID")),
+    };
+
+    Ok((since, request.limit))
+}
+
+/// Payload of the availability topic. It names the boot, so the `offline` left by a boot that crashed can be
+/// told apart from the `online` of the one after it.
+fn availability(online: bool) -> anyhow::Result<Vec<u8>> {
+    #[derive(Serialize)]
+    struct Availability {
+        state: &'static str,
+        boot_id: system::BootId,
+    }
+
+    let state = if online { "online" } else { "offline" };
+    Ok(serde_json::to_vec(&Availability {
+        state,
+        boot_id: system::boot_id(),
+    })?)
+}
+
+/// Discovery configs for the readings and the WiFi signal, which make the device show up in Home Assistant
+/// without any YAML.
+fn discovery(
+    prefix: &str,
+    state_topic: &str,
+    rssi_topic: &str,
+    availability_topic: &str,
+) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let device_id = system::device_id();
+    let device = DiscoveryDevice {
+        identifiers: [device_id],
+        name: labels::device_name(),
+        model: MODEL,
+        sw_version: env!("CARGO_PKG_VERSION"),
+    };
+    let topic = |object: &str| format!("{prefix}/sensor/{device_id}/{object}/config");
+
+    let mut configs = Vec::new();
+    for metric in [&registry::TEMPERATURE, &registry::TDS] {
+        let config = DiscoveryConfig {
+            name: labels::metric(metric),
+            unique_id: format!("{device_id}_{}", metric.name),
+            state_topic,
+            // A sensor switched off reports `disabled`, which Home Assistant shows as unknown
+            value_template: Some(format!("{{{{ value_json.{} | float(none) }}}}", metric.name)),
+            unit_of_measurement: metric.label,
+            device_class: metric.device_class,
+            state_class: "measurement",
+            suggested_display_precision: Some(metric.display_precision),
+            entity_category: None,
+            availability_topic,
+            availability_template: AVAILABILITY_TEMPLATE,
+            device: &device,
+        };
+        configs.push((topic(metric.name), serde_json::to_vec(&config)?));
+    }
+
+    let config = DiscoveryConfig {
+        name: "WiFi signal",
+        unique_id: format!("{device_id}_rssi"),
+        state_topic: rssi_topic,
+        value_template: None,
+        unit_of_measurement: "dBm",
+        device_class: Some("signal_strength"),
+        state_class: "measurement",
+        suggested_display_precision: None,
+        entity_category: Some("diagnostic"),
+        availability_topic,
+        availability_template: AVAILABILITY_TEMPLATE,
+        device: &device,
+    };
+    configs.push((topic("rssi"), serde_json::to_vec(&config)?));
+
+    Ok(configs)
+}
+
+pub(crate) async fn worker(ctx: Option<&mut Box<Context>>) -> anyhow::Result<()> {
+    // Without a broker, there is nothing to publish to
+    let Some(ctx) = ctx else {
+        return std::future::pending().await;
+    };
+
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = task::block_in_place(|| update(ctx)) {
+            error!("Failed to publish to MQTT: {e:?}");
+        }
+    }
+}
+
+fn update(ctx: &mut Context) -> anyhow::Result<()> {
+    if !CONNECTED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    // The last will may have replaced `online` while the connection was down
+    let connection = CONNECTIONS.load(Ordering::Relaxed);
+    let announce = ctx.announced != Some(connection);
+    if !announce {
+        answer_history_requests(ctx)?;
+        answer_commands(ctx)?;
+    }
+    // Republishes the latest state along with `online`, a broker that lost its retained messages gets it back
+    let values = measurements::get().filter(|values| announce || ctx.published_seq != Some(values.seq));
+    if !announce && values.is_none() {
+        return Ok(());
+    }
+    if !BREAKER.lock().unwrap().allow(Instant::now()) {
+        return Ok(());
+    }
+
+    let result = publish(ctx, connection, values);
+    let mut breaker = BREAKER.lock().unwrap();
+    match &result {
+        Ok(()) => breaker.success(),
+        Err(_) => {
+            if breaker.failure(Instant::now()) {
+                warn!("MQTT keeps failing, skipping it for {}s", breaker.cool_off().as_secs());
+            }
+        }
+    }
+
+    result
+}
+
+fn publish(ctx: &mut Context, connection: u32, values: Option<measurements::Values>) -> anyhow::Result<()> {
+    if ctx.announced != Some(connection) {
+        // Retained, so Home Assistant finds the device again after a restart of its own
+        for (topic, config) in &ctx.discovery {
+            ctx.client.publish(topic, QoS::AtMostOnce, true, config)?;
+        }
+        ctx.client
+            .publish(&ctx.availability_topic, QoS::AtMostOnce, true, &availability(true)?)?;
+        ctx.client.subscribe(&ctx.command_filter, QoS::AtLeastOnce)?;
+        ctx.announced = Some(connection);
+    }
+
+    let Some(values) = values else {
+        return Ok(());
+    };
+
+    // Home Assistant discovery reads the fields of the default payload, a template has to keep them for it
+    let payload = match &ctx.state_template {
+        Some((template, escape)) => template::render(template, *escape, &template::Fields::new(&values))?.into_bytes(),
+        None => serde_json::to_vec(&http::Message::from(values))?,
+    };
+    ctx.client.publish(&ctx.state_topic, QoS::AtMostOnce, true, &payload)?;
+    if let Some(rssi) = network::rssi() {
+        ctx.client
+            .publish(&ctx.rssi_topic, QoS::AtMostOnce, true, rssi.to_string().as_bytes())?;
+    }
+    ctx.published_seq = Some(values.seq);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    #[test]
+    fn history_requests_take_a_timestamp_or_record_id() {
+        assert_eq!(parse_history_request(b"").unwrap(), (measurements::Since::Start, None));
+        assert_eq!(
+            parse_history_request(br#"{"since":1700000000000,"limit":50}"#).unwrap(),
+            (measurements::Since::Timestamp(1_700_000_000_000), Some(50))
+        );
+        assert_eq!(
+            parse_history_request(br#"{"since":"1700000000000"}"#).unwrap(),
+            (measurements::Since::Timestamp(1_700_000_000_000), None)
+        );
+        for payload in [&br#"{"since":true}"#[..], br#"{"limit":-1}"#, br#"{"from":1}"#, b"[]"] {
+            assert!(
+                parse_history_request(payload).is_err(),
+                "{}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+    }
+
+    /// Plays the part of the broker: delivers `topic` the way the client's callback does and returns what
+    /// the device publishes in reply.
+    fn exchange(topic: &str, payload: &str, lockdown: bool) -> (String, Value) {
+        let name = topic.strip_prefix("tank/cmd/").unwrap();
+        let (id, reply) = run_command(name, payload.as_bytes(), lockdown);
+        (
+            result_topic("tank", name, id.as_deref()),
+            serde_json::to_value(reply).unwrap(),
+        )
+    }
+
+    #[test]
+    fn commands_are_answered_under_their_correlation_id() {
+        assert_eq!(
+            exchange("tank/cmd/measure_now", r#"{"id":"a1"}"#, false),
+            (
+                "tank/result/measure_now/a1".to_owned(),
+                json!({ "cmd": "measure_now", "result": null })
+            )
+        );
+        assert_eq!(
+            exchange(
+                "tank/cmd/display_test",
+                r#"{"id":"a2","args":{"pattern":"all_on","seconds":5}}"#,
+                false
+            ),
+            (
+                "tank/result/display_test/a2".to_owned(),
+                json!({ "cmd": "display_test", "result": { "seconds": 5 } })
+            )
+        );
+        crate::display::stop_test();
+        // Without an ID, the reply goes to the command's own result topic
+        assert_eq!(exchange("tank/cmd/measure_now", "", false).0, "tank/result/measure_now");
+    }
+
+    #[test]
+    fn commands_are_validated_like_over_http() {
+        let (topic, reply) = exchange("tank/cmd/reboot", r#"{"id":"b1"}"#, false);
+        assert_eq!(topic, "tank/result/reboot/b1");
+        assert!(
+            reply["error"].as_str().unwrap().starts_with("Unknown command reboot"),
+            "{reply}"
+        );
+
+        let (topic, reply) = exchange("tank/cmd/measure_now", r#"{"id":"b2","args":{"bogus":1}}"#, false);
+        assert_eq!(topic, "tank/result/measure_now/b2");
+        assert!(
+            reply["error"].as_str().unwrap().starts_with("Invalid arguments"),
+            "{reply}"
+        );
+
+        for payload in ["{", r#"{"id":""}"#, r#"{"id":"a/b"}"#, r##"{"id":"#"}"##] {
+            let (topic, reply) = exchange("tank/cmd/measure_now", payload, false);
+            assert_eq!(topic, "tank/result/measure_now", "{payload}");
+            assert!(reply["error"].is_string(), "{payload}");
+        }
+    }
+
+    #[test]
+    fn lockdown_refuses_commands() {
+        assert_eq!(
+            exchange("tank/cmd/measure_now", r#"{"id":"c1"}"#, true),
+            (
+                "tank/result/measure_now/c1".to_owned(),
+                json!({ "error": "Device is in lockdown, changes are disabled" })
+            )
+        );
+    }
+
+    #[test]
+    fn availability_names_the_boot() {
+        let boot_id = system::boot_id().to_string();
+        for (online, state) in [(true, "online"), (false, "offline")] {
+            let payload: Value = serde_json::from_slice(&availability(online).unwrap()).unwrap();
+            assert_eq!(payload, json!({ "state": state, "boot_id": boot_id }));
+        }
+    }
+
+    #[test]
+    fn discovery_reads_the_state_out_of_the_availability_payload() {
+        let configs = discovery("homeassistant", "tank/state", "tank/rssi", "tank/availability").unwrap();
+        assert_eq!(configs.len(), 3);
+        for (topic, config) in configs {
+            let config: Value = serde_json::from_slice(&config).unwrap();
+            assert_eq!(config["availability_topic"], "tank/availability", "{topic}");
+            assert_eq!(config["availability_template"], AVAILABILITY_TEMPLATE, "{topic}");
+        }
+    }
+}
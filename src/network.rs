@@ -3,21 +3,29 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use std::time::Duration;
+use std::{
+    ffi::CString,
+    net::Ipv4Addr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    hal::{delay::FreeRtos, io::Write, modem::Modem},
-    http::{
-        Method,
-        server::{Configuration as ServerConfiguration, EspHttpServer},
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
+    hal::{delay::FreeRtos, modem::Modem},
+    mdns::EspMdns,
+    sntp::{EspSntp, SntpConf, SyncMode, SyncStatus},
+    sys,
+    wifi::{
+        AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration as WifiConfiguration, EspWifi,
+        WifiEvent,
     },
-    sntp::{EspSntp, SntpConf, SyncStatus},
-    wifi::{ClientConfiguration, Configuration as WifiConfiguration, EspWifi},
 };
-use futures::executor;
-use log::error;
+use log::{error, info, warn};
 use serde::Serialize;
 use tokio::{
     sync::RwLock,
@@ -25,17 +33,63 @@ use tokio::{
     time::{MissedTickBehavior, interval},
 };
 
-use crate::{measurements, nvs};
+use crate::{
+    captive, journal, labels, lifecycle, nvs, retry::retry_blocking, simulation, system, thermal, troubleshoot,
+};
 
 const DELAY: u32 = 10;
 const MAX_TIMEOUT: u32 = 10_000 / DELAY; // 10 seconds / 10 ms
 
+// Connection attempts at boot before giving up on the stored credentials and opening the setup AP
+const CONNECT_ATTEMPTS: u32 = 3;
+// Minutes the setup AP waits for the form before rebooting to try the stored credentials again
+const DEFAULT_SETUP_TIMEOUT: u32 = 15;
+const MAX_SETUP_TIMEOUT: u32 = 24 * 60;
+const SETUP_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Each scan takes the AP off its channel for a moment, clients of the form hardly notice at this rate
+const SETUP_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+// Must match CONFIG_LWIP_SNTP_MAX_SERVERS in sdkconfig.defaults
+const MAX_NTP_SERVERS: usize = 3;
+// For boards set up through the access point, where the form leaves the server optional
+const DEFAULT_NTP_SERVER: &str = "pool.ntp.org";
+// Lower bound enforced by ESP-IDF
+const MIN_NTP_INTERVAL: u32 = 15;
+
+const TX_POWER_RANGE: std::ops::RangeInclusive<u8> = 2..=20;
+// Driver default, restored when throttling ends without a configured power
+const DEFAULT_TX_POWER: u8 = 20;
+const THROTTLED_TX_POWER: u8 = 8;
+
+// Found as `cobitis.local`, a second device on the same network needs a name of its own
+const DEFAULT_HOSTNAME: &str = "cobitis";
+// Longest DNS label
+const MAX_HOSTNAME_LEN: usize = 63;
+const HTTP_PORT: u16 = 80;
+
 pub(crate) struct Context<'a> {
     wifi: EspWifi<'a>,
+    tx_power_dbm: Option<u8>,
+    applied_tx_power: Option<u8>,
+    // Not started in setup mode, where there is no uplink
+    #[allow(dead_code)]
+    ntp: Option<EspSntp<'a>>,
+    // Keeps disconnect reasons coming in for the troubleshooting checks
     #[allow(dead_code)]
-    ntp: EspSntp<'a>,
+    wifi_events: EspSubscription<'static, System>,
+    // Answers for `<hostname>.local` while kept, not started in setup mode
     #[allow(dead_code)]
-    server: EspHttpServer<'a>,
+    mdns: Option<EspMdns>,
+    // Stored network the setup AP could not join, watched for so the device goes back to it
+    setup_ssid: Option<String>,
+}
+
+/// Access point opened for setup when the device has no working WiFi credentials.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SetupAp {
+    pub ssid: String,
+    pub password: String,
+    pub address: String,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -82,56 +136,360 @@ impl SignalQuality {
     }
 }
 
-#[derive(Debug, Serialize)]
-pub(crate) struct Message {
-    pub timestamp: i64,
-    pub temperature: f32,
-    pub tds: i32,
+/// Outcome of the most recent NTP synchronization.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct NtpStatus {
+    pub server: Option<String>,
+    pub last_sync: i64,
+    /// Estimated clock correction applied by this sync, unknown for the first one.
+    pub offset_ms: Option<i64>,
 }
 
-impl From<measurements::Values> for Message {
-    fn from(value: measurements::Values) -> Self {
-        Self {
-            timestamp: value.timestamp,
-            temperature: value.temperature,
-            tds: value.tds as i32,
-        }
-    }
+/// Configured and effective WiFi transmit power, which may be rounded to what the PHY supports.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct TxPower {
+    pub requested_dbm: u8,
+    pub actual_dbm: f32,
+}
+
+struct SetupSession {
+    timeout: Duration,
+    deadline: Instant,
+}
+
+struct NtpSync {
+    status: NtpStatus,
+    instant: Instant,
 }
 
 static STATUS: RwLock<Option<Status>> = RwLock::const_new(None);
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static NTP_SERVERS: OnceLock<Vec<String>> = OnceLock::new();
+static NTP_SYNC: Mutex<Option<NtpSync>> = Mutex::new(None);
+static TX_POWER: Mutex<Option<TxPower>> = Mutex::new(None);
+// Configured TX power in dBm, 0 for none. Picked up by the worker, so a change applies without a reboot
+static CONFIGURED_TX_POWER: AtomicU8 = AtomicU8::new(0);
+static SETUP: OnceLock<SetupAp> = OnceLock::new();
+// Only with stored credentials to go back to and a timeout configured
+static SETUP_SESSION: Mutex<Option<SetupSession>> = Mutex::new(None);
+static IP: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+static LAST_DISCONNECT: Mutex<Option<u16>> = Mutex::new(None);
+static RSSI: Mutex<Option<i32>> = Mutex::new(None);
 
 pub(crate) async fn get() -> Option<Status> {
     *STATUS.read().await
 }
 
+/// Whether WiFi was connected at the last check, readable from synchronous contexts.
+pub(crate) fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn tx_power() -> Option<TxPower> {
+    *TX_POWER.lock().unwrap()
+}
+
+pub(crate) fn ntp_status() -> Option<NtpStatus> {
+    NTP_SYNC.lock().unwrap().as_ref().map(|sync| sync.status.clone())
+}
+
+/// The setup access point, while the device is waiting to be given WiFi credentials.
+pub(crate) fn setup() -> Option<&'static SetupAp> {
+    SETUP.get()
+}
+
+/// Time left before the setup access point gives up and the stored credentials are tried again, `None`
+/// when it stays up until the form is submitted.
+pub(crate) fn setup_remaining() -> Option<Duration> {
+    let session = SETUP_SESSION.lock().unwrap();
+    session
+        .as_ref()
+        .map(|session| session.deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Restarts the setup timeout, for someone still typing. Returns the time left, `None` without a timeout.
+pub(crate) fn extend_setup() -> Option<Duration> {
+    let mut session = SETUP_SESSION.lock().unwrap();
+    let session = session.as_mut()?;
+    session.deadline = Instant::now() + session.timeout;
+    Some(session.timeout)
+}
+
+/// Address the router gave at the last successful connection, cleared while reconnecting.
+pub(crate) fn ip() -> Option<Ipv4Addr> {
+    *IP.lock().unwrap()
+}
+
+/// Signal strength in dBm at the last check, readable from synchronous contexts.
+pub(crate) fn rssi() -> Option<i32> {
+    *RSSI.lock().unwrap()
+}
+
+/// Reason code (`wifi_err_reason_t`) of the last time the station lost or failed to join the network,
+/// cleared once it associates.
+pub(crate) fn last_disconnect() -> Option<u16> {
+    *LAST_DISCONNECT.lock().unwrap()
+}
+
+/// Whether the station is associated with an access point, regardless of having an address yet.
+pub(crate) fn associated() -> bool {
+    associated_rssi().is_some()
+}
+
+fn associated_rssi() -> Option<i8> {
+    let mut record = sys::wifi_ap_record_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut record) })
+        .ok()
+        .map(|()| record.rssi)
+}
+
+/// Signal strength of `ssid` in dBm, `None` when it is not in range. Taken from the current association
+/// when there is one, since scanning would interrupt it; needs the station interface otherwise.
+pub(crate) fn find_ap(ssid: &str) -> anyhow::Result<Option<i8>> {
+    if let Some(rssi) = associated_rssi() {
+        return Ok(Some(rssi));
+    }
+
+    let ssid = CString::new(ssid)?;
+    let config = sys::wifi_scan_config_t {
+        ssid: ssid.as_ptr() as *mut u8,
+        ..Default::default()
+    };
+    sys::esp!(unsafe { sys::esp_wifi_scan_start(&config, true) })?;
+
+    // Records come strongest first, only the best one matters
+    let mut count = 1_u16;
+    let mut record = sys::wifi_ap_record_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_scan_get_ap_records(&mut count, &mut record) })?;
+
+    Ok((count > 0).then_some(record.rssi))
+}
+
+/// Joins the configured network, or opens the setup access point when there are no credentials or
+/// they don't get the device connected.
 pub(crate) fn init<'a>(modem: Modem, event_loop: EspSystemEventLoop) -> anyhow::Result<Box<Context<'a>>> {
     task::block_in_place(move || {
-        let wifi = init_wifi(modem, event_loop)?;
+        reload_tx_power()?;
+        let tx_power_dbm = configured_tx_power();
+
+        // Subscribed first, so the reasons the boot attempts fail for are kept
+        let wifi_events = event_loop.subscribe::<WifiEvent, _>(on_wifi_event)?;
+        let mut wifi = EspWifi::new(modem, event_loop, None)?;
+        let ssid = nvs::get_opt("net.wifi.ssid")?;
+        let connected = match &ssid {
+            Some(ssid) => {
+                let psk = nvs::get_opt("net.wifi.psk")?.unwrap_or_default();
+                match init_station(&mut wifi, ssid, &psk) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Failed to join {ssid}, opening the setup access point: {e:?}");
+                        false
+                    }
+                }
+            }
+            None => {
+                // Nothing to connect to counts as deliberately offline, not as a failure
+                lifecycle::reach(lifecycle::Milestone::Online);
+                warn!("No WiFi credentials, opening the setup access point");
+                false
+            }
+        };
+
+        if !connected {
+            init_setup_ap(&mut wifi, ssid.is_some())?;
+            return Ok(Box::new(Context {
+                wifi,
+                tx_power_dbm,
+                applied_tx_power: None,
+                ntp: None,
+                wifi_events,
+                mdns: None,
+                setup_ssid: ssid,
+            }));
+        }
+
+        if let Some(dbm) = tx_power_dbm {
+            apply_tx_power(dbm)?;
+        }
         let ntp = init_ntp()?;
-        let server = init_http_server()?;
+        // The address still works without it, only the name doesn't
+        let mdns = init_mdns().inspect_err(|e| warn!("Failed to start mDNS: {e:?}")).ok();
+
+        Ok(Box::new(Context {
+            wifi,
+            tx_power_dbm,
+            applied_tx_power: tx_power_dbm,
+            ntp: Some(ntp),
+            wifi_events,
+            mdns,
+            setup_ssid: None,
+        }))
+    })
+}
+
+fn init_station(wifi: &mut EspWifi<'_>, ssid: &str, psk: &str) -> anyhow::Result<()> {
+    wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration {
+        ssid: ssid.try_into().map_err(|e| anyhow!("{e:?}"))?,
+        password: psk.try_into().map_err(|e| anyhow!("{e:?}"))?,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
 
-        Ok(Box::new(Context { wifi, ntp, server }))
+    retry_blocking(CONNECT_ATTEMPTS, Duration::from_secs(1), || {
+        // A timed out attempt may still be in progress in the driver
+        let _ = wifi.disconnect();
+        connect_and_wait(wifi)
     })
 }
 
-fn init_wifi<'a>(modem: Modem, event_loop: EspSystemEventLoop) -> anyhow::Result<EspWifi<'a>> {
-    let ssid = nvs::get("ssid")?;
-    let psk = nvs::get("psk")?;
+/// Opens the setup access point. With stored credentials, the station stays up alongside to scan for their
+/// network, and the access point closes after `net.setup.timeout` minutes.
+fn init_setup_ap(wifi: &mut EspWifi<'_>, stored: bool) -> anyhow::Result<()> {
+    let ssid = format!("{}-setup", system::device_id());
+    // Without credentials the radio never came on, and the password needs it for entropy
+    if !wifi.is_started()? {
+        wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+    }
+    let password = system::setup_password()?;
 
-    let mut wifi = EspWifi::new(modem, event_loop, None)?;
-    wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration {
+    // Whatever the station attempt left behind is torn down first
+    let _ = wifi.stop();
+    let access_point = AccessPointConfiguration {
         ssid: ssid.as_str().try_into().map_err(|e| anyhow!("{e:?}"))?,
-        password: psk.as_str().try_into().map_err(|e| anyhow!("{e:?}"))?,
+        password: password.as_str().try_into().map_err(|e| anyhow!("{e:?}"))?,
+        auth_method: AuthMethod::WPA2Personal,
         ..Default::default()
-    }))?;
+    };
+    if stored {
+        wifi.set_configuration(&WifiConfiguration::Mixed(ClientConfiguration::default(), access_point))?;
+    } else {
+        wifi.set_configuration(&WifiConfiguration::AccessPoint(access_point))?;
+    }
     wifi.start()?;
-    connect_and_wait(&mut wifi)?;
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    let address = ip.to_string();
+    // The form works without it, only a phone won't bring it up by itself
+    if let Err(e) = captive::start(ip) {
+        warn!("Failed to start the captive portal DNS: {e:?}");
+    }
+
+    // A bad timeout falls back to the default, the device must not get stuck in setup mode over it
+    let minutes = match nvs::get_opt("net.setup.timeout")? {
+        Some(v) => parse_setup_timeout(&v).unwrap_or_else(|e| {
+            warn!("{e:#}, giving up after {DEFAULT_SETUP_TIMEOUT} minutes");
+            DEFAULT_SETUP_TIMEOUT
+        }),
+        None => DEFAULT_SETUP_TIMEOUT,
+    };
+    if stored && minutes > 0 {
+        let timeout = Duration::from_secs(u64::from(minutes) * 60);
+        *SETUP_SESSION.lock().unwrap() = Some(SetupSession {
+            timeout,
+            deadline: Instant::now() + timeout,
+        });
+    }
+
+    lifecycle::transition(lifecycle::State::Setup);
+    info!("Setup access point {ssid} up with password {password}, configure at http://{address}/");
+    let _ = SETUP.set(SetupAp {
+        ssid,
+        password,
+        address,
+    });
+
+    Ok(())
+}
+
+/// Parses the minutes the setup access point waits for the form, 0 waiting for as long as it takes.
+pub(crate) fn parse_setup_timeout(v: &str) -> anyhow::Result<u32> {
+    let minutes: u32 = v.trim().parse()?;
+    if minutes > MAX_SETUP_TIMEOUT {
+        return Err(anyhow!("Setup timeout must be at most {MAX_SETUP_TIMEOUT} minutes"));
+    }
+
+    Ok(minutes)
+}
+
+/// Re-reads the WiFi TX power, so a change made through `/config` applies with the next status update.
+///
+/// A bad value leaves the power to the driver, the device should stay reachable to have it corrected.
+pub(crate) fn reload_tx_power() -> anyhow::Result<()> {
+    let dbm = match nvs::get_opt("net.wifi.tx_power_dbm")? {
+        Some(v) => parse_tx_power(&v).unwrap_or_else(|e| {
+            warn!("{e:#}, using the driver default");
+            0
+        }),
+        None => 0,
+    };
+    CONFIGURED_TX_POWER.store(dbm, Ordering::Relaxed);
+
+    Ok(())
+}
+
+fn configured_tx_power() -> Option<u8> {
+    Some(CONFIGURED_TX_POWER.load(Ordering::Relaxed)).filter(|&dbm| dbm != 0)
+}
+
+/// Parses the WiFi transmit power in dBm.
+pub(crate) fn parse_tx_power(v: &str) -> anyhow::Result<u8> {
+    let dbm: u8 = v.trim().parse()?;
+    if !TX_POWER_RANGE.contains(&dbm) {
+        return Err(anyhow!("WiFi TX power must be within {TX_POWER_RANGE:?} dBm: {v}"));
+    }
+
+    Ok(dbm)
+}
+
+// Throttling caps the configured power, or the driver default when none is configured
+fn tx_power_target(configured: Option<u8>) -> Option<u8> {
+    if thermal::throttled() {
+        Some(configured.unwrap_or(DEFAULT_TX_POWER).min(THROTTLED_TX_POWER))
+    } else {
+        configured
+    }
+}
+
+/// Limits the transmit power. Needs a started driver, and is applied again after reconnecting since
+/// some IDF paths reset it.
+fn apply_tx_power(dbm: u8) -> anyhow::Result<()> {
+    let temperature = || system::chip_temperature().map_or("unknown".to_owned(), |v| format!("{v:.1} °C"));
+    let before = temperature();
+
+    // The driver takes the power in steps of 0.25 dBm
+    sys::esp!(unsafe { sys::esp_wifi_set_max_tx_power((dbm * 4) as i8) })?;
+    let mut quarter_dbm = 0_i8;
+    sys::esp!(unsafe { sys::esp_wifi_get_max_tx_power(&mut quarter_dbm) })?;
+
+    let actual_dbm = f32::from(quarter_dbm) / 4.0;
+    let previous = TX_POWER.lock().unwrap().replace(TxPower {
+        requested_dbm: dbm,
+        actual_dbm,
+    });
+    // Logged on every change, so the chip temperature can be followed as the power goes up or down
+    let previous = previous.map_or("driver default".to_owned(), |v| format!("{} dBm", v.actual_dbm));
+    info!(
+        "WiFi TX power {previous} -> {actual_dbm} dBm, chip temperature {before} before, {} after",
+        temperature()
+    );
 
-    Ok(wifi)
+    Ok(())
+}
+
+fn on_wifi_event(event: WifiEvent) {
+    match event {
+        WifiEvent::StaConnected(_) => *LAST_DISCONNECT.lock().unwrap() = None,
+        WifiEvent::StaDisconnected(disconnected) if troubleshoot::is_meaningful(disconnected.reason()) => {
+            *LAST_DISCONNECT.lock().unwrap() = Some(disconnected.reason());
+        }
+        _ => {}
+    }
 }
 
 fn connect_and_wait(wifi: &mut EspWifi<'_>) -> anyhow::Result<()> {
+    CONNECTED.store(false, Ordering::Relaxed);
+    *IP.lock().unwrap() = None;
+    lifecycle::set_connected(false);
     wifi.connect()?;
 
     // Wait for DNS to get ready
@@ -144,52 +502,179 @@ fn connect_and_wait(wifi: &mut EspWifi<'_>) -> anyhow::Result<()> {
             return Err(anyhow!("WiFi connection timeout"));
         }
     }
+    *IP.lock().unwrap() = Some(wifi.sta_netif().get_ip_info()?.ip);
+    CONNECTED.store(true, Ordering::Relaxed);
+    lifecycle::set_connected(true);
 
     Ok(())
 }
 
-fn init_ntp() -> anyhow::Result<EspSntp<'static>> {
-    let ntp_server = nvs::get("ntp_server")?;
+/// Answers `<net.mdns.hostname>.local` and advertises the HTTP server, so the device can be found without
+/// knowing the address the router gave it.
+fn init_mdns() -> anyhow::Result<EspMdns> {
+    let hostname = nvs::get_parsed_or("net.mdns.hostname", DEFAULT_HOSTNAME.to_owned(), parse_hostname)?;
+
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&hostname)?;
+    mdns.set_instance_name(labels::device_name())?;
+    mdns.add_service(
+        None,
+        "_http",
+        "_tcp",
+        HTTP_PORT,
+        &[("id", system::device_id()), ("path", "/api")],
+    )?;
+    info!("Advertising http://{hostname}.local/");
+
+    Ok(mdns)
+}
 
-    let ntp = EspSntp::new(&SntpConf {
-        servers: [&ntp_server],
-        ..Default::default()
-    })?;
+/// Validates a hostname, a single DNS label of letters, digits and hyphens, e.g. `cobitis-tank2`.
+pub(crate) fn parse_hostname(v: &str) -> anyhow::Result<String> {
+    let hostname = v.trim().to_ascii_lowercase();
+    let valid = (1..=MAX_HOSTNAME_LEN).contains(&hostname.len())
+        && hostname.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-');
+    if !valid {
+        return Err(anyhow!(
+            "Hostname must be up to {MAX_HOSTNAME_LEN} letters, digits and inner hyphens: {v}"
+        ));
+    }
+
+    Ok(hostname)
+}
+
+fn init_ntp() -> anyhow::Result<EspSntp<'static>> {
+    // A bad setting falls back to its default, refusing to start would leave the device without a clock
+    let ntp_servers = match nvs::get_opt("net.ntp.servers")? {
+        Some(v) => parse_ntp_servers(&v).unwrap_or_else(|e| {
+            warn!("{e:#}, using {DEFAULT_NTP_SERVER}");
+            vec![DEFAULT_NTP_SERVER.to_owned()]
+        }),
+        None => vec![DEFAULT_NTP_SERVER.to_owned()],
+    };
+    let sync_mode = match nvs::get_opt("net.ntp.sync_mode")? {
+        Some(v) => parse_ntp_sync_mode(&v).unwrap_or_else(|e| {
+            warn!("{e:#}, syncing immediately");
+            SyncMode::Immediate
+        }),
+        None => SyncMode::Immediate,
+    };
+    if let Some(v) = nvs::get_opt("net.ntp.sync_interval")? {
+        match parse_ntp_interval(&v) {
+            Ok(seconds) => unsafe { sys::sntp_set_sync_interval(seconds * 1000) },
+            Err(e) => warn!("{e:#}, keeping the ESP-IDF default"),
+        }
+    }
 
-    // Wait for NTP client to get synchronized
+    // Published before starting SNTP, since the first sync can arrive before `new_with_callback` returns
+    NTP_SERVERS
+        .set(ntp_servers)
+        .map_err(|_| anyhow!("NTP already initialized"))?;
+    let ntp_servers = NTP_SERVERS.get().unwrap();
+
+    // Unused slots repeat the last server, an empty name would be polled as a host of its own
+    let servers = std::array::from_fn(|i| ntp_servers[i.min(ntp_servers.len() - 1)].as_str());
+    let ntp = EspSntp::new_with_callback(
+        &SntpConf {
+            servers,
+            sync_mode,
+            ..Default::default()
+        },
+        on_ntp_sync,
+    )?;
+
+    // Wait for NTP client to get synchronized. Offline installations carry on without a clock, SNTP keeps
+    // retrying in the background
     let mut timeout = 0;
     while ntp.get_sync_status() != SyncStatus::Completed {
         FreeRtos::delay_ms(DELAY);
 
         timeout += 1;
         if timeout >= MAX_TIMEOUT {
-            return Err(anyhow!("NTP Sync timeout"));
+            warn!("NTP sync timeout, continuing without a valid clock");
+            break;
         }
     }
 
     Ok(ntp)
 }
 
-fn init_http_server() -> anyhow::Result<EspHttpServer<'static>> {
-    let mut server = EspHttpServer::new(&ServerConfiguration::default())?;
-    server.fn_handler("/", Method::Get, move |request| {
-        const NO_CONTENT: u16 = 204;
+/// Parses up to three NTP servers separated by commas, tried in order.
+pub(crate) fn parse_ntp_servers(v: &str) -> anyhow::Result<Vec<String>> {
+    let servers: Vec<_> = v
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if servers.is_empty() || servers.len() > MAX_NTP_SERVERS {
+        return Err(anyhow!(
+            "Expected 1 to {MAX_NTP_SERVERS} NTP servers, got {}",
+            servers.len()
+        ));
+    }
 
-        let (mut res, msg) = match executor::block_on(measurements::get()) {
-            Some(values) => (
-                request.into_ok_response()?,
-                serde_json::to_string(&Message::from(values))?.into_bytes(),
-            ),
-            None => (request.into_status_response(NO_CONTENT)?, vec![]),
-        };
-        res.write_all(&msg)?;
+    Ok(servers)
+}
 
-        anyhow::Ok(())
-    })?;
+/// Parses how the clock is corrected, `immediate` stepping it and `smooth` slewing it.
+pub(crate) fn parse_ntp_sync_mode(v: &str) -> anyhow::Result<SyncMode> {
+    match v.trim() {
+        "immediate" => Ok(SyncMode::Immediate),
+        "smooth" => Ok(SyncMode::Smooth),
+        _ => Err(anyhow!("Invalid NTP sync mode: {v}")),
+    }
+}
+
+/// Parses the seconds between NTP syncs.
+pub(crate) fn parse_ntp_interval(v: &str) -> anyhow::Result<u32> {
+    let seconds: u32 = v.trim().parse()?;
+    if seconds < MIN_NTP_INTERVAL {
+        return Err(anyhow!("NTP sync interval must be at least {MIN_NTP_INTERVAL} seconds"));
+    }
+
+    Ok(seconds)
+}
 
-    Ok(server)
+/// Records which server answered and how far the clock had drifted since the previous sync.
+fn on_ntp_sync(time: Duration) {
+    let last_sync = time.as_millis() as i64;
+    let instant = Instant::now();
+
+    // The lowest reachability bit is set when the latest poll of that server got an answer
+    let server = NTP_SERVERS.get().and_then(|servers| {
+        (0..servers.len())
+            .find(|&i| unsafe { sys::esp_sntp_getreachability(i as u8) } & 1 != 0)
+            .map(|i| servers[i].clone())
+    });
+
+    let mut sync = NTP_SYNC.lock().unwrap();
+    let offset_ms = sync.as_ref().map(|previous| {
+        let expected = previous.status.last_sync + instant.duration_since(previous.instant).as_millis() as i64;
+        last_sync - expected
+    });
+    info!(
+        "Clock synchronized by {}",
+        server.as_deref().unwrap_or("unknown server")
+    );
+
+    *sync = Some(NtpSync {
+        status: NtpStatus {
+            server,
+            last_sync,
+            offset_ms,
+        },
+        instant,
+    });
 }
+
 pub(crate) async fn worker(ctx: &mut Box<Context<'_>>) -> anyhow::Result<()> {
+    if setup().is_some() {
+        return setup_worker(ctx).await;
+    }
+
     let mut interval = interval(Duration::from_secs(5));
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
@@ -202,16 +687,77 @@ pub(crate) async fn worker(ctx: &mut Box<Context<'_>>) -> anyhow::Result<()> {
     }
 }
 
+/// Reboots into station mode once the setup timeout runs out, or once the stored network shows up after
+/// missing from the first scan. Seen from the start, it was there when joining failed and is no reason to
+/// try again sooner.
+async fn setup_worker(ctx: &mut Box<Context<'_>>) -> anyhow::Result<()> {
+    // Nothing to go back to, the access point stays up until the form is submitted
+    let Some(ssid) = ctx.setup_ssid.clone() else {
+        return std::future::pending().await;
+    };
+
+    let mut interval = interval(SETUP_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last_scan: Option<Instant> = None;
+    let mut seen_at_first_scan = None;
+
+    loop {
+        interval.tick().await;
+
+        let reason = if setup_remaining().is_some_and(|remaining| remaining.is_zero()) {
+            "setup timed out"
+        } else if last_scan.is_none_or(|scan| scan.elapsed() >= SETUP_SCAN_INTERVAL) {
+            last_scan = Some(Instant::now());
+            let visible = match task::block_in_place(|| find_ap(&ssid)) {
+                Ok(rssi) => rssi.is_some(),
+                Err(e) => {
+                    warn!("Failed to scan for {ssid}: {e:?}");
+                    continue;
+                }
+            };
+            match seen_at_first_scan {
+                None => {
+                    seen_at_first_scan = Some(visible);
+                    continue;
+                }
+                Some(false) if visible => "network is back",
+                Some(_) => continue,
+            }
+        } else {
+            continue;
+        };
+
+        info!("Closing the setup access point, {reason}, rebooting to join {ssid}");
+        system::restart_after(Duration::ZERO);
+        return std::future::pending().await;
+    }
+}
+
 async fn update<'a>(ctx: &mut Context<'a>) -> anyhow::Result<()> {
     let status = task::block_in_place(move || {
         // Reconnect to WiFi if disconnected
-        if !ctx.wifi.is_connected().unwrap_or(false) {
+        let reconnect = !ctx.wifi.is_connected().unwrap_or(false);
+        if reconnect {
             connect_and_wait(&mut ctx.wifi)?;
+            journal::record(journal::Event::WifiReconnect);
+        }
+
+        // Some IDF paths reset the TX power on reconnect, and throttling or /config changes it on the fly
+        ctx.tx_power_dbm = configured_tx_power();
+        let target = tx_power_target(ctx.tx_power_dbm);
+        if (reconnect && target.is_some()) || target != ctx.applied_tx_power {
+            apply_tx_power(target.unwrap_or(DEFAULT_TX_POWER))?;
+            ctx.applied_tx_power = target;
         }
 
         // Update WiFi status
-        let rssi = ctx.wifi.get_rssi()?;
+        let rssi = if simulation::enabled() {
+            simulation::rssi()
+        } else {
+            ctx.wifi.get_rssi()?
+        };
         let signal_quality = SignalQuality::from_rssi(rssi);
+        *RSSI.lock().unwrap() = Some(rssi);
 
         anyhow::Ok(Status { signal_quality })
     })?;
@@ -220,3 +766,73 @@ async fn update<'a>(ctx: &mut Context<'a>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tx_power_is_within_the_driver_range() {
+        assert_eq!(parse_tx_power("2").unwrap(), 2);
+        assert_eq!(parse_tx_power(" 20 ").unwrap(), 20);
+        assert!(parse_tx_power("1").is_err());
+        assert!(parse_tx_power("21").is_err());
+        assert!(parse_tx_power("0").is_err());
+        assert!(parse_tx_power("8.5").is_err());
+        assert!(parse_tx_power("").is_err());
+    }
+
+    #[test]
+    fn ntp_servers_are_one_to_three() {
+        assert_eq!(parse_ntp_servers("pool.ntp.org").unwrap(), ["pool.ntp.org"]);
+        assert_eq!(
+            parse_ntp_servers(" ntp.nict.jp, ,time.google.com,pool.ntp.org ").unwrap(),
+            ["ntp.nict.jp", "time.google.com", "pool.ntp.org"]
+        );
+        assert!(parse_ntp_servers("").is_err());
+        assert!(parse_ntp_servers(" , ").is_err());
+        assert!(parse_ntp_servers("a,b,c,d").is_err());
+    }
+
+    #[test]
+    fn ntp_sync_mode_is_immediate_or_smooth() {
+        assert!(matches!(parse_ntp_sync_mode("immediate").unwrap(), SyncMode::Immediate));
+        assert!(matches!(parse_ntp_sync_mode("smooth").unwrap(), SyncMode::Smooth));
+        assert!(parse_ntp_sync_mode("Smooth").is_err());
+        assert!(parse_ntp_sync_mode("").is_err());
+    }
+
+    #[test]
+    fn ntp_interval_has_a_lower_bound() {
+        assert_eq!(parse_ntp_interval("15").unwrap(), MIN_NTP_INTERVAL);
+        assert_eq!(parse_ntp_interval("3600").unwrap(), 3600);
+        assert!(parse_ntp_interval("14").is_err());
+        assert!(parse_ntp_interval("-60").is_err());
+        assert!(parse_ntp_interval("hourly").is_err());
+    }
+
+    #[test]
+    fn setup_timeout_is_minutes_up_to_a_day() {
+        assert_eq!(parse_setup_timeout("0").unwrap(), 0);
+        assert_eq!(parse_setup_timeout(" 15 ").unwrap(), 15);
+        assert_eq!(parse_setup_timeout("1440").unwrap(), MAX_SETUP_TIMEOUT);
+        assert!(parse_setup_timeout("1441").is_err());
+        assert!(parse_setup_timeout("-1").is_err());
+        assert!(parse_setup_timeout("15m").is_err());
+    }
+
+    #[test]
+    fn hostname_is_a_single_dns_label() {
+        assert_eq!(parse_hostname("cobitis-tank2").unwrap(), "cobitis-tank2");
+        assert_eq!(parse_hostname(" Cobitis-Tank2 ").unwrap(), "cobitis-tank2");
+        assert_eq!(parse_hostname("7").unwrap(), "7");
+
+        let longest = "a".repeat(MAX_HOSTNAME_LEN);
+        assert_eq!(parse_hostname(&longest).unwrap(), longest);
+        assert!(parse_hostname(&format!("{longest}a")).is_err());
+
+        for v in ["", "  ", "-tank", "tank-", "tank.local", "tank_2", "tank 2", "水槽"] {
+            assert!(parse_hostname(v).is_err(), "{v:?}");
+        }
+    }
+}
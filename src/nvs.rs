@@ -3,24 +3,946 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use std::sync::OnceLock;
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{sync::Notify, task};
 
-static NVS: OnceLock<EspNvs<NvsDefault>> = OnceLock::new();
+use crate::{journal, revision};
 
-pub(crate) fn get(key: &str) -> anyhow::Result<String> {
-    let mut buf = vec![0_u8; 128];
+/// A configuration setting, addressed in code by its logical name.
+struct Key {
+    name: &'static str,
+    /// Key in the config namespace, NVS limits these to 15 characters
+    nvs: &'static str,
+    /// Flat key used by older firmware, copied over once by `migrate`
+    legacy: Option<&'static str>,
+}
+
+// Legacy keys are copied to the current ones on the first boot of this layout, and left behind as
+// tombstones: never written again, still read as fallbacks should the migration fail, and still there
+// for a downgrade to find.
+const KEYS: &[Key] = &[
+    Key {
+        name: "net.wifi.ssid",
+        nvs: "wifi.ssid",
+        legacy: Some("ssid"),
+    },
+    Key {
+        name: "net.wifi.psk",
+        nvs: "wifi.psk",
+        legacy: Some("psk"),
+    },
+    Key {
+        name: "net.wifi.tx_power_dbm",
+        nvs: "wifi.tx_power",
+        legacy: None,
+    },
+    Key {
+        name: "net.setup.timeout",
+        nvs: "setup.timeout",
+        legacy: None,
+    },
+    Key {
+        name: "net.ntp.servers",
+        nvs: "ntp.servers",
+        legacy: Some("ntp_server"),
+    },
+    Key {
+        name: "net.ntp.sync_mode",
+        nvs: "ntp.sync_mode",
+        legacy: Some("ntp_sync_mode"),
+    },
+    Key {
+        name: "net.ntp.sync_interval",
+        nvs: "ntp.interval",
+        legacy: None,
+    },
+    Key {
+        name: "net.syslog.host",
+        nvs: "syslog.host",
+        legacy: Some("syslog_host"),
+    },
+    Key {
+        name: "net.syslog.port",
+        nvs: "syslog.port",
+        legacy: Some("syslog_port"),
+    },
+    Key {
+        name: "net.syslog.enabled",
+        nvs: "syslog.enabled",
+        legacy: None,
+    },
+    Key {
+        name: "net.syslog.level",
+        nvs: "syslog.level",
+        legacy: Some("syslog_level"),
+    },
+    Key {
+        name: "net.mdns.hostname",
+        nvs: "mdns.hostname",
+        legacy: None,
+    },
+    Key {
+        name: "net.mqtt.url",
+        nvs: "mqtt.url",
+        legacy: Some("mqtt_url"),
+    },
+    Key {
+        name: "net.mqtt.user",
+        nvs: "mqtt.user",
+        legacy: Some("mqtt_user"),
+    },
+    Key {
+        name: "net.mqtt.pass",
+        nvs: "mqtt.pass",
+        legacy: Some("mqtt_pass"),
+    },
+    Key {
+        name: "net.mqtt.topic_prefix",
+        nvs: "mqtt.prefix",
+        legacy: None,
+    },
+    Key {
+        name: "net.mqtt.discovery.enabled",
+        nvs: "mqtt.disc.en",
+        legacy: None,
+    },
+    Key {
+        name: "net.mqtt.discovery.prefix",
+        nvs: "mqtt.disc.pfx",
+        legacy: None,
+    },
+    Key {
+        name: "net.mqtt.state_template",
+        nvs: "mqtt.tmpl",
+        legacy: None,
+    },
+    Key {
+        name: "net.mqtt.state_template_json",
+        nvs: "mqtt.tmpl.json",
+        legacy: None,
+    },
+    Key {
+        name: "net.push.url",
+        nvs: "push.url",
+        legacy: Some("push_url"),
+    },
+    Key {
+        name: "net.push.token",
+        nvs: "push.token",
+        legacy: Some("push_token"),
+    },
+    Key {
+        name: "net.push.enabled",
+        nvs: "push.enabled",
+        legacy: None,
+    },
+    Key {
+        name: "net.push.template",
+        nvs: "push.tmpl",
+        legacy: None,
+    },
+    Key {
+        name: "net.push.template_json",
+        nvs: "push.tmpl.json",
+        legacy: None,
+    },
+    Key {
+        name: "display.timezone",
+        nvs: "disp.timezone",
+        legacy: Some("timezone"),
+    },
+    Key {
+        name: "display.decimal_separator",
+        nvs: "disp.decimal",
+        legacy: None,
+    },
+    Key {
+        name: "display.heartbeat",
+        nvs: "disp.heartbeat",
+        legacy: Some("heartbeat"),
+    },
+    Key {
+        name: "display.heartbeat_corner",
+        nvs: "disp.hb_corner",
+        legacy: None,
+    },
+    Key {
+        name: "display.address",
+        nvs: "disp.addr",
+        legacy: Some("display_addr"),
+    },
+    Key {
+        name: "display.idle_timeout",
+        nvs: "disp.idle",
+        legacy: None,
+    },
+    Key {
+        name: "display.interactive",
+        nvs: "disp.interact",
+        legacy: None,
+    },
+    Key {
+        name: "sensor.adc.sps",
+        nvs: "adc.sps",
+        legacy: Some("adc_sps"),
+    },
+    Key {
+        name: "sensor.adc.mode",
+        nvs: "adc.mode",
+        legacy: Some("adc_mode"),
+    },
+    Key {
+        name: "sensor.adc.burst_samples",
+        nvs: "adc.burst",
+        legacy: Some("adc_burst"),
+    },
+    Key {
+        name: "sensor.vref_monitor",
+        nvs: "sensor.vref_mon",
+        legacy: Some("vref_monitor"),
+    },
+    Key {
+        name: "sensor.warmup",
+        nvs: "sensor.warmup",
+        legacy: None,
+    },
+    Key {
+        name: "sensor.max_interval",
+        nvs: "sensor.max_int",
+        legacy: None,
+    },
+    Key {
+        name: "sensor.tds.k_factor",
+        nvs: "sensor.tds.k",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.min",
+        nvs: "alert.temp.min",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.max",
+        nvs: "alert.temp.max",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.min",
+        nvs: "alert.tds.min",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.max",
+        nvs: "alert.tds.max",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.min.hysteresis",
+        nvs: "alert.temp.minh",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.min.min_duration",
+        nvs: "alert.temp.mind",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.max.hysteresis",
+        nvs: "alert.temp.maxh",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.temperature.max.min_duration",
+        nvs: "alert.temp.maxd",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.min.hysteresis",
+        nvs: "alert.tds.minh",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.min.min_duration",
+        nvs: "alert.tds.mind",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.max.hysteresis",
+        nvs: "alert.tds.maxh",
+        legacy: None,
+    },
+    Key {
+        name: "alerts.tds.max.min_duration",
+        nvs: "alert.tds.maxd",
+        legacy: None,
+    },
+    Key {
+        name: "history.capacity",
+        nvs: "hist.capacity",
+        legacy: None,
+    },
+    Key {
+        name: "history.interval",
+        nvs: "hist.interval",
+        legacy: None,
+    },
+    Key {
+        name: "sensor.temperature.enabled",
+        nvs: "sensor.temp.en",
+        legacy: None,
+    },
+    Key {
+        name: "sensor.tds.enabled",
+        nvs: "sensor.tds.en",
+        legacy: None,
+    },
+    Key {
+        name: "site.altitude",
+        nvs: "site.altitude",
+        legacy: None,
+    },
+    Key {
+        name: "site.pressure",
+        nvs: "site.pressure",
+        legacy: None,
+    },
+    Key {
+        name: "sim.enabled",
+        nvs: "sim.enabled",
+        legacy: Some("simulate"),
+    },
+    Key {
+        name: "sim.seed",
+        nvs: "sim.seed",
+        legacy: Some("simulate_seed"),
+    },
+    Key {
+        name: "system.lockdown",
+        nvs: "sys.lockdown",
+        legacy: Some("lockdown"),
+    },
+    Key {
+        name: "board.pin.sda",
+        nvs: "pin.sda",
+        legacy: Some("pin_sda"),
+    },
+    Key {
+        name: "board.pin.scl",
+        nvs: "pin.scl",
+        legacy: Some("pin_scl"),
+    },
+    Key {
+        name: "board.pin.onewire",
+        nvs: "pin.onewire",
+        legacy: Some("pin_onewire"),
+    },
+    Key {
+        name: "board.pin.adc_ready",
+        nvs: "pin.adc_rdy",
+        legacy: None,
+    },
+    Key {
+        name: "system.thermal_limit",
+        nvs: "sys.therm_limit",
+        legacy: None,
+    },
+    Key {
+        name: "system.i2c_error_threshold",
+        nvs: "sys.i2c_err",
+        legacy: None,
+    },
+    Key {
+        name: "device.name",
+        nvs: "device.name",
+        legacy: None,
+    },
+    Key {
+        name: "label.temperature",
+        nvs: "label.temp",
+        legacy: None,
+    },
+    Key {
+        name: "label.tds",
+        nvs: "label.tds",
+        legacy: None,
+    },
+];
+
+// Upper bounds of the write duration histogram buckets, the last bucket takes everything slower
+const WRITE_BUCKETS_MS: [u64; 5] = [10, 50, 100, 250, 500];
+
+// A write this slow, typically a page erase, is long enough to make the display skip a tick
+const SLOW_WRITE: Duration = Duration::from_millis(100);
+
+// Pending deferred writes; distinct keys are few, so this is only reached if the worker stalls
+const QUEUE_CAPACITY: usize = 8;
+
+/// Durations of state writes and what happened to deferred ones, reported in `/diagnostics`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct WriteStats {
+    /// Writes per duration bucket, bounded by 10, 50, 100, 250 and 500 ms, and slower.
+    pub histogram: [u32; WRITE_BUCKETS_MS.len() + 1],
+    /// Deferred writes superseded by a newer value for the same key before reaching flash.
+    pub coalesced: u32,
+    /// Deferred writes evicted from a full queue.
+    pub dropped: u32,
+}
+
+// Settings whose values never leave the device
+const SECRETS: &[&str] = &["net.wifi.psk", "net.mqtt.pass", "net.push.token"];
+
+// Settings the network may not change, only flashing the NVS partition over the serial port can. Lifting
+// lockdown remotely would defeat it, and a wrong pin takes the sensors and display down with no way back.
+const LOCAL_ONLY: &[&str] = &[
+    "system.lockdown",
+    "board.pin.sda",
+    "board.pin.scl",
+    "board.pin.onewire",
+    "board.pin.adc_ready",
+];
+
+// Marks the config namespace as migrated. Flashing a CSV replaces the namespace and with it the marker,
+// so settings flashed under their legacy keys are migrated on the next boot
+const LAYOUT_KEY: &str = "nvs.layout";
+const LAYOUT: &str = "2";
+
+/// Longest value `get_opt` can read back, its buffer also holding the terminating NUL.
+pub(crate) const MAX_VALUE_LEN: usize = 127;
+
+static NVS: OnceLock<Mutex<EspNvs<NvsDefault>>> = OnceLock::new();
+
+// Firmware-owned state lives in its own read-write namespace, apart from the user configuration
+static STATE: OnceLock<Mutex<EspNvs<NvsDefault>>> = OnceLock::new();
+
+static WRITE_STATS: Mutex<WriteStats> = Mutex::new(WriteStats {
+    histogram: [0; WRITE_BUCKETS_MS.len() + 1],
+    coalesced: 0,
+    dropped: 0,
+});
+
+// Writes waiting for the worker, oldest first, `None` removing the key
+static QUEUE: Mutex<VecDeque<(&'static str, Option<Vec<u8>>)>> = Mutex::new(VecDeque::new());
+static WAKE: Notify = Notify::const_new();
+
+/// Reads a setting by its logical name, e.g. `net.wifi.ssid`.
+pub(crate) fn get_opt(name: &str) -> anyhow::Result<Option<String>> {
+    let key = find(name).ok_or(anyhow!("Unknown setting: {name}"))?;
+
+    let nvs = NVS.get().expect("NVS not initialized").lock().unwrap();
+    read_setting(&*nvs, key)
+}
+
+fn read_setting(store: &impl Store, key: &Key) -> anyhow::Result<Option<String>> {
+    if let Some(value) = store.read(key.nvs)? {
+        return Ok(Some(value));
+    }
+    match key.legacy {
+        Some(legacy) => store.read(legacy),
+        None => Ok(None),
+    }
+}
+
+/// Reads an on/off switch, treating a missing key as off.
+pub(crate) fn get_flag(name: &str) -> anyhow::Result<bool> {
+    get_flag_or(name, false)
+}
+
+/// Reads an on/off switch, treating a missing or invalid key as `default`.
+pub(crate) fn get_flag_or(name: &str, default: bool) -> anyhow::Result<bool> {
+    get_parsed_or(name, default, |v| parse_flag(v).ok_or(anyhow!("Expected on or off")))
+}
+
+/// Reads a setting through `parse`, treating a missing key as `default`.
+///
+/// An invalid value is warned about and also read as `default`, so a bad setting degrades a feature
+/// instead of keeping the device off the network it would be corrected over.
+pub(crate) fn get_parsed_or<T>(
+    name: &str,
+    default: T,
+    parse: impl FnOnce(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let Some(v) = get_opt(name)? else {
+        return Ok(default);
+    };
+
+    match parse(&v) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            warn!("Ignoring invalid {name} {v:?}, using the default: {e:#}");
+            revision::config_error(name);
+            Ok(default)
+        }
+    }
+}
+
+/// Parses the value of an on/off switch, accepting `1`/`0`, `true`/`false` and `on`/`off`.
+pub(crate) fn parse_flag(v: &str) -> Option<bool> {
+    match v {
+        "0" | "false" | "off" => Some(false),
+        "1" | "true" | "on" => Some(true),
+        _ => None,
+    }
+}
+
+/// Resolves a setting by its logical name, or by the legacy key older tooling knows it by.
+pub(crate) fn canonical_name(name: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .find(|key| key.name == name || key.legacy == Some(name))
+        .map(|key| key.name)
+}
+
+pub(crate) fn is_secret(name: &str) -> bool {
+    SECRETS.contains(&name)
+}
+
+/// Whether a setting is refused over the network, see `LOCAL_ONLY`.
+pub(crate) fn is_local_only(name: &str) -> bool {
+    LOCAL_ONLY.contains(&name)
+}
+
+/// Logical names of all known settings.
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    KEYS.iter().map(|key| key.name)
+}
+
+/// Stores a setting by its logical name. Most settings are read once at startup, so a change takes
+/// effect after the next reboot.
+pub(crate) fn set(name: &str, value: &str) -> anyhow::Result<()> {
+    let key = find(name).ok_or(anyhow!("Unknown setting: {name}"))?;
+    if value.len() > MAX_VALUE_LEN {
+        return Err(anyhow!("Value of {name} exceeds {MAX_VALUE_LEN} bytes"));
+    }
+
+    let mut nvs = NVS.get().expect("NVS not initialized").lock().unwrap();
+    nvs.set_str(key.nvs, value)?;
+    Ok(())
+}
+
+/// Unsets a setting by its logical name, its legacy key included, so it reads as missing again.
+pub(crate) fn remove(name: &str) -> anyhow::Result<()> {
+    let key = find(name).ok_or(anyhow!("Unknown setting: {name}"))?;
+
+    let mut nvs = NVS.get().expect("NVS not initialized").lock().unwrap();
+    for nvs_key in [Some(key.nvs), key.legacy].into_iter().flatten() {
+        nvs.remove(nvs_key)?;
+    }
+    Ok(())
+}
+
+fn find(name: &str) -> Option<&'static Key> {
+    KEYS.iter().find(|key| key.name == name)
+}
+
+/// String access to the config namespace, so the migration can be tested against a map.
+trait Store {
+    fn read(&self, key: &str) -> anyhow::Result<Option<String>>;
+    fn write(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+}
+
+impl Store for EspNvs<NvsDefault> {
+    fn read(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut buf = vec![0_u8; MAX_VALUE_LEN + 1];
+        let value = self.get_str(key, &mut buf)?;
+        Ok(value.map(|v| v.to_owned()))
+    }
+
+    fn write(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.set_str(key, value)?;
+        Ok(())
+    }
+}
+
+/// Copies settings only found under their legacy key to their current one, once per config namespace.
+///
+/// A setting already stored under its current key keeps its value. Every copy is read back before the
+/// namespace is marked as migrated, so a failed write is retried on the next boot instead of dropping the
+/// setting once the legacy keys are no longer looked at.
+fn migrate(store: &mut impl Store) -> anyhow::Result<()> {
+    if store.read(LAYOUT_KEY)?.as_deref() == Some(LAYOUT) {
+        return Ok(());
+    }
+
+    for key in KEYS {
+        let Some(legacy) = key.legacy else {
+            continue;
+        };
+        if store.read(key.nvs)?.is_some() {
+            continue;
+        }
+        let Some(value) = store.read(legacy)? else {
+            continue;
+        };
+
+        store.write(key.nvs, &value)?;
+        if store.read(key.nvs)?.as_deref() != Some(value.as_str()) {
+            return Err(anyhow!(
+                "{} read back differently after copying it from {legacy}",
+                key.nvs
+            ));
+        }
+        info!("Migrated setting {} from legacy key {legacy}", key.name);
+    }
+
+    store.write(LAYOUT_KEY, LAYOUT)
+}
+
+pub(crate) fn load_blob(key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let nvs = STATE.get().expect("NVS not initialized").lock().unwrap();
+
+    let Some(len) = nvs.blob_len(key)? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0_u8; len];
+    let value = nvs.get_blob(key, &mut buf)?;
+    Ok(value.map(|v| v.to_vec()))
+}
 
-    let nvs = NVS.get().expect("NVS not initialized");
-    let value = nvs.get_str(key, &mut buf)?;
-    value.ok_or(anyhow!("Value not found")).map(|v| v.to_owned())
+/// Envelope of persisted state, so a layout written by other firmware is told apart from corruption.
+///
+/// Blobs written before the envelope existed have no version and read as version 0.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    #[serde(default)]
+    version: u32,
+    #[serde(flatten)]
+    data: T,
+}
+
+/// Serializes state for `store_blob` or `persist`, tagged with the layout `version` of `T`.
+pub(crate) fn encode_state<T: Serialize>(version: u32, data: &T) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Versioned { version, data })?)
+}
+
+/// Loads state stored by `encode_state`.
+///
+/// Older layouts are read with the fields they lack defaulted. A newer layout, left behind by a
+/// downgrade, is discarded and journaled, since guessing at it could bring back garbage.
+pub(crate) fn load_state<T: DeserializeOwned>(key: &str, version: u32) -> anyhow::Result<Option<T>> {
+    let Some(blob) = load_blob(key)? else {
+        return Ok(None);
+    };
+
+    match decode_state(&blob, version)? {
+        Decoded::State(state) => Ok(Some(state)),
+        Decoded::Newer(stored) => {
+            warn!("Discarding {key} with layout version {stored}, newer than {version}");
+            remove_blob(key)?;
+            journal::record(journal::Event::StateDiscarded {
+                key: key.to_owned(),
+                version: stored,
+            });
+            Ok(None)
+        }
+    }
+}
+
+enum Decoded<T> {
+    State(T),
+    /// Left behind by newer firmware, with this layout version
+    Newer(u32),
+}
+
+fn decode_state<T: DeserializeOwned>(blob: &[u8], version: u32) -> anyhow::Result<Decoded<T>> {
+    #[derive(Deserialize)]
+    struct Header {
+        #[serde(default)]
+        version: u32,
+    }
+    let stored = serde_json::from_slice::<Header>(blob)?.version;
+    if stored > version {
+        return Ok(Decoded::Newer(stored));
+    }
+
+    let state: Versioned<T> = serde_json::from_slice(blob)?;
+    Ok(Decoded::State(state.data))
+}
+
+pub(crate) fn store_blob(key: &str, value: &[u8]) -> anyhow::Result<()> {
+    timed(key, |nvs| nvs.set_blob(key, value).map_err(Into::into))
+}
+
+pub(crate) fn remove_blob(key: &str) -> anyhow::Result<()> {
+    timed(key, |nvs| nvs.remove(key).map(|_| ()).map_err(Into::into))
+}
+
+fn timed<F>(key: &str, op: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut EspNvs<NvsDefault>) -> anyhow::Result<()>,
+{
+    let mut nvs = STATE.get().expect("NVS not initialized").lock().unwrap();
+
+    let started = Instant::now();
+    let result = op(&mut *nvs);
+    let elapsed = started.elapsed();
+
+    let ms = elapsed.as_millis() as u64;
+    let bucket = WRITE_BUCKETS_MS.iter().take_while(|&&bound| ms >= bound).count();
+    WRITE_STATS.lock().unwrap().histogram[bucket] += 1;
+    if elapsed >= SLOW_WRITE {
+        warn!("Writing {key} to NVS took {ms} ms");
+    }
+
+    result
+}
+
+/// Queues a blob write for the persistence worker, so the caller never waits on a flash erase.
+///
+/// A newer value for a key that is still queued replaces the old one in place, keeping its position.
+/// Writes to different keys reach flash in the order they were first queued.
+pub(crate) fn persist(key: &'static str, value: Option<Vec<u8>>) {
+    let mut queue = QUEUE.lock().unwrap();
+
+    if let Some(pending) = queue.iter_mut().find(|(k, _)| *k == key) {
+        pending.1 = value;
+        WRITE_STATS.lock().unwrap().coalesced += 1;
+    } else {
+        if queue.len() >= QUEUE_CAPACITY {
+            queue.pop_front();
+            WRITE_STATS.lock().unwrap().dropped += 1;
+        }
+        queue.push_back((key, value));
+    }
+    WAKE.notify_one();
+}
+
+pub(crate) fn write_stats() -> WriteStats {
+    *WRITE_STATS.lock().unwrap()
+}
+
+/// Writes queued blobs to flash, off the measurement and display loops.
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    loop {
+        WAKE.notified().await;
+        task::block_in_place(flush);
+    }
+}
+
+/// Writes everything queued right away, for callers about to reboot.
+pub(crate) fn flush() {
+    loop {
+        let Some((key, value)) = QUEUE.lock().unwrap().pop_front() else {
+            break;
+        };
+
+        let result = match &value {
+            Some(value) => store_blob(key, value),
+            None => remove_blob(key),
+        };
+        if let Err(e) = result {
+            error!("Failed to persist {key}: {e:?}");
+        }
+    }
 }
 
 pub(crate) fn init(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
-    let nvs = EspNvs::new(partition, "cobitis-config", false)?;
-    NVS.set(nvs).map_err(|_| anyhow!("NVS already initialized"))?;
+    let mut nvs = EspNvs::new(partition.clone(), "cobitis-config", true)?;
+    if let Err(e) = migrate(&mut nvs) {
+        error!("Failed to migrate legacy settings, reading them as fallbacks: {e:?}");
+    }
+    NVS.set(Mutex::new(nvs))
+        .map_err(|_| anyhow!("NVS already initialized"))?;
+
+    let state = EspNvs::new(partition, "cobitis-state", true)?;
+    STATE
+        .set(Mutex::new(state))
+        .map_err(|_| anyhow!("NVS already initialized"))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    struct Map {
+        values: BTreeMap<String, String>,
+        // Writes to this key are lost, like a write the flash didn't take
+        lossy: Option<&'static str>,
+    }
+
+    impl Store for Map {
+        fn read(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(self.values.get(key).cloned())
+        }
+
+        fn write(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+            if self.lossy != Some(key) {
+                self.values.insert(key.to_owned(), value.to_owned());
+            }
+            Ok(())
+        }
+    }
+
+    // What older firmware left in the config namespace, every setting under its flat key
+    fn legacy_namespace() -> Map {
+        let values = KEYS
+            .iter()
+            .filter_map(|key| key.legacy)
+            .map(|legacy| (legacy.to_owned(), format!("{legacy} value")))
+            .collect();
+        Map { values, lossy: None }
+    }
+
+    #[test]
+    fn nvs_keys_fit_and_are_unique() {
+        let mut seen = vec![LAYOUT_KEY];
+        for key in KEYS {
+            for nvs in [Some(key.nvs), key.legacy].into_iter().flatten() {
+                assert!(nvs.len() <= 15, "{nvs}");
+                assert!(!seen.contains(&nvs), "{nvs}");
+                seen.push(nvs);
+            }
+        }
+    }
+
+    #[test]
+    fn migration_copies_every_legacy_setting_and_leaves_tombstones() {
+        let mut store = legacy_namespace();
+        let before = store.values.clone();
+
+        migrate(&mut store).unwrap();
+
+        for key in KEYS {
+            let Some(legacy) = key.legacy else {
+                continue;
+            };
+            let expected = format!("{legacy} value");
+            assert_eq!(store.values.get(key.nvs), Some(&expected), "{}", key.name);
+            assert_eq!(read_setting(&store, key).unwrap(), Some(expected), "{}", key.name);
+            // Untouched, for a downgrade
+            assert_eq!(store.values.get(legacy), before.get(legacy), "{legacy}");
+        }
+        assert_eq!(store.values.get(LAYOUT_KEY).map(String::as_str), Some(LAYOUT));
+    }
+
+    #[test]
+    fn migration_keeps_settings_already_under_their_current_key() {
+        let mut store = legacy_namespace();
+        store.values.insert("wifi.ssid".to_owned(), "current".to_owned());
+
+        migrate(&mut store).unwrap();
+
+        assert_eq!(store.values.get("wifi.ssid").map(String::as_str), Some("current"));
+        assert_eq!(store.values.get("ssid").map(String::as_str), Some("ssid value"));
+    }
+
+    #[test]
+    fn migration_runs_once() {
+        let mut store = legacy_namespace();
+        migrate(&mut store).unwrap();
+
+        // A downgrade changing a tombstone doesn't overwrite the setting once back on this layout
+        store.values.insert("ssid".to_owned(), "older".to_owned());
+        migrate(&mut store).unwrap();
+
+        assert_eq!(store.values.get("wifi.ssid").map(String::as_str), Some("ssid value"));
+    }
+
+    #[test]
+    fn a_lost_copy_fails_the_migration_and_is_retried() {
+        let mut store = legacy_namespace();
+        store.lossy = Some("wifi.psk");
+
+        assert!(migrate(&mut store).is_err());
+        assert!(!store.values.contains_key(LAYOUT_KEY));
+        // Still read through the fallback meanwhile
+        let psk = find("net.wifi.psk").unwrap();
+        assert_eq!(read_setting(&store, psk).unwrap().as_deref(), Some("psk value"));
+
+        store.lossy = None;
+        migrate(&mut store).unwrap();
+        assert_eq!(store.values.get("wifi.psk").map(String::as_str), Some("psk value"));
+    }
+
+    #[test]
+    fn settings_exported_by_older_firmware_import_under_their_current_names() {
+        // An export of older firmware names settings by their flat keys, which /config/import resolves
+        for key in KEYS {
+            if let Some(legacy) = key.legacy {
+                assert_eq!(canonical_name(legacy), Some(key.name), "{legacy}");
+            }
+            assert_eq!(canonical_name(key.name), Some(key.name));
+        }
+    }
+
+    // Layout 2 of some state, which added `count` to layout 1 and `version` to the unversioned layout 0
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Layout2 {
+        name: String,
+        #[serde(default)]
+        count: u32,
+    }
+
+    fn decode(blob: &str) -> anyhow::Result<Decoded<Layout2>> {
+        decode_state(blob.as_bytes(), 2)
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let state = Layout2 {
+            name: "tank".to_owned(),
+            count: 3,
+        };
+        let blob = encode_state(2, &state).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&blob).unwrap()["version"], 2);
+        assert!(matches!(decode_state(&blob, 2).unwrap(), Decoded::State(decoded) if decoded == state));
+    }
+
+    #[test]
+    fn older_layouts_load_with_what_they_lack_defaulted() {
+        let expected = Layout2 {
+            name: "tank".to_owned(),
+            count: 0,
+        };
+        for blob in [r#"{"name":"tank"}"#, r#"{"version":1,"name":"tank"}"#] {
+            assert!(
+                matches!(decode(blob).unwrap(), Decoded::State(state) if state == expected),
+                "{blob}"
+            );
+        }
+    }
+
+    #[test]
+    fn newer_layouts_are_not_guessed_at() {
+        // Even one that would happen to parse
+        assert!(matches!(
+            decode(r#"{"version":3,"name":"tank"}"#).unwrap(),
+            Decoded::Newer(3)
+        ));
+        assert!(matches!(
+            decode(r#"{"version":7,"renamed":true}"#).unwrap(),
+            Decoded::Newer(7)
+        ));
+    }
+
+    #[test]
+    fn corrupt_state_is_an_error() {
+        for blob in ["", "{\"name\":", r#"{"version":"2","name":"tank"}"#, r#"{"version":2}"#] {
+            assert!(decode(blob).is_err(), "{blob:?}");
+        }
+    }
+
+    #[test]
+    fn flags_parse_in_every_spelling() {
+        for v in ["1", "true", "on"] {
+            assert_eq!(parse_flag(v), Some(true), "{v}");
+        }
+        for v in ["0", "false", "off"] {
+            assert_eq!(parse_flag(v), Some(false), "{v}");
+        }
+        assert_eq!(parse_flag("yes"), None);
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Installs updated images and protects against bad ones, which needs CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE.
+// The bootloader starts an image fresh from an update as pending verification and falls back to the
+// previous one if it resets before being marked valid, so a crash on boot rolls back without any help
+// from here.
+//
+// The `/ota` route itself is registered in http.rs with every other one, so the route registry puts it
+// under lockdown and load shedding like the rest; network.rs only manages the WiFi link.
+
+use std::{
+    ffi::CStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_svc::sys;
+use log::{error, info, warn};
+use tokio::{
+    task,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{journal, lifecycle, nvs, system};
+
+// Long enough for every WiFi attempt and the first measurement on a slow network
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// First byte of every app image header, checked before anything is written
+const IMAGE_MAGIC: u8 = 0xe9;
+// Read straight into flash from the HTTP server task, whose stack is small
+const CHUNK_LEN: usize = 1024;
+// Covers the image header, erased after an abort so the partial image can never pass for a bootable one
+const SECTOR_LEN: usize = 4096;
+/// Longest a single read of the upload blocks, also the socket timeout of the HTTP server. Shorter than the
+/// 5 s task watchdog, which is fed between reads.
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_secs(3);
+// An upload sending nothing for this long is aborted
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub(crate) enum InstallError {
+    /// The image or its transfer was bad, the boot partition is unchanged
+    Rejected(String),
+    /// The client stopped sending, the boot partition is unchanged
+    TimedOut,
+    Failed(anyhow::Error),
+}
+
+impl From<sys::EspError> for InstallError {
+    fn from(e: sys::EspError) -> Self {
+        Self::Failed(e.into())
+    }
+}
+
+/// An image written and set to boot next, pending verification once it does.
+#[derive(Debug, Clone)]
+pub(crate) struct Installed {
+    pub partition: String,
+    pub bytes: usize,
+}
+
+/// Where an update being installed is at, for the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Progress {
+    /// Bytes written so far, out of the Content-Length when the client sent one.
+    Receiving { received: usize, total: Option<u64> },
+    /// Installed and about to reboot into it.
+    Installed,
+}
+
+impl Progress {
+    /// Share of the image received, unknown without a Content-Length.
+    pub fn percent(&self) -> Option<u8> {
+        match *self {
+            Self::Receiving {
+                received,
+                total: Some(total),
+            } if total > 0 => Some((received as u64 * 100 / total).min(100) as u8),
+            Self::Receiving { .. } => None,
+            Self::Installed => Some(100),
+        }
+    }
+}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Progress of the update being installed, `None` when there is none.
+pub(crate) fn progress() -> Option<Progress> {
+    *PROGRESS.lock().unwrap()
+}
+
+fn set_progress(progress: Option<Progress>) {
+    *PROGRESS.lock().unwrap() = progress;
+}
+
+pub(crate) struct Context {
+    partition: String,
+}
+
+/// Returns a context only while the running image awaits verification after an update.
+pub(crate) fn init() -> anyhow::Result<Option<Box<Context>>> {
+    let mut state: sys::esp_ota_img_states_t = 0;
+    // Fails for a factory image or without OTA data, neither of which can be rolled back
+    let result = unsafe { sys::esp_ota_get_state_partition(sys::esp_ota_get_running_partition(), &mut state) };
+    if result != sys::ESP_OK || state != sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+        return Ok(None);
+    }
+
+    let partition = system::running_partition();
+    warn!(
+        "Image on {partition} pending verification, rolling back unless healthy within {}s",
+        VERIFY_TIMEOUT.as_secs()
+    );
+
+    Ok(Some(Box::new(Context { partition })))
+}
+
+pub(crate) async fn worker(ctx: Option<&mut Box<Context>>) -> anyhow::Result<()> {
+    // Nothing to verify, the image was accepted on an earlier boot or never came from an update
+    let Some(ctx) = ctx else {
+        return std::future::pending().await;
+    };
+
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        if lifecycle::healthy() {
+            match sys::esp!(unsafe { sys::esp_ota_mark_app_valid_cancel_rollback() }) {
+                Ok(()) => {
+                    record(ctx, true);
+                    info!("Image on {} verified, rollback cancelled", ctx.partition);
+                }
+                Err(e) => error!("Failed to mark the image valid: {e:?}"),
+            }
+            break;
+        }
+
+        if system::uptime() >= VERIFY_TIMEOUT.as_secs() {
+            let missing: Vec<_> = lifecycle::progress()
+                .into_iter()
+                .filter(|progress| progress.uptime_ms.is_none())
+                .map(|progress| progress.milestone)
+                .collect();
+            error!(
+                "Image on {} failed verification, missing {missing:?}, rolling back",
+                ctx.partition
+            );
+            record(ctx, false);
+
+            // The verdict is journaled before the reboot, the previous image reports it
+            task::block_in_place(nvs::flush);
+            lifecycle::transition(lifecycle::State::Rebooting);
+            // Only returns when there is no other image to go back to
+            if let Err(e) = sys::esp!(unsafe { sys::esp_ota_mark_app_invalid_rollback_and_reboot() }) {
+                error!("Rollback failed, keeping this image: {e:?}");
+            }
+            break;
+        }
+    }
+
+    std::future::pending().await
+}
+
+/// Writes an image obtained through `read` to the partition after the running one and makes it the boot
+/// partition. It is only validated once complete, any failure leaves the boot partition as it was.
+///
+/// `read` blocks for at most [`READ_TIMEOUT`], failing with `HTTPD_SOCK_ERR_TIMEOUT` when nothing arrived;
+/// such reads are retried until nothing has arrived for [`INACTIVITY_TIMEOUT`].
+pub(crate) fn install(
+    len: Option<u64>,
+    read: impl FnMut(&mut [u8]) -> Result<usize, sys::EspError>,
+) -> Result<Installed, InstallError> {
+    lifecycle::transition(lifecycle::State::Ota);
+    set_progress(Some(Progress::Receiving {
+        received: 0,
+        total: len,
+    }));
+    let installed = install_image(len, read);
+    // The caller reboots into a successful install, the display says so until then
+    if installed.is_ok() {
+        set_progress(Some(Progress::Installed));
+    } else {
+        set_progress(None);
+        lifecycle::resume();
+    }
+
+    installed
+}
+
+fn install_image(
+    len: Option<u64>,
+    mut read: impl FnMut(&mut [u8]) -> Result<usize, sys::EspError>,
+) -> Result<Installed, InstallError> {
+    let partition = unsafe { sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+    if partition.is_null() {
+        return Err(InstallError::Failed(anyhow!("No OTA partition to install to")));
+    }
+    let (label, size) = unsafe {
+        let label = CStr::from_ptr((*partition).label.as_ptr())
+            .to_string_lossy()
+            .into_owned();
+        (label, (*partition).size)
+    };
+    if len.is_some_and(|len| len > size as u64) {
+        return Err(InstallError::Rejected(format!(
+            "Image exceeds the {size} bytes of {label}"
+        )));
+    }
+
+    let mut handle: sys::esp_ota_handle_t = 0;
+    // Erases the whole partition up front, which takes a few seconds
+    sys::esp!(unsafe { sys::esp_ota_begin(partition, sys::OTA_SIZE_UNKNOWN as usize, &mut handle) })?;
+    let bytes = match write_image(handle, len, &mut read) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            unsafe { sys::esp_ota_abort(handle) };
+            if let Err(e) = sys::esp!(unsafe { sys::esp_partition_erase_range(partition, 0, SECTOR_LEN) }) {
+                warn!("Failed to erase the partial image on {label}: {e:?}");
+            }
+            return Err(e);
+        }
+    };
+
+    // Checks the image checksum, and its signature with secure boot; frees the handle either way
+    if let Err(e) = sys::esp!(unsafe { sys::esp_ota_end(handle) }) {
+        return Err(InstallError::Rejected(format!("Image failed validation: {e}")));
+    }
+    sys::esp!(unsafe { sys::esp_ota_set_boot_partition(partition) })?;
+
+    journal::record(journal::Event::OtaInstalled {
+        partition: label.clone(),
+        bytes,
+    });
+    info!("Installed {bytes} byte image on {label}");
+
+    Ok(Installed {
+        partition: label,
+        bytes,
+    })
+}
+
+fn write_image(
+    handle: sys::esp_ota_handle_t,
+    total: Option<u64>,
+    read: &mut impl FnMut(&mut [u8]) -> Result<usize, sys::EspError>,
+) -> Result<usize, InstallError> {
+    let watchdog = Watchdog::subscribe()?;
+    let mut chunk = [0_u8; CHUNK_LEN];
+    let mut written = 0;
+    let mut last_received = Instant::now();
+    loop {
+        let result = read(&mut chunk);
+        watchdog.feed();
+        let len = match result {
+            Ok(len) => len,
+            Err(e) if e.code() == sys::HTTPD_SOCK_ERR_TIMEOUT => {
+                if last_received.elapsed() >= INACTIVITY_TIMEOUT {
+                    warn!(
+                        "Nothing received for {} s, aborting the update",
+                        INACTIVITY_TIMEOUT.as_secs()
+                    );
+                    return Err(InstallError::TimedOut);
+                }
+                continue;
+            }
+            Err(e) => return Err(InstallError::Rejected(format!("Failed to read body: {e}"))),
+        };
+        if len == 0 {
+            break;
+        }
+        last_received = Instant::now();
+        if written == 0 && chunk[0] != IMAGE_MAGIC {
+            return Err(InstallError::Rejected("Not a firmware image".to_owned()));
+        }
+
+        sys::esp!(unsafe { sys::esp_ota_write(handle, chunk.as_ptr().cast(), len) })?;
+        watchdog.feed();
+        written += len;
+        set_progress(Some(Progress::Receiving {
+            received: written,
+            total,
+        }));
+    }
+
+    if written == 0 {
+        return Err(InstallError::Rejected("Empty image".to_owned()));
+    }
+    Ok(written)
+}
+
+/// Subscribes the image writer to the task watchdog, so a read or flash write stuck past its timeout
+/// resets the device instead of leaving it in OTA for good.
+struct Watchdog(sys::esp_task_wdt_user_handle_t);
+
+impl Watchdog {
+    fn subscribe() -> Result<Self, InstallError> {
+        let mut handle = std::ptr::null_mut();
+        sys::esp!(unsafe { sys::esp_task_wdt_add_user(c"ota".as_ptr(), &mut handle) })?;
+        Ok(Self(handle))
+    }
+
+    fn feed(&self) {
+        unsafe { sys::esp_task_wdt_reset_user(self.0) };
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        unsafe { sys::esp_task_wdt_delete_user(self.0) };
+    }
+}
+
+fn record(ctx: &Context, valid: bool) {
+    journal::record(journal::Event::OtaVerdict {
+        valid,
+        partition: ctx.partition.clone(),
+        uptime_ms: system::uptime_ms(),
+        milestones: lifecycle::progress(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_follows_the_content_length() {
+        let receiving = |received, total| Progress::Receiving { received, total };
+
+        assert_eq!(receiving(0, Some(1_200_000)).percent(), Some(0));
+        assert_eq!(receiving(600_000, Some(1_200_000)).percent(), Some(50));
+        assert_eq!(receiving(1_200_000, Some(1_200_000)).percent(), Some(100));
+        // A client sending more than it announced is rejected later, the bar just stays full
+        assert_eq!(receiving(1_300_000, Some(1_200_000)).percent(), Some(100));
+        assert_eq!(receiving(600_000, None).percent(), None);
+        assert_eq!(receiving(0, Some(0)).percent(), None);
+        assert_eq!(Progress::Installed.percent(), Some(100));
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Remembers which DS18B20 the calibration was made with, so a replaced probe doesn't quietly carry on
+// with it. The ROM code of the probe is pinned when first found, on calibration and on confirmation
+// through `/sensors/confirm`; a different one turning up afterwards leaves the calibration unverified
+// until the user says so.
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{display, journal, measurements, nvs};
+
+const PINNED_KEY: &str = "probe.pinned";
+// How long "New sensor?" stays on the display, once per boot
+const BANNER_DURATION: Duration = Duration::from_secs(60);
+
+static PINNED: Mutex<Option<u64>> = Mutex::new(None);
+static UNVERIFIED: AtomicBool = AtomicBool::new(false);
+static BANNER_SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Whether the calibration was made with the probe in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Calibration {
+    Verified,
+    /// Carried over from another probe, until confirmed or recalibrated.
+    Unverified,
+}
+
+/// How a probe found on the bus compares with the pinned one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    /// Nothing pinned yet, the probe is taken as the one calibrated with.
+    Pin,
+    Same,
+    Changed {
+        pinned: u64,
+    },
+}
+
+impl Check {
+    fn new(pinned: Option<u64>, found: u64) -> Self {
+        match pinned {
+            None => Self::Pin,
+            Some(pinned) if pinned == found => Self::Same,
+            Some(pinned) => Self::Changed { pinned },
+        }
+    }
+}
+
+/// The temperature probe against the pinned one, for `/diagnostics`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Status {
+    pub address: Option<String>,
+    pub pinned: Option<String>,
+    pub calibration: Calibration,
+}
+
+pub(crate) fn init() -> anyhow::Result<()> {
+    let pinned = nvs::load_blob(PINNED_KEY)?
+        .and_then(|blob| blob.try_into().ok())
+        .map(u64::from_le_bytes);
+    *PINNED.lock().unwrap() = pinned;
+
+    Ok(())
+}
+
+pub(crate) fn status() -> Status {
+    let calibration = if UNVERIFIED.load(Ordering::Relaxed) {
+        Calibration::Unverified
+    } else {
+        Calibration::Verified
+    };
+
+    Status {
+        address: measurements::probe_address().map(format_address),
+        pinned: PINNED.lock().unwrap().map(format_address),
+        calibration,
+    }
+}
+
+/// Compares a probe found on the bus with the pinned one, pinning it if there is none yet.
+pub(crate) fn discovered(address: u64) {
+    let mut pinned = PINNED.lock().unwrap();
+    match Check::new(*pinned, address) {
+        Check::Pin => {
+            if let Err(e) = nvs::store_blob(PINNED_KEY, &address.to_le_bytes()) {
+                warn!("Failed to pin the temperature probe: {e:?}");
+            }
+            *pinned = Some(address);
+        }
+        // The calibrated probe is back
+        Check::Same => UNVERIFIED.store(false, Ordering::Relaxed),
+        Check::Changed { pinned } => {
+            // Once per probe change, rediscovering the same probe says nothing new
+            if UNVERIFIED.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            warn!(
+                "Temperature probe changed from {} to {}, the calibration may not fit it. Confirm the probe \
+                 through POST /sensors/confirm or recalibrate",
+                format_address(pinned),
+                format_address(address)
+            );
+            journal::record(journal::Event::ProbeChanged {
+                pinned: format_address(pinned),
+                found: format_address(address),
+            });
+            BANNER_SINCE.lock().unwrap().get_or_insert_with(Instant::now);
+            display::wake();
+        }
+    }
+}
+
+/// Pins the probe in use, the calibration being known to fit it. Returns its ROM code.
+pub(crate) fn confirm() -> anyhow::Result<String> {
+    let address = measurements::probe_address().ok_or(anyhow!("No temperature probe found"))?;
+    nvs::store_blob(PINNED_KEY, &address.to_le_bytes())?;
+    *PINNED.lock().unwrap() = Some(address);
+    if UNVERIFIED.swap(false, Ordering::Relaxed) {
+        info!("Temperature probe {} confirmed", format_address(address));
+    }
+
+    Ok(format_address(address))
+}
+
+/// ROM code of the pinned probe.
+pub(crate) fn pinned() -> Option<String> {
+    PINNED.lock().unwrap().map(format_address)
+}
+
+/// Pins the probe with ROM code `address`, e.g. one calibrated on another board. The calibration stays
+/// unverified while a different probe is in use.
+pub(crate) fn pin(address: u64) -> anyhow::Result<()> {
+    nvs::store_blob(PINNED_KEY, &address.to_le_bytes())?;
+    *PINNED.lock().unwrap() = Some(address);
+    let found = measurements::probe_address();
+    UNVERIFIED.store(found.is_some_and(|found| found != address), Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Whether the display should ask about a new probe, for a while after one was found.
+pub(crate) fn banner() -> bool {
+    let since = *BANNER_SINCE.lock().unwrap();
+    since.is_some_and(|since| since.elapsed() < BANNER_DURATION) && UNVERIFIED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn format_address(address: u64) -> String {
+    format!("{address:016x}")
+}
+
+pub(crate) fn parse_address(address: &str) -> anyhow::Result<u64> {
+    // from_str_radix would also take a sign
+    if address.len() != 16 || !address.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("ROM code must be 16 hex digits: {address:?}"));
+    }
+    Ok(u64::from_str_radix(address, 16)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_different_probe_is_a_change() {
+        assert_eq!(Check::new(None, 0x28ff_0000_0000_0001), Check::Pin);
+        assert_eq!(
+            Check::new(Some(0x28ff_0000_0000_0001), 0x28ff_0000_0000_0001),
+            Check::Same
+        );
+        assert_eq!(
+            Check::new(Some(0x28ff_0000_0000_0001), 0x28ff_0000_0000_0002),
+            Check::Changed {
+                pinned: 0x28ff_0000_0000_0001
+            }
+        );
+        assert_eq!(format_address(0x28ff_0000_0000_0001), "28ff000000000001");
+        assert_eq!(parse_address("28ff000000000001").unwrap(), 0x28ff_0000_0000_0001);
+        for address in ["28ff00000000001", "28ff00000000000g", "+28ff00000000001"] {
+            assert!(parse_address(address).is_err(), "{address}");
+        }
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Pushes readings to an HTTP collector as InfluxDB line protocol, or one request per reading shaped by
+// `net.push.template`, for installations behind NAT where nothing can reach the embedded server. Requests
+// run on a thread of their own, a slow or unreachable collector never holds up the workers in `main`.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use esp_idf_svc::{
+    http::{
+        Method,
+        client::{Configuration as HttpConfiguration, EspHttpConnection},
+    },
+    io::Write,
+    sys,
+};
+use log::{info, warn};
+use tokio::task;
+
+use crate::{
+    breaker::{self, Breaker},
+    integrations, measurements, network, nvs, system,
+    template::{self, Escape},
+};
+
+// How often the thread looks for a new measurement, well below the measurement interval
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Points kept while the collector can't be reached, the oldest giving way to new ones
+const BACKLOG_LEN: usize = 120;
+// Points per request when catching up on a backlog, keeping each body a few kilobytes
+const BATCH_LEN: usize = 30;
+
+// TLS handshakes need more than the default pthread stack
+const STACK_SIZE: usize = 8 * 1024;
+
+const MEASUREMENT: &str = "cobitis";
+
+struct Collector {
+    url: String,
+    authorization: Option<String>,
+    /// Body of each request in place of line protocol, one reading per request.
+    template: Option<(String, Escape)>,
+}
+
+static COLLECTOR: OnceLock<Collector> = OnceLock::new();
+static SENT: AtomicU32 = AtomicU32::new(0);
+static FAILED: AtomicU32 = AtomicU32::new(0);
+static LAST_SUCCESS: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+static LAST_FAILURE: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+static BREAKER: Mutex<Breaker> = Mutex::new(Breaker::new());
+
+/// Starts pushing to `net.push.url` if it is configured and `net.push.enabled` is not off, unless the
+/// device runs its setup access point.
+pub(crate) fn init() -> anyhow::Result<()> {
+    task::block_in_place(init_blocking)
+}
+
+fn init_blocking() -> anyhow::Result<()> {
+    let Some(url) = nvs::get_opt("net.push.url")? else {
+        return Ok(());
+    };
+    if !nvs::get_flag_or("net.push.enabled", true)? {
+        info!("Pushing readings to {url} is disabled");
+        return Ok(());
+    }
+    if network::setup().is_some() {
+        return Ok(());
+    }
+    // A bare token gets InfluxDB's scheme, anything else names its own, e.g. `Bearer ...`
+    let authorization = nvs::get_opt("net.push.token")?.map(|token| {
+        if token.contains(' ') {
+            token
+        } else {
+            format!("Token {token}")
+        }
+    });
+
+    let template = template::load("net.push.template", "net.push.template_json")?;
+    COLLECTOR
+        .set(Collector {
+            url,
+            authorization,
+            template,
+        })
+        .map_err(|_| anyhow!("Push already initialized"))?;
+    std::thread::Builder::new()
+        .name("push".to_owned())
+        .stack_size(STACK_SIZE)
+        .spawn(run)?;
+    info!("Pushing readings to {}", COLLECTOR.get().unwrap().url);
+
+    Ok(())
+}
+
+/// Delivery state of the collector push for `/integrations`, `sent` counting points.
+pub(crate) fn status() -> integrations::Status {
+    integrations::Status {
+        enabled: COLLECTOR.get().is_some(),
+        endpoint: COLLECTOR.get().map(|collector| collector.url.clone()),
+        sent: SENT.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        last_success: *LAST_SUCCESS.lock().unwrap(),
+        last_failure: *LAST_FAILURE.lock().unwrap(),
+    }
+}
+
+/// Whether the collector is being skipped after failing repeatedly, for `/diagnostics`.
+pub(crate) fn breaker() -> Option<breaker::Status> {
+    COLLECTOR.get()?;
+    Some(BREAKER.lock().unwrap().status(Instant::now()))
+}
+
+/// Pushes the latest reading right away, outside of the backlog.
+pub(crate) fn test() -> anyhow::Result<()> {
+    let collector = COLLECTOR.get().ok_or(anyhow!("Push is not enabled"))?;
+    if !network::is_connected() {
+        return Err(anyhow!("WiFi is not connected"));
+    }
+    let values = measurements::get().ok_or(anyhow!("No reading to push"))?;
+    let point = point(collector, &values)?.ok_or(anyhow!("No reading to push"))?;
+
+    send(collector, &[point])
+}
+
+/// The body pushed for a reading, `None` when there is nothing to push.
+fn point(collector: &Collector, values: &measurements::Values) -> anyhow::Result<Option<String>> {
+    match &collector.template {
+        Some((template, escape)) => template::render(template, *escape, &template::Fields::new(values)).map(Some),
+        None => Ok(line(values)),
+    }
+}
+
+/// Points taken without a valid clock are left for the collector to timestamp on arrival.
+fn line(values: &measurements::Values) -> Option<String> {
+    let mut fields = Vec::new();
+    if values.temperature_enabled && values.temperature.is_finite() {
+        fields.push(format!("temperature={}", values.temperature));
+    }
+    if values.tds_enabled && values.tds.is_finite() {
+        fields.push(format!("tds={}i", values.tds.round() as i32));
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    // A string field rather than a tag, which would start a new series every boot
+    fields.push(format!("boot_id=\"{}\"", system::boot_id()));
+
+    let mut line = format!("{MEASUREMENT},device={} {}", system::device_id(), fields.join(","));
+    if values.simulated {
+        line.push_str(",simulated=true");
+    }
+    if values.clock_valid {
+        let _ = write!(line, " {}", values.timestamp * 1_000_000);
+    }
+
+    Some(line)
+}
+
+fn run() {
+    let collector = COLLECTOR.get().unwrap();
+    let mut backlog = VecDeque::with_capacity(BACKLOG_LEN);
+    let mut queued_seq = None;
+    let mut overflowing = false;
+
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+
+        // Warm-up readings stay out, like they do from `/history`
+        if let Some(values) = measurements::get().filter(|v| !v.warming_up && queued_seq != Some(v.seq)) {
+            queued_seq = Some(values.seq);
+            let point = point(collector, &values).unwrap_or_else(|e| {
+                warn!("Failed to render the push template: {e:#}");
+                None
+            });
+            if let Some(point) = point {
+                if backlog.len() == BACKLOG_LEN {
+                    backlog.pop_front();
+                    if !overflowing {
+                        warn!("Push backlog full, dropping the oldest readings");
+                        overflowing = true;
+                    }
+                }
+                backlog.push_back(point);
+            }
+        }
+
+        // Time offline doesn't count against the collector, failures while offline say nothing about it
+        if !network::is_connected() {
+            continue;
+        }
+
+        while !backlog.is_empty() && BREAKER.lock().unwrap().allow(Instant::now()) {
+            // A templated body holds a single reading, there is no telling how to join several
+            let batch_len = if collector.template.is_some() { 1 } else { BATCH_LEN };
+            let batch = backlog.len().min(batch_len);
+            match send(collector, &backlog.make_contiguous()[..batch]) {
+                Ok(()) => {
+                    backlog.drain(..batch);
+                    BREAKER.lock().unwrap().success();
+                    overflowing = false;
+                }
+                Err(e) => {
+                    let mut breaker = BREAKER.lock().unwrap();
+                    if breaker.failure(Instant::now()) {
+                        warn!(
+                            "Failed to push {} readings, skipping the collector for {}s: {e:?}",
+                            backlog.len(),
+                            breaker.cool_off().as_secs()
+                        );
+                    } else {
+                        warn!("Failed to push {} readings: {e:?}", backlog.len());
+                    }
+                    // Tried again on the next poll at the earliest
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn send(collector: &Collector, lines: &[String]) -> anyhow::Result<()> {
+    let result = post(collector, &lines.join("\n"));
+
+    let now = Utc::now();
+    match &result {
+        Ok(()) => {
+            SENT.fetch_add(lines.len() as u32, Ordering::Relaxed);
+            *LAST_SUCCESS.lock().unwrap() = Some(now);
+        }
+        Err(_) => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            *LAST_FAILURE.lock().unwrap() = Some(now);
+        }
+    }
+
+    result
+}
+
+fn post(collector: &Collector, body: &str) -> anyhow::Result<()> {
+    let mut connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(REQUEST_TIMEOUT),
+        crt_bundle_attach: Some(sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+
+    let content_type = match &collector.template {
+        Some((_, Escape::Json)) => "application/json",
+        _ => "text/plain; charset=utf-8",
+    };
+    let content_length = body.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", content_type),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if let Some(authorization) = &collector.authorization {
+        headers.push(("Authorization", authorization));
+    }
+
+    connection.initiate_request(Method::Post, &collector.url, &headers)?;
+    connection.write_all(body.as_bytes())?;
+    connection.initiate_response()?;
+
+    // InfluxDB answers 204, other collectors 200
+    match connection.status() {
+        200..=299 => Ok(()),
+        status => Err(anyhow!("Collector answered {status}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> measurements::Values {
+        measurements::Values {
+            timestamp: 1_700_000_000_000,
+            temperature: 25.5,
+            temperature_at: 1_700_000_000_000,
+            tds: 300.4,
+            tds_at: 1_700_000_000_000,
+            supply_voltage: None,
+            simulated: false,
+            uptime_ms: 60_000,
+            clock_valid: true,
+            seq: 7,
+            compensation: measurements::Compensation {
+                temperature: 25.5,
+                measured_at: 1_700_000_000_000,
+                fallback: false,
+                k_factor: 1.0,
+                voltage: None,
+            },
+            warming_up: false,
+            temperature_enabled: true,
+            tds_enabled: true,
+        }
+    }
+
+    #[test]
+    fn lines_carry_the_boot_id() {
+        assert_eq!(
+            line(&values()).unwrap(),
+            format!(
+                "cobitis,device={} temperature=25.5,tds=300i,boot_id=\"{}\" 1700000000000000000",
+                system::device_id(),
+                system::boot_id()
+            )
+        );
+    }
+
+    #[test]
+    fn templates_replace_line_protocol() {
+        let collector = Collector {
+            url: "http://collector.local/".to_owned(),
+            authorization: None,
+            template: Some((r#"{"t":{{temperature}},"id":"{{device_id}}"}"#.to_owned(), Escape::Json)),
+        };
+        assert_eq!(
+            point(&collector, &values()).unwrap().unwrap(),
+            format!(r#"{{"t":25.5,"id":"{}"}}"#, system::device_id())
+        );
+    }
+
+    #[test]
+    fn nothing_is_pushed_without_a_reading() {
+        let mut values = values();
+        values.temperature_enabled = false;
+        values.tds = f32::NAN;
+        assert_eq!(line(&values), None);
+    }
+}
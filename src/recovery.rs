@@ -0,0 +1,135 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{mem::MaybeUninit, ptr::addr_of_mut, sync::Mutex};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{journal, measurements, system};
+
+const MAGIC: u32 = 0x434f_4249; // "COBI"
+
+// Bumped whenever `Ring` or `Record` changes layout, a ring written by other firmware is discarded
+const VERSION: u32 = 3;
+const CAPACITY: usize = 64;
+
+/// Compact copy of a measurement kept in RTC memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Record {
+    /// Boot that took the measurement, so a post-mortem links back to its logs
+    #[serde(default)]
+    pub boot_id: system::BootId,
+    /// Sequence number of the snapshot within its boot; warm-up snapshots are never recorded
+    #[serde(default)]
+    pub seq: u32,
+    pub timestamp: i64,
+    pub temperature: f32,
+    pub tds: f32,
+}
+
+#[repr(C)]
+struct Ring {
+    magic: u32,
+    version: u32,
+    crc: u32,
+    head: u32,
+    len: u32,
+    records: [Record; CAPACITY],
+}
+
+// RTC memory keeps its contents across software resets and panics, but not across power loss
+#[link_section = ".rtc_noinit"]
+static mut RING: MaybeUninit<Ring> = MaybeUninit::uninit();
+
+// Guards RING; lives in regular RAM so it is always valid after a reset
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Validates the ring left by the previous boot and returns its records, oldest first.
+///
+/// The ring is reset when it is invalid or after a power-on reset, where RTC memory holds garbage.
+pub(crate) fn init(reset_reason: &str) -> Vec<Record> {
+    let _lock = LOCK.lock().unwrap();
+    let ring = unsafe { &mut *addr_of_mut!(RING).cast::<Ring>() };
+
+    let carried_over = reset_reason != "power_on" && ring.magic == MAGIC;
+    if carried_over && ring.version != VERSION {
+        warn!("Discarding RTC ring with layout version {}", ring.version);
+        journal::record(journal::Event::StateDiscarded {
+            key: "rtc_ring".to_owned(),
+            version: ring.version,
+        });
+    }
+
+    let valid = carried_over
+        && ring.version == VERSION
+        && ring.crc == checksum(ring)
+        && (ring.head as usize) < CAPACITY
+        && (ring.len as usize) <= CAPACITY;
+    if !valid {
+        ring.magic = MAGIC;
+        ring.version = VERSION;
+        ring.head = 0;
+        ring.len = 0;
+        ring.crc = checksum(ring);
+
+        return vec![];
+    }
+
+    let len = ring.len as usize;
+    let start = (ring.head as usize + CAPACITY - len) % CAPACITY;
+    let records: Vec<_> = (0..len).map(|i| ring.records[(start + i) % CAPACITY]).collect();
+    info!("Recovered {} measurements from RTC memory", records.len());
+
+    records
+}
+
+pub(crate) fn push(values: &measurements::Values) {
+    let _lock = LOCK.lock().unwrap();
+    let ring = unsafe { &mut *addr_of_mut!(RING).cast::<Ring>() };
+
+    ring.records[ring.head as usize] = Record {
+        boot_id: system::boot_id(),
+        seq: values.seq,
+        timestamp: values.timestamp,
+        temperature: values.temperature,
+        tds: values.tds,
+    };
+    ring.head = (ring.head + 1) % CAPACITY as u32;
+    ring.len = (ring.len + 1).min(CAPACITY as u32);
+    ring.crc = checksum(ring);
+}
+
+fn checksum(ring: &Ring) -> u32 {
+    let mut crc = crc32_update(!0, &ring.version.to_le_bytes());
+    crc = crc32_update(crc, &ring.head.to_le_bytes());
+    crc = crc32_update(crc, &ring.len.to_le_bytes());
+    for record in &ring.records {
+        crc = crc32_update(crc, &record.boot_id.0.to_le_bytes());
+        crc = crc32_update(crc, &record.seq.to_le_bytes());
+        crc = crc32_update(crc, &record.timestamp.to_le_bytes());
+        crc = crc32_update(crc, &record.temperature.to_le_bytes());
+        crc = crc32_update(crc, &record.tds.to_le_bytes());
+    }
+
+    !crc
+}
+
+/// Feeds `data` into a CRC-32 (IEEE) in progress, started from `!0` and inverted when done.
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
@@ -0,0 +1,112 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::ops::RangeInclusive;
+
+/// Single source of truth for how a metric is named, labeled and formatted in every output.
+#[derive(Debug)]
+pub(crate) struct Metric {
+    /// Canonical name, used as the JSON key
+    pub name: &'static str,
+    /// Unit of the value, spelled out in the Prometheus help text
+    pub unit: &'static str,
+    /// Unit label drawn next to the value on the display
+    pub label: &'static str,
+    /// Number of decimals transmitted
+    pub precision: usize,
+    /// Number of decimals drawn on the display
+    pub display_precision: usize,
+    /// Home Assistant device class, if one fits
+    pub device_class: Option<&'static str>,
+    /// Prometheus metric name
+    pub prometheus: &'static str,
+    /// Physically possible range, anything outside points at a broken or shorted probe
+    pub plausible: RangeInclusive<f32>,
+}
+
+pub(crate) const TEMPERATURE: Metric = Metric {
+    name: "temperature",
+    unit: "celsius",
+    label: "°C",
+    precision: 1,
+    display_precision: 1,
+    device_class: Some("temperature"),
+    prometheus: "cobitis_temperature_celsius",
+    plausible: -20.0..=60.0,
+};
+
+pub(crate) const TDS: Metric = Metric {
+    name: "tds",
+    unit: "ppm",
+    label: "ppm",
+    precision: 1,
+    display_precision: 0,
+    device_class: None,
+    prometheus: "cobitis_tds_ppm",
+    plausible: 0.0..=5000.0,
+};
+
+impl Metric {
+    /// Rounds a full-precision value to the metric's transmitted precision.
+    pub fn round(&self, value: f32) -> f32 {
+        round(value, self.precision)
+    }
+
+    pub fn is_plausible(&self, value: f32) -> bool {
+        self.plausible.contains(&value)
+    }
+}
+
+/// Rounds half away from zero, the one rounding rule shared by every output.
+///
+/// Negative zero is folded into zero so that e.g. -0.04 never shows up as "-0.0".
+pub(crate) fn round(value: f32, precision: usize) -> f32 {
+    let factor = 10_f32.powi(precision as i32);
+    (value * factor).round() / factor + 0.0
+}
+
+/// Formats a value with the same rounding as [`round`].
+///
+/// `format!` alone rounds ties to even (186.5 becomes "186"), which would disagree with the transmitted
+/// value. Rounding first leaves it nothing to round.
+pub(crate) fn format(value: f32, precision: usize) -> String {
+    format!("{:.precision$}", round(value, precision))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_ties_away_from_zero() {
+        assert_eq!(round(186.5, 0), 187.0);
+        assert_eq!(round(-186.5, 0), -187.0);
+        assert_eq!(round(0.25, 1), 0.3);
+        assert_eq!(round(-0.05, 1), -0.1);
+    }
+
+    #[test]
+    fn round_carries_into_the_next_digit() {
+        assert_eq!(round(99.96, 1), 100.0);
+        assert_eq!(format(99.96, 1), "100.0");
+    }
+
+    #[test]
+    fn round_folds_negative_zero_into_zero() {
+        let rounded = round(-0.04, 1);
+        assert_eq!(rounded, 0.0);
+        assert!(rounded.is_sign_positive());
+        assert_eq!(format(-0.04, 1), "0.0");
+        assert_eq!(format(-0.4, 0), "0");
+    }
+
+    #[test]
+    fn format_agrees_with_the_transmitted_value() {
+        assert_eq!(format(186.5, 0), "187");
+        assert_eq!(format(24.56, 1), "24.6");
+        assert_eq!(format(TDS.round(186.46), TDS.precision), "186.5");
+        assert_eq!(TEMPERATURE.round(24.56), 24.6);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{fmt, time::Duration};
+
+use esp_idf_svc::hal::delay::FreeRtos;
+
+/// Error of an operation that failed on every attempt, keeping each attempt's error in order.
+#[derive(Debug)]
+pub(crate) struct Exhausted {
+    pub errors: Vec<anyhow::Error>,
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed after {} attempts", self.errors.len())?;
+        for (i, e) in self.errors.iter().enumerate() {
+            write!(f, "; #{}: {e}", i + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Exhausted {}
+
+/// Runs `op` up to `attempts` times, sleeping `backoff` between attempts, and returns the first success.
+///
+/// Blocks the calling thread, so async callers go through `task::block_in_place` as with any other
+/// hardware access.
+pub(crate) fn retry_blocking<T, F>(attempts: u32, backoff: Duration, mut op: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    let mut errors = vec![];
+
+    for attempt in 0..attempts {
+        if attempt > 0 && !backoff.is_zero() {
+            FreeRtos::delay_ms(backoff.as_millis() as u32);
+        }
+
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    Err(Exhausted { errors }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn succeeds_on_the_last_attempt() {
+        let mut calls = 0;
+        let result = retry_blocking(3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(anyhow!("attempt {calls}"))
+            } else {
+                Ok(calls)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn stops_at_the_first_success() {
+        let mut calls = 0;
+        let result = retry_blocking(5, Duration::ZERO, || {
+            calls += 1;
+            anyhow::Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn total_failure_keeps_every_error_in_order() {
+        let mut calls = 0;
+        let error = retry_blocking(3, Duration::ZERO, || -> anyhow::Result<()> {
+            calls += 1;
+            Err(anyhow!("attempt {calls}"))
+        })
+        .unwrap_err();
+
+        let exhausted = error.downcast_ref::<Exhausted>().unwrap();
+        let errors: Vec<_> = exhausted.errors.iter().map(ToString::to_string).collect();
+        assert_eq!(errors, ["attempt 1", "attempt 2", "attempt 3"]);
+        assert_eq!(
+            error.to_string(),
+            "Failed after 3 attempts; #1: attempt 1; #2: attempt 2; #3: attempt 3"
+        );
+    }
+
+    #[test]
+    fn backoff_is_slept_between_attempts_only() {
+        const BACKOFF: Duration = Duration::from_millis(50);
+
+        let started = Instant::now();
+        let _ = retry_blocking(3, BACKOFF, || -> anyhow::Result<()> { Err(anyhow!("failed")) });
+        let elapsed = started.elapsed();
+        assert!(elapsed >= 2 * BACKOFF, "{elapsed:?}");
+        assert!(elapsed < 3 * BACKOFF, "{elapsed:?}");
+
+        // Neither before the first attempt nor after a success
+        let started = Instant::now();
+        retry_blocking(3, BACKOFF, || anyhow::Ok(())).unwrap();
+        assert!(started.elapsed() < BACKOFF);
+    }
+}
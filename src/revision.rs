@@ -0,0 +1,367 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Numbers every change applied through `/config` and keeps the whole configuration from before the
+// latest one, so a change that turns out bad can be undone through `/config/rollback`. A change stays
+// on probation for a while after it is applied, and again for a while after the next boot since most
+// settings only take effect then; settings that keep failing to parse during that time roll it back on
+// their own. Changes applied in quick succession, e.g. a form saved field by field, count as one.
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::{alerts, journal, measurements, network, nvs, system};
+
+const NVS_KEY: &str = "config.rev";
+const VERSION: u32 = 1;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Changes this close together make up one revision, rolled back as a whole
+const DEBOUNCE: Duration = Duration::from_secs(30);
+// How long a change is on probation, after applying it and after the next boot
+const PROBATION: Duration = Duration::from_secs(120);
+// Invalid settings read during probation before the change is rolled back
+const MAX_ERRORS: u32 = 3;
+
+// Leaves the log line and the journal entry time to get out before the restart
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Settings by logical name, those unset left out.
+pub(crate) type Config = BTreeMap<String, String>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    revision: u32,
+    /// The configuration before the latest revision.
+    previous: Option<Config>,
+    /// Whether the latest revision is still to pass probation, possibly across a reboot.
+    on_probation: bool,
+}
+
+/// Outcome of a rollback.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Rollback {
+    pub revision: u32,
+    /// Settings changed back, those unset in the restored configuration included.
+    pub restored: Vec<String>,
+}
+
+/// What happens after an invalid setting is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Ignore,
+    RollBack,
+}
+
+/// Probation of the latest revision, by uptime in ms.
+#[derive(Debug, Default)]
+struct Tracker {
+    probation_since: Option<i64>,
+    errors: u32,
+    /// When the latest change was applied, for debouncing.
+    applied_at: Option<i64>,
+    rolling_back: bool,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            probation_since: None,
+            errors: 0,
+            applied_at: None,
+            rolling_back: false,
+        }
+    }
+
+    /// Records a change applied at `now`, returning whether it starts a new revision.
+    fn applied(&mut self, now: i64) -> bool {
+        let new = self.applied_at.is_none_or(|at| now - at >= DEBOUNCE.as_millis() as i64);
+        self.applied_at = Some(now);
+        self.probation_since = Some(now);
+        self.errors = 0;
+
+        new
+    }
+
+    fn error(&mut self, now: i64) -> Verdict {
+        let Some(since) = self.probation_since.filter(|_| !self.rolling_back) else {
+            return Verdict::Ignore;
+        };
+        if now - since >= PROBATION.as_millis() as i64 {
+            return Verdict::Ignore;
+        }
+
+        self.errors += 1;
+        if self.errors < MAX_ERRORS {
+            return Verdict::Ignore;
+        }
+        self.rolling_back = true;
+        Verdict::RollBack
+    }
+
+    /// Ends probation once it passed without a rollback, returning whether it just did.
+    fn settle(&mut self, now: i64) -> bool {
+        let passed = self
+            .probation_since
+            .is_some_and(|since| !self.rolling_back && now - since >= PROBATION.as_millis() as i64);
+        if passed {
+            self.probation_since = None;
+        }
+
+        passed
+    }
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// Resumes the probation of a revision applied before the reboot.
+pub(crate) fn init() -> anyhow::Result<()> {
+    let stored = load()?;
+    if stored.on_probation {
+        info!("Configuration revision {} is on probation", stored.revision);
+        TRACKER.lock().unwrap().probation_since = Some(system::uptime_ms());
+    }
+
+    Ok(())
+}
+
+/// The current configuration, settings that can only be changed over the serial port left out.
+pub(crate) fn snapshot() -> anyhow::Result<Config> {
+    let mut config = Config::new();
+    for name in nvs::names().filter(|name| !nvs::is_local_only(name)) {
+        if let Some(value) = nvs::get_opt(name)? {
+            config.insert(name.to_owned(), value);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Records a change made to `before`, returning the revision it belongs to.
+pub(crate) fn applied(before: Config) -> anyhow::Result<u32> {
+    let mut stored = load()?;
+    if TRACKER.lock().unwrap().applied(system::uptime_ms()) || stored.previous.is_none() {
+        stored.revision = stored.revision.wrapping_add(1);
+        stored.previous = Some(before);
+    }
+    stored.on_probation = true;
+    save(&stored)?;
+
+    Ok(stored.revision)
+}
+
+/// Counts a setting that failed to parse. A few of them while the latest change is on probation roll
+/// it back.
+pub(crate) fn config_error(name: &str) {
+    if TRACKER.lock().unwrap().error(system::uptime_ms()) == Verdict::RollBack {
+        warn!("Settings keep failing to parse since the last change, {name} among them; rolling it back");
+    }
+}
+
+/// Restores the configuration from before the latest revision. The configuration replaced is kept in
+/// its place, so rolling back again undoes the rollback.
+pub(crate) fn roll_back() -> anyhow::Result<Rollback> {
+    let mut stored = load()?;
+    let previous = stored
+        .previous
+        .take()
+        .ok_or(anyhow!("No earlier revision to roll back to"))?;
+    let current = snapshot()?;
+
+    let changes = diff(&current, &previous);
+    for (name, value) in &changes {
+        match value {
+            Some(value) => nvs::set(name, value)?,
+            None => nvs::remove(name)?,
+        }
+    }
+    stored.revision = stored.revision.wrapping_add(1);
+    stored.previous = Some(current);
+    stored.on_probation = false;
+    save(&stored)?;
+    *TRACKER.lock().unwrap() = Tracker::new();
+
+    let restored: Vec<_> = changes.into_iter().map(|(name, _)| name.to_owned()).collect();
+    info!(
+        "Configuration rolled back to revision {}: {}",
+        stored.revision,
+        restored.join(", ")
+    );
+    measurements::reload_switches()?;
+    alerts::reload()?;
+    network::reload_tx_power()?;
+
+    Ok(Rollback {
+        revision: stored.revision,
+        restored,
+    })
+}
+
+/// What to write to turn `current` into `target`, `None` unsetting a setting.
+fn diff<'a>(current: &'a Config, target: &'a Config) -> Vec<(&'a str, Option<&'a str>)> {
+    let changed = target
+        .iter()
+        .filter(|&(name, value)| current.get(name) != Some(value))
+        .map(|(name, value)| (name.as_str(), Some(value.as_str())));
+    let removed = current
+        .keys()
+        .filter(|name| !target.contains_key(*name))
+        .map(|name| (name.as_str(), None));
+
+    changed.chain(removed).collect()
+}
+
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let (rolling_back, settled) = {
+            let mut tracker = TRACKER.lock().unwrap();
+            (tracker.rolling_back, tracker.settle(system::uptime_ms()))
+        };
+        if settled {
+            info!("Configuration passed probation");
+            if let Err(e) = load().and_then(|stored| {
+                save(&Stored {
+                    on_probation: false,
+                    ..stored
+                })
+            }) {
+                error!("Failed to end configuration probation: {e:?}");
+            }
+        }
+        if rolling_back {
+            match tokio::task::block_in_place(roll_back) {
+                Ok(rollback) => {
+                    journal::record(journal::Event::ConfigRollback {
+                        revision: rollback.revision,
+                        automatic: true,
+                        restored: rollback.restored,
+                    });
+                    // Most settings are only read at boot
+                    system::restart_after(RESTART_DELAY);
+                    return std::future::pending().await;
+                }
+                Err(e) => {
+                    error!("Failed to roll back the configuration: {e:?}");
+                    *TRACKER.lock().unwrap() = Tracker::new();
+                }
+            }
+        }
+    }
+}
+
+fn load() -> anyhow::Result<Stored> {
+    Ok(nvs::load_state(NVS_KEY, VERSION)?.unwrap_or_default())
+}
+
+fn save(stored: &Stored) -> anyhow::Result<()> {
+    nvs::store_blob(NVS_KEY, &nvs::encode_state(VERSION, stored)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i64 = 1000;
+
+    fn config(settings: &[(&str, &str)]) -> Config {
+        settings
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn changes_close_together_are_one_revision() {
+        let mut tracker = Tracker::new();
+
+        assert!(tracker.applied(0));
+        assert!(!tracker.applied(10 * SECOND));
+        assert!(!tracker.applied(35 * SECOND));
+        assert!(tracker.applied(70 * SECOND));
+    }
+
+    #[test]
+    fn repeated_errors_on_probation_roll_back() {
+        let mut tracker = Tracker::new();
+        tracker.applied(0);
+
+        assert_eq!(tracker.error(10 * SECOND), Verdict::Ignore);
+        assert_eq!(tracker.error(20 * SECOND), Verdict::Ignore);
+        assert_eq!(tracker.error(30 * SECOND), Verdict::RollBack);
+        // Once under way, nothing triggers it again
+        assert_eq!(tracker.error(31 * SECOND), Verdict::Ignore);
+        assert!(!tracker.settle(200 * SECOND));
+    }
+
+    #[test]
+    fn errors_after_probation_are_ignored() {
+        let mut tracker = Tracker::new();
+        // Nothing applied, nothing to roll back
+        for at in 0..5 {
+            assert_eq!(tracker.error(at * SECOND), Verdict::Ignore);
+        }
+
+        tracker.applied(0);
+        tracker.error(SECOND);
+        assert!(!tracker.settle(119 * SECOND));
+        assert!(tracker.settle(120 * SECOND));
+        for at in 121..130 {
+            assert_eq!(tracker.error(at * SECOND), Verdict::Ignore);
+        }
+    }
+
+    #[test]
+    fn a_new_change_restarts_probation() {
+        let mut tracker = Tracker::new();
+        tracker.applied(0);
+        tracker.error(SECOND);
+        tracker.error(2 * SECOND);
+
+        tracker.applied(100 * SECOND);
+        assert_eq!(tracker.error(101 * SECOND), Verdict::Ignore);
+        assert_eq!(tracker.error(102 * SECOND), Verdict::Ignore);
+        assert_eq!(tracker.error(200 * SECOND), Verdict::RollBack);
+    }
+
+    #[test]
+    fn a_bad_change_is_rolled_back() {
+        let good = config(&[("alerts.temperature.max", "28"), ("net.push.url", "http://collector/")]);
+        // The bad change sets one limit wrong and adds a template
+        let bad = config(&[
+            ("alerts.temperature.max", "280"),
+            ("net.push.url", "http://collector/"),
+            ("net.push.template", "{{tds}}"),
+        ]);
+
+        let mut tracker = Tracker::new();
+        tracker.applied(0);
+        let verdicts: Vec<_> = (1..=3).map(|i| tracker.error(i * SECOND)).collect();
+        assert_eq!(verdicts.last(), Some(&Verdict::RollBack));
+
+        let changes = diff(&bad, &good);
+        assert_eq!(
+            changes,
+            [("alerts.temperature.max", Some("28")), ("net.push.template", None)]
+        );
+
+        let mut restored = bad.clone();
+        for (name, value) in changes {
+            match value {
+                Some(value) => restored.insert(name.to_owned(), value.to_owned()),
+                None => restored.remove(name),
+            };
+        }
+        assert_eq!(restored, good);
+    }
+}
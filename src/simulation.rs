@@ -0,0 +1,123 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    f32::consts::TAU,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::nvs;
+
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+// Temperature follows a slow sine around 25 °C
+const TEMPERATURE_MEAN: f32 = 25.0;
+const TEMPERATURE_AMPLITUDE: f32 = 0.8;
+const TEMPERATURE_PERIOD: f32 = 6.0 * 3600.0;
+
+// TDS creeps upwards and drops back once in a while, like after a water change
+const TDS_START: f32 = 150.0;
+const TDS_DRIFT_PER_HOUR: f32 = 2.0;
+const TDS_WATER_CHANGE_CHANCE: f32 = 0.0005;
+const TDS_WATER_CHANGE_RATIO: f32 = 0.7;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RSSI: Mutex<Option<(Rng, i32)>> = Mutex::new(None);
+
+/// Whether hardware reads are replaced with synthetic values, as configured by the `sim.enabled` flag.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads the `sim.enabled` flag and the optional `sim.seed`. Returns a generator if simulation is on.
+pub(crate) fn init() -> anyhow::Result<Option<Simulator>> {
+    if !nvs::get_flag("sim.enabled")? {
+        return Ok(None);
+    }
+
+    let seed = nvs::get_parsed_or("sim.seed", DEFAULT_SEED, parse_seed)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    *RSSI.lock().unwrap() = Some((Rng::new(seed ^ 0xa5a5_a5a5), -60));
+
+    Ok(Some(Simulator::new(seed)))
+}
+
+/// Parses `sim.seed`, any 64-bit unsigned number.
+pub(crate) fn parse_seed(v: &str) -> anyhow::Result<u64> {
+    Ok(v.parse()?)
+}
+
+/// Deterministic stand-in for the temperature and TDS probes.
+pub(crate) struct Simulator {
+    rng: Rng,
+    elapsed: f32,
+    tds: f32,
+}
+
+impl Simulator {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            elapsed: 0.0,
+            tds: TDS_START,
+        }
+    }
+
+    /// Advances the simulated clock by `seconds` and returns the temperature and TDS at that point.
+    pub fn step(&mut self, seconds: f32) -> (f32, f32) {
+        self.elapsed += seconds;
+
+        let phase = TAU * self.elapsed / TEMPERATURE_PERIOD;
+        let temperature = TEMPERATURE_MEAN + TEMPERATURE_AMPLITUDE * phase.sin() + self.rng.noise(0.05);
+
+        self.tds += TDS_DRIFT_PER_HOUR * seconds / 3600.0;
+        if self.rng.unit() < TDS_WATER_CHANGE_CHANCE {
+            self.tds *= TDS_WATER_CHANGE_RATIO;
+        }
+        let tds = self.tds + self.rng.noise(1.0);
+
+        (temperature, tds)
+    }
+}
+
+/// Wandering RSSI for the simulated signal quality.
+pub(crate) fn rssi() -> i32 {
+    let mut rssi = RSSI.lock().unwrap();
+    let Some((rng, value)) = rssi.as_mut() else {
+        return -60;
+    };
+
+    *value = (*value + rng.noise(3.0).round() as i32).clamp(-90, -40);
+    *value
+}
+
+/// xorshift64*, small and good enough for synthetic noise.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    fn unit(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1_u64 << 24) as f32
+    }
+
+    /// Uniform in [-amplitude, amplitude).
+    fn noise(&mut self, amplitude: f32) -> f32 {
+        (self.unit() * 2.0 - 1.0) * amplitude
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{ops::RangeInclusive, sync::OnceLock};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::{compensation, nvs};
+
+const ALTITUDE_RANGE: RangeInclusive<f32> = -500.0..=9000.0;
+const PRESSURE_RANGE: RangeInclusive<f32> = 50.0..=110.0;
+
+/// Where the tank is, for conversions that depend on the air pressure.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct Site {
+    /// Meters above sea level.
+    pub altitude: f32,
+    /// Fixed barometric pressure in kPa, overriding the one derived from the altitude.
+    pub pressure: Option<f32>,
+}
+
+impl Site {
+    /// Barometric pressure in kPa, as configured or estimated from the altitude.
+    pub fn pressure(&self) -> f32 {
+        self.pressure
+            .unwrap_or_else(|| compensation::pressure_at_altitude(self.altitude))
+    }
+}
+
+static SITE: OnceLock<Site> = OnceLock::new();
+
+/// Reads and validates `site.altitude` and `site.pressure`.
+pub(crate) fn init() -> anyhow::Result<()> {
+    let altitude = nvs::get_parsed_or("site.altitude", 0.0, parse_altitude)?;
+    let pressure = nvs::get_parsed_or("site.pressure", None, |v| parse_pressure(v).map(Some))?;
+
+    SITE.get_or_init(|| Site { altitude, pressure });
+
+    Ok(())
+}
+
+/// Parses `site.altitude`, in m.
+pub(crate) fn parse_altitude(v: &str) -> anyhow::Result<f32> {
+    let altitude = v.parse()?;
+    if !ALTITUDE_RANGE.contains(&altitude) {
+        return Err(anyhow!("Altitude out of range: {altitude} m"));
+    }
+
+    Ok(altitude)
+}
+
+/// Parses `site.pressure`, in kPa.
+pub(crate) fn parse_pressure(v: &str) -> anyhow::Result<f32> {
+    let pressure = v.parse()?;
+    if !PRESSURE_RANGE.contains(&pressure) {
+        return Err(anyhow!("Barometric pressure out of range: {pressure} kPa"));
+    }
+
+    Ok(pressure)
+}
+
+pub(crate) fn get() -> Site {
+    SITE.get().copied().unwrap_or_default()
+}
@@ -0,0 +1,273 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{ffi::CStr, fmt, sync::OnceLock, time::Duration};
+
+use chrono::{Datelike, Utc};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::{board, journal, labels, lifecycle, nvs};
+
+// The clock starts at the epoch after a power-on reset, anything earlier than this was never set
+const MIN_VALID_YEAR: i32 = 2025;
+
+// Characters of the setup password, leaving out 0, 1, l and o that are easily misread off the display
+const PASSWORD_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+// 60 bits, out of reach of an offline guess at a captured WPA2 handshake
+const PASSWORD_LEN: usize = 12;
+const PASSWORD_KEY: &str = "setup.password";
+
+/// Random per boot, attached to everything the device reports so messages from several units sharing a
+/// receiver can be told apart. Formatted as 8 hex digits.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BootId(pub u32);
+
+impl fmt::Display for BootId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl Serialize for BootId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u32::from_str_radix(&s, 16).map(Self).map_err(de::Error::custom)
+    }
+}
+
+/// Identity of the running firmware, served at `/version` and printed in the boot banner.
+#[derive(Debug, Serialize)]
+pub(crate) struct Version {
+    pub device_id: &'static str,
+    pub device_name: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub partition: String,
+    pub pins: Option<board::Pins>,
+}
+
+impl Version {
+    pub fn current() -> Self {
+        Self {
+            device_id: device_id(),
+            device_name: labels::device_name(),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("COBITIS_GIT_HASH"),
+            partition: running_partition(),
+            pins: board::pins(),
+        }
+    }
+}
+
+/// Label of the app partition the firmware runs from, e.g. `ota_0`.
+pub(crate) fn running_partition() -> String {
+    unsafe {
+        let partition = sys::esp_ota_get_running_partition();
+        if partition.is_null() {
+            "unknown".to_owned()
+        } else {
+            CStr::from_ptr((*partition).label.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+/// The boot banner, `Version` with what only this boot has.
+#[derive(Serialize)]
+struct Banner<'a> {
+    #[serde(flatten)]
+    version: Version,
+    boot_id: BootId,
+    reset_reason: &'a str,
+}
+
+/// Prints a single machine-readable line for provisioning jigs scraping the serial console.
+pub(crate) fn print_boot_banner(reset_reason: &str) {
+    let banner = Banner {
+        version: Version::current(),
+        boot_id: boot_id(),
+        reset_reason,
+    };
+    if let Ok(json) = serde_json::to_string(&banner) {
+        println!("COBITIS_BOOT {json}");
+    }
+}
+
+/// Random per boot, set on first use.
+pub(crate) fn boot_id() -> BootId {
+    static BOOT_ID: OnceLock<BootId> = OnceLock::new();
+
+    *BOOT_ID.get_or_init(|| BootId(unsafe { sys::esp_random() }))
+}
+
+/// 32-bit FNV-1a, cheap enough to run over every response body.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Reboots from a separate thread once `delay` has passed, so the caller can still finish responding.
+pub(crate) fn restart_after(delay: Duration) {
+    lifecycle::transition(lifecycle::State::Rebooting);
+    journal::sync();
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        unsafe { sys::esp_restart() };
+    });
+}
+
+/// Password of the setup access point, drawn at random the first time and kept in NVS, so it stays the
+/// same between setup sessions. Nothing the device broadcasts gives it away, it is only shown on the
+/// display and the serial console.
+///
+/// The hardware RNG is only truly random while the radio is on, so this must be called after WiFi started.
+pub(crate) fn setup_password() -> anyhow::Result<String> {
+    let stored = nvs::load_blob(PASSWORD_KEY)?.and_then(|blob| String::from_utf8(blob).ok());
+    if let Some(password) = stored.filter(|password| password.len() == PASSWORD_LEN) {
+        return Ok(password);
+    }
+
+    let password: String = (0..PASSWORD_LEN)
+        .map(|_| PASSWORD_ALPHABET[unsafe { sys::esp_random() } as usize % PASSWORD_ALPHABET.len()] as char)
+        .collect();
+    nvs::store_blob(PASSWORD_KEY, password.as_bytes())?;
+
+    Ok(password)
+}
+
+/// Stable identifier derived from the factory MAC address, e.g. `cobitis-a1b2c3`.
+pub(crate) fn device_id() -> &'static str {
+    static DEVICE_ID: OnceLock<String> = OnceLock::new();
+
+    DEVICE_ID.get_or_init(|| {
+        let mut mac = [0_u8; 6];
+        unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+        format!("cobitis-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+    })
+}
+
+pub(crate) fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// Time since boot in seconds.
+pub(crate) fn uptime() -> u64 {
+    (uptime_ms() / 1000) as u64
+}
+
+/// Time since boot in milliseconds, monotonic regardless of the wall clock.
+pub(crate) fn uptime_ms() -> i64 {
+    (unsafe { sys::esp_timer_get_time() }) / 1000
+}
+
+/// Whether the wall clock has been set, by NTP or before a software reset.
+pub(crate) fn clock_valid() -> bool {
+    Utc::now().year() >= MIN_VALID_YEAR
+}
+
+pub(crate) fn free_heap() -> u32 {
+    unsafe { sys::esp_get_free_heap_size() }
+}
+
+/// Die temperature from the internal sensor, which is coarse but enough to see thermal trends.
+pub(crate) fn chip_temperature() -> Option<f32> {
+    // The handle is stored as an address, raw pointers are not Sync
+    static SENSOR: OnceLock<Option<usize>> = OnceLock::new();
+
+    let handle = SENSOR.get_or_init(|| {
+        let config = sys::temperature_sensor_config_t {
+            range_min: -10,
+            range_max: 80,
+            ..Default::default()
+        };
+        let mut handle: sys::temperature_sensor_handle_t = std::ptr::null_mut();
+        unsafe {
+            sys::esp!(sys::temperature_sensor_install(&config, &mut handle)).ok()?;
+            sys::esp!(sys::temperature_sensor_enable(handle)).ok()?;
+        }
+        Some(handle as usize)
+    });
+
+    let mut celsius = 0.0;
+    let handle = (*handle)? as sys::temperature_sensor_handle_t;
+    unsafe { sys::esp!(sys::temperature_sensor_get_celsius(handle, &mut celsius)) }.ok()?;
+
+    Some(celsius)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn version() -> Version {
+        Version {
+            device_id: "cobitis-a1b2c3",
+            device_name: "Tank 2",
+            version: "1.4.0",
+            git_hash: "0348710",
+            partition: "ota_1".to_owned(),
+            pins: None,
+        }
+    }
+
+    // Provisioning jigs parse these, renaming a field breaks them
+    #[test]
+    fn version_and_banner_keep_their_field_names() {
+        let expected = json!({
+            "device_id": "cobitis-a1b2c3",
+            "device_name": "Tank 2",
+            "version": "1.4.0",
+            "git_hash": "0348710",
+            "partition": "ota_1",
+            "pins": null,
+        });
+        assert_eq!(serde_json::to_value(version()).unwrap(), expected);
+
+        let banner = Banner {
+            version: version(),
+            boot_id: BootId(0x00c0_ffee),
+            reset_reason: "power_on",
+        };
+        let mut expected = expected;
+        expected["boot_id"] = json!("00c0ffee");
+        expected["reset_reason"] = json!("power_on");
+        assert_eq!(serde_json::to_value(banner).unwrap(), expected);
+    }
+
+    #[test]
+    fn boot_ids_round_trip_as_eight_hex_digits() {
+        for id in [0, 0x00c0_ffee, u32::MAX] {
+            let json = serde_json::to_value(BootId(id)).unwrap();
+            assert_eq!(json.as_str().unwrap().len(), 8, "{json}");
+            assert_eq!(serde_json::from_value::<BootId>(json).unwrap(), BootId(id));
+        }
+        assert!(serde_json::from_value::<BootId>(json!("not hex")).is_err());
+    }
+}
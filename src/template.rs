@@ -0,0 +1,234 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Fills `{{name}}` placeholders in a payload template set by the user, for receivers that expect a shape
+// of their own, e.g. `{"water":{{temperature}},"at":"{{timestamp_iso}}"}`. Templates are checked when
+// written through `/config`, so a bad one never gets as far as a delivery.
+
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat};
+
+use crate::{alerts, measurements, nvs, registry, system};
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+pub(crate) const PLACEHOLDERS: [&str; 5] = ["temperature", "tds", "timestamp_iso", "device_id", "alarm_name"];
+
+/// Values substituted for the placeholders, `None` for one that has nothing to show.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Fields {
+    pub temperature: Option<String>,
+    pub tds: Option<String>,
+    pub timestamp_iso: Option<String>,
+    pub device_id: Option<String>,
+    pub alarm_name: Option<String>,
+}
+
+impl Fields {
+    /// The fields of a measurement, with the alarm raised when it was taken, e.g. `tds_max`.
+    pub fn new(values: &measurements::Values) -> Self {
+        let reading = |enabled: bool, value: f32, metric: &registry::Metric| {
+            (enabled && value.is_finite()).then(|| format!("{:.*}", metric.precision, value))
+        };
+        let alarm_name = measurements::Sensor::ALL.into_iter().find_map(|sensor| {
+            alerts::alarm(sensor).map(|level| {
+                let limit = match level {
+                    alerts::Level::Low => "min",
+                    alerts::Level::High => "max",
+                };
+                format!("{}_{limit}", sensor.name())
+            })
+        });
+
+        Self {
+            temperature: reading(values.temperature_enabled, values.temperature, &registry::TEMPERATURE),
+            tds: reading(values.tds_enabled, values.tds, &registry::TDS),
+            timestamp_iso: values
+                .clock_valid
+                .then(|| DateTime::from_timestamp_millis(values.timestamp))
+                .flatten()
+                .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            device_id: Some(system::device_id().to_owned()),
+            alarm_name,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "temperature" => self.temperature.as_deref(),
+            "tds" => self.tds.as_deref(),
+            "timestamp_iso" => self.timestamp_iso.as_deref(),
+            "device_id" => self.device_id.as_deref(),
+            "alarm_name" => self.alarm_name.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// How values are written into a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Escape {
+    /// Escaped for use inside a JSON string, a missing value becoming `null`. Numbers go unquoted, strings
+    /// between quotes in the template.
+    Json,
+    /// As they are, a missing value becoming empty.
+    Raw,
+}
+
+/// The template in `setting` with its escaping, JSON unless `json_setting` is off.
+pub(crate) fn load(setting: &str, json_setting: &str) -> anyhow::Result<Option<(String, Escape)>> {
+    let Some(template) = nvs::get_opt(setting)? else {
+        return Ok(None);
+    };
+    let escape = if nvs::get_flag_or(json_setting, true)? {
+        Escape::Json
+    } else {
+        Escape::Raw
+    };
+
+    Ok(Some((template, escape)))
+}
+
+/// Checks that every placeholder of `template` is known and closed.
+pub(crate) fn validate(template: &str) -> anyhow::Result<()> {
+    render(template, Escape::Raw, &Fields::default()).map(drop)
+}
+
+/// Fills the placeholders of `template` with `fields`. Braces around a placeholder stay as they are, so
+/// `{{{tds}}}` gives `{300}`.
+pub(crate) fn render(template: &str, escape: Escape, fields: &Fields) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        // Extra opening braces are literal, the placeholder starts at the last two
+        let extra = rest[start + OPEN.len()..].bytes().take_while(|&b| b == b'{').count();
+        out.push_str(&rest[..start + extra]);
+        rest = &rest[start + extra + OPEN.len()..];
+
+        let end = rest
+            .find(CLOSE)
+            .ok_or_else(|| anyhow!("Unclosed placeholder at {OPEN}{rest}"))?;
+        let name = rest[..end].trim();
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(anyhow!(
+                "Unknown placeholder {OPEN}{name}{CLOSE}, expected one of {}",
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        match (fields.get(name), escape) {
+            (Some(value), Escape::Json) => escape_json(&mut out, value),
+            (Some(value), Escape::Raw) => out.push_str(value),
+            (None, Escape::Json) => out.push_str("null"),
+            (None, Escape::Raw) => {}
+        }
+        rest = &rest[end + CLOSE.len()..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+fn escape_json(out: &mut String, value: &str) {
+    // A JSON string of the value, minus its quotes
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    out.push_str(&quoted[1..quoted.len() - 1]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        Fields {
+            temperature: Some("25.5".to_owned()),
+            tds: Some("300.4".to_owned()),
+            timestamp_iso: Some("2023-11-14T22:13:20Z".to_owned()),
+            device_id: Some("cobitis-a1b2c3".to_owned()),
+            alarm_name: None,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let template = r#"{"t":{{temperature}},"tds":{{ tds }},"at":"{{timestamp_iso}}","id":"{{device_id}}"}"#;
+        assert_eq!(
+            render(template, Escape::Json, &fields()).unwrap(),
+            r#"{"t":25.5,"tds":300.4,"at":"2023-11-14T22:13:20Z","id":"cobitis-a1b2c3"}"#
+        );
+        assert_eq!(
+            render("tank {{device_id}} at {{temperature}} C", Escape::Raw, &fields()).unwrap(),
+            "tank cobitis-a1b2c3 at 25.5 C"
+        );
+        assert_eq!(
+            render("no placeholders", Escape::Json, &fields()).unwrap(),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn json_escaping_keeps_strings_intact() {
+        let mut fields = fields();
+        fields.device_id = Some("tank \"2\"\\back\nroom\u{1}".to_owned());
+
+        let rendered = render(r#"{"id":"{{device_id}}"}"#, Escape::Json, &fields).unwrap();
+        assert_eq!(rendered, r#"{"id":"tank \"2\"\\back\nroom\u0001"}"#);
+        let parsed: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(parsed["id"], "tank \"2\"\\back\nroom\u{1}");
+
+        // Raw leaves them alone
+        assert_eq!(
+            render("{{device_id}}", Escape::Raw, &fields).unwrap(),
+            "tank \"2\"\\back\nroom\u{1}"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_null_or_empty() {
+        let mut fields = fields();
+        fields.temperature = None;
+
+        assert_eq!(
+            render(r#"{"t":{{temperature}},"alarm":{{alarm_name}}}"#, Escape::Json, &fields).unwrap(),
+            r#"{"t":null,"alarm":null}"#
+        );
+        assert_eq!(render("t={{temperature}};", Escape::Raw, &fields).unwrap(), "t=;");
+    }
+
+    #[test]
+    fn braces_around_placeholders_are_literal() {
+        assert_eq!(render("{{{tds}}}", Escape::Raw, &fields()).unwrap(), "{300.4}");
+        assert_eq!(render("{{{{tds}}}}", Escape::Raw, &fields()).unwrap(), "{{300.4}}");
+        assert_eq!(
+            render(r#"{"a":{"b":{{tds}}}}"#, Escape::Json, &fields()).unwrap(),
+            r#"{"a":{"b":300.4}}"#
+        );
+        // Single braces and stray closing ones never start anything
+        assert_eq!(render("{tds} }}", Escape::Raw, &fields()).unwrap(), "{tds} }}");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_rejected() {
+        for template in [
+            "{{humidity}}",
+            "{{}}",
+            "{{ Temperature }}",
+            "{{tds",
+            "{{tds}",
+            "x {{device_id} y",
+        ] {
+            assert!(validate(template).is_err(), "{template:?}");
+        }
+        for template in [
+            "",
+            "{}",
+            "{{tds}}",
+            r#"{"id":"{{device_id}}","alarm":"{{alarm_name}}"}"#,
+        ] {
+            assert!(validate(template).is_ok(), "{template:?}");
+        }
+    }
+}
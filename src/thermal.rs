@@ -0,0 +1,94 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use tokio::{
+    task,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{lifecycle, nvs, system};
+
+const INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_LIMIT: f32 = 80.0;
+// Below this the die would throttle at room temperature, above it the sensor reads nothing useful
+const LIMIT_RANGE: RangeInclusive<f32> = 40.0..=125.0;
+// Throttling ends only once the die has cooled this far below the limit, so it doesn't flap
+const HYSTERESIS: f32 = 5.0;
+
+/// Work scaled back while throttled, named as reported in `/diagnostics`.
+const ACTIONS: &[&str] = &["wifi_tx_power", "display_refresh", "heavy_endpoints"];
+
+static THROTTLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) struct Context {
+    limit: f32,
+}
+
+/// Whether nonessential work is scaled back because the die is too hot.
+pub(crate) fn throttled() -> bool {
+    THROTTLED.load(Ordering::Relaxed)
+}
+
+/// Throttling actions currently in effect.
+pub(crate) fn actions() -> &'static [&'static str] {
+    if throttled() { ACTIONS } else { &[] }
+}
+
+pub(crate) fn init() -> anyhow::Result<Box<Context>> {
+    let limit = nvs::get_parsed_or("system.thermal_limit", DEFAULT_LIMIT, parse_limit)?;
+
+    Ok(Box::new(Context { limit }))
+}
+
+/// Parses `system.thermal_limit`, in °C.
+pub(crate) fn parse_limit(v: &str) -> anyhow::Result<f32> {
+    let limit = v.parse()?;
+    if !LIMIT_RANGE.contains(&limit) {
+        return Err(anyhow!("Thermal limit must be within {LIMIT_RANGE:?} °C: {limit}"));
+    }
+
+    Ok(limit)
+}
+
+pub(crate) async fn worker(ctx: &mut Box<Context>) -> anyhow::Result<()> {
+    let mut interval = interval(INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        task::block_in_place(|| update(ctx));
+    }
+}
+
+fn update(ctx: &Context) {
+    let Some(temperature) = system::chip_temperature() else {
+        return;
+    };
+
+    if !throttled() && temperature >= ctx.limit {
+        THROTTLED.store(true, Ordering::Relaxed);
+        lifecycle::set_throttled(true);
+        warn!(
+            "Die temperature {temperature:.1} °C reached {:.1} °C, throttling",
+            ctx.limit
+        );
+        for action in ACTIONS {
+            warn!("Throttling {action}");
+        }
+    } else if throttled() && temperature <= ctx.limit - HYSTERESIS {
+        THROTTLED.store(false, Ordering::Relaxed);
+        lifecycle::set_throttled(false);
+        info!("Die temperature {temperature:.1} °C, throttling ended");
+    }
+}
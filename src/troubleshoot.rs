@@ -0,0 +1,521 @@
+// Copyright © 2025 Akira Miyakoda
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+// Walks through the network on the display one check at a time, in words for someone who has never
+// heard of `/diagnostics`. Each check is a plain function of what was observed, the worker only gathers
+// the observations and paces the screens.
+
+use std::{
+    net::{Ipv4Addr, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use log::info;
+use serde::Serialize;
+use tokio::{
+    sync::Notify,
+    task,
+    time::{sleep, timeout},
+};
+
+use crate::{display, network, nvs};
+
+/// Checks in the order they run, each one only meaningful once the previous passed.
+pub(crate) const STEPS: [Step; 5] = [
+    Step::ApVisible,
+    Step::Associated,
+    Step::GotIp,
+    Step::Internet,
+    Step::Ntp,
+];
+
+// Long enough to read a verdict before the next check replaces it
+const STEP_DWELL: Duration = Duration::from_secs(3);
+// The suggestion stays up this long, then the normal screens return
+const ADVICE_DURATION: Duration = Duration::from_secs(60);
+
+// Any host reachable from a working uplink, the check only opens a TCP connection to it
+const INTERNET_PROBE: (&str, u16) = ("example.com", 80);
+const INTERNET_TIMEOUT: Duration = Duration::from_secs(5);
+// SNTP polls hourly by default, a sync older than two polls means the servers stopped answering
+const NTP_MAX_AGE: Duration = Duration::from_secs(2 * 3600);
+
+const ALL_GOOD: &str = "All good. If readings still don't arrive, check the receiving end.";
+
+// Raised by our own disconnect between connection attempts, it says nothing about the network
+const REASON_ASSOC_LEAVE: u16 = 8;
+const REASON_NO_AP_FOUND: u16 = 201;
+
+/// What a group of WiFi disconnect reason codes means to someone at home, and what to do about it.
+struct Reason {
+    codes: &'static [u16],
+    text: &'static str,
+    advice: &'static str,
+}
+
+const NOT_IN_RANGE: Reason = Reason {
+    codes: &[REASON_NO_AP_FOUND],
+    text: "not in range?",
+    advice: "Check the router is on, or move the device closer to it.",
+};
+
+// Codes from `wifi_err_reason_t`, the handshake failures all look like a wrong password from here
+const REASONS: &[Reason] = &[
+    Reason {
+        codes: &[2, 14, 15, 16, 23, 202, 204],
+        text: "wrong password?",
+        advice: "Check the WiFi password, then enter it again on the setup page.",
+    },
+    NOT_IN_RANGE,
+    Reason {
+        codes: &[210, 211],
+        text: "security mismatch",
+        advice: "Set the router to WPA2 or WPA2/WPA3 mixed mode.",
+    },
+    Reason {
+        codes: &[200, 212],
+        text: "signal too weak",
+        advice: "Move the device or the router so they are closer.",
+    },
+    Reason {
+        codes: &[5],
+        text: "router full",
+        advice: "Disconnect another device or restart the router.",
+    },
+    Reason {
+        codes: &[3, 4, 6, 7, 203, 205],
+        text: "router refused",
+        advice: "Restart the router and check it doesn't block new devices.",
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Step {
+    ApVisible,
+    Associated,
+    GotIp,
+    Internet,
+    Ntp,
+}
+
+impl Step {
+    /// The question titling the step's screen.
+    pub fn question(&self) -> &'static str {
+        match self {
+            Self::ApVisible => "AP visible?",
+            Self::Associated => "Associated?",
+            Self::GotIp => "Got IP?",
+            Self::Internet => "Internet?",
+            Self::Ntp => "NTP?",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    Yes,
+    No,
+    /// Not run, an earlier check already failed.
+    Skipped,
+}
+
+/// Result of one check in plain language.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Verdict {
+    pub step: Step,
+    pub outcome: Outcome,
+    /// What was seen, e.g. `-58 dBm`, or the likely cause of a failure.
+    pub detail: String,
+    /// What to do about a failure, empty when there is nothing to do.
+    pub advice: &'static str,
+}
+
+impl Verdict {
+    fn yes(step: Step, detail: String) -> Self {
+        Self {
+            step,
+            outcome: Outcome::Yes,
+            detail,
+            advice: "",
+        }
+    }
+
+    fn no(step: Step, detail: &str, advice: &'static str) -> Self {
+        Self {
+            step,
+            outcome: Outcome::No,
+            detail: detail.to_owned(),
+            advice,
+        }
+    }
+
+    fn skipped(step: Step) -> Self {
+        Self {
+            step,
+            outcome: Outcome::Skipped,
+            detail: String::new(),
+            advice: "",
+        }
+    }
+
+    /// One line for the panel, e.g. `yes (-58 dBm)` or `no - wrong password?`.
+    pub fn summary(&self) -> String {
+        match (self.outcome, self.detail.is_empty()) {
+            (Outcome::Skipped, _) => "-".to_owned(),
+            (Outcome::Yes, true) => "yes".to_owned(),
+            (Outcome::Yes, false) => format!("yes ({})", self.detail),
+            (Outcome::No, true) => "no".to_owned(),
+            (Outcome::No, false) => format!("no - {}", self.detail),
+        }
+    }
+}
+
+/// Where the troubleshooting is, shown on the display.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum State {
+    Idle,
+    /// `verdicts` includes the one for `step` once it is in, while it stays up for reading.
+    Running {
+        step: Step,
+        verdicts: Vec<Verdict>,
+    },
+    Done {
+        verdicts: Vec<Verdict>,
+        advice: &'static str,
+    },
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Idle);
+static START: Notify = Notify::const_new();
+
+pub(crate) fn state() -> State {
+    STATE.lock().unwrap().clone()
+}
+
+/// Starts the checks unless they are already running, or runs them again over the last suggestion.
+pub(crate) fn start() -> anyhow::Result<()> {
+    if matches!(*STATE.lock().unwrap(), State::Running { .. }) {
+        return Err(anyhow!("Network troubleshooting already running"));
+    }
+
+    display::wake();
+    START.notify_one();
+    Ok(())
+}
+
+pub(crate) async fn worker() -> anyhow::Result<()> {
+    loop {
+        START.notified().await;
+
+        loop {
+            run().await;
+            if timeout(ADVICE_DURATION, START.notified()).await.is_err() {
+                break;
+            }
+        }
+        *STATE.lock().unwrap() = State::Idle;
+    }
+}
+
+async fn run() {
+    info!("Network troubleshooting started");
+
+    let mut verdicts: Vec<Verdict> = Vec::new();
+    for step in STEPS {
+        *STATE.lock().unwrap() = State::Running {
+            step,
+            verdicts: verdicts.clone(),
+        };
+
+        // After a failure the rest go by without a pause, there is nothing new to read on them
+        let failed = verdicts.iter().any(|verdict| verdict.outcome != Outcome::Yes);
+        let verdict = if failed {
+            Verdict::skipped(step)
+        } else {
+            task::block_in_place(|| observe(step))
+        };
+        info!("Network check {}: {}", step.question(), verdict.summary());
+        verdicts.push(verdict);
+
+        if !failed {
+            *STATE.lock().unwrap() = State::Running {
+                step,
+                verdicts: verdicts.clone(),
+            };
+            sleep(STEP_DWELL).await;
+        }
+    }
+
+    let advice = advice(&verdicts);
+    info!("Network troubleshooting done: {advice}");
+    *STATE.lock().unwrap() = State::Done { verdicts, advice };
+    display::wake();
+}
+
+// Gathers what a check needs from the live system
+fn observe(step: Step) -> Verdict {
+    match step {
+        Step::ApVisible => {
+            let ssid = nvs::get_opt("net.wifi.ssid").ok().flatten();
+            let scan = match &ssid {
+                Some(ssid) => network::find_ap(ssid).map_err(|e| format!("{e:#}")),
+                None => Ok(None),
+            };
+            check_ap_visible(ssid.is_some(), scan, network::last_disconnect())
+        }
+        Step::Associated => check_associated(network::associated(), network::last_disconnect()),
+        Step::GotIp => check_ip(network::ip()),
+        Step::Internet => check_internet(probe_internet()),
+        Step::Ntp => check_ntp(
+            network::ntp_status().map(|status| status.last_sync),
+            Utc::now().timestamp_millis(),
+        ),
+    }
+}
+
+fn probe_internet() -> Result<Duration, &'static str> {
+    let started = Instant::now();
+    let address = INTERNET_PROBE
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or("no DNS")?;
+    TcpStream::connect_timeout(&address, INTERNET_TIMEOUT).map_err(|_| "blocked?")?;
+
+    Ok(started.elapsed())
+}
+
+/// Whether the configured network is in range, by a scan or, when the radio can't scan, by how the last
+/// connection attempt ended.
+fn check_ap_visible(configured: bool, scan: Result<Option<i8>, String>, reason: Option<u16>) -> Verdict {
+    const STEP: Step = Step::ApVisible;
+
+    if !configured {
+        return Verdict::no(
+            STEP,
+            "no WiFi set up",
+            "Join the setup network shown on the display and enter your WiFi.",
+        );
+    }
+    match (scan, reason) {
+        (Ok(Some(rssi)), _) => Verdict::yes(STEP, format!("{rssi} dBm")),
+        (Ok(None), _) | (Err(_), Some(REASON_NO_AP_FOUND)) => {
+            Verdict::no(STEP, NOT_IN_RANGE.text, NOT_IN_RANGE.advice)
+        }
+        // Any other failure means the access point answered
+        (Err(_), Some(_)) => Verdict::yes(STEP, "seen earlier".to_owned()),
+        (Err(_), None) => Verdict::no(STEP, "can't scan", "Restart the device and run the check again."),
+    }
+}
+
+/// Whether the device joined the network, explaining the last disconnect reason when it didn't.
+fn check_associated(associated: bool, reason: Option<u16>) -> Verdict {
+    const STEP: Step = Step::Associated;
+
+    if associated {
+        return Verdict::yes(STEP, String::new());
+    }
+    match reason.and_then(explain) {
+        Some(reason) => Verdict::no(STEP, reason.text, reason.advice),
+        None => Verdict::no(STEP, "not connected", "Restart the router, then the device."),
+    }
+}
+
+fn check_ip(ip: Option<Ipv4Addr>) -> Verdict {
+    const STEP: Step = Step::GotIp;
+
+    match ip {
+        Some(ip) => Verdict::yes(STEP, ip.to_string()),
+        None => Verdict::no(
+            STEP,
+            "router gave none",
+            "Restart the router, it may have run out of addresses.",
+        ),
+    }
+}
+
+fn check_internet(probe: Result<Duration, &'static str>) -> Verdict {
+    const STEP: Step = Step::Internet;
+
+    match probe {
+        Ok(elapsed) => Verdict::yes(STEP, format!("{} ms", elapsed.as_millis())),
+        Err(cause) => Verdict::no(STEP, cause, "Check the router's internet connection."),
+    }
+}
+
+/// Whether the clock was synchronized recently, from the wall clock time of the last sync in ms.
+fn check_ntp(last_sync: Option<i64>, now: i64) -> Verdict {
+    const STEP: Step = Step::Ntp;
+    const ADVICE: &str = "Time servers don't answer, check net.ntp.servers or the firewall.";
+
+    match last_sync {
+        None => Verdict::no(STEP, "never synced", ADVICE),
+        Some(last_sync) if now - last_sync > NTP_MAX_AGE.as_millis() as i64 => {
+            Verdict::no(STEP, &format!("{}h old", (now - last_sync) / 3_600_000), ADVICE)
+        }
+        Some(last_sync) => Verdict::yes(STEP, format!("{}m ago", (now - last_sync) / 60_000)),
+    }
+}
+
+fn explain(code: u16) -> Option<&'static Reason> {
+    REASONS.iter().find(|reason| reason.codes.contains(&code))
+}
+
+/// Whether a disconnect reason is worth keeping for the checks, our own disconnects being noise.
+pub(crate) fn is_meaningful(code: u16) -> bool {
+    code != REASON_ASSOC_LEAVE
+}
+
+// The first failure is the one to fix, the later checks never ran
+fn advice(verdicts: &[Verdict]) -> &'static str {
+    verdicts
+        .iter()
+        .find(|verdict| verdict.outcome == Outcome::No)
+        .map_or(ALL_GOOD, |verdict| verdict.advice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE_MS: i64 = 60_000;
+
+    fn outcomes(verdict: &Verdict) -> (Outcome, &str) {
+        (verdict.outcome, verdict.detail.as_str())
+    }
+
+    #[test]
+    fn ap_is_visible_by_scan_or_by_how_the_last_attempt_ended() {
+        let unscannable = || Err("ESP_ERR_WIFI_STATE".to_owned());
+        for (configured, scan, reason, expected) in [
+            (true, Ok(Some(-58)), None, (Outcome::Yes, "-58 dBm")),
+            // A scan that answers outweighs an older disconnect
+            (true, Ok(Some(-71)), Some(REASON_NO_AP_FOUND), (Outcome::Yes, "-71 dBm")),
+            (true, Ok(None), None, (Outcome::No, "not in range?")),
+            (
+                true,
+                unscannable(),
+                Some(REASON_NO_AP_FOUND),
+                (Outcome::No, "not in range?"),
+            ),
+            (true, unscannable(), Some(15), (Outcome::Yes, "seen earlier")),
+            (true, unscannable(), None, (Outcome::No, "can't scan")),
+            (false, Ok(Some(-58)), None, (Outcome::No, "no WiFi set up")),
+        ] {
+            let verdict = check_ap_visible(configured, scan.clone(), reason);
+            assert_eq!(verdict.step, Step::ApVisible);
+            assert_eq!(outcomes(&verdict), expected, "{configured} {scan:?} {reason:?}");
+            assert_eq!(verdict.advice.is_empty(), verdict.outcome == Outcome::Yes);
+        }
+    }
+
+    #[test]
+    fn association_failures_are_explained_by_their_reason_code() {
+        let verdict = check_associated(true, Some(15));
+        assert_eq!(outcomes(&verdict), (Outcome::Yes, ""));
+        assert_eq!(verdict.summary(), "yes");
+
+        for (reason, expected) in [
+            (Some(15), "wrong password?"),
+            (Some(204), "wrong password?"),
+            (Some(REASON_NO_AP_FOUND), "not in range?"),
+            (Some(210), "security mismatch"),
+            (Some(200), "signal too weak"),
+            (Some(5), "router full"),
+            (Some(203), "router refused"),
+            // Unknown codes and no code at all still get a suggestion
+            (Some(999), "not connected"),
+            (None, "not connected"),
+        ] {
+            let verdict = check_associated(false, reason);
+            assert_eq!(outcomes(&verdict), (Outcome::No, expected), "{reason:?}");
+            assert!(!verdict.advice.is_empty(), "{reason:?}");
+        }
+    }
+
+    #[test]
+    fn reason_codes_map_to_one_explanation_each() {
+        for (i, reason) in REASONS.iter().enumerate() {
+            for code in reason.codes {
+                assert!(
+                    REASONS[i + 1..].iter().all(|other| !other.codes.contains(code)),
+                    "{code} explained twice"
+                );
+                assert_eq!(explain(*code).map(|r| r.text), Some(reason.text));
+            }
+        }
+        assert!(explain(REASON_ASSOC_LEAVE).is_none());
+
+        assert!(!is_meaningful(REASON_ASSOC_LEAVE));
+        assert!(is_meaningful(REASON_NO_AP_FOUND));
+        assert!(is_meaningful(15));
+    }
+
+    #[test]
+    fn ip_and_internet_show_what_was_seen() {
+        let verdict = check_ip(Some(Ipv4Addr::new(192, 168, 1, 23)));
+        assert_eq!(verdict.summary(), "yes (192.168.1.23)");
+        assert_eq!(outcomes(&check_ip(None)), (Outcome::No, "router gave none"));
+
+        let verdict = check_internet(Ok(Duration::from_millis(84)));
+        assert_eq!(
+            (verdict.step, verdict.summary().as_str()),
+            (Step::Internet, "yes (84 ms)")
+        );
+        for cause in ["no DNS", "blocked?"] {
+            let verdict = check_internet(Err(cause));
+            assert_eq!(outcomes(&verdict), (Outcome::No, cause));
+            assert_eq!(verdict.summary(), format!("no - {cause}"));
+        }
+    }
+
+    #[test]
+    fn ntp_fails_once_two_polls_are_missed() {
+        let now = 1_700_000_000_000;
+        let max_age = NTP_MAX_AGE.as_millis() as i64;
+        for (last_sync, expected) in [
+            (Some(now - 5 * MINUTE_MS), (Outcome::Yes, "5m ago")),
+            (Some(now - max_age), (Outcome::Yes, "120m ago")),
+            (Some(now - max_age - 1), (Outcome::No, "2h old")),
+            (Some(now - 30 * 60 * MINUTE_MS), (Outcome::No, "30h old")),
+            (None, (Outcome::No, "never synced")),
+        ] {
+            let verdict = check_ntp(last_sync, now);
+            assert_eq!(verdict.step, Step::Ntp);
+            assert_eq!(outcomes(&verdict), expected, "{last_sync:?}");
+        }
+    }
+
+    #[test]
+    fn advice_comes_from_the_first_failure() {
+        let passed = Verdict::yes(Step::ApVisible, "-58 dBm".to_owned());
+        let no_password = check_associated(false, Some(15));
+        let no_ip = check_ip(None);
+
+        assert_eq!(advice(&[]), ALL_GOOD);
+        assert_eq!(advice(&[passed.clone()]), ALL_GOOD);
+        assert_eq!(
+            advice(&[passed.clone(), no_password.clone(), Verdict::skipped(Step::GotIp)]),
+            no_password.advice
+        );
+        // Skipped checks are no failures of their own
+        assert_eq!(advice(&[Verdict::skipped(Step::ApVisible), passed]), ALL_GOOD);
+        assert_eq!(advice(&[no_ip.clone(), no_password]), no_ip.advice);
+    }
+
+    #[test]
+    fn summaries_read_as_one_line() {
+        assert_eq!(Verdict::skipped(Step::Ntp).summary(), "-");
+        assert_eq!(check_associated(false, Some(15)).summary(), "no - wrong password?");
+        assert_eq!(Verdict::no(Step::Ntp, "", "").summary(), "no");
+        assert_eq!(check_ap_visible(true, Ok(Some(-58)), None).summary(), "yes (-58 dBm)");
+    }
+}